@@ -0,0 +1,304 @@
+//! Streaming encoder and decoder for the CaRT container format.
+//!
+//! CaRT neuters a file so it can be stored or moved around without being executed or flagged by
+//! anti-virus software. A CaRT file is laid out as:
+//!
+//! - a mandatory header: magic, version, the RC4 key and the size of the optional header
+//! - an optional JSON header, RC4 encrypted
+//! - the body, zlib compressed then RC4 encrypted
+//! - an optional JSON footer, RC4 encrypted, holding the digests of the original content
+//! - a mandatory footer locating the optional footer
+//!
+//! The encoder and decoder here don't do any IO themselves, data is pushed in as it arrives and the
+//! output for that data is handed back, so they can sit between a blob stream and a network response.
+
+use anyhow::{bail, Context, Result};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use rc4::{KeyInit, StreamCipher};
+use sha2::Digest;
+
+/// Metadata carried in the header and footer of a CaRT file
+pub type JsonMap = serde_json::Map<String, serde_json::Value>;
+
+type Rc4 = rc4::Rc4<rc4::consts::U16>;
+
+const HEADER_MAGIC: &[u8; 4] = b"CART";
+const FOOTER_MAGIC: &[u8; 4] = b"TRAC";
+const MAJOR_VERSION: i16 = 1;
+const RESERVED: u64 = 0;
+const MANDATORY_HEADER_SIZE: usize = 38;
+const MANDATORY_FOOTER_SIZE: usize = 28;
+
+/// The default key is the first 8 digits of pi, twice
+const DEFAULT_RC4_KEY: [u8; 16] = [3, 1, 4, 1, 5, 9, 2, 6, 3, 1, 4, 1, 5, 9, 2, 6];
+
+/// Largest optional header or footer that will be accepted while decoding
+const MAX_METADATA_SIZE: usize = 1 << 20;
+
+/// How much room to make in output buffers between calls to zlib
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Check if data, such as the first bytes of a blob, starts like a CaRT file.
+/// At least six bytes are needed to tell.
+pub fn is_cart(data: &[u8]) -> bool {
+    data.len() >= 6 && data.starts_with(HEADER_MAGIC) && data[4..6] == MAJOR_VERSION.to_le_bytes()
+}
+
+fn cipher(key: &[u8]) -> Result<Rc4> {
+    Rc4::new_from_slice(key).map_err(|_| anyhow::anyhow!("Invalid CaRT key"))
+}
+
+/// Packs data into a CaRT file as it is provided
+pub struct CartEncoder {
+    /// Bytes produced before any data was given, the mandatory and optional headers
+    pending: Vec<u8>,
+    cipher: Rc4,
+    deflate: Compress,
+    /// Size of both headers, where the body starts
+    header_size: u64,
+    footer: JsonMap,
+    md5: md5::Md5,
+    sha1: sha1::Sha1,
+    sha256: sha2::Sha256,
+    length: u64,
+}
+
+impl CartEncoder {
+    /// Start a CaRT file carrying the given header, the footer is extended with the digests of the data
+    pub fn new(header: Option<JsonMap>, footer: Option<JsonMap>) -> Result<Self> {
+        let mut optional_header = match header {
+            Some(header) => serde_json::to_vec(&header)?,
+            None => vec![],
+        };
+        cipher(&DEFAULT_RC4_KEY)?.apply_keystream(&mut optional_header);
+
+        let mut pending = Vec::with_capacity(MANDATORY_HEADER_SIZE + optional_header.len());
+        pending.extend_from_slice(HEADER_MAGIC);
+        pending.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        pending.extend_from_slice(&RESERVED.to_le_bytes());
+        pending.extend_from_slice(&DEFAULT_RC4_KEY);
+        pending.extend_from_slice(&(optional_header.len() as u64).to_le_bytes());
+        pending.extend_from_slice(&optional_header);
+
+        Ok(Self {
+            header_size: pending.len() as u64,
+            pending,
+            cipher: cipher(&DEFAULT_RC4_KEY)?,
+            deflate: Compress::new(Compression::fast(), true),
+            footer: footer.unwrap_or_default(),
+            md5: Default::default(),
+            sha1: Default::default(),
+            sha256: Default::default(),
+            length: 0,
+        })
+    }
+
+    /// Encode the next part of the content, returning whatever output is ready
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.md5.update(data);
+        self.sha1.update(data);
+        self.sha256.update(data);
+        self.length += data.len() as u64;
+
+        let mut output = std::mem::take(&mut self.pending);
+        let start = output.len();
+        let mut consumed = 0;
+        while consumed < data.len() {
+            output.reserve(CHUNK_SIZE);
+            let before = self.deflate.total_in();
+            self.deflate.compress_vec(&data[consumed..], &mut output, FlushCompress::None)?;
+            consumed += (self.deflate.total_in() - before) as usize;
+        }
+        self.cipher.apply_keystream(&mut output[start..]);
+        Ok(output)
+    }
+
+    /// Complete the body and write out the footers
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut output = std::mem::take(&mut self.pending);
+        let start = output.len();
+        loop {
+            output.reserve(CHUNK_SIZE);
+            if self.deflate.compress_vec(&[], &mut output, FlushCompress::Finish)? == Status::StreamEnd {
+                break
+            }
+        }
+        self.cipher.apply_keystream(&mut output[start..]);
+
+        // the optional footer goes right after the body
+        let footer_position = self.header_size + self.deflate.total_out();
+
+        self.footer.insert("md5".to_owned(), format!("{:x}", self.md5.finalize()).into());
+        self.footer.insert("sha1".to_owned(), format!("{:x}", self.sha1.finalize()).into());
+        self.footer.insert("sha256".to_owned(), format!("{:x}", self.sha256.finalize()).into());
+        self.footer.insert("length".to_owned(), self.length.to_string().into());
+        let mut optional_footer = serde_json::to_vec(&self.footer)?;
+        cipher(&DEFAULT_RC4_KEY)?.apply_keystream(&mut optional_footer);
+        output.extend_from_slice(&optional_footer);
+
+        output.extend_from_slice(FOOTER_MAGIC);
+        output.extend_from_slice(&RESERVED.to_le_bytes());
+        output.extend_from_slice(&footer_position.to_le_bytes());
+        output.extend_from_slice(&(optional_footer.len() as u64).to_le_bytes());
+        Ok(output)
+    }
+
+}
+
+enum Stage {
+    /// Collecting the mandatory and optional headers
+    Header,
+    /// Unpacking the body
+    Body { cipher: Box<Rc4>, inflate: Decompress },
+    /// Collecting everything after the body, the footers
+    Trailer,
+}
+
+/// Unpacks a CaRT file as it is provided
+pub struct CartDecoder {
+    stage: Stage,
+    /// Header or trailer bytes that haven't been parsed yet
+    buffer: Vec<u8>,
+    key: Vec<u8>,
+    header: Option<JsonMap>,
+    /// Digest and size of the content produced, checked against the footer
+    sha256: sha2::Sha256,
+    length: u64,
+}
+
+impl Default for CartDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CartDecoder {
+    /// Start decoding a CaRT file
+    pub fn new() -> Self {
+        Self { stage: Stage::Header, buffer: vec![], key: vec![], header: None, sha256: Default::default(), length: 0 }
+    }
+
+    /// The optional header, once enough of the file has been given to read it
+    pub fn header(&self) -> Option<&JsonMap> {
+        self.header.as_ref()
+    }
+
+    /// Decode the next part of the file, returning whatever content is ready
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let rest;
+        let mut data = data;
+        if let Stage::Header = self.stage {
+            self.buffer.extend_from_slice(data);
+            match self.read_header()? {
+                Some(body) => { rest = body; data = &rest },
+                None => return Ok(vec![]),
+            }
+        }
+
+        let mut output = vec![];
+        let Stage::Body { cipher, inflate } = &mut self.stage else {
+            self.buffer.extend_from_slice(data);
+            if self.buffer.len() > MAX_METADATA_SIZE + MANDATORY_FOOTER_SIZE {
+                bail!("Unexpected data after CaRT body")
+            }
+            return Ok(output)
+        };
+
+        let mut plain = data.to_vec();
+        cipher.apply_keystream(&mut plain);
+        let mut consumed = 0;
+        loop {
+            output.reserve(CHUNK_SIZE);
+            let before = inflate.total_in();
+            let status = inflate.decompress_vec(&plain[consumed..], &mut output, FlushDecompress::None)
+                .context("Corrupted CaRT body")?;
+            consumed += (inflate.total_in() - before) as usize;
+
+            if status == Status::StreamEnd {
+                // the footers are encrypted on their own, keep them as they were given
+                self.buffer = data[consumed..].to_vec();
+                self.stage = Stage::Trailer;
+                break
+            }
+            if consumed == plain.len() && output.len() < output.capacity() {
+                break
+            }
+        }
+        self.sha256.update(&output);
+        self.length += output.len() as u64;
+        Ok(output)
+    }
+
+    /// Parse the headers once they have been buffered, returning the data that follows them
+    fn read_header(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buffer.len() < MANDATORY_HEADER_SIZE {
+            return Ok(None)
+        }
+        if !is_cart(&self.buffer) || self.buffer[6..14] != RESERVED.to_le_bytes() {
+            bail!("Not a CaRT file")
+        }
+        let key = self.buffer[14..30].to_vec();
+        let size = u64::from_le_bytes(self.buffer[30..38].try_into()?);
+        let size = usize::try_from(size).ok().filter(|size| *size <= MAX_METADATA_SIZE)
+            .context("CaRT header is too large")?;
+        if self.buffer.len() < MANDATORY_HEADER_SIZE + size {
+            return Ok(None)
+        }
+
+        if size > 0 {
+            let mut header = self.buffer[MANDATORY_HEADER_SIZE..MANDATORY_HEADER_SIZE + size].to_vec();
+            cipher(&key)?.apply_keystream(&mut header);
+            self.header = Some(serde_json::from_slice(&header).context("Corrupted CaRT header")?);
+        }
+
+        let body = self.buffer.split_off(MANDATORY_HEADER_SIZE + size);
+        self.buffer.clear();
+        self.stage = Stage::Body { cipher: Box::new(cipher(&key)?), inflate: Decompress::new(true) };
+        self.key = key;
+        Ok(Some(body))
+    }
+
+    /// Check the file was complete and its content matches the digests in the footer,
+    /// returning the optional header and footer
+    pub fn finish(self) -> Result<(Option<JsonMap>, Option<JsonMap>)> {
+        if !matches!(self.stage, Stage::Trailer) || self.buffer.len() < MANDATORY_FOOTER_SIZE {
+            bail!("CaRT file is truncated")
+        }
+
+        let footer_start = self.buffer.len() - MANDATORY_FOOTER_SIZE;
+        let footer = &self.buffer[footer_start..];
+        if !footer.starts_with(FOOTER_MAGIC) || footer[4..12] != RESERVED.to_le_bytes() {
+            bail!("Corrupted CaRT footer")
+        }
+        let size = u64::from_le_bytes(footer[20..28].try_into()?);
+        let size = usize::try_from(size).ok().filter(|size| *size <= footer_start)
+            .context("Corrupted CaRT footer")?;
+
+        let mut optional_footer: Option<JsonMap> = None;
+        if size > 0 {
+            let mut footer = self.buffer[footer_start - size..footer_start].to_vec();
+            cipher(&self.key)?.apply_keystream(&mut footer);
+            optional_footer = Some(serde_json::from_slice(&footer).context("Corrupted CaRT footer")?);
+        }
+
+        // digests are optional in the format, but any that are present have to match
+        if let Some(footer) = &optional_footer {
+            if let Some(expected) = footer.get("sha256") {
+                let sha256 = format!("{:x}", self.sha256.finalize());
+                if expected.as_str().is_none_or(|expected| !expected.eq_ignore_ascii_case(&sha256)) {
+                    bail!("CaRT content does not match the sha256 in its footer")
+                }
+            }
+            if let Some(expected) = footer.get("length") {
+                let expected = match expected {
+                    serde_json::Value::String(length) => length.parse().ok(),
+                    other => other.as_u64(),
+                };
+                if expected != Some(self.length) {
+                    bail!("CaRT content does not match the length in its footer")
+                }
+            }
+        }
+        Ok((self.header, optional_footer))
+    }
+}
@@ -10,3 +10,10 @@ pub struct ConnectionError;
 #[error("Attempted a write operation on a read only endpoint")]
 pub struct ReadOnlyError;
 
+/// An error produced when unpacking a container would write more than the limit given
+#[derive(thiserror::Error, Debug)]
+#[error("Unpacked content is larger than the limit of {limit} bytes")]
+pub struct TooLargeError {
+    /// Most bytes the content was allowed to take
+    pub limit: u64,
+}
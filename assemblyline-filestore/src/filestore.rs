@@ -1,10 +1,16 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use log::warn;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
 
+use crate::cart::{self, CartDecoder, CartEncoder, JsonMap};
+use crate::errors::{ReadOnlyError, TooLargeError};
+use crate::replication::{ReconcileReport, ReplicationPolicy, TransportHealth, TransportStatus};
 use crate::transport::Transport;
 use crate::transport::local::LocalTransport;
 
@@ -12,29 +18,100 @@ use crate::transport::local::LocalTransport;
 #[derive(Debug)]
 pub struct FileStore {
     transports: Vec<Box<dyn Transport>>,
+    health: Vec<TransportHealth>,
+    policy: ReplicationPolicy,
+    /// Blobs waiting to be copied to transports that missed them
+    backfill: Option<mpsc::Sender<Backfill>>,
+}
+
+/// Blobs are compared by reconcile one leading character at a time, these first since sha256 names start
+/// with them. The rest of printable ascii follows, names starting with anything else aren't reconciled as
+/// finding them would take listing the whole store again.
+const RECONCILE_PREFIXES: &str = "0123456789abcdef";
+
+/// Every character reconcile uses as a prefix, a leading slash is dropped from names so it isn't one of them
+fn reconcile_prefixes() -> impl Iterator<Item = char> {
+    RECONCILE_PREFIXES.chars().chain((' '..='~').filter(|c| *c != '/' && !RECONCILE_PREFIXES.contains(*c)))
+}
+
+/// Most blobs reconcile copies at once
+const RECONCILE_CONCURRENCY: usize = 8;
+
+/// Work left for the background worker on transports that missed an operation
+#[derive(Debug)]
+enum Backfill {
+    /// A blob to copy from a transport holding it to the transports missing it
+    Copy {
+        name: String,
+        source: usize,
+        targets: Vec<usize>,
+    },
+    /// A deleted blob to remove from the transports that failed to delete it
+    Delete {
+        name: String,
+        targets: Vec<usize>,
+    },
+}
+
+/// Where the body of a blob being written comes from
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Buffer(&'a Bytes),
+    File(&'a Path),
 }
 
 
 impl FileStore {
     /// Open all urls with default parameters
     pub async fn open(urls: &[String]) -> Result<Arc<FileStore>> {
+        Self::open_with_policy(urls, ReplicationPolicy::default()).await
+    }
+
+    /// Open all urls, replicating writes between them according to the policy
+    pub async fn open_with_policy(urls: &[String], policy: ReplicationPolicy) -> Result<Arc<FileStore>> {
         let mut transports = vec![];
         for url in urls {
             transports.push(Self::create_transport(url, None).await?)
         }
-        Ok(Arc::new(Self { 
-            transports 
-        }))
+        Ok(Self::new(transports, policy))
     }
 
     /// Open a single url with retrying disabled
     pub async fn with_limit_retries(url: &str) -> Result<Arc<FileStore>> {
-        Ok(Arc::new(Self { 
-            transports: vec![Self::create_transport(url, Some(1)).await?]
-        }))
+        Ok(Self::new(vec![Self::create_transport(url, Some(1)).await?], ReplicationPolicy::default()))
+    }
+
+    fn new(transports: Vec<Box<dyn Transport>>, policy: ReplicationPolicy) -> Arc<FileStore> {
+        let health = transports.iter().map(|_| TransportHealth::default()).collect();
+
+        // there is nothing to copy between with a single transport
+        if transports.len() < 2 || policy.backfill_queue == 0 {
+            return Arc::new(Self { transports, health, policy, backfill: None })
+        }
+
+        let (send, recv) = mpsc::channel(policy.backfill_queue);
+        let store = Arc::new(Self { transports, health, policy, backfill: Some(send) });
+        tokio::spawn(backfill_worker(Arc::downgrade(&store), recv));
+        store
     }
 
     async fn create_transport(address: &str, connection_attempts: Option<usize>) -> Result<Box<dyn Transport>> {
+        use crate::transport::envelope::{EnvelopeParameters, TransportEnvelope};
+        let transport = Self::create_scheme_transport(address, connection_attempts).await?;
+
+        // blobs on any scheme can be compressed and encrypted before they are stored
+        let url: url::Url = address.parse()?;
+        let mut parameters = EnvelopeParameters::default();
+        for (name, value) in url.query_pairs() {
+            parameters.read_parameter(&name, &value)?;
+        }
+        if !parameters.is_enabled() {
+            return Ok(transport)
+        }
+        Ok(Box::new(TransportEnvelope::new(transport, parameters).await?))
+    }
+
+    async fn create_scheme_transport(address: &str, connection_attempts: Option<usize>) -> Result<Box<dyn Transport>> {
         let url: url::Url = address.parse()?;
 
         let mut base = match url.path() {
@@ -52,8 +129,6 @@ impl FileStore {
             percent_encoding::percent_decode_str(password).decode_utf8_lossy().to_string()
         });
 
-        // user = parsed.username or ''
-    
 
         match url.scheme() {
             "file" => {
@@ -119,6 +194,54 @@ impl FileStore {
         
                 Ok(Box::new(TransportS3::new(base, host.map(str::to_string), port, user, password, connection_attempts, parameters).await?))
             }
+            "ftp" | "ftps" => {
+                use crate::transport::ftp::{FtpParameters, TransportFtp};
+                let mut parameters = FtpParameters::default();
+                for (name, value) in url.query_pairs() {
+                    match name.as_ref() {
+                        "use_tls" => parameters.use_tls = read_bool(&value),
+                        "verify" => parameters.verify = read_bool(&value),
+                        _ => {}
+                    }
+                }
+                if url.scheme() == "ftps" {
+                    parameters.use_tls = true;
+                }
+
+                let Some(host) = host else { bail!("a host must be provided for ftp connections") };
+                Ok(Box::new(TransportFtp::new(host.to_owned(), port, user_name(&url), password.unwrap_or_default(), base, parameters, connection_attempts)))
+            }
+            "sftp" => {
+                use crate::transport::sftp::{SftpParameters, TransportSftp};
+                let mut parameters = SftpParameters::default();
+                for (name, value) in url.query_pairs() {
+                    match name.as_ref() {
+                        "private_key" => parameters.private_key = Some(value.to_string().into()),
+                        "private_key_pass" => parameters.private_key_pass = Some(value.to_string()),
+                        "validate_host" => parameters.validate_host = read_bool(&value),
+                        _ => {}
+                    }
+                }
+
+                let Some(host) = host else { bail!("a host must be provided for sftp connections") };
+                Ok(Box::new(TransportSftp::new(host.to_owned(), port, user_name(&url), password.unwrap_or_default(), base, parameters, connection_attempts)))
+            }
+            "http" | "https" => {
+                use crate::transport::http::{HttpParameters, TransportHttp};
+                let mut parameters = HttpParameters::default();
+                for (name, value) in url.query_pairs() {
+                    match name.as_ref() {
+                        "pki" => parameters.pki = Some(value.to_string().into()),
+                        "verify" => parameters.verify = read_bool(&value),
+                        _ => {}
+                    }
+                }
+
+                let Some(host) = host else { bail!("a host must be provided for http connections") };
+                let port = port.map(|port| format!(":{port}")).unwrap_or_default();
+                let address = format!("{}://{host}{port}{base}", url.scheme());
+                Ok(Box::new(TransportHttp::new(&address, user_name(&url), password, parameters, connection_attempts).await?))
+            }
             _ => {
                 bail!("Not an accepted filestore scheme: {}", url.scheme());
             }
@@ -127,18 +250,15 @@ impl FileStore {
 
     /// Upload a buffer to all transports
     pub async fn put(&self, name: &str, body: &Bytes) -> Result<()> {
-        for transport in &self.transports {
-            transport.put(name, body).await?;
-        }
-        Ok(())
+        self.replicate(name, Source::Buffer(body)).await
     }
 
     /// Check if a given blob is defined in any transport.
     /// Errors will be supressed as long as any transport contains the file.
     pub async fn exists(&self, name: &str) -> Result<bool> {
         let mut last_error = None;
-        for transport in &self.transports {
-            match transport.exists(name).await {
+        for (transport, health) in self.transports.iter().zip(&self.health) {
+            match health.record(transport.exists(name).await) {
                 Ok(true) => return Ok(true),
                 Ok(false) => continue,
                 Err(err) => {
@@ -155,11 +275,17 @@ impl FileStore {
 
     /// Pull blob to in memory buffer.
     /// Returns errors only if all transports fail, otherwise errors will be logged as warnings.
+    /// A blob found after other transports reported it missing is queued to be copied back to them.
     pub async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
         let mut last_error = None;
-        for transport in &self.transports {
-            match transport.get(name).await {
-                Ok(bytes) => return Ok(bytes),
+        let mut missing = vec![];
+        for (index, (transport, health)) in self.transports.iter().zip(&self.health).enumerate() {
+            match health.record(transport.get(name).await) {
+                Ok(Some(body)) => {
+                    self.queue_backfill(Backfill::Copy { name: name.to_owned(), source: index, targets: missing });
+                    return Ok(Some(body))
+                },
+                Ok(None) => missing.push(index),
                 Err(err) => {
                     warn!("error fetching blob [{name}] from transport {transport:?}: {err}");
                     last_error = Some(err);
@@ -168,8 +294,8 @@ impl FileStore {
             }
         }
         match last_error {
-            Some(error) => Err(error).context("All transports failed to fetch"),
-            None => Ok(None)
+            Some(error) if missing.is_empty() => Err(error).context("All transports failed to fetch"),
+            _ => Ok(None)
         }
     }
 
@@ -177,8 +303,8 @@ impl FileStore {
     /// If the file does not exist it will be created. If it does exist it will be replaced.
     pub async fn download(&self, name: &str, path: &Path) -> Result<()> {
         let mut errors = vec![];
-        for transport in &self.transports {
-            match transport.download(name, path).await {
+        for (transport, health) in self.transports.iter().zip(&self.health) {
+            match health.record(transport.download(name, path).await) {
                 Ok(()) => return Ok(()),
                 Err(err) => {
                     errors.push(format!("Could not download file: [{name}] from {transport:?}: {err}"));
@@ -194,16 +320,7 @@ impl FileStore {
 
     /// Upload a local file as a named blob.
     pub async fn upload(&self, path: &Path, name: &str) -> Result<()> {
-        let mut last_error = None;
-        for transport in &self.transports {
-            if let Err(err) = transport.upload(path, name).await {
-                last_error = Some(err);
-            }
-        }
-        match last_error {
-            Some(error) => Err(error).context("A transport failed to upload"),
-            None => Ok(())
-        }
+        self.replicate(name, Source::File(path)).await
     }
 
     /// Upload a collection of local files.
@@ -221,8 +338,8 @@ impl FileStore {
     /// Returns the total expected length of the stream and a message receiver of data buffers.
     pub async fn stream(&self, name: &str) -> Result<(u64, tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>)> {
         let mut last_error = None;
-        for transport in &self.transports {
-            match transport.stream(name).await {
+        for (transport, health) in self.transports.iter().zip(&self.health) {
+            match health.record(transport.stream(name).await) {
                 Ok((size, stream)) => return Ok((size, stream)),
                 Err(err) => last_error = Some(err),
             }
@@ -233,15 +350,362 @@ impl FileStore {
         }
     }
 
-    /// Remove a blob from the storage
-    pub async fn delete(&self, name: &str) -> Result<()> { 
-        for transport in &self.transports {
-            transport.delete(name).await?;
+    /// Stream the content of a blob packed into a CaRT container with the given header metadata.
+    /// The size of the container isn't known until it has been written.
+    pub async fn stream_cart(&self, name: &str, header: Option<JsonMap>) -> Result<mpsc::Receiver<Result<Bytes, std::io::Error>>> {
+        let (_, mut stream) = self.stream(name).await?;
+        let mut encoder = CartEncoder::new(header, None)?;
+        let (send, recv) = mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Some(chunk) = stream.recv().await {
+                let output = match chunk.and_then(|chunk| encoder.update(&chunk).map_err(std::io::Error::other)) {
+                    Ok(output) if output.is_empty() => continue,
+                    Ok(output) => Ok(Bytes::from(output)),
+                    Err(err) => Err(err),
+                };
+                let failed = output.is_err();
+                if send.send(output).await.is_err() || failed {
+                    return
+                }
+            }
+            _ = send.send(encoder.finish().map(Bytes::from).map_err(std::io::Error::other)).await;
+        });
+        Ok(recv)
+    }
+
+    /// Download the file held in a blob that is a CaRT container, returning the metadata from its header.
+    /// Returns None without writing anything if the blob isn't a CaRT container.
+    /// Fails with a `TooLargeError` as soon as the unpacked file grows past the limit in bytes.
+    pub async fn unpack_cart(&self, name: &str, path: &Path, limit: u64) -> Result<Option<JsonMap>> {
+        let (_, mut stream) = self.stream(name).await?;
+
+        // read enough of the blob to recognize it before creating the output
+        let mut start = vec![];
+        while !cart::is_cart(&start) && start.len() < 6 {
+            match stream.recv().await {
+                Some(chunk) => start.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        if !cart::is_cart(&start) {
+            return Ok(None)
+        }
+
+        let mut decoder = CartDecoder::new();
+        let mut output = tokio::fs::File::create(path).await?;
+        let mut written = 0u64;
+        let mut chunk = start;
+        loop {
+            let plain = decoder.update(&chunk)?;
+            written += plain.len() as u64;
+            if written > limit {
+                return Err(TooLargeError { limit }.into())
+            }
+            output.write_all(&plain).await?;
+
+            chunk = match stream.recv().await {
+                Some(chunk) => chunk?.to_vec(),
+                None => break,
+            };
+        }
+        output.flush().await?;
+        let (header, _) = decoder.finish()?;
+        Ok(Some(header.unwrap_or_default()))
+    }
+
+    /// Remove a blob from every transport, failing if fewer than the write quorum remove it.
+    /// Read only transports are skipped, transports that fail to delete it are queued to try again.
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        let mut deleted = 0;
+        let mut writable = 0;
+        let mut missed = vec![];
+        let mut last_error = None;
+        for (index, (transport, health)) in self.transports.iter().zip(&self.health).enumerate() {
+            let result = transport.delete(name).await;
+            if result.as_ref().is_err_and(|err| err.is::<ReadOnlyError>()) {
+                last_error.get_or_insert(result.unwrap_err());
+                continue
+            }
+            writable += 1;
+            match health.record(result) {
+                Ok(()) => deleted += 1,
+                Err(err) => {
+                    warn!("Could not delete [{name}] from {transport:?}: {err:#}");
+                    missed.push(index);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        let Some(error) = last_error else { return Ok(()) };
+        if writable == 0 {
+            return Err(error)
+        }
+        if deleted > 0 {
+            self.queue_backfill(Backfill::Delete { name: name.to_owned(), targets: missed });
+        }
+        let quorum = self.policy.quorum(writable);
+        if deleted < quorum {
+            return Err(error).context(format!("Blob [{name}] deleted from {deleted} transports, {quorum} required"))
+        }
+        Ok(())
+    }
+
+    /// Write a blob to every transport, failing if fewer than the write quorum accept it.
+    /// Read only transports are skipped, transports that missed a write that reached any other
+    /// transport are queued for backfill.
+    async fn replicate(&self, name: &str, source: Source<'_>) -> Result<()> {
+        let mut written = None;
+        let mut writable = 0;
+        let mut missed = vec![];
+        let mut last_error = None;
+        for (index, (transport, health)) in self.transports.iter().zip(&self.health).enumerate() {
+            let result = match source {
+                Source::Buffer(body) => transport.put(name, body).await,
+                Source::File(path) => transport.upload(path, name).await,
+            };
+            if result.as_ref().is_err_and(|err| err.is::<ReadOnlyError>()) {
+                last_error.get_or_insert(result.unwrap_err());
+                continue
+            }
+            writable += 1;
+            match health.record(result) {
+                Ok(()) => { written.get_or_insert(index); },
+                Err(err) => {
+                    warn!("Could not write [{name}] to {transport:?}: {err:#}");
+                    missed.push(index);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        let Some(source) = written else {
+            return match last_error {
+                Some(error) => Err(error).context("All transports failed to write"),
+                None => bail!("No transports to write to"),
+            }
+        };
+        let written = writable - missed.len();
+        self.queue_backfill(Backfill::Copy { name: name.to_owned(), source, targets: missed });
+
+        let quorum = self.policy.quorum(writable);
+        if written < quorum {
+            let error = last_error.unwrap_or_else(|| anyhow::anyhow!("transport failure"));
+            return Err(error).context(format!("Blob [{name}] written to {written} transports, {quorum} required"))
         }
         Ok(())
     }
+
+    /// Queue work for the background worker, dropped with a warning when the queue is full
+    fn queue_backfill(&self, job: Backfill) {
+        let (Backfill::Copy { name, targets, .. } | Backfill::Delete { name, targets }) = &job;
+        if targets.is_empty() {
+            return
+        }
+        let Some(backfill) = &self.backfill else { return };
+        let name = name.clone();
+        if let Err(err) = backfill.try_send(job) {
+            warn!("Backfill queue unavailable, [{name}] left for reconcile: {err}");
+        }
+    }
+
+    /// Number of blobs waiting to be copied to transports that missed them
+    pub fn backfill_pending(&self) -> usize {
+        match &self.backfill {
+            Some(backfill) => backfill.max_capacity() - backfill.capacity(),
+            None => 0,
+        }
+    }
+
+    /// Health of each transport, in the order they were configured
+    pub fn health(&self) -> Vec<TransportStatus> {
+        self.transports.iter().zip(&self.health)
+            .map(|(transport, health)| health.status(format!("{transport:?}")))
+            .collect()
+    }
+
+    /// Copy a blob to every transport that is missing it.
+    /// Returns the number of transports it was copied to.
+    pub async fn repair(&self, name: &str) -> Result<usize> {
+        let mut source = None;
+        let mut targets = vec![];
+        for (index, (transport, health)) in self.transports.iter().zip(&self.health).enumerate() {
+            match health.record(transport.exists(name).await) {
+                Ok(true) => { source.get_or_insert(index); },
+                Ok(false) => targets.push(index),
+                Err(err) => warn!("Could not check for [{name}] on {transport:?}: {err:#}"),
+            }
+        }
+
+        if targets.is_empty() {
+            return Ok(0)
+        }
+        let Some(source) = source else {
+            bail!("Blob [{name}] could not be found in any transport")
+        };
+        self.copy_blob(name, source, &targets).await
+    }
+
+    /// Compare the listing of every transport and copy each blob to the transports missing it.
+    /// Every transport must support listing.
+    ///
+    /// Listings are compared one leading character at a time so only part of the store is held in memory,
+    /// and a few blobs are copied at once.
+    pub async fn reconcile(self: &Arc<Self>) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        for prefix in reconcile_prefixes() {
+            let holders = self.list_holders(&prefix.to_string()).await?;
+            self.reconcile_names(holders, &mut report).await;
+        }
+
+        info!("Reconciled {} blobs, {} repaired, {} failed", report.blobs, report.repaired, report.failed.len());
+        Ok(report)
+    }
+
+    /// Which transports list each name starting with the prefix
+    async fn list_holders(&self, prefix: &str) -> Result<HashMap<String, Vec<usize>>> {
+        let mut holders: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, (transport, health)) in self.transports.iter().zip(&self.health).enumerate() {
+            let mut listing = health.record(transport.list(Some(prefix)).await).with_context(|| format!("Could not list {transport:?}"))?;
+            while let Some(name) = listing.recv().await {
+                let name = health.record(name).with_context(|| format!("Could not list {transport:?}"))?;
+                holders.entry(name).or_default().push(index);
+            }
+        }
+        Ok(holders)
+    }
+
+    /// Copy each blob from the first transport holding it to the rest
+    async fn reconcile_names(self: &Arc<Self>, holders: HashMap<String, Vec<usize>>, report: &mut ReconcileReport) {
+        let mut copies = tokio::task::JoinSet::new();
+        for (name, holding) in holders {
+            report.blobs += 1;
+            let targets: Vec<usize> = (0..self.transports.len()).filter(|index| !holding.contains(index)).collect();
+            if targets.is_empty() {
+                continue
+            }
+
+            while copies.len() >= RECONCILE_CONCURRENCY {
+                if let Some(copied) = copies.join_next().await {
+                    Self::record_reconcile(copied, report);
+                }
+            }
+            let store = self.clone();
+            copies.spawn(async move {
+                let result = store.copy_blob(&name, holding[0], &targets).await;
+                (name, result)
+            });
+        }
+        while let Some(copied) = copies.join_next().await {
+            Self::record_reconcile(copied, report);
+        }
+    }
+
+    fn record_reconcile(copied: Result<(String, Result<usize>), tokio::task::JoinError>, report: &mut ReconcileReport) {
+        match copied {
+            Ok((_, Ok(0))) => {},
+            Ok((_, Ok(_))) => report.repaired += 1,
+            Ok((name, Err(err))) => report.failed.push((name, format!("{err:#}"))),
+            Err(err) => warn!("Reconcile copy stopped unexpectedly: {err}"),
+        }
+    }
+
+    /// Copy a blob from one transport to others, through a local temporary file.
+    /// Read only transports are skipped. Returns the number of transports written to.
+    async fn copy_blob(&self, name: &str, source: usize, targets: &[usize]) -> Result<usize> {
+        let temp = tempfile::NamedTempFile::new()?;
+        let transport = &self.transports[source];
+        self.health[source].record(transport.download(name, temp.path()).await)
+            .with_context(|| format!("Could not read [{name}] from {transport:?}"))?;
+
+        let mut copied = 0;
+        let mut last_error = None;
+        for &index in targets {
+            let (transport, health) = (&self.transports[index], &self.health[index]);
+            let result = transport.upload(temp.path(), name).await;
+            if result.as_ref().is_err_and(|err| err.is::<ReadOnlyError>()) {
+                continue
+            }
+            match health.record(result) {
+                Ok(()) => {
+                    health.repaired();
+                    copied += 1;
+                },
+                Err(err) => {
+                    warn!("Could not copy [{name}] to {transport:?}: {err:#}");
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match last_error {
+            Some(error) => Err(error).context(format!("Blob [{name}] copied to {copied} of {} transports", targets.len())),
+            None => Ok(copied),
+        }
+    }
+
+    /// List the names of stored blobs, limited to those starting with the prefix if one is given.
+    /// Only the first transport is listed unless merge is set, then the blobs of every transport
+    /// are listed with each name reported once.
+    pub async fn list(&self, prefix: Option<&str>, merge: bool) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let Some(first) = self.transports.first() else {
+            bail!("No transports to list")
+        };
+        if !merge {
+            return first.list(prefix).await
+        }
+
+        let mut listings = vec![];
+        for transport in &self.transports {
+            listings.push(transport.list(prefix).await.with_context(|| format!("Could not list {transport:?}"))?);
+        }
+
+        let (send, recv) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut seen = HashSet::new();
+            for mut listing in listings {
+                while let Some(name) = listing.recv().await {
+                    if let Ok(name) = &name {
+                        if !seen.insert(name.clone()) {
+                            continue
+                        }
+                    }
+                    if send.send(name).await.is_err() {
+                        return
+                    }
+                }
+            }
+        });
+        Ok(recv)
+    }
+}
+
+/// Copy or remove blobs queued for backfill until the filestore is dropped
+async fn backfill_worker(store: Weak<FileStore>, mut queue: mpsc::Receiver<Backfill>) {
+    while let Some(job) = queue.recv().await {
+        let Some(store) = store.upgrade() else { return };
+        match job {
+            Backfill::Copy { name, source, targets } => {
+                if let Err(err) = store.copy_blob(&name, source, &targets).await {
+                    warn!("Backfill of [{name}] failed: {err:#}");
+                }
+            },
+            Backfill::Delete { name, targets } => {
+                for index in targets {
+                    let (transport, health) = (&store.transports[index], &store.health[index]);
+                    if let Err(err) = health.record(transport.delete(&name).await) {
+                        warn!("Backfill delete of [{name}] from {transport:?} failed: {err:#}");
+                    }
+                }
+            },
+        }
+    }
+}
+
+fn user_name(url: &url::Url) -> String {
+    percent_encoding::percent_decode_str(url.username()).decode_utf8_lossy().to_string()
 }
 
-fn read_bool(value: &str) -> bool {
+pub(crate) fn read_bool(value: &str) -> bool {
     matches!(value.to_ascii_lowercase().as_str(), "true" | "1")
 }
\ No newline at end of file
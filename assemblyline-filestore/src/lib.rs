@@ -11,10 +11,13 @@
 )]
 
 mod filestore;
+mod replication;
 mod transport;
 pub mod errors;
+pub mod cart;
 
 pub use filestore::FileStore;
+pub use replication::{ReconcileReport, ReplicationPolicy, TransportStatus};
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,107 @@
+//! How writes are spread over the transports of a filestore and how healthy each transport is.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Result;
+
+/// Rules for replicating blobs written to a filestore with more than one transport
+#[derive(Debug, Clone)]
+pub struct ReplicationPolicy {
+    /// Number of transports a write must reach before it succeeds, every transport when not set
+    pub write_quorum: Option<usize>,
+    /// Number of blobs that can wait to be copied to transports that missed them, zero disables backfill
+    pub backfill_queue: usize,
+}
+
+impl Default for ReplicationPolicy {
+    fn default() -> Self {
+        Self { write_quorum: None, backfill_queue: 1000 }
+    }
+}
+
+impl ReplicationPolicy {
+    /// Number of successful writes needed with the given number of transports
+    pub(crate) fn quorum(&self, transports: usize) -> usize {
+        self.write_quorum.unwrap_or(transports).clamp(1, transports.max(1))
+    }
+}
+
+/// Running record of the operations made against one transport
+#[derive(Debug, Default)]
+pub(crate) struct TransportHealth {
+    successes: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU64,
+    repaired: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl TransportHealth {
+    /// Count the outcome of an operation, passing it through unchanged
+    pub fn record<T>(&self, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => {
+                self.successes.fetch_add(1, Ordering::Relaxed);
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+            },
+            Err(err) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap_or_else(PoisonError::into_inner) = Some(format!("{err:#}"));
+            },
+        }
+        result
+    }
+
+    /// Count a blob copied to this transport because it was missing
+    pub fn repaired(&self) {
+        self.repaired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn status(&self, transport: String) -> TransportStatus {
+        TransportStatus {
+            transport,
+            successes: self.successes.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            repaired: self.repaired.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+        }
+    }
+}
+
+/// Snapshot of the health of one transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportStatus {
+    /// Description of the transport
+    pub transport: String,
+    /// Operations that completed, including those that found a blob missing
+    pub successes: u64,
+    /// Operations that failed
+    pub failures: u64,
+    /// Operations that have failed since the last one to succeed
+    pub consecutive_failures: u64,
+    /// Blobs copied to this transport by backfill, repair or reconcile
+    pub repaired: u64,
+    /// Most recent failure
+    pub last_error: Option<String>,
+}
+
+impl TransportStatus {
+    /// A transport is degraded while its latest operation failed
+    pub fn is_degraded(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+}
+
+/// Outcome of reconciling the contents of every transport
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Distinct blobs found across all transports
+    pub blobs: u64,
+    /// Blobs that were copied to at least one transport missing them
+    pub repaired: u64,
+    /// Blobs that could not be copied everywhere, with the reason
+    pub failed: Vec<(String, String)>,
+}
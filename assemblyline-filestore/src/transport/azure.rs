@@ -349,6 +349,37 @@ impl Transport for TransportAzure {
 //             if not isinstance(error.cause, ResourceNotFoundError):
 //                 raise
     }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        // blobs are stored under the base path, which isn't part of the names handed back
+        let base = match &self.base_path {
+            Some(base) => base.to_string_lossy().trim_end_matches('/').to_owned() + "/",
+            None => String::new(),
+        };
+        let prefix = base.clone() + prefix.unwrap_or_default();
+
+        let mut stream = self.container_client.list_blobs().prefix(prefix).into_stream();
+        let (send, recv) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            while let Some(page) = stream.next().await {
+                let page = match page {
+                    Ok(page) => page,
+                    Err(err) => {
+                        _ = send.send(Err(err.into())).await;
+                        return
+                    }
+                };
+
+                for blob in page.blobs.blobs() {
+                    let name = blob.name.strip_prefix(&base).unwrap_or(&blob.name);
+                    if send.send(Ok(name.to_owned())).await.is_err() {
+                        return
+                    }
+                }
+            }
+        });
+        Ok(recv)
+    }
 }
 
 impl std::fmt::Debug for TransportAzure {
@@ -366,12 +397,6 @@ impl std::fmt::Debug for TransportAzure {
 //         pass
 
 
-//     def list(self, prefix: Optional[str] = None) -> Iterable[str]:
-//         for blob in self.container_client.list_blobs(name_starts_with=prefix):
-//             yield blob['name']
-
-
-
 // macro_rules! retry {
 //     ($body: expr) => {
 //         {
@@ -0,0 +1,515 @@
+//! A transport wrapping another to compress and encrypt blobs before they are stored.
+//!
+//! Blobs written through the envelope start with a header describing how the body was encoded. Transports
+//! have no common way to attach metadata, so the header carries it instead: the size of the original
+//! content, whether it was compressed and the id of the key that encrypted it. Every blob is encrypted
+//! under its own random data key, which is stored in the header sealed with the named key from the key file.
+//!
+//! Bodies are compressed with zstd and then encrypted with AES-256-GCM in fixed size segments so they can be
+//! written and read as streams. Each segment nonce is built from a random per-blob prefix, the segment
+//! number and a flag marking the final segment, so segments can't be reordered and truncation is detected.
+//!
+//! Blobs without the header are passed through unchanged, so a store can be switched over to the envelope
+//! without rewriting what it already holds. Once everything has been rewritten `require_encryption` refuses
+//! to read blobs that weren't encrypted, so plain text placed in the store can't be served as if it were trusted.
+
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::Transport;
+
+/// Marks a blob written through the envelope, the last byte is the format version
+const MAGIC: &[u8; 8] = b"ALBLOB\x00\x01";
+
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 2;
+
+/// zstd level used when compression is requested without one
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Plain text bytes sealed together in each segment of an encrypted body
+const SEGMENT_SIZE: usize = 1 << 16;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const NONCE_PREFIX_SIZE: usize = 7;
+const KEY_SIZE: usize = 32;
+
+/// Size of the buffers handed out when streaming a blob
+const CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Default)]
+pub struct EnvelopeParameters {
+    /// zstd level new blobs are compressed with, not compressed when unset
+    pub compression_level: Option<i32>,
+    /// File of `id=base64 key` lines holding the keys blobs are encrypted with
+    pub encryption_keys: Option<PathBuf>,
+    /// Key new blobs are encrypted with, the last key in the file when unset
+    pub encryption_key_id: Option<String>,
+    /// Refuse to read blobs that weren't encrypted, instead of passing them through
+    pub require_encryption: bool,
+}
+
+impl EnvelopeParameters {
+    /// Read one of the url query parameters configuring the envelope, unrelated parameters are ignored
+    pub fn read_parameter(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "compression" => match value {
+                "zstd" => { self.compression_level.get_or_insert(DEFAULT_COMPRESSION_LEVEL); },
+                "none" => self.compression_level = None,
+                other => bail!("Unsupported filestore compression: {other}"),
+            },
+            "compression_level" => self.compression_level = Some(value.parse().context("compression_level")?),
+            "encryption_keys" => self.encryption_keys = Some(value.into()),
+            "encryption_key_id" => self.encryption_key_id = Some(value.to_owned()),
+            "require_encryption" => self.require_encryption = crate::filestore::read_bool(value),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.compression_level.is_some() || self.encryption_keys.is_some() || self.require_encryption
+    }
+}
+
+pub struct TransportEnvelope {
+    inner: Box<dyn Transport>,
+    envelope: Arc<Envelope>,
+}
+
+/// Settings for encoding blobs, shared with the blocking tasks doing the work
+struct Envelope {
+    compression_level: Option<i32>,
+    keys: HashMap<String, Key<Aes256Gcm>>,
+    /// Key new blobs are encrypted with
+    write_key: Option<String>,
+    /// Blobs that weren't encrypted are refused
+    require_encryption: bool,
+}
+
+impl TransportEnvelope {
+    pub async fn new(inner: Box<dyn Transport>, parameters: EnvelopeParameters) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut write_key = None;
+        if let Some(path) = &parameters.encryption_keys {
+            let body = tokio::fs::read_to_string(path).await.with_context(|| format!("Could not read encryption keys from {path:?}"))?;
+            for line in body.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                let Some((id, key)) = line.split_once('=') else {
+                    bail!("Encryption key lines should be id=key")
+                };
+                let (id, key) = (id.trim(), key.trim());
+                if id.is_empty() || id.len() > u8::MAX as usize {
+                    bail!("Encryption key ids must be between 1 and 255 bytes")
+                }
+                let key = base64::engine::general_purpose::STANDARD.decode(key).with_context(|| format!("Encryption key {id} isn't base64"))?;
+                let Ok(key) = <[u8; KEY_SIZE]>::try_from(key) else {
+                    bail!("Encryption key {id} must be {KEY_SIZE} bytes")
+                };
+                keys.insert(id.to_owned(), Key::<Aes256Gcm>::from(key));
+                write_key = Some(id.to_owned());
+            }
+
+            if let Some(id) = parameters.encryption_key_id {
+                if !keys.contains_key(&id) {
+                    bail!("Encryption key {id} isn't in {path:?}")
+                }
+                write_key = Some(id);
+            }
+            if write_key.is_none() {
+                bail!("No encryption keys in {path:?}")
+            }
+        } else if parameters.require_encryption {
+            bail!("Encryption can't be required without encryption_keys")
+        }
+
+        Ok(Self {
+            inner,
+            envelope: Arc::new(Envelope {
+                compression_level: parameters.compression_level,
+                keys,
+                write_key,
+                require_encryption: parameters.require_encryption,
+            }),
+        })
+    }
+
+    /// Encode the blob in a file to a temporary file from the blocking thread pool
+    async fn encode_file(&self, source: &Path) -> Result<tempfile::NamedTempFile> {
+        let envelope = self.envelope.clone();
+        let source = source.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let input = std::fs::File::open(&source)?;
+            let size = input.metadata()?.len();
+            let mut output = tempfile::NamedTempFile::new()?;
+            envelope.encode(input, size, std::io::BufWriter::new(output.as_file_mut()))?;
+            Ok(output)
+        }).await?
+    }
+}
+
+impl Envelope {
+    /// Write the header and encoded body of a blob
+    fn encode(&self, input: impl Read, size: u64, mut output: impl Write) -> Result<()> {
+        let mut header = MAGIC.to_vec();
+        let mut flags = 0;
+        if self.compression_level.is_some() {
+            flags |= FLAG_COMPRESSED;
+        }
+        if self.write_key.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        header.push(flags);
+        header.extend(size.to_be_bytes());
+
+        let Some(key_id) = &self.write_key else {
+            output.write_all(&header)?;
+            self.compress(input, &mut output)?;
+            output.flush()?;
+            return Ok(())
+        };
+
+        // seal a fresh data key with the named key, the header so far is authenticated along with it
+        header.push(key_id.len() as u8);
+        header.extend(key_id.as_bytes());
+        let mut data_key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut data_key);
+        let mut key_nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut key_nonce);
+        let sealed_key = Aes256Gcm::new(&self.keys[key_id])
+            .encrypt(&Nonce::from(key_nonce), Payload { msg: &data_key, aad: &header })
+            .map_err(|_| anyhow::anyhow!("Could not seal data key"))?;
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+
+        header.extend(key_nonce);
+        header.extend(sealed_key);
+        header.extend(prefix);
+        output.write_all(&header)?;
+
+        let mut writer = SealWriter::new(Aes256Gcm::new(&Key::<Aes256Gcm>::from(data_key)), prefix, output);
+        self.compress(input, &mut writer)?;
+        writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    fn compress(&self, mut input: impl Read, output: &mut impl Write) -> std::io::Result<()> {
+        match self.compression_level {
+            Some(level) => zstd::stream::copy_encode(input, output, level),
+            None => std::io::copy(&mut input, output).map(|_| ()),
+        }
+    }
+
+    /// Read the header of a blob, returning the size of its content and a reader that decodes the body.
+    /// Blobs without a header are returned as they are, with the size given, unless encryption is required.
+    fn decode(&self, mut input: impl Read + Send + 'static, stored_size: u64) -> Result<(u64, Box<dyn Read + Send>)> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        (&mut input).take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+        if magic != MAGIC {
+            if self.require_encryption {
+                bail!("Blob isn't encrypted and encryption is required")
+            }
+            return Ok((stored_size, Box::new(Cursor::new(magic).chain(input))))
+        }
+
+        let mut header = magic;
+        let mut fixed = [0u8; 9];
+        input.read_exact(&mut fixed).context("Blob header truncated")?;
+        header.extend(fixed);
+        let flags = fixed[0];
+        let size = u64::from_be_bytes(fixed[1..].try_into()?);
+        if self.require_encryption && flags & FLAG_ENCRYPTED == 0 {
+            bail!("Blob isn't encrypted and encryption is required")
+        }
+
+        let mut body: Box<dyn Read + Send> = Box::new(input);
+        if flags & FLAG_ENCRYPTED != 0 {
+            let mut length = [0u8];
+            body.read_exact(&mut length).context("Blob header truncated")?;
+            let mut key_id = vec![0u8; length[0] as usize];
+            body.read_exact(&mut key_id).context("Blob header truncated")?;
+            header.extend(length);
+            header.extend(&key_id);
+            let key_id = String::from_utf8_lossy(&key_id);
+            let Some(key) = self.keys.get(key_id.as_ref()) else {
+                bail!("Blob is encrypted with key {key_id} which isn't configured")
+            };
+
+            let mut key_nonce = [0u8; NONCE_SIZE];
+            let mut sealed_key = [0u8; KEY_SIZE + TAG_SIZE];
+            let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+            body.read_exact(&mut key_nonce).context("Blob header truncated")?;
+            body.read_exact(&mut sealed_key).context("Blob header truncated")?;
+            body.read_exact(&mut prefix).context("Blob header truncated")?;
+            let data_key = Aes256Gcm::new(key)
+                .decrypt(&Nonce::from(key_nonce), Payload { msg: &sealed_key, aad: &header })
+                .map_err(|_| anyhow::anyhow!("Could not open the data key of blob with key {key_id}"))?;
+            let data_key = <[u8; KEY_SIZE]>::try_from(data_key).map_err(|_| anyhow::anyhow!("Blob data key is corrupt"))?;
+
+            body = Box::new(OpenReader::new(Aes256Gcm::new(&Key::<Aes256Gcm>::from(data_key)), prefix, body));
+        }
+        if flags & FLAG_COMPRESSED != 0 {
+            body = Box::new(zstd::stream::read::Decoder::new(body)?);
+        }
+        Ok((size, body))
+    }
+}
+
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce
+}
+
+/// Encrypts everything written to it in segments, each written as a last flag, length and sealed bytes
+struct SealWriter<W> {
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+    output: W,
+}
+
+impl<W: Write> SealWriter<W> {
+    fn new(cipher: Aes256Gcm, prefix: [u8; NONCE_PREFIX_SIZE], output: W) -> Self {
+        Self { cipher, prefix, counter: 0, buffer: Vec::with_capacity(SEGMENT_SIZE), output }
+    }
+
+    fn seal(&mut self, last: bool) -> std::io::Result<()> {
+        let nonce = segment_nonce(&self.prefix, self.counter, last);
+        let segment = self.cipher.encrypt(&Nonce::from(nonce), self.buffer.as_slice())
+            .map_err(|_| std::io::Error::other("Could not encrypt blob segment"))?;
+        self.output.write_all(&[last as u8])?;
+        self.output.write_all(&(segment.len() as u32).to_be_bytes())?;
+        self.output.write_all(&segment)?;
+        self.buffer.clear();
+        self.counter = self.counter.checked_add(1).ok_or_else(|| std::io::Error::other("Blob too large to encrypt"))?;
+        Ok(())
+    }
+
+    /// Seal the final segment and hand back the output
+    fn finish(mut self) -> std::io::Result<W> {
+        self.seal(true)?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let taken = data.len().min(SEGMENT_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..taken]);
+        if self.buffer.len() == SEGMENT_SIZE {
+            self.seal(false)?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // partial segments are only sealed by finish so the last one can be marked
+        Ok(())
+    }
+}
+
+/// Decrypts the segments written by a `SealWriter`
+struct OpenReader<R> {
+    cipher: Aes256Gcm,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    input: R,
+    plain: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> OpenReader<R> {
+    fn new(cipher: Aes256Gcm, prefix: [u8; NONCE_PREFIX_SIZE], input: R) -> Self {
+        Self { cipher, prefix, counter: 0, input, plain: vec![], position: 0, finished: false }
+    }
+
+    fn next_segment(&mut self) -> std::io::Result<()> {
+        let truncated = |err: std::io::Error| match err.kind() {
+            ErrorKind::UnexpectedEof => std::io::Error::new(ErrorKind::InvalidData, "Encrypted blob is truncated"),
+            _ => err,
+        };
+
+        let mut head = [0u8; 5];
+        self.input.read_exact(&mut head).map_err(truncated)?;
+        let last = match head[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(std::io::Error::new(ErrorKind::InvalidData, "Encrypted blob is corrupt")),
+        };
+        let length = u32::from_be_bytes(head[1..].try_into().unwrap_or_default()) as usize;
+        if length > SEGMENT_SIZE + TAG_SIZE {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "Encrypted blob is corrupt"))
+        }
+
+        let mut segment = vec![0u8; length];
+        self.input.read_exact(&mut segment).map_err(truncated)?;
+        let nonce = segment_nonce(&self.prefix, self.counter, last);
+        self.plain = self.cipher.decrypt(&Nonce::from(nonce), segment.as_slice())
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Encrypted blob failed authentication"))?;
+        self.position = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.finished = last;
+
+        // nothing may follow the final segment
+        if last {
+            let mut trailing = vec![];
+            (&mut self.input).take(1).read_to_end(&mut trailing)?;
+            if !trailing.is_empty() {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "Encrypted blob has data after its final segment"))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.finished {
+                return Ok(0)
+            }
+            self.next_segment()?;
+        }
+        let size = buf.len().min(self.plain.len() - self.position);
+        buf[..size].copy_from_slice(&self.plain[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+/// Blocking reader over the chunks streamed from a transport, for use from the blocking thread pool
+struct ChannelReader {
+    recv: mpsc::Receiver<Result<Bytes, std::io::Error>>,
+    current: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.recv.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.current.len());
+        buf[..size].copy_from_slice(&self.current.split_to(size));
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl Transport for TransportEnvelope {
+    async fn put(&self, name: &str, body: &Bytes) -> Result<()> {
+        let envelope = self.envelope.clone();
+        let plain = body.clone();
+        let encoded = tokio::task::spawn_blocking(move || {
+            let mut encoded = vec![];
+            envelope.encode(Cursor::new(&plain), plain.len() as u64, &mut encoded)?;
+            anyhow::Ok(encoded)
+        }).await??;
+        self.inner.put(name, &encoded.into()).await
+    }
+
+    async fn upload(&self, source: &Path, name: &str) -> Result<()> {
+        let encoded = self.encode_file(source).await?;
+        self.inner.upload(encoded.path(), name).await
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(body) = self.inner.get(name).await? else {
+            return Ok(None)
+        };
+        let envelope = self.envelope.clone();
+        tokio::task::spawn_blocking(move || {
+            let size = body.len() as u64;
+            let (size, mut reader) = envelope.decode(Cursor::new(body), size)?;
+            let mut plain = Vec::with_capacity(size.min(1 << 30) as usize);
+            reader.read_to_end(&mut plain)?;
+            Ok(Some(plain))
+        }).await?
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        self.inner.exists(name).await
+    }
+
+    /// Decode the stream of the wrapped transport from the blocking thread pool as it arrives
+    async fn stream(&self, name: &str) -> Result<(u64, mpsc::Receiver<Result<Bytes, std::io::Error>>)> {
+        let (stored_size, recv) = self.inner.stream(name).await?;
+        let envelope = self.envelope.clone();
+        let (size_send, size_recv) = tokio::sync::oneshot::channel();
+        let (send, output) = mpsc::channel(8);
+        tokio::task::spawn_blocking(move || {
+            let input = ChannelReader { recv, current: Bytes::new() };
+            let mut reader = match envelope.decode(input, stored_size) {
+                Ok((size, reader)) => {
+                    if size_send.send(Ok(size)).is_err() {
+                        return
+                    }
+                    reader
+                },
+                Err(err) => {
+                    _ = size_send.send(Err(err));
+                    return
+                }
+            };
+
+            loop {
+                let mut buf = vec![0u8; CHUNK_SIZE];
+                let size = match reader.read(&mut buf) {
+                    Ok(size) => size,
+                    Err(err) => {
+                        _ = send.blocking_send(Err(err));
+                        return
+                    }
+                };
+
+                if size == 0 { break }
+                buf.truncate(size);
+
+                if send.blocking_send(Ok(buf.into())).is_err() {
+                    break
+                }
+            }
+        });
+
+        let size = size_recv.await.context("Blob decoding stopped")??;
+        Ok((size, output))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.inner.delete(name).await
+    }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<mpsc::Receiver<Result<String>>> {
+        self.inner.list(prefix).await
+    }
+}
+
+impl std::fmt::Debug for TransportEnvelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)?;
+        if self.envelope.compression_level.is_some() {
+            f.write_str(" (zstd)")?;
+        }
+        if let Some(key) = &self.envelope.write_key {
+            f.write_fmt(format_args!(" (encrypted with {key})"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+//! Blob storage on an FTP server, optionally secured with explicit TLS.
+//!
+//! Control connections are pooled so each operation has one to itself, transfers use passive mode.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsConnector;
+
+use super::{list_remote, remote_path, ConnectionPool, Transport};
+
+const DEFAULT_PORT: u16 = 21;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Reply code used by servers when a file doesn't exist or can't be accessed
+const FILE_UNAVAILABLE: u16 = 550;
+
+#[derive(Debug, Clone)]
+pub struct FtpParameters {
+    pub use_tls: bool,
+    pub verify: bool,
+}
+
+impl Default for FtpParameters {
+    fn default() -> Self {
+        Self { use_tls: false, verify: true }
+    }
+}
+
+/// An unexpected reply from the server, the connection is still usable after one of these
+#[derive(thiserror::Error, Debug)]
+#[error("FTP server replied {code}: {message}")]
+pub struct FtpError {
+    pub code: u16,
+    pub message: String,
+}
+
+fn is_unavailable(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<FtpError>(), Some(FtpError { code: FILE_UNAVAILABLE, .. }))
+}
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+#[derive(Clone)]
+pub struct TransportFtp {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    base: String,
+    parameters: FtpParameters,
+    connection_attempts: Option<usize>,
+    connections: Arc<ConnectionPool<FtpConnection>>,
+}
+
+/// Run an operation on a pooled connection, reconnecting and trying again if the connection fails.
+/// Evaluates to the connection, which goes back to the pool when dropped, along with the result of the operation.
+macro_rules! with_connection {
+    ($transport: expr, |$conn: ident| $body: expr) => {
+        {
+            let transport = $transport;
+            let mut backoff = MIN_BACKOFF;
+            let mut attempts = 0;
+            loop {
+                let mut guard = transport.connections.take();
+                let reused = guard.conn.is_some();
+                let result: Result<_> = async {
+                    if guard.conn.is_none() {
+                        guard.conn = Some(transport.connect().await?);
+                    }
+                    let Some($conn) = guard.conn.as_mut() else { unreachable!() };
+                    $body
+                }.await;
+
+                let err = match result {
+                    Ok(value) => break Ok((guard, value)),
+                    Err(err) => err,
+                };
+
+                // after a complete reply the connection is in a known state, anything else and it is dropped
+                if err.is::<FtpError>() {
+                    break Err(err)
+                }
+                guard.discard();
+                drop(guard);
+                if !err.is::<std::io::Error>() {
+                    break Err(err)
+                }
+
+                // idle connections may have been closed by the server, try again with a new one straight away
+                if reused {
+                    continue
+                }
+
+                attempts += 1;
+                if let Some(limit) = transport.connection_attempts {
+                    if attempts >= limit {
+                        break Err(err.context(crate::errors::ConnectionError))
+                    }
+                }
+                log::warn!("No connection to FTP transport {transport:?}, retrying: {err}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    };
+}
+
+impl TransportFtp {
+    pub fn new(host: String, port: Option<u16>, user: String, password: String, base: String, parameters: FtpParameters, connection_attempts: Option<usize>) -> Self {
+        Self {
+            host,
+            port: port.unwrap_or(DEFAULT_PORT),
+            user,
+            password,
+            base,
+            parameters,
+            connection_attempts,
+            connections: ConnectionPool::new(),
+        }
+    }
+
+    async fn connect(&self) -> Result<FtpConnection> {
+        let user = if self.user.is_empty() { "anonymous" } else { &self.user };
+        FtpConnection::connect(&self.host, self.port, user, &self.password, &self.parameters).await
+    }
+
+    /// Entries of a directory relative to the base directory, a missing directory is empty
+    async fn read_dir(&self, directory: &str) -> Result<Vec<(String, bool)>> {
+        let path = format!("{}/{directory}", self.base.trim_end_matches('/'));
+        match with_connection!(self, |conn| conn.list_directory(&path).await) {
+            Ok((_, entries)) => Ok(entries),
+            Err(err) if is_unavailable(&err) => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TransportFtp {
+    async fn put(&self, name: &str, body: &Bytes) -> Result<()> {
+        let path = remote_path(&self.base, name)?;
+        with_connection!(self, |conn| conn.store(&path, &mut &body[..]).await)?;
+        Ok(())
+    }
+
+    async fn upload(&self, source: &Path, name: &str) -> Result<()> {
+        let path = remote_path(&self.base, name)?;
+        // a missing source file shouldn't be mistaken for a connection problem
+        tokio::fs::metadata(source).await?;
+        with_connection!(self, |conn| {
+            let mut file = tokio::fs::File::open(source).await?;
+            conn.store(&path, &mut file).await
+        })?;
+        Ok(())
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let path = remote_path(&self.base, name)?;
+        match with_connection!(self, |conn| conn.size(&path).await) {
+            Ok(_) => Ok(true),
+            Err(err) if is_unavailable(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = remote_path(&self.base, name)?;
+        let result = with_connection!(self, |conn| {
+            let mut data = conn.retrieve(&path).await?;
+            let mut body = vec![];
+            data.read_to_end(&mut body).await?;
+            drop(data);
+            conn.finish_transfer().await?;
+            Ok(body)
+        });
+        match result {
+            Ok((_, body)) => Ok(Some(body)),
+            Err(err) if is_unavailable(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn stream(&self, name: &str) -> Result<(u64, tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>)> {
+        let path = remote_path(&self.base, name)?;
+        let (mut guard, (size, mut data)) = with_connection!(self, |conn| {
+            let size = conn.size(&path).await?;
+            Ok((size, conn.retrieve(&path).await?))
+        })?;
+
+        // the connection is kept out of the pool until the transfer is complete
+        let (send, recv) = tokio::sync::mpsc::channel(8);
+        tokio::spawn(async move {
+            loop {
+                let mut buf = vec![0u8; 1 << 14];
+                let size = match data.read(&mut buf).await {
+                    Ok(size) => size,
+                    Err(err) => {
+                        guard.discard();
+                        _ = send.send(Err(err)).await;
+                        return
+                    }
+                };
+
+                if size == 0 { break }
+                buf.truncate(size);
+
+                if send.send(Ok(buf.into())).await.is_err() {
+                    // abandoned part way through, the connection can't be reused
+                    guard.discard();
+                    return
+                }
+            }
+            drop(data);
+
+            let Some(conn) = guard.conn.as_mut() else { return };
+            if let Err(err) = conn.finish_transfer().await {
+                guard.discard();
+                _ = send.send(Err(std::io::Error::other(err))).await;
+            }
+        });
+        Ok((size, recv))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = remote_path(&self.base, name)?;
+        match with_connection!(self, |conn| conn.command_expect(&format!("DELE {path}"), &[250]).await) {
+            Ok(_) => Ok(()),
+            Err(err) if is_unavailable(&err) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let transport = Arc::new(self.clone());
+        Ok(list_remote(prefix, move |directory| {
+            let transport = transport.clone();
+            async move { transport.read_dir(&directory).await }
+        }))
+    }
+}
+
+impl std::fmt::Debug for TransportFtp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.parameters.use_tls { "ftps" } else { "ftp" };
+        f.write_fmt(format_args!("{scheme}://{}@{}:{}{}", self.user, self.host, self.port, self.base))
+    }
+}
+
+/// An authenticated control connection in binary mode
+struct FtpConnection {
+    control: BufStream<Box<dyn Socket>>,
+    /// Address the control connection reached, passive data connections go to the same place
+    peer: IpAddr,
+    /// Set when data connections should be secured as well
+    tls: Option<(TlsConnector, String)>,
+}
+
+impl FtpConnection {
+    async fn connect(host: &str, port: u16, user: &str, password: &str, parameters: &FtpParameters) -> Result<Self> {
+        let stream = TcpStream::connect((host, port)).await?;
+        let peer = stream.peer_addr()?.ip();
+        let mut conn = Self { control: BufStream::new(Box::new(stream)), peer, tls: None };
+        conn.expect(&[220]).await?;
+
+        if parameters.use_tls {
+            conn.command_expect("AUTH TLS", &[234]).await?;
+            let connector = TlsConnector::from(native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(!parameters.verify)
+                .build()?);
+            let stream = connector.connect(host, conn.control.into_inner()).await?;
+            conn.control = BufStream::new(Box::new(stream));
+            conn.tls = Some((connector, host.to_owned()));
+        }
+
+        let (code, message) = conn.command(&format!("USER {user}")).await?;
+        match code {
+            230 => {},
+            331 => { conn.command_expect(&format!("PASS {password}"), &[202, 230]).await?; },
+            code => return Err(FtpError { code, message }.into()),
+        }
+
+        if conn.tls.is_some() {
+            conn.command_expect("PBSZ 0", &[200]).await?;
+            conn.command_expect("PROT P", &[200]).await?;
+        }
+        conn.command_expect("TYPE I", &[200]).await?;
+        Ok(conn)
+    }
+
+    /// Read a possibly multi-line reply from the server
+    async fn read_reply(&mut self) -> Result<(u16, String)> {
+        let mut line = String::new();
+        if self.control.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+        }
+        let Some(code) = line.get(..3).and_then(|code| code.parse::<u16>().ok()) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed FTP reply: {line}")).into())
+        };
+
+        let mut message = line[3..].trim().to_owned();
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{code} ");
+            loop {
+                line.clear();
+                if self.control.read_line(&mut line).await? == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+                }
+                message.push('\n');
+                message.push_str(line.trim_end());
+                if line.starts_with(&last) { break }
+            }
+        }
+        Ok((code, message))
+    }
+
+    async fn expect(&mut self, codes: &[u16]) -> Result<String> {
+        let (code, message) = self.read_reply().await?;
+        if codes.contains(&code) {
+            Ok(message)
+        } else {
+            Err(FtpError { code, message }.into())
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> Result<(u16, String)> {
+        self.control.write_all(command.as_bytes()).await?;
+        self.control.write_all(b"\r\n").await?;
+        self.control.flush().await?;
+        self.read_reply().await
+    }
+
+    async fn command_expect(&mut self, command: &str, codes: &[u16]) -> Result<String> {
+        let (code, message) = self.command(command).await?;
+        if codes.contains(&code) {
+            Ok(message)
+        } else {
+            Err(FtpError { code, message }.into())
+        }
+    }
+
+    /// Open a passive mode data connection
+    async fn open_data(&mut self) -> Result<Box<dyn Socket>> {
+        let malformed = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed passive reply: {message}"));
+
+        // prefer extended passive mode, it works over ipv6 and through more firewalls
+        let (code, message) = self.command("EPSV").await?;
+        let port = if code == 229 {
+            // Entering Extended Passive Mode (|||port|)
+            message.split('|').nth(3).and_then(|port| port.parse::<u16>().ok()).ok_or_else(|| malformed(&message))?
+        } else {
+            // Entering Passive Mode (h1,h2,h3,h4,p1,p2), the address is ignored as it is often wrong behind NAT
+            let message = self.command_expect("PASV", &[227]).await?;
+            let numbers = message.split(['(', ')']).nth(1).unwrap_or_default();
+            let numbers: Vec<u16> = numbers.split(',').filter_map(|part| part.trim().parse().ok()).collect();
+            match numbers[..] {
+                [_, _, _, _, high, low] if high < 256 && low < 256 => high << 8 | low,
+                _ => return Err(malformed(&message).into()),
+            }
+        };
+
+        let stream = TcpStream::connect((self.peer, port)).await?;
+        Ok(match &self.tls {
+            Some((connector, domain)) => Box::new(connector.connect(domain, stream).await?),
+            None => Box::new(stream),
+        })
+    }
+
+    /// Wait for the server to confirm a transfer has completed
+    async fn finish_transfer(&mut self) -> Result<()> {
+        self.expect(&[226, 250]).await?;
+        Ok(())
+    }
+
+    async fn size(&mut self, path: &str) -> Result<u64> {
+        let message = self.command_expect(&format!("SIZE {path}"), &[213]).await?;
+        match message.parse() {
+            Ok(size) => Ok(size),
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed size reply: {message}")).into()),
+        }
+    }
+
+    /// Start downloading a file, returning the data connection it can be read from
+    async fn retrieve(&mut self, path: &str) -> Result<Box<dyn Socket>> {
+        let data = self.open_data().await?;
+        self.command_expect(&format!("RETR {path}"), &[125, 150]).await?;
+        Ok(data)
+    }
+
+    async fn store(&mut self, path: &str, body: &mut (impl AsyncRead + Unpin)) -> Result<()> {
+        self.make_parents(path).await?;
+        let mut data = self.open_data().await?;
+        self.command_expect(&format!("STOR {path}"), &[125, 150]).await?;
+        tokio::io::copy(body, &mut data).await?;
+        data.shutdown().await?;
+        drop(data);
+        self.finish_transfer().await
+    }
+
+    /// Read the entries of a directory, with whether each is a directory, from a machine readable listing
+    async fn list_directory(&mut self, path: &str) -> Result<Vec<(String, bool)>> {
+        let mut data = self.open_data().await?;
+        self.command_expect(&format!("MLSD {path}"), &[125, 150]).await?;
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await?;
+        drop(data);
+        self.finish_transfer().await?;
+
+        // each line is a set of facts like 'type=file;size=10;' followed by a space and the name
+        let mut entries = vec![];
+        for line in listing.lines() {
+            let Some((facts, name)) = line.split_once(' ') else { continue };
+            let facts = facts.to_ascii_lowercase();
+            match facts.split(';').find_map(|fact| fact.strip_prefix("type=")) {
+                Some("dir") => entries.push((name.to_owned(), true)),
+                Some("file") => entries.push((name.to_owned(), false)),
+                _ => {}
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Create the directories leading up to a path, ones that already exist are left alone
+    async fn make_parents(&mut self, path: &str) -> Result<()> {
+        let Some((parent, _)) = path.rsplit_once('/') else { return Ok(()) };
+        let mut directory = String::new();
+        for part in parent.split('/').filter(|part| !part.is_empty()) {
+            if parent.starts_with('/') || !directory.is_empty() {
+                directory.push('/');
+            }
+            directory.push_str(part);
+            self.command(&format!("MKD {directory}")).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Read only blob storage served over HTTP(S), such as a mirror of another filestore.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{Method, StatusCode};
+use tokio_stream::StreamExt;
+
+use super::{list_remote, remote_path, Transport};
+use crate::errors::ReadOnlyError;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Characters escaped in each segment of a blob path
+const PATH_SEGMENT: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?')
+    .add(b'`').add(b'{').add(b'}');
+
+#[derive(Debug)]
+pub struct HttpParameters {
+    /// PEM file holding the client certificate and key to identify with
+    pub pki: Option<PathBuf>,
+    pub verify: bool,
+}
+
+impl Default for HttpParameters {
+    fn default() -> Self {
+        Self { pki: None, verify: true }
+    }
+}
+
+#[derive(Clone)]
+pub struct TransportHttp {
+    client: reqwest::Client,
+    /// Address of the base directory, without a trailing slash
+    base_url: String,
+    user: String,
+    password: Option<String>,
+    connection_attempts: Option<usize>,
+}
+
+impl TransportHttp {
+    /// Open a transport reading from the directory at the given address
+    pub async fn new(address: &str, user: String, password: Option<String>, parameters: HttpParameters, connection_attempts: Option<usize>) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(!parameters.verify);
+        if let Some(pki) = &parameters.pki {
+            let pem = tokio::fs::read(pki).await?;
+            builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&pem, &pem)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            base_url: address.trim_end_matches('/').to_owned(),
+            user,
+            password,
+            connection_attempts,
+        })
+    }
+
+    fn url(&self, name: &str) -> Result<String> {
+        // the base url is already part of the address, only the blob path needs to be resolved
+        let path = remote_path("", name)?;
+        let path: Vec<String> = path.split('/').map(|part| utf8_percent_encode(part, PATH_SEGMENT).to_string()).collect();
+        Ok(self.base_url.clone() + &path.join("/"))
+    }
+
+    /// Make a request for a blob, trying again while the server can't be reached
+    async fn request(&self, method: Method, name: &str) -> Result<reqwest::Response> {
+        self.send(method, &self.url(name)?).await
+    }
+
+    async fn send(&self, method: Method, url: &str) -> Result<reqwest::Response> {
+        let mut backoff = MIN_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            let mut request = self.client.request(method.clone(), url);
+            if !self.user.is_empty() {
+                request = request.basic_auth(&self.user, self.password.as_ref());
+            }
+
+            let err = match request.send().await {
+                Ok(response) => return Ok(response),
+                Err(err) if err.is_connect() || err.is_timeout() => err,
+                Err(err) => return Err(err.into()),
+            };
+
+            attempts += 1;
+            if let Some(limit) = self.connection_attempts {
+                if attempts >= limit {
+                    return Err(anyhow::Error::from(err).context(crate::errors::ConnectionError))
+                }
+            }
+            warn!("No connection to HTTP transport {}, retrying: {err}", self.base_url);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Entries of a directory relative to the base directory, read from the index page the server
+    /// generates for it. Links ending with a slash are directories, a missing directory is empty.
+    async fn read_dir(&self, directory: &str) -> Result<Vec<(String, bool)>> {
+        let mut url = self.base_url.clone() + "/";
+        for part in directory.split('/').filter(|part| !part.is_empty()) {
+            url += &utf8_percent_encode(part, PATH_SEGMENT).to_string();
+            url += "/";
+        }
+
+        let response = self.send(Method::GET, &url).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![])
+        }
+        let page = response.error_for_status()?.text().await?;
+
+        let mut entries = vec![];
+        for link in page.split("href=\"").skip(1) {
+            let Some((link, _)) = link.split_once('"') else { continue };
+            // only relative links to entries of this directory, not sorting options or parent directories
+            let link = link.strip_prefix("./").unwrap_or(link);
+            if link.is_empty() || link.starts_with(['?', '/', '#']) || link.starts_with("..") || link.contains("://") {
+                continue
+            }
+            let (link, is_dir) = match link.strip_suffix('/') {
+                Some(link) => (link, true),
+                None => (link, false),
+            };
+            if link.contains('/') {
+                continue
+            }
+            let name = percent_decode_str(link).decode_utf8_lossy().into_owned();
+            if !entries.contains(&(name.clone(), is_dir)) {
+                entries.push((name, is_dir));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl Transport for TransportHttp {
+    async fn put(&self, _name: &str, _body: &Bytes) -> Result<()> {
+        Err(ReadOnlyError.into())
+    }
+
+    async fn upload(&self, _source: &Path, _name: &str) -> Result<()> {
+        Err(ReadOnlyError.into())
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let response = self.request(Method::HEAD, name).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false)
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, name).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None)
+        }
+        Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+    }
+
+    async fn stream(&self, name: &str) -> Result<(u64, tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>)> {
+        let response = self.request(Method::GET, name).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            bail!("Blob [{name}] not found at {}", self.base_url);
+        }
+        let response = response.error_for_status()?;
+        let size = response.content_length().unwrap_or_default();
+
+        let mut stream = response.bytes_stream();
+        let (send, recv) = tokio::sync::mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(std::io::Error::other);
+                let failed = chunk.is_err();
+                if send.send(chunk).await.is_err() || failed {
+                    return
+                }
+            }
+        });
+        Ok((size, recv))
+    }
+
+    async fn delete(&self, _name: &str) -> Result<()> {
+        Err(ReadOnlyError.into())
+    }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let transport = self.clone();
+        Ok(list_remote(prefix, move |directory| {
+            let transport = transport.clone();
+            async move { transport.read_dir(&directory).await }
+        }))
+    }
+}
+
+impl std::fmt::Debug for TransportHttp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.base_url)
+    }
+}
@@ -5,7 +5,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
 
-use super::Transport;
+use super::{denormalize, Transport};
 
 pub struct LocalTransport {
     path: PathBuf,
@@ -132,6 +132,57 @@ impl Transport for LocalTransport {
         }
         Ok(())
     }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let root = self.path.clone();
+        let prefix = prefix.unwrap_or_default().trim_start_matches('/').to_owned();
+        let (send, recv) = tokio::sync::mpsc::channel(64);
+        tokio::task::spawn_blocking(move || {
+            list_directory(&root, &root, &prefix, &send);
+        });
+        Ok(recv)
+    }
+}
+
+/// Send the name of every blob under a directory that starts with the prefix.
+/// Returns false once the receiver has gone away.
+fn list_directory(root: &Path, directory: &Path, prefix: &str, send: &tokio::sync::mpsc::Sender<Result<String>>) -> bool {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => return send.blocking_send(Err(err.into())).is_ok(),
+    };
+
+    for entry in entries {
+        let (entry, file_type) = match entry.and_then(|entry| { let file_type = entry.file_type()?; Ok((entry, file_type)) }) {
+            Ok(entry) => entry,
+            Err(err) => return send.blocking_send(Err(err.into())).is_ok(),
+        };
+
+        if file_type.is_dir() {
+            // skip shard directories that can't hold a name with this prefix
+            let depth = directory.strip_prefix(root).map(|path| path.components().count()).unwrap_or_default();
+            if depth < 4 {
+                let name = entry.file_name();
+                if let (Some(name), Some(expected)) = (name.to_str(), prefix.chars().nth(depth)) {
+                    if name.chars().count() == 1 && !name.starts_with(expected) {
+                        continue
+                    }
+                }
+            }
+
+            if !list_directory(root, &entry.path(), prefix, send) {
+                return false
+            }
+        } else {
+            let path = entry.path();
+            let Ok(relative) = path.strip_prefix(root) else { continue };
+            let name = denormalize(&relative.to_string_lossy());
+            if name.starts_with(prefix) && send.blocking_send(Ok(name)).is_err() {
+                return false
+            }
+        }
+    }
+    true
 }
 
 impl std::fmt::Debug for LocalTransport {
@@ -1,13 +1,18 @@
 use std::io::Write;
 use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
 
 use async_trait::async_trait;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 
 pub mod local;
 pub mod azure;
 pub mod s3;
+pub mod ftp;
+pub mod sftp;
+pub mod http;
+pub mod envelope;
 
 #[async_trait]
 pub trait Transport: Send + Sync + std::fmt::Debug {
@@ -39,5 +44,133 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     async fn stream(&self, name: &str) -> Result<(u64, tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>)>;
 
     async fn delete(&self, name: &str) -> Result<()>;
+
+    /// List the names of the stored blobs, limited to those starting with the prefix if one is given.
+    /// Names are sent as they are found, in no particular order.
+    async fn list(&self, _prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        bail!("Listing blobs is not supported by {self:?}")
+    }
+}
+
+/// Most idle connections a transport keeps open to its server
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Connections to a server that are waiting to be reused.
+///
+/// Each operation takes a connection of its own so a long transfer doesn't hold up anything else,
+/// connections are put back when the operation is done with them unless the pool is already full.
+pub(crate) struct ConnectionPool<C> {
+    idle: Mutex<Vec<C>>,
+}
+
+impl<C> ConnectionPool<C> {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { idle: Mutex::new(vec![]) })
+    }
+
+    /// Take an idle connection, if there isn't one the caller needs to fill in a new connection
+    pub fn take(self: &Arc<Self>) -> Pooled<C> {
+        let conn = self.idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+        Pooled { pool: self.clone(), conn }
+    }
+}
+
+/// A connection taken from a pool, returned to it when dropped. Connections in an unknown state
+/// should be discarded so they are closed instead.
+pub(crate) struct Pooled<C> {
+    pool: Arc<ConnectionPool<C>>,
+    pub conn: Option<C>,
+}
+
+impl<C> Pooled<C> {
+    /// Close the connection instead of returning it to the pool
+    pub fn discard(&mut self) {
+        self.conn = None;
+    }
+}
+
+impl<C> Drop for Pooled<C> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else { return };
+        let mut idle = self.pool.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Location of a blob on a remote file system under the given base directory.
+///
+/// Blobs are spread over directories named for the first four characters of their name,
+/// the same layout the local transport and the python transports use, so existing stores can be shared.
+pub(crate) fn remote_path(base: &str, name: &str) -> Result<String> {
+    let name = name.trim_start_matches('/');
+    if name.split('/').any(|part| part == "..") {
+        bail!("Blob names can't refer to parent directories: {name}");
+    }
+
+    let base = base.trim_end_matches('/');
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some(a), Some(b), Some(c), Some(d)) => Ok(format!("{base}/{a}/{b}/{c}/{d}/{name}")),
+        _ => Ok(format!("{base}/{name}")),
+    }
 }
 
+/// Recover the name of a blob from its path relative to the base directory, undoing the sharding in `remote_path`
+pub(crate) fn denormalize(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() > 4 && parts[..4].iter().all(|part| part.chars().count() == 1) {
+        let name = parts[4..].join("/");
+        if name.starts_with(&parts[..4].concat()) {
+            return name
+        }
+    }
+    path.to_owned()
+}
+
+/// List the blobs on a remote file system that start with the prefix, reading each directory with the given function.
+///
+/// The function is given a directory relative to the base directory and returns the names of its entries,
+/// and whether each is a directory. Shard directories that can't hold a name with the prefix are skipped.
+pub(crate) fn list_remote<F, Fut>(prefix: Option<&str>, read_dir: F) -> tokio::sync::mpsc::Receiver<Result<String>>
+where
+    F: Fn(String) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<Vec<(String, bool)>>> + Send,
+{
+    let prefix = prefix.unwrap_or_default().trim_start_matches('/').to_owned();
+    let (send, recv) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        let mut directories = vec![String::new()];
+        while let Some(directory) = directories.pop() {
+            let entries = match read_dir(directory.clone()).await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    _ = send.send(Err(err)).await;
+                    return
+                }
+            };
+
+            let depth = directory.split('/').filter(|part| !part.is_empty()).count();
+            for (name, is_dir) in entries {
+                let path = if directory.is_empty() { name.clone() } else { format!("{directory}/{name}") };
+                if is_dir {
+                    if depth < 4 && name.chars().count() == 1 {
+                        if let Some(expected) = prefix.chars().nth(depth) {
+                            if !name.starts_with(expected) {
+                                continue
+                            }
+                        }
+                    }
+                    directories.push(path);
+                } else {
+                    let name = denormalize(&path);
+                    if name.starts_with(&prefix) && send.send(Ok(name)).await.is_err() {
+                        return
+                    }
+                }
+            }
+        }
+    });
+    recv
+}
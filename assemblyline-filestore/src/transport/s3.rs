@@ -376,20 +376,52 @@ impl Transport for TransportS3 {
                 .send().await
         })
     }
-}
 
+    /// Page through the bucket listing in a task, following the continuation token of each page
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let client = self.client.clone();
+        let bucket = self.parameters.s3_bucket.clone();
+        let prefix = prefix.unwrap_or_default().to_owned();
+        let retry_limit = self.retry_limit;
+
+        let (send, recv) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut continuation = None;
+            loop {
+                let page = retry!(retry_limit, {
+                    client
+                        .list_objects_v2()
+                        .bucket(&bucket)
+                        .prefix(&prefix)
+                        .set_continuation_token(continuation.clone())
+                        .send().await
+                });
+
+                let page = match page {
+                    Ok(page) => page,
+                    Err(err) => {
+                        _ = send.send(Err(err)).await;
+                        return
+                    }
+                };
+
+                for object in page.contents() {
+                    if let Some(key) = object.key() {
+                        if send.send(Ok(key.to_owned())).await.is_err() {
+                            return
+                        }
+                    }
+                }
 
-//     def list(self, prefix: Optional[str] = None) -> Iterable[str]:
-//         args = {
-//             'Bucket': self.bucket,
-//             'Prefix': prefix or '',
-//             'MaxKeys': 50000,
-//         }
-//         while args.get('ContinuationToken', None) != '':
-//             data = self.client.list_objects_v2(**args)
-//             args['ContinuationToken'] = data.get("NextContinuationToken", '')
-//             for chunk in data.get('Contents', []):
-//                 yield chunk['Key']
+                continuation = page.next_continuation_token;
+                if continuation.is_none() {
+                    return
+                }
+            }
+        });
+        Ok(recv)
+    }
+}
 
 
 mod verifier {
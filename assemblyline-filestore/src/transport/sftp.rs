@@ -0,0 +1,300 @@
+//! Blob storage on an SFTP server.
+//!
+//! libssh2 is blocking, all of its calls are made from the blocking thread pool. Sessions are pooled
+//! so each operation has one to itself, sessions that fail are closed rather than reused.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+
+use super::{list_remote, remote_path, ConnectionPool, Pooled, Transport};
+
+const DEFAULT_PORT: u16 = 22;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Longest a single call to the server may block
+const SESSION_TIMEOUT_MS: u32 = 60_000;
+
+/// SFTP status code for a missing file
+const NO_SUCH_FILE: i32 = 2;
+
+#[derive(Debug, Default)]
+pub struct SftpParameters {
+    pub private_key: Option<PathBuf>,
+    pub private_key_pass: Option<String>,
+    pub validate_host: bool,
+}
+
+#[derive(Clone)]
+pub struct TransportSftp {
+    client: Arc<SftpClient>,
+}
+
+struct SftpClient {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    base: String,
+    parameters: SftpParameters,
+    connection_attempts: Option<usize>,
+    connections: Arc<ConnectionPool<SftpConnection>>,
+}
+
+struct SftpConnection {
+    _session: Session,
+    sftp: Sftp,
+}
+
+fn is_missing(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ssh2::Error>().is_some_and(|err| err.code() == ErrorCode::SFTP(NO_SUCH_FILE))
+}
+
+impl TransportSftp {
+    pub fn new(host: String, port: Option<u16>, user: String, password: String, base: String, parameters: SftpParameters, connection_attempts: Option<usize>) -> Self {
+        Self {
+            client: Arc::new(SftpClient {
+                host,
+                port: port.unwrap_or(DEFAULT_PORT),
+                user,
+                password,
+                base,
+                parameters,
+                connection_attempts,
+                connections: ConnectionPool::new(),
+            })
+        }
+    }
+
+    /// Run an operation against the server from the blocking thread pool
+    async fn run<T: Send + 'static>(&self, op: impl Fn(&Sftp) -> Result<T> + Send + 'static) -> Result<T> {
+        let client = self.client.clone();
+        let (_, value) = tokio::task::spawn_blocking(move || client.with_connection(op)).await??;
+        Ok(value)
+    }
+
+    /// Entries of a directory relative to the base directory, a missing directory is empty
+    async fn read_dir(&self, directory: &str) -> Result<Vec<(String, bool)>> {
+        let path = PathBuf::from(format!("{}/{directory}", self.client.base.trim_end_matches('/')));
+        match self.run(move |sftp| Ok(sftp.readdir(&path)?)).await {
+            Ok(entries) => Ok(entries.into_iter()
+                .filter_map(|(path, stat)| Some((path.file_name()?.to_string_lossy().into_owned(), stat.is_dir())))
+                .collect()),
+            Err(err) if is_missing(&err) => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl SftpClient {
+    fn connect(&self) -> Result<SftpConnection> {
+        let stream = std::net::TcpStream::connect((self.host.as_str(), self.port))?;
+        let mut session = Session::new()?;
+        session.set_timeout(SESSION_TIMEOUT_MS);
+        session.set_tcp_stream(stream);
+        session.handshake()?;
+
+        if self.parameters.validate_host {
+            self.check_host_key(&session)?;
+        }
+
+        match &self.parameters.private_key {
+            Some(key) => session.userauth_pubkey_file(&self.user, None, key, self.parameters.private_key_pass.as_deref())?,
+            None => session.userauth_password(&self.user, &self.password)?,
+        }
+
+        let sftp = session.sftp()?;
+        Ok(SftpConnection { _session: session, sftp })
+    }
+
+    /// Make sure the server's key matches the one recorded for it in the user's known_hosts file
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let Some((key, _)) = session.host_key() else {
+            bail!("SFTP server {} didn't offer a host key", self.host)
+        };
+        let mut known_hosts = session.known_hosts()?;
+        let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        known_hosts.read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH)?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => bail!("Host key of SFTP server {} doesn't match known_hosts", self.host),
+            CheckResult::NotFound => bail!("SFTP server {} isn't in known_hosts", self.host),
+            CheckResult::Failure => bail!("Could not check the host key of SFTP server {}", self.host),
+        }
+    }
+
+    /// Run an operation on a pooled session, reconnecting and trying again if the session fails.
+    /// The session is returned along with the result, it goes back to the pool when dropped.
+    fn with_connection<T>(&self, op: impl Fn(&Sftp) -> Result<T>) -> Result<(Pooled<SftpConnection>, T)> {
+        let mut backoff = MIN_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            let mut connection = self.connections.take();
+            let reused = connection.conn.is_some();
+            let conn = match connection.conn.take() {
+                Some(conn) => Ok(conn),
+                None => self.connect(),
+            };
+            let err = match conn {
+                Ok(conn) => match op(&conn.sftp) {
+                    Ok(value) => {
+                        connection.conn = Some(conn);
+                        return Ok((connection, value))
+                    },
+                    // errors reported by the sftp subsystem leave the session usable
+                    Err(err) if err.downcast_ref::<ssh2::Error>().is_some_and(|err| matches!(err.code(), ErrorCode::SFTP(_))) => {
+                        connection.conn = Some(conn);
+                        return Err(err)
+                    },
+                    // idle sessions may have been closed by the server, try again with a new one straight away
+                    Err(_) if reused => continue,
+                    Err(err) => err,
+                },
+                // only failing to reach the server is worth trying again, not failing to log in
+                Err(err) if err.is::<std::io::Error>() => err,
+                Err(err) => return Err(err),
+            };
+
+            attempts += 1;
+            if let Some(limit) = self.connection_attempts {
+                if attempts >= limit {
+                    return Err(err.context(crate::errors::ConnectionError))
+                }
+            }
+            warn!("No connection to SFTP transport {}:{}, retrying: {err}", self.host, self.port);
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Create the directories leading up to a path, failures are left to show up when the file is written
+fn make_parents(sftp: &Sftp, path: &Path) {
+    let mut parents: Vec<&Path> = path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()).collect();
+    parents.reverse();
+    for directory in parents {
+        if sftp.stat(directory).is_err() {
+            _ = sftp.mkdir(directory, 0o755);
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TransportSftp {
+    async fn put(&self, name: &str, body: &Bytes) -> Result<()> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        let body = body.clone();
+        self.run(move |sftp| {
+            make_parents(sftp, &path);
+            let mut file = sftp.create(&path)?;
+            file.write_all(&body)?;
+            Ok(())
+        }).await
+    }
+
+    async fn upload(&self, source: &Path, name: &str) -> Result<()> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        // a missing source file shouldn't be mistaken for a connection problem
+        tokio::fs::metadata(source).await?;
+        let source = source.to_owned();
+        self.run(move |sftp| {
+            make_parents(sftp, &path);
+            let mut input = std::fs::File::open(&source)?;
+            let mut file = sftp.create(&path)?;
+            std::io::copy(&mut input, &mut file)?;
+            Ok(())
+        }).await
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        match self.run(move |sftp| Ok(sftp.stat(&path)?)).await {
+            Ok(_) => Ok(true),
+            Err(err) if is_missing(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        let result = self.run(move |sftp| {
+            let mut body = vec![];
+            sftp.open(&path)?.read_to_end(&mut body)?;
+            Ok(body)
+        }).await;
+        match result {
+            Ok(body) => Ok(Some(body)),
+            Err(err) if is_missing(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn stream(&self, name: &str) -> Result<(u64, tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>)> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        let client = self.client.clone();
+        let (mut connection, (size, mut file)) = tokio::task::spawn_blocking(move || client.with_connection(move |sftp| {
+            let mut file = sftp.open(&path)?;
+            let size = file.stat()?.size.unwrap_or_default();
+            Ok((size, file))
+        })).await??;
+
+        // the session is kept out of the pool until the transfer is complete
+        let (send, recv) = tokio::sync::mpsc::channel(8);
+        tokio::task::spawn_blocking(move || {
+            loop {
+                let mut buf = vec![0u8; 1 << 14];
+                let size = match file.read(&mut buf) {
+                    Ok(size) => size,
+                    Err(err) => {
+                        drop(file);
+                        connection.discard();
+                        _ = send.blocking_send(Err(err));
+                        return
+                    }
+                };
+
+                if size == 0 { break }
+                buf.truncate(size);
+
+                if send.blocking_send(Ok(buf.into())).is_err() {
+                    break
+                }
+            }
+            drop(file);
+            drop(connection);
+        });
+        Ok((size, recv))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = PathBuf::from(remote_path(&self.client.base, name)?);
+        match self.run(move |sftp| Ok(sftp.unlink(&path)?)).await {
+            Ok(()) => Ok(()),
+            Err(err) if is_missing(&err) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
+        let transport = self.clone();
+        Ok(list_remote(prefix, move |directory| {
+            let transport = transport.clone();
+            async move { transport.read_dir(&directory).await }
+        }))
+    }
+}
+
+impl std::fmt::Debug for TransportSftp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let client = &self.client;
+        f.write_fmt(format_args!("sftp://{}@{}:{}{}", client.user, client.host, client.port, client.base))
+    }
+}
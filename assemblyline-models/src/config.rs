@@ -3,6 +3,7 @@ use std::{collections::HashMap, path::PathBuf};
 use serde::{Deserialize, Serialize};
 use serde_with::{SerializeDisplay, DeserializeFromStr};
 
+use crate::datastore::service::EnvironmentVariable;
 use crate::types::ServiceName;
 
 
@@ -491,6 +492,43 @@ pub struct Auth {
 //     "threshold": 500
 // }
 
+/// Alerter Configuration
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Alerter {
+    /// Time to live (days) for an alert in the system
+    pub alert_ttl: u32,
+    /// List of fields that should not change during an alert update
+    pub constant_alert_fields: Vec<String>,
+    /// Default field used for alert grouping view
+    pub default_group_field: String,
+    /// Time in seconds that we give extended scans and workflow to complete their work before we start showing alerts in the alert viewer.
+    pub delay: u32,
+    /// List of group fields that when selected will ignore certain alerts where this field is missing.
+    pub filtering_group_fields: Vec<String>,
+    /// List of group fields that are sure to be present in all alerts.
+    pub non_filtering_group_fields: Vec<String>,
+    /// Minimum score to reach for a submission to be considered an alert.
+    pub threshold: i32,
+    /// Keep the alert queue in a redis stream so alerts aren't lost if an alerter stops while processing them
+    pub durable_queue: bool,
+}
+
+impl Default for Alerter {
+    fn default() -> Self {
+        Self {
+            alert_ttl: 90,
+            constant_alert_fields: vec!["alert_id".to_owned(), "file".to_owned(), "ts".to_owned()],
+            default_group_field: "file.sha256".to_owned(),
+            delay: 300,
+            filtering_group_fields: vec!["file.name".to_owned(), "status".to_owned(), "priority".to_owned()],
+            non_filtering_group_fields: vec!["file.md5".to_owned(), "file.sha1".to_owned(), "file.sha256".to_owned()],
+            threshold: 500,
+            durable_queue: false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct Classification {
@@ -516,17 +554,39 @@ pub struct Dispatcher {
     pub timeout: f64,
     /// Maximum submissions allowed to be in-flight
     pub max_inflight: u64,
+    /// Keep the submission queue in a redis stream so submissions aren't lost if a dispatcher stops while taking them
+    pub durable_queue: bool,
+    /// Share the service queues between tenants by deficit round robin rather than strictly by priority
+    pub service_queue_fairness: QueueFairness,
+    /// Share of the service queues each tenant gets relative to the others when fairness is enabled, tenants not listed get a weight of one
+    pub service_queue_weights: HashMap<String, f64>,
 }
 
 impl Default for Dispatcher {
     fn default() -> Self {
         Self {
             timeout: 15.0 * 60.0,
-            max_inflight: 1000
+            max_inflight: 1000,
+            durable_queue: false,
+            service_queue_fairness: QueueFairness::Disabled,
+            service_queue_weights: Default::default(),
         }
     }
 }
 
+/// Who service queues are shared fairly between, priority is only respected within each tenant
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueFairness {
+    /// Tasks are taken strictly by priority
+    #[default]
+    Disabled,
+    /// Each submitting user gets an equal share
+    Submitter,
+    /// Each group gets an equal share, based on the first group of the submitting user
+    Group,
+}
+
 
 // Configuration options regarding data expiry
 #[derive(Serialize, Deserialize)]
@@ -632,6 +692,13 @@ pub struct Ingester {
     pub cache_dtl: u32,
     /// Always create submissions even on cache hit?
     pub always_create_submission: bool,
+    /// Keep the ingest queue in a redis stream so submissions aren't lost if an ingester stops while taking them
+    pub durable_queue: bool,
+    /// Limits on how many submissions can be ingested over time
+    pub rate_limits: IngestRateLimits,
+    /// Submit the file packed in CaRT files given for ingestion, keeping the CaRT header as submission metadata.
+    /// Off by default as every ingested file has to be read to check for a CaRT header.
+    pub unwrap_cart: bool,
 }
 
 impl Default for Ingester {
@@ -659,28 +726,85 @@ impl Default for Ingester {
             ].into_iter().collect(),
             max_inflight: 5000,
             always_create_submission: false,
+            durable_queue: false,
+            rate_limits: Default::default(),
+            unwrap_cart: false,
         }
     }
 }
 
+/// Number of submissions allowed in the last hour and in the last day, unset values are unlimited
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Submissions allowed over the last hour
+    pub hourly: Option<u64>,
+    /// Submissions allowed over the last day
+    pub daily: Option<u64>,
+}
+
+/// Submission rate limits enforced by the ingester
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct IngestRateLimits {
+    /// Limits for each submitter without an entry in `users`
+    pub per_user: RateLimit,
+    /// Limits for specific submitters
+    pub users: HashMap<String, RateLimit>,
+    /// Limits for each submission type, shared by all the submissions of that type
+    pub ingest_types: HashMap<String, RateLimit>,
+    /// Limits for each submitter group, shared by all the submissions made for that group
+    pub groups: HashMap<String, RateLimit>,
+}
+
 
 /// Redis Service configuration
 #[derive(Serialize, Deserialize)]
 pub struct RedisServer {
-    /// Hostname of Redis instance
+    /// Hostname of Redis instance, `memory://<name>` keeps the data in the memory of the process instead
     pub host: String,
     /// Port of Redis instance
     pub port: u16,
     /// Which db to connect to
     #[serde(default)]
     pub db: i64,
+    /// Find the master through these sentinels rather than connecting to `host` and `port`
+    #[serde(default)]
+    pub sentinel: Option<RedisSentinel>,
+    /// Connect to a redis cluster made of these nodes rather than to `host` and `port`, `db` must be 0.
+    /// Tls to a cluster uses the system trust store, client certificates and certificate authorities aren't supported.
+    #[serde(default)]
+    pub cluster_nodes: Vec<RedisNode>,
+}
+
+/// Address of a single redis or sentinel server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RedisNode {
+    /// Hostname of the server
+    pub host: String,
+    /// Port of the server
+    pub port: u16,
+}
+
+/// Sentinels monitoring a redis master
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedisSentinel {
+    /// Sentinel servers to ask for the address of the master
+    pub hosts: Vec<RedisNode>,
+    /// Name the master is monitored under
+    #[serde(default = "default_sentinel_master_name")]
+    pub master_name: String,
 }
 
+fn default_sentinel_master_name() -> String { "mymaster".to_owned() }
+
 fn default_redis_nonpersistant() -> RedisServer {
     RedisServer {
         host: "127.0.0.1".to_owned(),
         port: 6379,
         db: 0,
+        sentinel: None,
+        cluster_nodes: vec![],
     }
 }
 
@@ -689,28 +813,54 @@ fn default_redis_persistant() -> RedisServer {
         host: "127.0.0.1".to_owned(),
         port: 6380,
         db: 0,
+        sentinel: None,
+        cluster_nodes: vec![],
     }
 }
 
 
-// @odm.model(index=False, store=False)
-// class ESMetrics(odm.Model):
-//     hosts: List[str] = odm.Optional(odm.List(odm.Keyword()), description="Elasticsearch hosts")
-//     host_certificates: str = odm.Optional(odm.Keyword(), description="Host certificates")
-//     warm = odm.Integer(description="How long, per unit of time, should a document remain in the 'warm' tier?")
-//     cold = odm.Integer(description="How long, per unit of time, should a document remain in the 'cold' tier?")
-//     delete = odm.Integer(description="How long, per unit of time, should a document remain before being deleted?")
-//     unit = odm.Enum(['d', 'h', 'm'], description="Unit of time used by `warm`, `cold`, `delete` phases")
-
-
-// DEFAULT_ES_METRICS = {
-//     'hosts': None,
-//     'host_certificates': None,
-//     'warm': 2,
-//     'cold': 30,
-//     'delete': 90,
-//     'unit': 'd'
-// }
+/// Unit of time used by the lifecycle phases of metrics indices
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsTimeUnit {
+    /// Days
+    D,
+    /// Hours
+    H,
+    /// Minutes
+    M,
+}
+
+/// Elasticsearch cluster the metrics rollups are written to
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ESMetrics {
+    /// Elasticsearch hosts, rollups are not written when this is empty
+    pub hosts: Option<Vec<String>>,
+    /// Host certificates
+    pub host_certificates: Option<String>,
+    /// How long, per unit of time, should a document remain in the 'warm' tier?
+    pub warm: u32,
+    /// How long, per unit of time, should a document remain in the 'cold' tier?
+    pub cold: u32,
+    /// How long, per unit of time, should a document remain before being deleted?
+    pub delete: u32,
+    /// Unit of time used by `warm`, `cold`, `delete` phases
+    pub unit: MetricsTimeUnit,
+}
+
+impl Default for ESMetrics {
+    fn default() -> Self {
+        Self {
+            hosts: None,
+            host_certificates: None,
+            warm: 2,
+            cold: 30,
+            delete: 90,
+            unit: MetricsTimeUnit::D,
+        }
+    }
+}
 
 
 #[derive(Serialize, Deserialize, Default)]
@@ -723,13 +873,26 @@ pub struct APMServer {
 }
 
 
+/// OpenTelemetry collector traces are exported to over OTLP/HTTP
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct OTLPServer {
+    /// URL traces are posted to, including the path (ie, http://collector:4318/v1/traces)
+    pub server_url: Option<String>,
+    /// Extra headers sent with every export, such as authentication for the collector
+    pub headers: HashMap<String, String>,
+}
+
 /// Metrics Configuration
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct Metrics {
     /// APM server configuration
     pub apm_server: APMServer,
-//     elasticsearch: ESMetrics = odm.Compound(ESMetrics, default=DEFAULT_ES_METRICS, description="Where to export metrics?")
+    /// OTLP trace export configuration
+    pub otlp_server: OTLPServer,
+    /// Where to export metrics?
+    pub elasticsearch: ESMetrics,
     /// How often should we be exporting metrics in seconds?
     pub export_interval: u32,
     /// Redis for Dashboard metrics
@@ -740,6 +903,8 @@ impl Default for Metrics {
     fn default() -> Self {
         Self {
             apm_server: Default::default(),
+            otlp_server: Default::default(),
+            elasticsearch: Default::default(),
             export_interval: 5,
             redis: default_redis_nonpersistant()
         }
@@ -879,6 +1044,48 @@ impl Default for Redis {
 // }
 
 
+/// A set of default values to be used running a service when no other value is set
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScalerServiceDefaults {
+    /// Period, in seconds, to wait before scaling up a service deployment
+    pub growth: u32,
+    /// Period, in seconds, to wait before scaling down a service deployment
+    pub shrink: u32,
+    /// Backlog threshold that dictates scaling adjustments
+    pub backlog: u32,
+    /// The minimum number of service instances to be running
+    pub min_instances: u32,
+    /// Environment variables to pass onto services
+    pub environment: Vec<EnvironmentVariable>,
+}
+
+impl Default for ScalerServiceDefaults {
+    fn default() -> Self {
+        Self {
+            growth: 60,
+            shrink: 30,
+            backlog: 100,
+            min_instances: 0,
+            environment: vec![
+                EnvironmentVariable { name: "SERVICE_API_HOST".to_owned(), value: "http://service-server:5003".to_owned() },
+                EnvironmentVariable { name: "AL_SERVICE_TASK_LIMIT".to_owned(), value: "inf".to_owned() },
+            ],
+        }
+    }
+}
+
+/// Scaler Configuration
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Scaler {
+    /// Defaults Scaler will assign to a service.
+    pub service_defaults: ScalerServiceDefaults,
+    /// Additional labels to be applied to services('=' delimited)
+    pub additional_labels: Option<Vec<String>>,
+}
+
+
 // @odm.model(index=False, store=False)
 // class RegistryConfiguration(odm.Model):
 //     name: str = odm.Text(description="Name of container registry")
@@ -954,9 +1161,8 @@ impl Default for Redis {
 #[serde(default)]
 // @odm.model(index=False, store=False, description="")
 pub struct Core {
-    // /// Configuration for Alerter
-    // #[serde(default)]
-    // pub alerter: Alerter,
+    /// Configuration for Alerter
+    pub alerter: Alerter,
     /// Configuration for the permanent submission archive
     pub archiver: Archiver,
     /// Configuration for Dispatcher
@@ -971,9 +1177,8 @@ pub struct Core {
     pub plumber: Plumber,
     /// Configuration for Redis instances
     pub redis: Redis,
-    // /// Configuration for Scaler
-    // #[serde(default)]
-    // pub scaler: Scaler,
+    /// Configuration for Scaler
+    pub scaler: Scaler,
     // /// Configuration for Updater
     // #[serde(default)]
     // pub updater: Updater,
@@ -1002,13 +1207,16 @@ pub struct Plumber {
     pub notification_queue_interval: u64,
     /// Max age in seconds notification queue messages can be
     pub notification_queue_max_age: u64,
+    /// Interval in seconds at which blobs missing from some of the filestore transports are copied to them, zero to never check
+    pub filestore_reconcile_interval: u64,
 }
 
 impl Default for Plumber {
     fn default() -> Self {
         Self {
             notification_queue_interval: 30 * 60,
-            notification_queue_max_age: 24 * 60 * 60
+            notification_queue_max_age: 24 * 60 * 60,
+            filestore_reconcile_interval: 24 * 60 * 60,
         }
     }
 }
@@ -1113,6 +1321,8 @@ pub struct Filestore {
     pub cache: Vec<String>,
     /// List of filestores used for storage
     pub storage: Vec<String>,
+    /// How writes are replicated between the filestores used for storage
+    pub replication: FilestoreReplication,
 }
 
 impl Default for Filestore {
@@ -1120,7 +1330,27 @@ impl Default for Filestore {
         Self {
             archive: vec!["s3://al_storage_key:Ch@ngeTh!sPa33w0rd@localhost:9000?s3_bucket=al-archive&use_ssl=False".to_string()],
             cache: vec!["s3://al_storage_key:Ch@ngeTh!sPa33w0rd@localhost:9000?s3_bucket=al-cache&use_ssl=False".to_string()],
-            storage: vec!["s3://al_storage_key:Ch@ngeTh!sPa33w0rd@localhost:9000?s3_bucket=al-storage&use_ssl=False".to_string()]
+            storage: vec!["s3://al_storage_key:Ch@ngeTh!sPa33w0rd@localhost:9000?s3_bucket=al-storage&use_ssl=False".to_string()],
+            replication: Default::default(),
+        }
+    }
+}
+
+/// Replication of writes across filestores when more than one is listed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FilestoreReplication {
+    /// Number of filestores a write must reach to succeed, every filestore when not set
+    pub write_quorum: Option<usize>,
+    /// Number of blobs that can wait to be copied to filestores that missed them, zero disables backfill
+    pub backfill_queue: usize,
+}
+
+impl Default for FilestoreReplication {
+    fn default() -> Self {
+        Self {
+            write_quorum: None,
+            backfill_queue: 1000,
         }
     }
 }
@@ -1166,8 +1396,8 @@ pub struct Logging {
     // pub export_interval: int = odm.Integer(")
     /// Log in JSON format?
     pub log_as_json: bool,
-    // /// Add a health check to core components.<br>If `true`, core components will touch this path regularly to tell the container environment it is healthy
-    // pub heartbeat_file: str = odm.Optional(odm.Keyword(),")
+    /// Add a health check to core components.<br>If set, core components will touch this path regularly to tell the container environment it is healthy
+    pub heartbeat_file: Option<String>,
 }
 
 impl Default for Logging {
@@ -1183,7 +1413,7 @@ impl Default for Logging {
             syslog_port: 514,
             syslog_transport: SyslogTransport::Tcp,
             // export_interval: 5,
-            // heartbeat_file: "/tmp/heartbeat"
+            heartbeat_file: Some("/tmp/heartbeat".to_owned()),
         }
     }
 }
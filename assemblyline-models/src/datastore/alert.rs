@@ -6,12 +6,12 @@ use serde::{Deserialize, Serialize};
 use serde_with::{SerializeDisplay, DeserializeFromStr};
 use struct_metadata::Described;
 
-use crate::types::{Wildcard, Domain, ExpandingClassification, Sha1, Sha256, Uri, Uuid, MD5};
-use crate::ElasticMeta;
+use crate::types::{Wildcard, Domain, ExpandingClassification, Sha1, Sha256, Sid, Uri, MD5};
+use crate::{ElasticMeta, Readable};
 use super::workflow::{Statuses, Priorities};
 
 
-#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug)]
+#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug, Clone, Copy, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[strum(serialize_all = "lowercase")]
 pub enum ExtendedScanValues {
@@ -21,7 +21,7 @@ pub enum ExtendedScanValues {
     Complete,
 }
 
-#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described)]
+#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[metadata_type(ElasticMeta)]
 #[strum(serialize_all = "lowercase")]
 pub enum ItemVerdict {
@@ -40,7 +40,7 @@ pub enum EntityType {
 }
 
 
-#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described)]
+#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug, Clone, Copy, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[strum(serialize_all = "UPPERCASE")]
 pub enum Subtype {
@@ -53,7 +53,7 @@ pub enum Subtype {
 
 
 /// Assemblyline Results Block
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Described, Debug, Clone, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=false)]
 pub struct DetailedItem {
@@ -69,7 +69,7 @@ pub struct DetailedItem {
 }
 
 /// Assemblyline Detailed result block
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Default, Described)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=false)]
 pub struct DetailedResults {
@@ -106,7 +106,7 @@ pub struct DetailedResults {
 }
 
 /// Assemblyline Results Block
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Default, Described)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=false)]
 pub struct ALResults {
@@ -226,7 +226,7 @@ pub struct Verdict {
 }
 
 /// Heuristic Block
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Default, Described)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=false)]
 pub struct Heuristic {
@@ -236,7 +236,7 @@ pub struct Heuristic {
 }
 
 /// ATT&CK Block
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Default, Described)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=false)]
 pub struct Attack {
@@ -277,15 +277,15 @@ pub struct Event {
 }
 
 /// Describes the relationship between different submissions that are linked to the formation of the alert, highlighting parent-child connections.
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Described, Debug, Clone, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=true)]
 pub struct Relationship {
     /// The identifier of the child submission in the relationship.
-    pub child: Uuid,
+    pub child: Sid,
     /// The identifier of the parent submission, if applicable.
     #[serde(default)]
-    pub parent: Option<Uuid>,
+    pub parent: Option<Sid>,
 }
 
 /// Model for Alerts
@@ -351,4 +351,8 @@ pub struct Alert {
     /// Have all workflows ran on this alert?
     #[serde(default)]
     pub workflows_completed: bool,
-}
\ No newline at end of file
+}
+
+impl Readable for Alert {
+    fn set_from_archive(&mut self, _from_archive: bool) {}
+}
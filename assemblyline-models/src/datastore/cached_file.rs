@@ -0,0 +1,26 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use struct_metadata::Described;
+
+use crate::{ElasticMeta, Readable};
+
+// @odm.model(index=True, store=True, description="CachedFile Model")
+// class CachedFile(odm.Model):
+//     component = odm.Keyword(description="Name of component which created the file")
+//     expiry_ts = odm.Date(store=False, description="Expiry timestamp")
+
+/// Model of a file kept in the cache filestore by a component
+#[derive(Debug, Serialize, Deserialize, Described, Clone, PartialEq, Eq)]
+#[metadata_type(ElasticMeta)]
+#[metadata(index=true, store=true)]
+pub struct CachedFile {
+    /// Name of component which created the file
+    pub component: String,
+    /// Expiry timestamp
+    #[metadata(store=false)]
+    pub expiry_ts: DateTime<Utc>,
+}
+
+impl Readable for CachedFile {
+    fn set_from_archive(&mut self, _from_archive: bool) {}
+}
@@ -16,6 +16,7 @@ pub mod badlist;
 pub mod heuristic;
 pub mod safelist;
 pub mod apikey;
+pub mod cached_file;
 
 pub use submission::Submission;
 pub use error::Error;
@@ -28,6 +29,7 @@ pub use service::Service;
 pub use service_delta::ServiceDelta;
 pub use retrohunt::{Retrohunt, RetrohuntHit};
 pub use emptyresult::EmptyResult;
+pub use cached_file::CachedFile;
 
 
 // #[cfg(test)]
@@ -83,7 +85,7 @@ mod python {
     use crate::datastore::safelist::Safelist;
     use crate::datastore::user::User;
     use crate::datastore::{Retrohunt, Service, ServiceDelta, Tagging, Workflow};
-    use crate::datastore::{Alert, EmptyResult, Error, Result, Submission, filescore::FileScore, heuristic::Heuristic};
+    use crate::datastore::{Alert, CachedFile, EmptyResult, Error, Result, Submission, filescore::FileScore, heuristic::Heuristic};
     use crate::meta::Mappings;
 
     use crate::meta::build_mapping;
@@ -123,6 +125,13 @@ mod python {
         assert_eq!(mapping, py_mappings);
     }
 
+    #[test]
+    fn cached_file_schema() {
+        let py_mappings = load_mapping("cached_file", "CachedFile");
+        let mapping = build_mapping::<CachedFile>().unwrap();
+        assert_eq!(mapping, py_mappings);
+    }
+
     #[test]
     fn emptyresult_schema() {
         let py_mappings = load_mapping("emptyresult", "EmptyResult");
@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use serde_with::{SerializeDisplay, DeserializeFromStr};
 use struct_metadata::Described;
 
-use crate::{ElasticMeta, types::{Sha256, ClassificationString, Text, ExpandingClassification}};
+use crate::{ElasticMeta, Readable, types::{Sha256, ClassificationString, Text, ExpandingClassification}};
 
 #[derive(SerializeDisplay, DeserializeFromStr, Debug, PartialEq, Eq, strum::Display, strum::EnumString, Described, Clone, Copy)]
 #[metadata_type(ElasticMeta)]
@@ -90,6 +90,14 @@ pub struct RetrohuntHit {
     pub search: String,
 }
 
+impl Readable for Retrohunt {
+    fn set_from_archive(&mut self, _from_archive: bool) {}
+}
+
+impl Readable for RetrohuntHit {
+    fn set_from_archive(&mut self, _from_archive: bool) {}
+}
+
 #[cfg(test)]
 mod test {
     use chrono::Utc;
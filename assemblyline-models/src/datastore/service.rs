@@ -191,7 +191,7 @@ pub struct UpdateSource {
     /// Update check interval, in seconds, for this source
     #[serde(default)]
     #[metadata(mapping="integer")]
-    update_interval: Option<NonZeroInteger>,
+    pub update_interval: Option<NonZeroInteger>,
     /// Ignore source caching and forcefully fetch from source
     #[serde(default)]
     pub ignore_cache: bool,
@@ -4,10 +4,10 @@ use serde_with::{SerializeDisplay, DeserializeFromStr};
 use struct_metadata::Described;
 
 use crate::types::{Uuid, ExpandingClassification};
-use crate::ElasticMeta;
+use crate::{ElasticMeta, Readable};
 
 
-#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described)]
+#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug, Clone, Copy, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[strum(serialize_all = "UPPERCASE")]
 pub enum Priorities {
@@ -17,7 +17,7 @@ pub enum Priorities {
     Critical,
 }
 
-#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described)]
+#[derive(SerializeDisplay, DeserializeFromStr, strum::Display, strum::EnumString, Described, Debug, Clone, Copy, PartialEq, Eq)]
 #[metadata_type(ElasticMeta)]
 #[strum(serialize_all = "SCREAMING-KEBAB-CASE")]
 pub enum Statuses {
@@ -28,7 +28,7 @@ pub enum Statuses {
 }
 
 /// Model of Workflow
-#[derive(Serialize, Deserialize, Described)]
+#[derive(Serialize, Deserialize, Described, Debug, Clone)]
 #[metadata_type(ElasticMeta)]
 #[metadata(index=true, store=true)]
 pub struct Workflow {
@@ -75,4 +75,8 @@ pub struct Workflow {
     pub workflow_id: Option<Uuid>,
 }
 
-fn default_enabled() -> bool { true }
\ No newline at end of file
+fn default_enabled() -> bool { true }
+
+impl Readable for Workflow {
+    fn set_from_archive(&mut self, _from_archive: bool) {}
+}
\ No newline at end of file
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+use super::ingest_heartbeat::{BusySeconds, CPUSeconds};
+
+// from assemblyline import odm
+// from assemblyline.odm.messages import PerformanceTimer
+
+// MSG_TYPES = {"AlerterHeartbeat"}
+// LOADER_CLASS = "assemblyline.odm.messages.alerter_heartbeat.AlerterMessage"
+
+
+// @odm.model(description="Alerter Queues")
+// class Queues(odm.Model):
+//     alert = odm.Integer(description="Number of alerts in queue")
+//     alert_retry = odm.Integer(description="Number of alerts in retry queue")
+
+
+// @odm.model(description="Alerter Metrics")
+// class Metrics(odm.Model):
+//     created = odm.Integer(description="Number of alerts created")
+//     error = odm.Integer(description="Number of alerts with errors")
+//     received = odm.Integer(description="Number of alerts received")
+//     updated = odm.Integer(description="Number of alerts updated")
+//     wait = odm.Integer(description="Number of alerts waiting for submission to complete")
+//     cpu_seconds = PerformanceTimer(description="CPU time")
+//     busy_seconds = PerformanceTimer(description="Busy CPU time")
+
+/// Alerter Metrics
+#[derive(Serialize, Deserialize, Default)]
+pub struct Metrics {
+    /// Number of alerts created
+    pub created: u32,
+    /// Number of alerts with errors
+    pub error: u32,
+    /// Number of alerts received
+    pub received: u32,
+    /// Number of alerts updated
+    pub updated: u32,
+    /// Number of alerts waiting for submission to complete
+    pub wait: u32,
+
+    /// Counter to track used cpu time
+    #[serde(flatten)]
+    pub cpu_seconds: CPUSeconds,
+
+    /// Busy CPU time
+    #[serde(flatten)]
+    pub busy_seconds: BusySeconds,
+}
+
+
+// @odm.model(description="Heartbeat Model for Alerter")
+// class Heartbeat(odm.Model):
+//     instances = odm.Integer(description="Number of alerter processes")
+//     metrics = odm.Compound(Metrics, description="Alert metrics")
+//     queues = odm.Compound(Queues, description="Alert queues")
+
+
+// @odm.model(description="Model of Alerter Heartbeat Message")
+// class AlerterMessage(odm.Model):
+//     msg = odm.Compound(Heartbeat, description="Heartbeat message from Alerter")
+//     msg_loader = odm.Enum(values={LOADER_CLASS}, default=LOADER_CLASS, description="Loader class for message")
+//     msg_type = odm.Enum(values=MSG_TYPES, default="AlerterHeartbeat", description="Type of message")
+//     sender = odm.Keyword(description="Sender of message")
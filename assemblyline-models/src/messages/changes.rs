@@ -45,17 +45,10 @@ pub struct HeuristicChange {
 }
 
 
-// @dataclass
-// class SignatureChange:
-//     signature_id: str
-//     signature_type: str
-//     source: str
-//     operation: Operation
-
-//     @staticmethod
-//     def serialize(obj: SignatureChange) -> str:
-//         return json.dumps(asdict(obj))
-
-//     @staticmethod
-//     def deserialize(data: str) -> SignatureChange:
-//         return SignatureChange(**json.loads(data))
\ No newline at end of file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignatureChange {
+    pub signature_id: String,
+    pub signature_type: String,
+    pub source: String,
+    pub operation: Operation,
+}
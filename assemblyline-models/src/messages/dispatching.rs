@@ -19,6 +19,9 @@ pub struct SubmissionDispatchMessage {
     pub file_tree: HashMap<Sha256, FileTreeData>,
     #[serde(default)]
     pub errors: Vec<String>,
+    /// Trace the submission was started under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<super::TraceContext>,
 }
 
 impl SubmissionDispatchMessage {
@@ -30,6 +33,7 @@ impl SubmissionDispatchMessage {
             file_tree: Default::default(),
             errors: Default::default(),
             results: Default::default(),
+            trace_context: None,
         }
     }
 
@@ -54,6 +58,11 @@ impl SubmissionDispatchMessage {
         self
     }
 
+    pub fn set_trace_context(mut self, trace_context: Option<super::TraceContext>) -> Self {
+        self.trace_context = trace_context;
+        self
+    }
+
 
 }
 
@@ -53,6 +53,8 @@ pub struct Metrics {
     pub whitelisted: u32,
     /// Number of retried submissions
     pub retries: u32,
+    /// Number of submissions refused by rate limits
+    pub rate_limited: u32,
 
     /// Counter to track used cpu time
     #[serde(flatten)]
@@ -91,6 +93,13 @@ pub struct BusySeconds {
     pub total: f64,
 }
 
+impl BusySeconds {
+    pub fn increment(&mut self, time: f64) {
+        self.count += 1;
+        self.total += time;
+    }
+}
+
 
 // @odm.model(description="Processing")
 // class Processing(odm.Model):
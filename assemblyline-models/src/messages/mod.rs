@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::types::Sid;
@@ -9,7 +11,13 @@ pub mod submission;
 pub mod ingest_heartbeat;
 pub mod service_heartbeat;
 pub mod dispatcher_heartbeat;
+pub mod alerter_heartbeat;
+pub mod archive_heartbeat;
+pub mod expiry_heartbeat;
 
+/// W3C trace context headers (`traceparent`, `tracestate`) carried by a message so
+/// the work it describes can be followed across components
+pub type TraceContext = HashMap<String, String>;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all="lowercase")]
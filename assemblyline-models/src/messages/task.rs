@@ -133,6 +133,10 @@ pub struct Task {
     /// Safelisting configuration (as defined in global configuration)
     #[serde(default="task_default_safelist_config")]
     pub safelist_config: ServiceSafelist, // ", default={'enabled': False})
+
+    /// Trace of the submission this task is part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<super::TraceContext>,
 }
 
 #[cfg(feature = "rand")]
@@ -160,6 +164,7 @@ impl rand::distr::Distribution<Task> for rand::distr::StandardUniform {
             ignore_filtering: rng.random(),
             priority: rng.random(),
             safelist_config: Default::default(),
+            trace_context: None,
         }
     }
 }
@@ -38,12 +38,16 @@ impl Readable for JsonMap {
 #[metadata_type(ElasticMeta)]
 pub struct NonZeroInteger(u64);
 
+impl NonZeroInteger {
+    pub fn get(&self) -> u64 { self.0 }
+}
+
 impl<'de> Deserialize<'de> for NonZeroInteger {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de> 
     {
         let raw: i64 = i64::deserialize(deserializer)?;
-        Ok(NonZeroInteger(raw.min(1) as u64))
+        Ok(NonZeroInteger(raw.max(1) as u64))
     }
 }
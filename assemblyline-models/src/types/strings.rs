@@ -482,6 +482,7 @@ fn find_top_level_domains() -> &'static HashSet<String> {
     &TLDS
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DomainValidator;
 impl StringValidator for DomainValidator {
     fn validate<'a>(data: &'a str) -> Result<Cow<'a, str>, ValidationError> {
@@ -557,6 +558,7 @@ pub fn check_uri(value: &str) -> Result<String, UriParseError> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct UriValidator;
 impl StringValidator for UriValidator {
     fn validate<'a>(data: &'a str) -> Result<Cow<'a, str>, ValidationError> {
@@ -697,6 +699,7 @@ pub fn is_mac(value: &str) -> bool {
 }
 
 // MARK: Email
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EmailValidator;
 impl StringValidator for EmailValidator {
     fn validate<'a>(data: &'a str) -> Result<Cow<'a, str>, ValidationError> {
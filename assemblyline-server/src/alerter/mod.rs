@@ -0,0 +1,614 @@
+//! The alerter turns submissions flagged by post-processing into alert documents.
+//!
+//! Messages are read from the alert queue, the completed submission and its results
+//! are summarized into an alert, and the alert is either created or merged into an
+//! existing alert with the same id (which is how extended scans update the alert
+//! of their parent submission).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use assemblyline_markings::classification::ClassificationParser;
+use assemblyline_models::datastore::alert::{ALResults, Alert, Attack, DetailedItem, DetailedResults, ExtendedScanValues, File as AlertFile, Heuristic as AlertHeuristic, ItemVerdict, Relationship, Subtype, Verdict};
+use assemblyline_models::datastore::submission::{Submission, SubmissionState};
+use assemblyline_models::datastore::Result as ResultModel;
+use assemblyline_models::messages::alerter_heartbeat::Metrics;
+use assemblyline_models::messages::submission::Submission as MessageSubmission;
+use assemblyline_models::types::{ExpandingClassification, JsonMap, Sid};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use md5::Digest;
+use redis_objects::{increment, AutoExportingMetrics, PriorityQueue};
+use serde::{Deserialize, Serialize};
+
+use crate::common::metrics::CPUTracker;
+use crate::constants::{ALERT_QUEUE_NAME, ALERT_RETRY_QUEUE_NAME, METRICS_CHANNEL};
+use crate::elastic::Version;
+use crate::work_queue::WorkQueue;
+use crate::Core;
+
+#[cfg(test)]
+mod tests;
+
+const MAX_RETRIES: u32 = 10;
+const RETRY_DELAY: chrono::TimeDelta = chrono::TimeDelta::seconds(30);
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Tag types whose values are listed as attribution on the alert, along with the subtype they get in the detailed block
+const ATTRIBUTION_TAGS: [(&str, Option<Subtype>); 8] = [
+    ("attribution.actor", Some(Subtype::Ta)),
+    ("attribution.campaign", None),
+    ("attribution.category", None),
+    ("attribution.exploit", Some(Subtype::Exp)),
+    ("attribution.family", None),
+    ("attribution.implant", Some(Subtype::Imp)),
+    ("technique.config", Some(Subtype::Cfg)),
+    ("technique.obfuscation", Some(Subtype::Ob)),
+];
+
+// This is a simple macro that wraps the given method in a retry loop
+macro_rules! retry {
+    ($name: expr, $alerter: ident, $method: ident) => {
+        {
+            let name = $name;
+            let alerter: Arc<Alerter> = $alerter.clone();
+            async move {
+                while let Err(err) = alerter.clone().$method().await {
+                    error!("Error in {name}: {err}");
+                    alerter.core.sleep(ERROR_BACKOFF).await;
+                }
+                info!("{name} worker stopped");
+            }
+        }
+    };
+}
+
+pub async fn main(core: Core) -> Result<()> {
+    // Initialize alerter internal state
+    let alerter = Arc::new(Alerter::new(core));
+    alerter.core.running.install_terminate_handler(false)?;
+    alerter.core.install_activation_handler("alerter").await?;
+
+    // launch the assorted daemons within the alerter
+    let mut components = tokio::task::JoinSet::new();
+    alerter.start(&mut components);
+
+    // Wait for all of these components to terminate
+    while components.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Message format produced by post-processing when a submission should raise an alert
+#[derive(Serialize, Deserialize)]
+pub struct AlertMessage {
+    pub submission: MessageSubmission,
+    pub score: i32,
+    pub extended_scan: ExtendedScanValues,
+    #[serde(default)]
+    pub ingest_id: Option<String>,
+    #[serde(default)]
+    pub alert_retries: u32,
+}
+
+/// Raised when the submission an alert is about hasn't been written as completed yet
+#[derive(Debug, thiserror::Error)]
+#[error("Submission not finalized: {0}")]
+struct SubmissionNotFinalized(Sid);
+
+/// Which way a processed alert message was written to the datastore
+#[derive(Debug, PartialEq, Eq)]
+pub enum AlertAction {
+    Create,
+    Update,
+}
+
+pub struct Alerter {
+    core: Core,
+    alert_queue: WorkQueue<serde_json::Value>,
+    retry_queue: PriorityQueue<AlertMessage>,
+    counter: AutoExportingMetrics<Metrics>,
+}
+
+impl Alerter {
+    pub fn new(core: Core) -> Self {
+        Self {
+            alert_queue: WorkQueue::open(&core.redis_persistant, ALERT_QUEUE_NAME, "alerter", core.config.core.alerter.durable_queue),
+            retry_queue: core.redis_persistant.priority_queue(ALERT_RETRY_QUEUE_NAME.to_owned()),
+            counter: core.redis_metrics.auto_exporting_metrics(METRICS_CHANNEL.to_owned(), "alerter".to_owned())
+                .counter_name("alerter".to_owned())
+                .export_interval(Duration::from_secs(core.config.core.metrics.export_interval as u64))
+                .start(),
+            core,
+        }
+    }
+
+    pub fn start(self: &Arc<Self>, components: &mut tokio::task::JoinSet<()>) {
+        components.spawn(retry!("Alert Processor".to_string(), self, handle_alerts));
+        components.spawn(retry!("Retry Handler".to_string(), self, handle_retries));
+        components.spawn(retry!("Metrics Reporter".to_string(), self, handle_metrics));
+    }
+
+    async fn handle_metrics(self: Arc<Self>) -> Result<()> {
+        let mut tracker = CPUTracker::new().await;
+        while self.core.is_running() {
+            let value = tracker.read().await;
+            increment!(timer, self.counter, cpu_seconds, value);
+            self.core.sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    }
+
+    async fn handle_alerts(self: Arc<Self>) -> Result<()> {
+        while self.core.is_running() {
+            while !self.core.is_active() {
+                // Alerter is disabled... waiting for it to be reactivated
+                self.core.sleep(Duration::from_millis(100)).await;
+            }
+
+            self.run_once().await?;
+        }
+        Ok(())
+    }
+
+    /// Move alerts whose retry delay has passed back into the alert queue
+    async fn handle_retries(self: Arc<Self>) -> Result<()> {
+        while self.core.is_running() {
+            let now = chrono::Utc::now().timestamp();
+            let messages = self.retry_queue.dequeue_range(None, Some(now), None, Some(100)).await?;
+            let message_count = messages.len();
+
+            for message in messages {
+                self.alert_queue.push(&serde_json::to_value(message)?).await?;
+            }
+
+            if message_count == 0 {
+                self.core.sleep(Duration::from_secs(3)).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_once(&self) -> Result<()> {
+        let (message, receipt) = match self.alert_queue.pop_timeout(Duration::from_secs(1)).await? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        increment!(self.counter, received);
+
+        // the message is only acknowledged once it has been written somewhere, so if processing
+        // fails part way a durable queue hands it out again
+        let start = Instant::now();
+        self.handle_message(message).await?;
+        self.alert_queue.ack(receipt).await?;
+        increment!(timer, self.counter, busy_seconds, start.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    /// Write the alert for a message, or put it in the retry queue if that fails
+    async fn handle_message(&self, message: serde_json::Value) -> Result<()> {
+
+        let mut message: AlertMessage = match serde_json::from_value(message) {
+            Ok(message) => message,
+            Err(err) => {
+                increment!(self.counter, error);
+                error!("Dropped malformed alert message: {err}");
+                return Ok(())
+            }
+        };
+
+        let sid = message.submission.sid;
+        let err = match self.process_alert_message(&message).await {
+            Ok(AlertAction::Create) => { increment!(self.counter, created); return Ok(()) },
+            Ok(AlertAction::Update) => { increment!(self.counter, updated); return Ok(()) },
+            Err(err) => err,
+        };
+
+        increment!(self.counter, error);
+        message.alert_retries += 1;
+        if message.alert_retries > MAX_RETRIES {
+            error!("[{sid}] Max retries exceeded for alert: {err:?}");
+            return Ok(())
+        }
+
+        if err.downcast_ref::<SubmissionNotFinalized>().is_some() {
+            info!("[{sid}] Waiting for submission to be completed");
+            increment!(self.counter, wait);
+        } else {
+            error!("[{sid}] Unhandled error processing alert: {err:?}");
+        }
+
+        let retry_at = Utc::now() + RETRY_DELAY;
+        self.retry_queue.push(retry_at.timestamp() as f64, &message).await?;
+        Ok(())
+    }
+
+    /// Build the alert for a message and write it to the datastore, merging with any existing alert
+    pub async fn process_alert_message(&self, message: &AlertMessage) -> Result<AlertAction> {
+        let alert = self.build_alert(message).await?;
+        let alert_id = alert.alert_id.clone();
+        let cl_engine = self.core.classification_parser.as_ref();
+
+        loop {
+            let (document, version, action) = match self.core.datastore.alert.get_if_exists(&alert_id, None).await? {
+                Some((existing, version)) => {
+                    let merged = merge_alerts(existing, &alert, cl_engine, &self.core.config.core.alerter.constant_alert_fields)?;
+                    (merged, version, AlertAction::Update)
+                },
+                None => (serde_json::to_value(&alert)?, Version::Create, AlertAction::Create),
+            };
+
+            let serde_json::Value::Object(mut document) = document else {
+                anyhow::bail!("Alert did not serialize to a json document");
+            };
+
+            match self.core.datastore.alert.save_json(&alert_id, &mut document, Some(version), None).await {
+                Ok(()) => {
+                    match action {
+                        AlertAction::Create => info!("Alert {alert_id} has been created."),
+                        AlertAction::Update => info!("Alert {alert_id} has been updated."),
+                    }
+                    return Ok(action)
+                },
+                Err(err) if err.is_version_conflict() => {
+                    debug!("Retrying save of alert {alert_id} due to version conflict: {err}");
+                    continue
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Collect everything needed from the datastore to describe a submission as an alert
+    async fn build_alert(&self, message: &AlertMessage) -> Result<Alert> {
+        let submission = &message.submission;
+        let sid = submission.sid;
+        let psid = submission.params.psid;
+        let config = &self.core.config.core.alerter;
+
+        let record = self.get_submission_record(sid).await?;
+        let summary = self.summarize(&record).await?;
+
+        // An extended scan is complete unless it produced errors the original submission didn't have
+        let extended_scan = match psid {
+            Some(psid) => {
+                let parent = self.get_submission_record(psid).await?;
+                if record.errors.iter().all(|err| parent.errors.contains(err)) {
+                    ExtendedScanValues::Complete
+                } else {
+                    ExtendedScanValues::Incomplete
+                }
+            },
+            None => message.extended_scan,
+        };
+
+        // Load the details of the root file
+        let root = submission.files.first().context("Alert submission has no files")?;
+        let file = match self.core.datastore.file.get(&root.sha256.to_string(), None).await? {
+            Some(file) => file,
+            None => anyhow::bail!("File {} missing from datastore", root.sha256),
+        };
+
+        // Some submissions carry their alert type and timestamp in their metadata
+        let mut metadata = submission.metadata.clone();
+        let alert_type = metadata.remove("type").map(|value| value.to_string());
+        let alert_ts = metadata.remove("ts").and_then(|value| DateTime::parse_from_rfc3339(&value.to_string()).ok());
+
+        let now = Utc::now();
+        let classification = ExpandingClassification::new(record.classification.classification.clone(), &self.core.classification_parser)?;
+        Ok(Alert {
+            alert_id: generate_alert_id(message),
+            al: summary.al_results(message.score, record.times.completed.unwrap_or(now))?,
+            archive_ts: None,
+            attack: summary.attack(),
+            classification,
+            expiry_ts: if config.alert_ttl > 0 { Some(now + chrono::TimeDelta::days(config.alert_ttl as i64)) } else { None },
+            extended_scan,
+            file: AlertFile {
+                md5: file.md5,
+                name: root.name.clone(),
+                sha1: file.sha1,
+                sha256: file.sha256,
+                size: file.size as i64,
+                file_type: file.file_type,
+                screenshots: vec![],
+            },
+            filtered: false,
+            heuristic: summary.heuristic(),
+            label: vec![],
+            metadata,
+            owner: None,
+            priority: None,
+            reporting_ts: now,
+            submission_relations: vec![Relationship { child: sid, parent: psid }],
+            sid: sid.to_string(),
+            status: None,
+            ts: alert_ts.map(|ts| ts.to_utc()).unwrap_or(submission.time),
+            alert_type: alert_type.unwrap_or_else(|| submission.params.submission_type.clone()),
+            verdict: Verdict::default(),
+            events: vec![],
+            workflows_completed: false,
+        })
+    }
+
+    async fn get_submission_record(&self, sid: Sid) -> Result<Submission> {
+        match self.core.datastore.submission.get(&sid.to_string(), None).await? {
+            Some(record) if record.state == SubmissionState::Completed => Ok(record),
+            _ => Err(SubmissionNotFinalized(sid).into()),
+        }
+    }
+
+    /// Gather the tags, heuristics and attack ids raised by the results of a submission
+    async fn summarize(&self, record: &Submission) -> Result<Summary> {
+        let keys: Vec<String> = record.results.iter()
+            .map(|key| key.to_string())
+            .filter(|key| !key.ends_with(".e"))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+        let results: HashMap<String, ResultModel> = self.core.datastore.result.multiget(&keys, Some(false), None).await?;
+
+        let mut summary = Summary::default();
+        for result in results.values() {
+            summary.add_result(result)?;
+        }
+        Ok(summary)
+    }
+}
+
+/// Alert ids are taken from the ingestion id when available so that extended scans land on the same alert
+fn generate_alert_id(message: &AlertMessage) -> String {
+    if let Some(ingest_id) = &message.ingest_id {
+        return ingest_id.clone()
+    }
+    let submission = &message.submission;
+    let sid = submission.params.psid.unwrap_or(submission.sid);
+    let mut hasher = md5::Md5::new();
+    hasher.update(format!("{sid}-{}", submission.time.to_rfc3339()).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Map a heuristic score onto the verdict shown in the detailed alert blocks
+fn heuristic_verdict(score: i32) -> ItemVerdict {
+    if score < 0 {
+        ItemVerdict::Safe
+    } else if score < 300 {
+        ItemVerdict::Info
+    } else if score < 1000 {
+        ItemVerdict::Suspicious
+    } else {
+        ItemVerdict::Malicious
+    }
+}
+
+/// Summary of the results of a submission, keeping the worst verdict seen for each item
+#[derive(Default)]
+struct Summary {
+    /// (tag type, value) -> verdict
+    tags: BTreeMap<(String, String), ItemVerdict>,
+    /// heuristic id -> (name, verdict)
+    heuristics: BTreeMap<String, (String, ItemVerdict)>,
+    /// attack id -> (pattern, categories, verdict)
+    attack: BTreeMap<String, (String, Vec<String>, ItemVerdict)>,
+}
+
+impl Summary {
+    fn add_result(&mut self, result: &ResultModel) -> Result<()> {
+        for section in &result.result.sections {
+            let verdict = match &section.heuristic {
+                Some(heuristic) => {
+                    let verdict = heuristic_verdict(heuristic.score);
+                    let entry = self.heuristics.entry(heuristic.heur_id.clone()).or_insert((heuristic.name.clone(), verdict));
+                    entry.1 = entry.1.max(verdict);
+                    for attack in &heuristic.attack {
+                        let entry = self.attack.entry(attack.attack_id.clone()).or_insert((attack.pattern.clone(), attack.categories.clone(), verdict));
+                        entry.2 = entry.2.max(verdict);
+                    }
+                    verdict
+                },
+                None => ItemVerdict::Info,
+            };
+
+            for tag in section.tags.to_list(None)? {
+                let entry = self.tags.entry((tag.tag_type, tag.value.to_string())).or_insert(verdict);
+                *entry = (*entry).max(verdict);
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterate over the (value, verdict) of tags matching a filter on the tag type
+    fn tags<'a>(&'a self, filter: impl Fn(&str) -> bool + 'a) -> impl Iterator<Item=(&'a str, &'a str, ItemVerdict)> + 'a {
+        self.tags.iter()
+            .filter(move |((tag_type, _), _)| filter(tag_type))
+            .map(|((tag_type, value), verdict)| (tag_type.as_str(), value.as_str(), *verdict))
+    }
+
+    /// Collect the values of matching tags, silently dropping any that don't parse as the output type
+    fn values<T: std::str::FromStr>(&self, filter: impl Fn(&str) -> bool) -> Vec<T> {
+        let values: BTreeSet<&str> = self.tags(filter).map(|(_, value, _)| value).collect();
+        values.into_iter().filter_map(|value| value.parse().ok()).collect()
+    }
+
+    fn detailed(&self, filter: impl Fn(&str) -> bool) -> Vec<DetailedItem> {
+        self.tags(filter).map(|(tag_type, value, verdict)| DetailedItem {
+            item_type: tag_type.to_owned(),
+            value: value.to_owned(),
+            verdict,
+            subtype: None,
+        }).collect()
+    }
+
+    fn al_results(&self, score: i32, request_end_time: DateTime<Utc>) -> Result<ALResults> {
+        let is_attribution = |tag_type: &str| ATTRIBUTION_TAGS.iter().any(|(name, _)| *name == tag_type);
+        let is_av = |tag_type: &str| tag_type == "av.virus_name";
+        let is_behavior = |tag_type: &str| tag_type == "file.behavior";
+        let is_yara = |tag_type: &str| tag_type == "file.rule.yara";
+        let network = |kind: &'static str, dynamic: Option<bool>| move |tag_type: &str| match dynamic {
+            Some(true) => tag_type == format!("network.dynamic.{kind}"),
+            Some(false) => tag_type == format!("network.static.{kind}"),
+            None => tag_type == format!("network.dynamic.{kind}") || tag_type == format!("network.static.{kind}"),
+        };
+
+        let mut attrib = self.detailed(is_attribution);
+        for item in &mut attrib {
+            item.subtype = ATTRIBUTION_TAGS.iter()
+                .find(|(name, _)| *name == item.item_type)
+                .and_then(|(_, subtype)| *subtype);
+        }
+
+        Ok(ALResults {
+            attrib: self.values(is_attribution),
+            av: self.values(is_av),
+            behavior: self.values(is_behavior),
+            detailed: DetailedResults {
+                attack_pattern: self.attack.iter().map(|(attack_id, (pattern, _, verdict))| DetailedItem {
+                    item_type: attack_id.clone(),
+                    value: pattern.clone(),
+                    verdict: *verdict,
+                    subtype: None,
+                }).collect(),
+                attack_category: {
+                    let mut categories: BTreeMap<&str, ItemVerdict> = Default::default();
+                    for (_, categories_list, verdict) in self.attack.values() {
+                        for category in categories_list {
+                            let entry = categories.entry(category).or_insert(*verdict);
+                            *entry = (*entry).max(*verdict);
+                        }
+                    }
+                    categories.into_iter().map(|(category, verdict)| DetailedItem {
+                        item_type: category.to_owned(),
+                        value: category.to_owned(),
+                        verdict,
+                        subtype: None,
+                    }).collect()
+                },
+                attrib,
+                av: self.detailed(is_av),
+                behavior: self.detailed(is_behavior),
+                domain: self.detailed(network("domain", None)),
+                heuristic: self.heuristics.iter().map(|(heur_id, (name, verdict))| DetailedItem {
+                    item_type: heur_id.clone(),
+                    value: name.clone(),
+                    verdict: *verdict,
+                    subtype: None,
+                }).collect(),
+                ip: self.detailed(network("ip", None)),
+                uri: self.detailed(network("uri", None)),
+                yara: self.detailed(is_yara),
+            },
+            domain: self.values(network("domain", None)),
+            domain_dynamic: self.values(network("domain", Some(true))),
+            domain_static: self.values(network("domain", Some(false))),
+            ip: self.values(network("ip", None)),
+            ip_dynamic: self.values(network("ip", Some(true))),
+            ip_static: self.values(network("ip", Some(false))),
+            request_end_time,
+            score,
+            uri: self.values(network("uri", None)),
+            uri_dynamic: self.values(network("uri", Some(true))),
+            uri_static: self.values(network("uri", Some(false))),
+            yara: self.values(is_yara),
+        })
+    }
+
+    fn attack(&self) -> Attack {
+        let mut category: BTreeSet<String> = Default::default();
+        for (_, categories, _) in self.attack.values() {
+            category.extend(categories.iter().cloned());
+        }
+        Attack {
+            pattern: self.attack.values().map(|(pattern, _, _)| pattern.clone()).collect(),
+            category: category.into_iter().collect(),
+        }
+    }
+
+    fn heuristic(&self) -> AlertHeuristic {
+        AlertHeuristic {
+            name: self.heuristics.values().map(|(name, _)| name.clone()).collect(),
+        }
+    }
+}
+
+/// Add the items from the new list that are not already in the old one
+fn union<T: PartialEq + Clone>(old: &mut Vec<T>, new: &[T]) {
+    for item in new {
+        if !old.contains(item) {
+            old.push(item.clone());
+        }
+    }
+}
+
+/// Merge a newly built alert into an existing alert document.
+///
+/// Lists of findings are combined, the alert takes on the state of the newest submission,
+/// and fields that are configured as constant keep the value from the existing alert.
+fn merge_alerts(mut old: Alert, new: &Alert, cl_engine: &ClassificationParser, constant_fields: &[String]) -> Result<serde_json::Value> {
+    let original: JsonMap = match serde_json::to_value(&old)? {
+        serde_json::Value::Object(original) => original,
+        _ => anyhow::bail!("Alert did not serialize to a json document"),
+    };
+
+    // Combine the assemblyline result blocks
+    union(&mut old.al.attrib, &new.al.attrib);
+    union(&mut old.al.av, &new.al.av);
+    union(&mut old.al.behavior, &new.al.behavior);
+    union(&mut old.al.domain, &new.al.domain);
+    union(&mut old.al.domain_dynamic, &new.al.domain_dynamic);
+    union(&mut old.al.domain_static, &new.al.domain_static);
+    union(&mut old.al.ip, &new.al.ip);
+    union(&mut old.al.ip_dynamic, &new.al.ip_dynamic);
+    union(&mut old.al.ip_static, &new.al.ip_static);
+    union(&mut old.al.uri, &new.al.uri);
+    union(&mut old.al.uri_dynamic, &new.al.uri_dynamic);
+    union(&mut old.al.uri_static, &new.al.uri_static);
+    union(&mut old.al.yara, &new.al.yara);
+    old.al.score = old.al.score.max(new.al.score);
+    old.al.request_end_time = old.al.request_end_time.max(new.al.request_end_time);
+
+    let detailed = &mut old.al.detailed;
+    union(&mut detailed.attack_pattern, &new.al.detailed.attack_pattern);
+    union(&mut detailed.attack_category, &new.al.detailed.attack_category);
+    union(&mut detailed.attrib, &new.al.detailed.attrib);
+    union(&mut detailed.av, &new.al.detailed.av);
+    union(&mut detailed.behavior, &new.al.detailed.behavior);
+    union(&mut detailed.domain, &new.al.detailed.domain);
+    union(&mut detailed.heuristic, &new.al.detailed.heuristic);
+    union(&mut detailed.ip, &new.al.detailed.ip);
+    union(&mut detailed.uri, &new.al.detailed.uri);
+    union(&mut detailed.yara, &new.al.detailed.yara);
+
+    union(&mut old.attack.pattern, &new.attack.pattern);
+    union(&mut old.attack.category, &new.attack.category);
+    union(&mut old.heuristic.name, &new.heuristic.name);
+    union(&mut old.submission_relations, &new.submission_relations);
+
+    // Take on the state of the latest submission
+    let classification = cl_engine.max_classification(&old.classification.classification, &new.classification.classification, None)?;
+    old.classification = ExpandingClassification::new(classification, cl_engine)?;
+    old.expiry_ts = old.expiry_ts.max(new.expiry_ts);
+    old.extended_scan = new.extended_scan;
+    old.filtered |= new.filtered;
+    for (key, value) in &new.metadata {
+        old.metadata.insert(key.clone(), value.clone());
+    }
+    old.sid = new.sid.clone();
+    old.workflows_completed = false;
+
+    // Restore any constant fields that would have been changed
+    let mut merged = serde_json::to_value(&old)?;
+    let new_value = serde_json::to_value(new)?;
+    if let Some(merged) = merged.as_object_mut() {
+        for field in constant_fields {
+            let previous = original.get(field);
+            if previous != new_value.get(field) {
+                warn!("Constant alert field {field} changed for {}, keeping original value", old.alert_id);
+            }
+            match previous {
+                Some(value) => merged.insert(field.clone(), value.clone()),
+                None => merged.remove(field),
+            };
+        }
+    }
+    Ok(merged)
+}
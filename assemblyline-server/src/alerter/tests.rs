@@ -0,0 +1,136 @@
+use assemblyline_models::datastore::alert::{ExtendedScanValues, ItemVerdict};
+use assemblyline_models::datastore::submission::SubmissionState;
+use assemblyline_models::datastore::{File, Submission};
+use assemblyline_models::datastore::Result as ResultModel;
+use assemblyline_models::messages::submission::Submission as MessageSubmission;
+use assemblyline_models::types::Sid;
+use rand::Rng;
+
+use crate::constants::ALERT_QUEUE_NAME;
+use crate::work_queue::WorkQueue;
+use crate::Core;
+
+use super::{generate_alert_id, heuristic_verdict, AlertAction, AlertMessage, Alerter};
+
+#[test]
+fn test_heuristic_verdict() {
+    assert_eq!(heuristic_verdict(-1000), ItemVerdict::Safe);
+    assert_eq!(heuristic_verdict(0), ItemVerdict::Info);
+    assert_eq!(heuristic_verdict(300), ItemVerdict::Suspicious);
+    assert_eq!(heuristic_verdict(999), ItemVerdict::Suspicious);
+    assert_eq!(heuristic_verdict(1000), ItemVerdict::Malicious);
+}
+
+/// Write a completed submission with a single result and file into the datastore
+async fn save_submission(core: &Core, psid: Option<Sid>, errors: Vec<String>) -> Submission {
+    let data: [u8; 32] = rand::rng().random();
+    let file = File::gen_for_sample(&data, &mut rand::rng());
+    core.datastore.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+
+    let mut result: ResultModel = rand::rng().random();
+    result.sha256 = file.sha256.clone();
+    let result_key = result.build_key(None).unwrap();
+    core.datastore.result.save(&result_key, &result, None, None).await.unwrap();
+
+    let mut submission: Submission = rand::rng().random();
+    submission.state = SubmissionState::Completed;
+    submission.params.psid = psid;
+    submission.files[0].sha256 = file.sha256.clone();
+    submission.results = vec![result_key.into()];
+    submission.errors = errors;
+    core.datastore.submission.save(&submission.sid.to_string(), &submission, None, None).await.unwrap();
+    submission
+}
+
+fn alert_message(submission: &Submission, score: i32, extended_scan: ExtendedScanValues) -> AlertMessage {
+    AlertMessage {
+        submission: MessageSubmission::from(submission),
+        score,
+        extended_scan,
+        ingest_id: Some("ingest-id".to_owned()),
+        alert_retries: 0,
+    }
+}
+
+#[test]
+fn test_alert_id() {
+    let submission: Submission = rand::rng().random();
+    let mut message = alert_message(&submission, 10, ExtendedScanValues::Skipped);
+    assert_eq!(generate_alert_id(&message), "ingest-id");
+
+    // without an ingest id the id is stable for the same submission
+    message.ingest_id = None;
+    let alert_id = generate_alert_id(&message);
+    assert_eq!(alert_id.len(), 32);
+    assert_eq!(alert_id, generate_alert_id(&message));
+}
+
+#[tokio::test]
+async fn test_create_and_extend_alert() {
+    let (core, _guard) = Core::test_setup().await;
+    let alerter = Alerter::new(core.clone());
+
+    // create an alert for the original submission
+    let original = save_submission(&core, None, vec![]).await;
+    let message = alert_message(&original, 500, ExtendedScanValues::Submitted);
+    assert_eq!(alerter.process_alert_message(&message).await.unwrap(), AlertAction::Create);
+
+    let alert = core.datastore.alert.get("ingest-id", None).await.unwrap().unwrap();
+    assert_eq!(alert.sid, original.sid.to_string());
+    assert_eq!(alert.al.score, 500);
+    assert_eq!(alert.extended_scan, ExtendedScanValues::Submitted);
+    assert_eq!(alert.submission_relations.len(), 1);
+
+    // the extended scan updates the same alert
+    let extended = save_submission(&core, Some(original.sid), vec![]).await;
+    let message = alert_message(&extended, 800, ExtendedScanValues::Submitted);
+    assert_eq!(alerter.process_alert_message(&message).await.unwrap(), AlertAction::Update);
+
+    let updated = core.datastore.alert.get("ingest-id", None).await.unwrap().unwrap();
+    assert_eq!(updated.sid, extended.sid.to_string());
+    assert_eq!(updated.al.score, 800);
+    assert_eq!(updated.extended_scan, ExtendedScanValues::Complete);
+    assert_eq!(updated.submission_relations.len(), 2);
+    // constant fields are not changed by the update
+    assert_eq!(updated.file.sha256, alert.file.sha256);
+    assert_eq!(updated.ts, alert.ts);
+}
+
+#[tokio::test]
+async fn test_wait_for_submission() {
+    let (core, _guard) = Core::test_setup().await;
+    let alerter = Alerter::new(core.clone());
+
+    // an alert for a submission that isn't finished yet should be delayed
+    let mut submission: Submission = rand::rng().random();
+    submission.state = SubmissionState::Submitted;
+    core.datastore.submission.save(&submission.sid.to_string(), &submission, None, None).await.unwrap();
+
+    let message = alert_message(&submission, 500, ExtendedScanValues::Skipped);
+    alerter.alert_queue.push(&serde_json::to_value(&message).unwrap()).await.unwrap();
+    alerter.run_once().await.unwrap();
+
+    assert_eq!(alerter.alert_queue.length().await.unwrap(), 0);
+    assert_eq!(alerter.retry_queue.length().await.unwrap(), 1);
+    assert!(core.datastore.alert.get("ingest-id", None).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_durable_alert_queue() {
+    let (core, _guard) = Core::test_custom_setup(|config| {
+        config.core.alerter.durable_queue = true;
+    }).await;
+    let alerter = Alerter::new(core.clone());
+    let WorkQueue::Stream { stream, .. } = &alerter.alert_queue else { panic!("expected a stream") };
+
+    // post-processing pushes to the list, the message is moved to the stream and only leaves it once handled
+    let original = save_submission(&core, None, vec![]).await;
+    let message = alert_message(&original, 500, ExtendedScanValues::Skipped);
+    let list = core.redis_persistant.queue::<AlertMessage>(ALERT_QUEUE_NAME.to_owned(), None);
+    list.push(&message).await.unwrap();
+    alerter.run_once().await.unwrap();
+
+    assert!(core.datastore.alert.get("ingest-id", None).await.unwrap().is_some());
+    assert_eq!(alerter.alert_queue.length().await.unwrap(), 0);
+    assert!(stream.pending(10).await.unwrap().is_empty());
+}
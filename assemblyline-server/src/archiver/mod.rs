@@ -0,0 +1,314 @@
+//! The archiver moves submissions selected for long term storage into the malware archive.
+//!
+//! Messages pushed by the `ArchiveManager` name a submission to archive. The submission, its
+//! results, errors and files are copied to the archive side of their collections, and the file
+//! blobs are copied from the regular filestore into the archive filestore. Every step checks
+//! what has already been done, so a message can be processed again after an interruption.
+//!
+//! Messages are moved from the archive queue into a processing list owned by the archiver in a
+//! single step, so they are never missing from both. Each archiver keeps a lease alive while it
+//! runs, messages left in the processing list of an archiver whose lease has lapsed are put back
+//! in the queue. Messages that fail are retried a limited number of times.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use assemblyline_filestore::FileStore;
+use assemblyline_models::datastore::Result as ResultModel;
+use assemblyline_models::messages::archive_heartbeat::Metrics;
+use assemblyline_models::types::Sha256;
+use log::{error, info, warn};
+use redis_objects::{increment, AutoExportingMetrics, Hashmap, Queue};
+
+use crate::constants::{ARCHIVE_ATTEMPTS_HASH, ARCHIVE_QUEUE_NAME, ARCHIVE_WORKERS_HASH, METRICS_CHANNEL};
+use crate::elastic::{Elastic, Index};
+use crate::Core;
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long an archiver is considered alive after its last heartbeat
+const WORKER_LEASE: Duration = Duration::from_secs(60);
+
+/// How many times a message is tried before it is given up on
+const MAX_ATTEMPTS: i64 = 3;
+
+/// Messages in the archive queue: (archive type, id, delete after archiving)
+type ArchiveMessage = (String, String, bool);
+
+/// Name of the list holding the message an archiver is working on. The hash tag keeps it
+/// in the same slot as the archive queue so messages can be moved between them on a cluster.
+fn processing_name(worker_id: &str) -> String {
+    format!("{{{ARCHIVE_QUEUE_NAME}}}-processing-{worker_id}")
+}
+
+pub async fn main(core: Core) -> Result<()> {
+    let archiver = Arc::new(Archiver::new(core).await?);
+    archiver.core.running.install_terminate_handler(false)?;
+
+    let mut components = tokio::task::JoinSet::new();
+
+    // Keep the lease on messages being processed, and put back those abandoned by other instances
+    archiver.heartbeat().await?;
+    let this = archiver.clone();
+    components.spawn(async move {
+        while this.core.is_running() {
+            if let Err(err) = this.heartbeat().await {
+                error!("Error renewing archiver lease: {err:?}");
+            }
+            if let Err(err) = this.requeue_interrupted().await {
+                error!("Error requeuing interrupted archive messages: {err:?}");
+            }
+            this.core.sleep(WORKER_LEASE / 3).await;
+        }
+    });
+
+    let this = archiver.clone();
+    components.spawn(async move {
+        while let Err(err) = this.handle_archive().await {
+            error!("Error in archiver: {err:?}");
+            this.core.sleep(ERROR_BACKOFF).await;
+        }
+        info!("Archiver worker stopped");
+    });
+
+    while components.join_next().await.is_some() {}
+    Ok(())
+}
+
+pub struct Archiver {
+    core: Core,
+    datastore: Arc<Elastic>,
+    archive_storage: Arc<FileStore>,
+    archive_queue: Queue<ArchiveMessage>,
+    /// Message being processed by this archiver
+    processing: Queue<ArchiveMessage>,
+    /// Failed attempts at each message
+    attempts: Hashmap<i64>,
+    /// Archivers currently running, with the time their lease runs out
+    workers: Hashmap<i64>,
+    worker_id: String,
+    counter: AutoExportingMetrics<Metrics>,
+}
+
+impl Archiver {
+    pub async fn new(core: Core) -> Result<Self> {
+        let archive_storage = FileStore::open(&core.config.filestore.archive).await.context("initializing archive filestore")?;
+        let worker_id = format!("{:016x}", rand::random::<u64>());
+        Ok(Self {
+            datastore: core.datastore.with_archive_access().await?,
+            archive_storage,
+            archive_queue: core.redis_persistant.queue(ARCHIVE_QUEUE_NAME.to_owned(), None),
+            processing: core.redis_persistant.queue(processing_name(&worker_id), None),
+            attempts: core.redis_persistant.hashmap(ARCHIVE_ATTEMPTS_HASH.to_owned(), None),
+            workers: core.redis_persistant.hashmap(ARCHIVE_WORKERS_HASH.to_owned(), None),
+            worker_id,
+            counter: core.redis_metrics.auto_exporting_metrics(METRICS_CHANNEL.to_owned(), "archive".to_owned())
+                .counter_name("archive".to_owned())
+                .export_interval(Duration::from_secs(core.config.core.metrics.export_interval as u64))
+                .start(),
+            core,
+        })
+    }
+
+    /// Mark this archiver as alive, keeping other instances from taking over its messages
+    async fn heartbeat(&self) -> Result<()> {
+        let expiry = chrono::Utc::now() + WORKER_LEASE;
+        self.workers.set(&self.worker_id, &expiry.timestamp()).await?;
+        Ok(())
+    }
+
+    /// Put back messages left in the processing lists of archivers that are no longer running
+    async fn requeue_interrupted(&self) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let workers = self.workers.items().await?;
+        for (worker, expiry) in &workers {
+            if *expiry < now {
+                self.workers.conditional_remove(worker, expiry).await?;
+            }
+        }
+
+        let prefix = processing_name("");
+        for name in self.core.redis_persistant.keys(&format!("{prefix}*")).await? {
+            let Some(worker) = name.strip_prefix(&prefix) else { continue };
+            if worker == self.worker_id || workers.get(worker).is_some_and(|expiry| *expiry >= now) {
+                continue
+            }
+            let processing = self.core.redis_persistant.queue(name.clone(), None);
+            while let Some((archive_type, type_id, _)) = processing.peek_next().await? {
+                warn!("Requeuing interrupted archive of {archive_type} {type_id}");
+                self.retry(&processing).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the message in a processing list back in the queue unless it has already been tried too many times
+    async fn retry(&self, processing: &Queue<ArchiveMessage>) -> Result<()> {
+        let Some((archive_type, type_id, _)) = processing.peek_next().await? else { return Ok(()) };
+        let key = format!("{archive_type}-{type_id}");
+        let attempts = self.attempts.increment(&key, 1).await?;
+        if attempts >= MAX_ATTEMPTS {
+            error!("Giving up on archiving {archive_type} {type_id} after {attempts} attempts");
+            processing.pop().await?;
+            self.attempts.pop(&key).await?;
+            return Ok(())
+        }
+        processing.pop_into(&self.archive_queue).await?;
+        Ok(())
+    }
+
+    async fn handle_archive(&self) -> Result<()> {
+        while self.core.is_running() {
+            if !self.core.config.datastore.archive.enabled {
+                warn!("Archive is disabled in the configuration.");
+                self.core.sleep(Duration::from_secs(60)).await;
+                continue
+            }
+
+            // the message stays in the processing list until it is finished with
+            let message = match self.archive_queue.pop_into_timeout(&self.processing, Duration::from_secs(1)).await? {
+                Some(message) => message,
+                None => continue,
+            };
+            increment!(self.counter, received);
+            self.run_once(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Process the message taken into the processing list, removing it once it is finished
+    async fn run_once(&self, message: ArchiveMessage) -> Result<()> {
+        let (archive_type, type_id, delete_after) = &message;
+        let key = format!("{archive_type}-{type_id}");
+
+        let result = match archive_type.as_str() {
+            "submission" => self.archive_submission(type_id, *delete_after).await,
+            _ => {
+                increment!(self.counter, invalid);
+                warn!("Unsupported archive type: {archive_type}");
+                Ok(())
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                self.attempts.pop(&key).await?;
+                self.processing.delete().await?;
+            },
+            Err(err) => {
+                increment!(self.counter, exception);
+                error!("Error occurred while archiving {archive_type} {type_id}: {err:?}");
+                self.retry(&self.processing).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a submission and everything it references into the archive
+    pub async fn archive_submission(&self, sid: &str, delete_after: bool) -> Result<()> {
+        info!("Archiving submission: {sid}");
+
+        // Both indices are searched, so a submission moved by an interrupted attempt is still found
+        let submission = match self.datastore.submission.get(sid, None).await? {
+            Some(submission) => submission,
+            None => {
+                increment!(self.counter, not_found);
+                warn!("Submission {sid} could not be found for archiving");
+                return Ok(())
+            }
+        };
+
+        // Collect the files in the submission and the files attached to its results
+        let mut files: Vec<Sha256> = submission.files.iter().map(|file| file.sha256.clone()).collect();
+        let result_keys: Vec<String> = submission.results.iter()
+            .map(|key| key.to_string())
+            .filter(|key| !key.ends_with(".e"))
+            .collect();
+        let keys: Vec<&str> = result_keys.iter().map(|key| key.as_str()).collect();
+        let results: HashMap<String, ResultModel> = self.datastore.result.multiget(&keys, Some(false), None).await?;
+        for result in results.values() {
+            for file in result.response.extracted.iter().chain(result.response.supplementary.iter()) {
+                if !files.contains(&file.sha256) {
+                    files.push(file.sha256.clone());
+                }
+            }
+        }
+
+        // Copy over the files, then the documents that reference them, leaving the submission for last
+        // so that a partially archived submission is always visible to a repeated attempt.
+        for sha256 in &files {
+            self.archive_file(sha256).await?;
+        }
+
+        for key in &result_keys {
+            if self.datastore.result.archive(key, delete_after).await? {
+                increment!(self.counter, result);
+            }
+        }
+
+        for key in &submission.errors {
+            if self.datastore.error.archive(key, delete_after).await? {
+                increment!(self.counter, error);
+            }
+        }
+
+        if self.datastore.submission.archive(sid, delete_after).await? {
+            increment!(self.counter, submission);
+        }
+
+        // Files can be shared with other submissions, only remove those nothing else needs
+        if delete_after {
+            self.datastore.submission.commit(Some(Index::Hot)).await?;
+            self.datastore.result.commit(Some(Index::Hot)).await?;
+            for sha256 in &files {
+                self.release_file(sha256).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a file blob into the archive filestore and its document into the archive index
+    async fn archive_file(&self, sha256: &Sha256) -> Result<()> {
+        let name = sha256.to_string();
+
+        if !self.archive_storage.exists(&name).await? {
+            if self.core.filestore.exists(&name).await? {
+                let temp = tempfile::NamedTempFile::new()?;
+                self.core.filestore.download(&name, temp.path()).await?;
+                self.archive_storage.upload(temp.path(), &name).await?;
+            } else {
+                warn!("File {name} is missing from the filestore and could not be archived");
+            }
+        }
+
+        if self.datastore.file.archive(&name, false).await? {
+            increment!(self.counter, file);
+        }
+        Ok(())
+    }
+
+    /// Remove an archived file from the hot index and filestore unless a submission or result still uses it
+    async fn release_file(&self, sha256: &Sha256) -> Result<()> {
+        let name = sha256.to_string();
+        let submissions = self.datastore.submission.count(&format!("files.sha256:{name}"), vec![], None, Some(Index::Hot)).await?;
+        let results = self.datastore.result.count(
+            &format!("sha256:{name} OR response.extracted.sha256:{name} OR response.supplementary.sha256:{name}"),
+            vec![], None, Some(Index::Hot)
+        ).await?;
+        if submissions > 0 || results > 0 {
+            info!("Keeping file {name} in the filestore, it is still used by {submissions} submissions and {results} results");
+            return Ok(())
+        }
+
+        self.datastore.file.delete(&name, Some(Index::Hot)).await?;
+        if self.archive_storage.exists(&name).await? {
+            self.core.filestore.delete(&name).await?;
+        }
+        Ok(())
+    }
+}
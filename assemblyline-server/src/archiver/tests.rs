@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use assemblyline_models::datastore::{File, Submission};
+use assemblyline_models::datastore::Result as ResultModel;
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::elastic::Index;
+use crate::Core;
+
+use super::{Archiver, MAX_ATTEMPTS};
+
+async fn setup() -> (Core, crate::TestGuard, Archiver) {
+    let (core, guard) = Core::test_custom_setup(|config| {
+        config.datastore.archive.enabled = true;
+    }).await;
+    let archiver = Archiver::new(core.clone()).await.unwrap();
+    (core, guard, archiver)
+}
+
+/// Write a submission with one file and result to the hot indices and filestore
+async fn save_submission(core: &Core) -> (Submission, File, String) {
+    let data: [u8; 64] = rand::rng().random();
+    let file = File::gen_for_sample(&data, &mut rand::rng());
+    core.filestore.put(&file.sha256.to_string(), &Bytes::copy_from_slice(&data)).await.unwrap();
+    core.datastore.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+
+    let mut result: ResultModel = rand::rng().random();
+    result.sha256 = file.sha256.clone();
+    let result_key = result.build_key(None).unwrap();
+    core.datastore.result.save(&result_key, &result, None, None).await.unwrap();
+
+    let mut submission: Submission = rand::rng().random();
+    submission.files[0].sha256 = file.sha256.clone();
+    submission.results = vec![result_key.clone().into()];
+    submission.errors = vec![];
+    core.datastore.submission.save(&submission.sid.to_string(), &submission, None, None).await.unwrap();
+    (submission, file, result_key)
+}
+
+#[tokio::test]
+async fn test_archive_submission() {
+    let (core, _guard, archiver) = setup().await;
+    let (submission, file, result_key) = save_submission(&core).await;
+    let sid = submission.sid.to_string();
+
+    archiver.archive_submission(&sid, false).await.unwrap();
+
+    // documents are in both indices, and the hot copies know they have been archived
+    let datastore = &archiver.datastore;
+    assert!(datastore.submission.exists(&sid, Some(Index::Archive)).await.unwrap());
+    assert!(datastore.result.exists(&result_key, Some(Index::Archive)).await.unwrap());
+    assert!(datastore.file.exists(&file.sha256.to_string(), Some(Index::Archive)).await.unwrap());
+    let hot = datastore.submission.get(&sid, Some(Index::Hot)).await.unwrap().unwrap();
+    assert!(hot.archive_ts.is_some());
+    let archived = datastore.submission.get(&sid, Some(Index::Archive)).await.unwrap().unwrap();
+    assert!(archived.expiry_ts.is_none());
+    assert!(archiver.archive_storage.exists(&file.sha256.to_string()).await.unwrap());
+
+    // running it again changes nothing
+    archiver.archive_submission(&sid, false).await.unwrap();
+    assert!(datastore.submission.exists(&sid, Some(Index::Hot)).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_archive_delete_after() {
+    let (core, _guard, archiver) = setup().await;
+    let (submission, file, result_key) = save_submission(&core).await;
+    let sid = submission.sid.to_string();
+
+    archiver.archive_submission(&sid, true).await.unwrap();
+
+    let datastore = &archiver.datastore;
+    assert!(!datastore.submission.exists(&sid, Some(Index::Hot)).await.unwrap());
+    assert!(!datastore.result.exists(&result_key, Some(Index::Hot)).await.unwrap());
+    assert!(!datastore.file.exists(&file.sha256.to_string(), Some(Index::Hot)).await.unwrap());
+    assert!(datastore.submission.exists(&sid, Some(Index::Archive)).await.unwrap());
+
+    // a repeated message finds the work already done
+    archiver.archive_submission(&sid, true).await.unwrap();
+    assert!(datastore.submission.exists(&sid, Some(Index::Archive)).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_archive_delete_after_shared_file() {
+    let (core, _guard, archiver) = setup().await;
+    let (first, file, _) = save_submission(&core).await;
+    let mut second: Submission = rand::rng().random();
+    second.files[0].sha256 = file.sha256.clone();
+    second.results = vec![];
+    second.errors = vec![];
+    core.datastore.submission.save(&second.sid.to_string(), &second, None, None).await.unwrap();
+    let sha256 = file.sha256.to_string();
+
+    // the other submission still needs the file
+    archiver.archive_submission(&first.sid.to_string(), true).await.unwrap();
+    let datastore = &archiver.datastore;
+    assert!(datastore.file.exists(&sha256, Some(Index::Hot)).await.unwrap());
+    assert!(datastore.file.exists(&sha256, Some(Index::Archive)).await.unwrap());
+    assert!(core.filestore.exists(&sha256).await.unwrap());
+
+    // once the last user is archived the file goes too
+    archiver.archive_submission(&second.sid.to_string(), true).await.unwrap();
+    assert!(!datastore.file.exists(&sha256, Some(Index::Hot)).await.unwrap());
+    assert!(!core.filestore.exists(&sha256).await.unwrap());
+    assert!(archiver.archive_storage.exists(&sha256).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_requeue_interrupted() {
+    let (core, _guard, archiver) = setup().await;
+    let (submission, _, _) = save_submission(&core).await;
+    let message = ("submission".to_owned(), submission.sid.to_string(), false);
+
+    // messages held by running archivers are left alone
+    let other = Archiver::new(core.clone()).await.unwrap();
+    other.heartbeat().await.unwrap();
+    archiver.archive_queue.push(&message).await.unwrap();
+    assert_eq!(archiver.archive_queue.pop_into(&other.processing).await.unwrap(), Some(message.clone()));
+    archiver.requeue_interrupted().await.unwrap();
+    assert_eq!(other.processing.length().await.unwrap(), 1);
+    assert_eq!(archiver.archive_queue.length().await.unwrap(), 0);
+
+    // simulate an archiver that stopped while processing a message
+    archiver.workers.set(&other.worker_id, &0).await.unwrap();
+    archiver.requeue_interrupted().await.unwrap();
+    assert_eq!(other.processing.length().await.unwrap(), 0);
+    assert!(!archiver.workers.exists(&other.worker_id).await.unwrap());
+
+    // a finished message is removed from the processing list
+    let message = archiver.archive_queue.pop_into_timeout(&archiver.processing, Duration::from_secs(1)).await.unwrap().unwrap();
+    archiver.run_once(message).await.unwrap();
+    assert_eq!(archiver.processing.length().await.unwrap(), 0);
+    assert_eq!(archiver.archive_queue.length().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_retry_limit() {
+    let (_core, _guard, archiver) = setup().await;
+    let message = ("submission".to_owned(), "failing".to_owned(), false);
+
+    // the message is put back until it has failed too many times
+    archiver.archive_queue.push(&message).await.unwrap();
+    for _ in 1..MAX_ATTEMPTS {
+        assert_eq!(archiver.archive_queue.pop_into(&archiver.processing).await.unwrap(), Some(message.clone()));
+        archiver.retry(&archiver.processing).await.unwrap();
+        assert_eq!(archiver.processing.length().await.unwrap(), 0);
+        assert_eq!(archiver.archive_queue.length().await.unwrap(), 1);
+    }
+    archiver.archive_queue.pop_into(&archiver.processing).await.unwrap();
+    archiver.retry(&archiver.processing).await.unwrap();
+    assert_eq!(archiver.processing.length().await.unwrap(), 0);
+    assert_eq!(archiver.archive_queue.length().await.unwrap(), 0);
+    assert_eq!(archiver.attempts.length().await.unwrap(), 0);
+}
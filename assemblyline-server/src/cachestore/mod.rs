@@ -0,0 +1,145 @@
+//! Storage for files shared between instances of a component.
+//!
+//! Each cached blob is stored in the cache filestore under `{component}_{key}` and described by a
+//! document in the cached_file collection, the expiry_ts of that document is how the expiry daemon
+//! knows when the blob can be removed. A cachestore can optionally keep recently fetched blobs in
+//! memory, they are reused as long as the version of their cached_file document doesn't change.
+
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+
+use anyhow::{bail, Result};
+use assemblyline_filestore::FileStore;
+use assemblyline_models::datastore::CachedFile;
+use bytes::Bytes;
+use chrono::{TimeDelta, Utc};
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::elastic::{Elastic, Version};
+
+#[cfg(test)]
+mod tests;
+
+/// Default time a cached file is kept for, when no ttl is given
+pub const DEFAULT_CACHE_LEN: TimeDelta = TimeDelta::hours(1);
+
+static COMPONENT_VALIDATOR: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new("^[a-zA-Z0-9][a-zA-Z0-9_.]*$").unwrap()
+});
+
+/// Blobs held in memory along with the version of the cached_file document they were fetched at
+type MemoryCache = Arc<Mutex<LruCache<String, (Version, Arc<Vec<u8>>)>>>;
+
+#[derive(Clone)]
+pub struct CacheStore {
+    component: String,
+    datastore: Arc<Elastic>,
+    filestore: Arc<FileStore>,
+    memory: Option<MemoryCache>,
+}
+
+impl CacheStore {
+    pub fn new(component: String, datastore: Arc<Elastic>, filestore: Arc<FileStore>) -> Result<Self> {
+        if component.is_empty() {
+            bail!("Cannot instantiate a cachestore without providing a component name.")
+        }
+
+        if !COMPONENT_VALIDATOR.is_match(&component) {
+            bail!("Invalid component name. (Only letters, numbers, underscores and dots allowed)")
+        }
+
+        Ok(Self{ component, datastore, filestore, memory: None })
+    }
+
+    /// Keep up to `capacity` recently fetched blobs in memory
+    pub fn with_memory_cache(mut self, capacity: NonZeroUsize) -> Self {
+        self.memory = Some(Arc::new(Mutex::new(LruCache::new(capacity))));
+        self
+    }
+
+    /// Validate a cache key and build the name it is stored under
+    fn build_key(&self, cache_key: &str) -> Result<String> {
+        if !COMPONENT_VALIDATOR.is_match(cache_key) {
+            bail!("Invalid cache_key for cache item. (Only letters, numbers, underscores and dots allowed)")
+        }
+        Ok(format!("{}_{cache_key}", self.component))
+    }
+
+    /// Write (or refresh) the document tracking the expiry of a cached file
+    async fn save_record(&self, new_key: &str, ttl: Option<TimeDelta>) -> Result<()> {
+        let record = CachedFile {
+            component: self.component.clone(),
+            expiry_ts: Utc::now() + ttl.unwrap_or(DEFAULT_CACHE_LEN),
+        };
+        self.datastore.cached_file.save(new_key, &record, None, None).await?;
+        Ok(())
+    }
+
+    pub async fn save(&self, cache_key: &str, data: &Bytes, ttl: Option<TimeDelta>) -> Result<()> {
+        let new_key = self.build_key(cache_key)?;
+        // the blob goes first so a record never points at a missing file
+        self.filestore.put(&new_key, data).await?;
+        self.save_record(&new_key, ttl).await
+    }
+
+    /// Extend the lifetime of a file already in the cache
+    pub async fn touch(&self, cache_key: &str, ttl: Option<TimeDelta>) -> Result<()> {
+        let new_key = self.build_key(cache_key)?;
+        if !self.filestore.exists(&new_key).await? {
+            bail!("Cache item not found: {cache_key}")
+        }
+        self.save_record(&new_key, ttl).await
+    }
+
+    pub async fn get(&self, cache_key: &str) -> Result<Option<Vec<u8>>> {
+        let new_key = self.build_key(cache_key)?;
+        Ok(self.fetch(&new_key).await?.map(|data| data.as_ref().clone()))
+    }
+
+    pub async fn download(&self, cache_key: &str, path: &Path) -> Result<()> {
+        let new_key = self.build_key(cache_key)?;
+        if self.memory.is_none() {
+            return self.filestore.download(&new_key, path).await
+        }
+
+        match self.fetch(&new_key).await? {
+            Some(data) => Ok(tokio::fs::write(path, data.as_ref()).await?),
+            None => bail!("Cache item not found: {cache_key}"),
+        }
+    }
+
+    /// Fetch a blob, going through the memory cache if one is configured
+    async fn fetch(&self, new_key: &str) -> Result<Option<Arc<Vec<u8>>>> {
+        let memory = match &self.memory {
+            Some(memory) => memory,
+            None => return Ok(self.filestore.get(new_key).await?.map(Arc::new)),
+        };
+
+        // Files written without a record can't be checked for changes, so they are never held in memory
+        let version = match self.datastore.cached_file.get_if_exists(new_key, None).await? {
+            Some((_, version)) => version,
+            None => {
+                memory.lock().pop(new_key);
+                return Ok(self.filestore.get(new_key).await?.map(Arc::new))
+            }
+        };
+
+        if let Some((cached_version, data)) = memory.lock().get(new_key) {
+            if *cached_version == version {
+                return Ok(Some(data.clone()))
+            }
+        }
+
+        let data = match self.filestore.get(new_key).await? {
+            Some(data) => Arc::new(data),
+            None => {
+                memory.lock().pop(new_key);
+                return Ok(None)
+            }
+        };
+        memory.lock().put(new_key.to_owned(), (version, data.clone()));
+        Ok(Some(data))
+    }
+}
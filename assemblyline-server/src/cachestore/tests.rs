@@ -0,0 +1,79 @@
+use std::num::NonZeroUsize;
+
+use bytes::Bytes;
+use chrono::{TimeDelta, Utc};
+
+use crate::Core;
+
+use super::CacheStore;
+
+fn cachestore(core: &Core) -> CacheStore {
+    CacheStore::new("test".to_owned(), core.datastore.clone(), core.filestore.clone()).unwrap()
+}
+
+#[tokio::test]
+async fn test_invalid_names() {
+    let (core, _guard) = Core::test_setup().await;
+    assert!(CacheStore::new("".to_owned(), core.datastore.clone(), core.filestore.clone()).is_err());
+    assert!(CacheStore::new("_test".to_owned(), core.datastore.clone(), core.filestore.clone()).is_err());
+    assert!(CacheStore::new("test/path".to_owned(), core.datastore.clone(), core.filestore.clone()).is_err());
+
+    let cache = cachestore(&core);
+    assert!(cache.save("bad key", &Bytes::from_static(b"data"), None).await.is_err());
+    assert!(cache.get("../escape").await.is_err());
+}
+
+#[tokio::test]
+async fn test_save_get_touch() {
+    let (core, _guard) = Core::test_setup().await;
+    let cache = cachestore(&core);
+
+    cache.save("item", &Bytes::from_static(b"cached data"), None).await.unwrap();
+    assert!(core.filestore.exists("test_item").await.unwrap());
+    assert_eq!(cache.get("item").await.unwrap().unwrap(), b"cached data");
+
+    // the expiry of the record is written with the file
+    let (record, _) = core.datastore.cached_file.get_if_exists("test_item", None).await.unwrap().unwrap();
+    assert_eq!(record.component, "test");
+    assert!(record.expiry_ts > Utc::now() + TimeDelta::minutes(59));
+
+    // touching extends the expiry
+    cache.touch("item", Some(TimeDelta::days(1))).await.unwrap();
+    let (record, _) = core.datastore.cached_file.get_if_exists("test_item", None).await.unwrap().unwrap();
+    assert!(record.expiry_ts > Utc::now() + TimeDelta::hours(23));
+    assert!(cache.touch("missing", None).await.is_err());
+    assert!(cache.get("missing").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_download() {
+    let (core, _guard) = Core::test_setup().await;
+    let cache = cachestore(&core);
+
+    cache.save("download", &Bytes::from_static(b"downloaded data"), None).await.unwrap();
+
+    let target = tempfile::NamedTempFile::new().unwrap();
+    cache.download("download", target.path()).await.unwrap();
+    assert_eq!(std::fs::read(target.path()).unwrap(), b"downloaded data");
+}
+
+#[tokio::test]
+async fn test_memory_cache() {
+    let (core, _guard) = Core::test_setup().await;
+    let cache = cachestore(&core).with_memory_cache(NonZeroUsize::new(2).unwrap());
+
+    cache.save("item", &Bytes::from_static(b"first"), None).await.unwrap();
+    assert_eq!(cache.get("item").await.unwrap().unwrap(), b"first");
+
+    // while the record is unchanged the copy in memory is used
+    core.filestore.put("test_item", &Bytes::from_static(b"changed underneath")).await.unwrap();
+    assert_eq!(cache.get("item").await.unwrap().unwrap(), b"first");
+
+    // saving through the cachestore updates the record so the new data is fetched
+    cache.save("item", &Bytes::from_static(b"second"), None).await.unwrap();
+    assert_eq!(cache.get("item").await.unwrap().unwrap(), b"second");
+
+    let target = tempfile::NamedTempFile::new().unwrap();
+    cache.download("item", target.path()).await.unwrap();
+    assert_eq!(std::fs::read(target.path()).unwrap(), b"second");
+}
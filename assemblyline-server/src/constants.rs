@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 pub(crate) const NOTIFICATION_QUEUE_PREFIX: &str = "nq-";
 pub(crate) const INGEST_INTERNAL_QUEUE_NAME: &str = "m-unique";
 pub(crate) const ALERT_QUEUE_NAME: &str = "m-alert";
+pub(crate) const ALERT_RETRY_QUEUE_NAME: &str = "m-alert-retry";
 pub(crate) const CONFIG_HASH_NAME: &str = "config-data";
 pub(crate) const CONFIG_HASH: &str = "al-config";
 pub(crate) const POST_PROCESS_CONFIG_KEY: &str = "post-process-actions";
@@ -15,6 +16,8 @@ pub(crate) const COMPLETE_QUEUE_NAME: &str = "m-complete";
 pub(crate) const INGEST_QUEUE_NAME: &str = "m-ingest";
 pub(crate) const SUBMISSION_QUEUE: &str = "dispatch-submission-queue";
 pub(crate) const ARCHIVE_QUEUE_NAME: &str = "m-archive";
+pub(crate) const ARCHIVE_ATTEMPTS_HASH: &str = "m-archive-attempts";
+pub(crate) const ARCHIVE_WORKERS_HASH: &str = "m-archive-workers";
 pub(crate) const DISPATCH_TASK_HASH: &str = "dispatch-active-submissions";
 // pub(crate) const DISPATCH_RUNNING_TASK_HASH: &str = "dispatch-active-tasks";
 pub(crate) const SCALER_TIMEOUT_QUEUE: &str = "scaler-timeout-queue";
@@ -1,18 +1,24 @@
 //! Extension to the core module for accessing information about dispatcher instances
 
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 
+use assemblyline_models::config::QueueFairness;
+use assemblyline_models::datastore::submission::SubmissionParams;
 use assemblyline_models::messages::task::Task;
 use assemblyline_models::messages::dispatching::SubmissionDispatchMessage;
 use assemblyline_models::types::Sid;
+use redis_objects::{ErrorTypes, FairQueue, PriorityQueue, RedisObjects};
 
 use crate::constants::service_queue_name;
 use crate::Core;
 
 pub const DISPATCH_DIRECTORY: &str = "dispatchers-directory";
 pub const DISPATCH_TASK_ASSIGNMENT: &str = "dispatcher-tasks-assigned-to-";
+pub const DISPATCH_QUEUE_LAYOUT: &str = "dispatchers-queue-layout";
 
 
 impl Core {
@@ -21,6 +27,12 @@ impl Core {
         self.redis_persistant.hashmap::<i64>(DISPATCH_DIRECTORY.to_owned(), None)
     }
 
+    /// The service queue fairness each running dispatcher was started with, dispatchers only keep
+    /// track of the tasks they queued in the layout that setting selects
+    pub fn dispatcher_queue_layouts(&self) -> redis_objects::Hashmap<QueueFairness> {
+        self.redis_persistant.hashmap::<QueueFairness>(DISPATCH_QUEUE_LAYOUT.to_owned(), None)
+    }
+
     pub async fn dispatcher_instances(&self) -> Result<Vec<String>, redis_objects::ErrorTypes> {
         self.dispatcher_instances_table().keys().await
     }
@@ -36,8 +48,8 @@ impl Core {
         )
     }
 
-    pub fn get_service_queue(&self, service: &str) -> redis_objects::PriorityQueue<Task> {
-        self.redis_volatile.priority_queue(service_queue_name(service))
+    pub fn get_service_queue(&self, service: &str) -> ServiceQueue {
+        ServiceQueue::open(&self.redis_volatile, service, self.config.core.dispatcher.service_queue_fairness)
     }
 
     // def dispatcher_queue_lengths(redis, instance_id):
@@ -47,4 +59,84 @@ impl Core {
     //         'command': NamedQueue(DISPATCH_COMMAND_QUEUE + instance_id, host=redis).length()
     //     }
 
-}
\ No newline at end of file
+}
+
+/// The queue of tasks waiting for a service, shared between tenants when fairness is enabled
+pub enum ServiceQueue {
+    Plain(PriorityQueue<Task>),
+    Fair(FairQueue<Task>),
+}
+
+impl ServiceQueue {
+    pub fn open(redis: &Arc<RedisObjects>, service: &str, fairness: QueueFairness) -> Self {
+        match fairness {
+            QueueFairness::Disabled => Self::Plain(redis.priority_queue(service_queue_name(service))),
+            _ => Self::Fair(redis.fair_queue(service_queue_name(service))),
+        }
+    }
+
+    /// Whether tasks are kept in the fair layout, which is the same whoever the tenants are
+    pub fn is_fair(fairness: QueueFairness) -> bool {
+        fairness != QueueFairness::Disabled
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ServiceQueue::Plain(queue) => queue.name(),
+            ServiceQueue::Fair(queue) => queue.name(),
+        }
+    }
+
+    /// Add a task on behalf of a tenant, returns the key used to find it again with `rank` and `remove`
+    pub async fn push(&self, tenant: &str, priority: f64, task: &Task) -> Result<Vec<u8>, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) => queue.push(priority, task).await,
+            ServiceQueue::Fair(queue) => queue.push(tenant, priority, task).await,
+        }
+    }
+
+    /// Take the next task, the lowest priority one of the chosen tenant if `low_priority` is set
+    pub async fn pop(&self, low_priority: bool) -> Result<Option<Task>, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) if low_priority => Ok(queue.unpush(1).await?.pop()),
+            ServiceQueue::Plain(queue) => Ok(queue.pop(1).await?.pop()),
+            ServiceQueue::Fair(queue) => queue.pop(low_priority).await,
+        }
+    }
+
+    pub async fn blocking_pop(&self, timeout: Duration, low_priority: bool) -> Result<Option<Task>, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) => queue.blocking_pop(timeout, low_priority).await,
+            ServiceQueue::Fair(queue) => queue.blocking_pop(timeout, low_priority).await,
+        }
+    }
+
+    pub async fn rank(&self, key: &[u8]) -> Result<Option<u64>, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) => queue.rank(key).await,
+            ServiceQueue::Fair(queue) => queue.rank(key).await,
+        }
+    }
+
+    pub async fn remove(&self, key: &[u8]) -> Result<bool, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) => queue.remove(key).await,
+            ServiceQueue::Fair(queue) => queue.remove(key).await,
+        }
+    }
+
+    pub async fn length(&self) -> Result<u64, ErrorTypes> {
+        match self {
+            ServiceQueue::Plain(queue) => queue.length().await,
+            ServiceQueue::Fair(queue) => queue.length().await,
+        }
+    }
+}
+
+/// Tenant a submission's tasks are queued under
+pub fn queue_tenant(fairness: QueueFairness, params: &SubmissionParams) -> &str {
+    match fairness {
+        QueueFairness::Group => params.groups.first().map(|group| &**group).unwrap_or(&params.submitter),
+        _ => &params.submitter,
+    }
+}
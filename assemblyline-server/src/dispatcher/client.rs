@@ -9,6 +9,7 @@ use std::time::Duration;
 
 use anyhow::{bail, Result};
 
+use assemblyline_models::config::QueueFairness;
 use assemblyline_models::datastore::{result, EmptyResult, Error};
 use assemblyline_models::messages::dispatching::{SubmissionDispatchMessage, WatchQueueMessage};
 use assemblyline_models::messages::task::{ResultSummary, ServiceError, ServiceResult, Task as ServiceTask};
@@ -19,8 +20,10 @@ use redis_objects::RedisObjects;
 use reqwest::StatusCode;
 use tokio::sync::Mutex;
 
-use crate::constants::{make_watcher_list_name, service_queue_name, SUBMISSION_QUEUE};
+use crate::constants::{make_watcher_list_name, SUBMISSION_QUEUE};
+use crate::core_dispatcher::ServiceQueue;
 use crate::elastic::{Elastic, Version};
+use crate::work_queue::WorkQueue;
 use crate::Core;
 
 use super::ServiceStartMessage;
@@ -40,8 +43,9 @@ pub trait DispatchCapable: 'static + Send + Sync {
 pub struct DispatchClient {
     datastore: Arc<Elastic>,
     redis_volatile: Arc<redis_objects::RedisObjects>,
+    queue_fairness: QueueFairness,
 
-    submission_queue: redis_objects::Queue<SubmissionDispatchMessage>,
+    submission_queue: WorkQueue<SubmissionDispatchMessage>,
     dispatcher_table: redis_objects::Hashmap<i64>,
     dispatcher_data: Mutex<DispatcherList>,
 
@@ -157,6 +161,7 @@ impl DispatchCapable for DispatchClient {
         }
 
         let sid = task.sid;
+        let headers = crate::telemetry::trace_headers(task.trace_context.as_ref());
         let sha256 = result.sha256.clone();
         let service_name = task.service_name;
 
@@ -186,7 +191,7 @@ impl DispatchCapable for DispatchClient {
 
         loop {
             // Let the dispatcher know we failed this task
-            let response = self.http_client.post(&url).json(&message).send().await;
+            let response = self.http_client.post(&url).headers(headers.clone()).json(&message).send().await;
 
             match response {
                 // if we got a complete response of any kind, treat
@@ -237,6 +242,7 @@ impl DispatchCapable for DispatchClient {
         // })
         let dispatcher = task.dispatcher.clone();
         let url = format!("https://{}/error", task.dispatcher_address);
+        let headers = crate::telemetry::trace_headers(task.trace_context.as_ref());
         let message = ServiceError {
             sid: task.sid,
             service_task: task,
@@ -248,6 +254,7 @@ impl DispatchCapable for DispatchClient {
             // Let the dispatcher know we failed this task
             let response = self.http_client
                 .post(&url)
+                .headers(headers.clone())
                 .json(&message)
                 .send().await;
 
@@ -311,10 +318,11 @@ impl DispatchClient {
 
         Ok(Self {
             datastore: core.datastore.clone(),
-            submission_queue: core.redis_volatile.queue(SUBMISSION_QUEUE.to_owned(), None),
+            submission_queue: WorkQueue::open(&core.redis_volatile, SUBMISSION_QUEUE, "dispatcher", core.config.core.dispatcher.durable_queue),
             dispatcher_table: core.dispatcher_instances_table(),
             dispatcher_data: Mutex::new(Default::default()),
             redis_volatile: core.redis_volatile.clone(),
+            queue_fairness: core.config.core.dispatcher.service_queue_fairness,
             http_client: http_client.build()?,
             emptyresult_dtl: chrono::TimeDelta::days(core.config.submission.emptyresult_dtl.into()),
             // running_tasks: core.redis_volatile.hashmap(DISPATCH_RUNNING_TASK_HASH.to_owned(), None),
@@ -430,13 +438,11 @@ impl DispatchClient {
         }
 
         // Get work from the queue
-        let work_queue = self.redis_volatile.priority_queue::<ServiceTask>(service_queue_name(&service_name));
+        let work_queue = ServiceQueue::open(&self.redis_volatile, &service_name, self.queue_fairness);
         let result = if blocking {
             work_queue.blocking_pop(timeout, low_priority).await?
-        } else if low_priority {
-            work_queue.unpush(1).await?.pop()
         } else {
-            work_queue.pop(1).await?.pop()
+            work_queue.pop(low_priority).await?
         };
 
         let mut task = match result {
@@ -454,6 +460,7 @@ impl DispatchClient {
         task.metadata.insert("worker__".to_string(), worker_id.into());
 
         let url = format!("https://{}/start", task.dispatcher_address);
+        let headers = crate::telemetry::trace_headers(task.trace_context.as_ref());
         let message = ServiceStartMessage {
             sid: task.sid,
             sha: task.fileinfo.sha256.clone(),
@@ -465,7 +472,7 @@ impl DispatchClient {
 
         loop {
             // Let the dispatcher know we want to start this task
-            let response = self.http_client.post(&url).json(&message).send().await;
+            let response = self.http_client.post(&url).headers(headers.clone()).json(&message).send().await;
 
             match response {
                 // if we got a complete response of any kind, treat as accepted
@@ -579,8 +586,7 @@ impl DispatchCapable for MockDispatchClient {
             let timeout = timeout.unwrap_or(Duration::from_secs(1));
             Ok(queue.blocking_pop(timeout, false).await?)
         } else {
-            let mut row = queue.pop(1).await?;
-            Ok(row.pop())
+            Ok(queue.pop(false).await?)
         }
     }
 
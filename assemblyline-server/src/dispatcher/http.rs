@@ -11,7 +11,7 @@ use assemblyline_models::messages::task::{ServiceError, ServiceResponse, Service
 use log::error;
 use poem::http::StatusCode;
 use poem::web::{Data, Json};
-use poem::{get, handler, post, EndpointExt, Route, Server};
+use poem::{get, handler, post, Endpoint, EndpointExt, Route, Server};
 use serde::{Serialize, Deserialize};
 
 
@@ -89,17 +89,22 @@ fn get_status(Data(dispatcher): Data<&Arc<Dispatcher>>) -> Json<BasicStatus> {
     })
 }
 
-pub async fn start(acceptor: TlsAcceptor, dispatcher: Arc<Dispatcher>) {
-    let app = Route::new()
+/// Routes served by the dispatcher
+pub(super) fn app(dispatcher: Arc<Dispatcher>) -> impl Endpoint {
+    Route::new()
     .at("/alive", get(get_status))
     .at("/start", post(start_task))
     .at("/error", post(handle_task_error))
     .at("/result", post(handle_task_result))
+    .nest("/healthz", crate::health::api(dispatcher.core.clone()))
     .data(dispatcher)
-        .with(LoggerMiddleware);
+        .with(LoggerMiddleware)
+        .with(crate::telemetry::TraceContextMiddleware)
+}
 
+pub async fn start(acceptor: TlsAcceptor, dispatcher: Arc<Dispatcher>) {
     let result = Server::new_with_acceptor(acceptor)
-        .run(app)
+        .run(app(dispatcher))
         .await;
     if let Err(err) = result {
         error!("http interface failed: {err}");
@@ -9,7 +9,7 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result, anyhow};
-use assemblyline_models::config::{Config, TemporaryKeyType};
+use assemblyline_models::config::{Config, QueueFairness, TemporaryKeyType};
 use assemblyline_models::datastore::submission::{SubmissionState, TraceEvent};
 use assemblyline_models::datastore::tagging::TagValue;
 use assemblyline_models::datastore::user::User;
@@ -17,7 +17,7 @@ use assemblyline_models::datastore::{error, Service, Submission};
 use assemblyline_models::messages::dispatching::{CreateWatch, DispatcherCommand, DispatcherCommandMessage, FileTreeData, ListOutstanding, SubmissionDispatchMessage, WatchQueueMessage, WatchQueueStatus};
 use assemblyline_models::messages::submission::SubmissionMessage;
 use assemblyline_models::messages::task::{DataItem, FileInfo, ResultSummary, ServiceResponse, ServiceResult, TagEntry, TagItem, Task as ServiceTask};
-use assemblyline_models::messages::KillContainerCommand;
+use assemblyline_models::messages::{KillContainerCommand, TraceContext};
 use assemblyline_models::messages::service_heartbeat::Metrics as ServiceMetrics;
 use assemblyline_models::messages::dispatcher_heartbeat::Metrics;
 use assemblyline_models::types::{Wildcard, ExpandingClassification, JsonMap, Sha256, Sid, ServiceName};
@@ -32,16 +32,18 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::{mpsc, oneshot, RwLock};
 use rand::Rng;
-use tracing::instrument;
+use tracing::{instrument, Instrument};
 
 
 use crate::common::metrics::CPUTracker;
 use crate::constants::{make_watcher_list_name, COMPLETE_QUEUE_NAME, DISPATCH_TASK_HASH, METRICS_CHANNEL, SCALER_TIMEOUT_QUEUE, SUBMISSION_QUEUE};
+use crate::core_dispatcher::queue_tenant;
 use crate::elastic::Elastic;
 use crate::http::TlsAcceptor;
 use crate::logging::FormattedList;
 use crate::postprocessing::ActionWorker;
 use crate::services::{get_schedule_names, ServiceHelper};
+use crate::work_queue::WorkQueue;
 use crate::{Core, Flag};
 
 // APM_SPAN_TYPE = 'handle_message'
@@ -53,6 +55,8 @@ const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
 const ONE_DAY: Duration = Duration::from_secs(ONE_HOUR.as_secs() * 24);
 const DEFAULT_RESULT_BATCH_SIZE: usize = 50;
 const USER_CACHE_TIME: Duration = Duration::from_secs(30);
+/// Longest the worker loops go between iterations when there is nothing to do
+const LOOP_INTERVAL: Duration = Duration::from_secs(5);
 
 /// How long to wait after verifying that a task is enqueued before checking again.
 /// This prevents the dispatcher from:
@@ -232,6 +236,9 @@ struct SubmissionTask {
     // any children (recursively) of that file
     _forbidden_services: HashMap<Sha256, HashSet<ServiceName>>,
     _parent_map: HashMap<Sha256, HashSet<Sha256>>,
+
+    /// trace the submission was started in, continued by the tasks sent to services
+    trace_context: Option<TraceContext>,
 }
 
 impl std::fmt::Debug for SubmissionTask {
@@ -247,6 +254,7 @@ impl SubmissionTask {
         let mut out = Self {
             submission: args.submission,
             completed_queue: args.completed_queue,
+            trace_context: args.trace_context,
             service_access_control: access_control,
             internal_task_queue: Default::default(),
 
@@ -789,7 +797,7 @@ pub struct Dispatcher {
     // Output. Duplicate our input traffic into this queue so it may be cloned by other systems
     traffic_queue: redis_objects::Publisher,
     quota_tracker: UserQuotaTracker,
-    submission_queue: redis_objects::Queue<SubmissionDispatchMessage>,
+    submission_queue: WorkQueue<SubmissionDispatchMessage>,
 
     // Tables to track what submissions are running where
     submissions_assignments: redis_objects::Hashmap<String>,
@@ -799,6 +807,7 @@ pub struct Dispatcher {
     // Table to track the running dispatchers
     dispatchers_directory: redis_objects::Hashmap<i64>,
     dispatchers_directory_finalize: redis_objects::Hashmap<i64>,
+    dispatchers_queue_layout: redis_objects::Hashmap<QueueFairness>,
     running_dispatchers_estimate: std::sync::atomic::AtomicU32,
 
     // Build some utility classes
@@ -863,7 +872,7 @@ impl Dispatcher {
             // Output. Duplicate our input traffic into this queue so it may be cloned by other systems
             traffic_queue: core.redis_volatile.publisher("submissions".to_owned()),
             quota_tracker: core.redis_persistant.user_quota_tracker("submissions".to_string()).set_timeout(QUOTA_TIMEOUT),
-            submission_queue: core.redis_volatile.queue(SUBMISSION_QUEUE.to_string(), None),
+            submission_queue: WorkQueue::open(&core.redis_volatile, SUBMISSION_QUEUE, "dispatcher", core.config.core.dispatcher.durable_queue),
 
             // Tables to track what submissions are running where
             submissions_assignments: core.redis_persistant.hashmap(DISPATCH_TASK_HASH.to_owned(), None),
@@ -876,6 +885,7 @@ impl Dispatcher {
             // Table to track the running dispatchers
             dispatchers_directory: core.dispatcher_instances_table(),
             dispatchers_directory_finalize: core.redis_persistant.hashmap(DISPATCH_DIRECTORY_FINALIZE.to_string(), None),
+            dispatchers_queue_layout: core.dispatcher_queue_layouts(),
             running_dispatchers_estimate: AtomicU32::new(1),
 
             // Track the tasks that should be running right now
@@ -990,9 +1000,12 @@ impl Dispatcher {
     }
 
     async fn pull_submissions(self: &Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("pull_submissions", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             while !self.core.is_active() {
                 // Dispatcher is disabled... waiting for it to be reactivated
+                heartbeat.beat();
                 self.core.sleep(std::time::Duration::from_millis(100)).await;
             }
 
@@ -1028,7 +1041,7 @@ impl Dispatcher {
         }
 
         // Grab a submission message
-        let message = match self.submission_queue.pop_timeout(ONE_SECOND).await? {
+        let (message, receipt) = match self.submission_queue.pop_timeout(ONE_SECOND).await? {
             Some(message) => message,
             None => return Ok(())
         };
@@ -1043,12 +1056,16 @@ impl Dispatcher {
         // This is probably a complete task
         let task = SubmissionTask::new(message, access_control, &self.core.services, &self.core.config);
         self.dispatch_submission(task).await.context("dispatch_submission")?;
+
+        // the submission is tracked in the active submissions table from here on
+        self.submission_queue.ack(receipt).await?;
         return Ok(())
     }
 
     async fn pull_service_starts(self: &Arc<Self>) -> Result<()> {
-
+        let heartbeat = self.core.health.register("pull_service_starts", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             // get a batch of start messages
             let mut messages = self.start_queue.pop_batch(100).await?;
 
@@ -1068,7 +1085,9 @@ impl Dispatcher {
     }
 
     async fn pull_service_results(self: &Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("pull_service_results", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             // Try to get a batch of results to process
             let mut messages = self.result_queue.pop_batch(self.result_batch_size).await?;
 
@@ -1093,8 +1112,12 @@ impl Dispatcher {
         let mut old_value = chrono::Utc::now().timestamp();
         let id = self.instance_id.to_string();
         self.dispatchers_directory.set(&id, &old_value).await?;
+        // the plumber leaves tasks in this layout alone while we may be holding keys to them
+        self.dispatchers_queue_layout.set(&id, &self.core.config.core.dispatcher.service_queue_fairness).await?;
 
+        let heartbeat = self.core.health.register("work_guard", check_interval);
         while self.core.sleep(check_interval).await {
+            heartbeat.beat();
             // Increase the guard number
             let gap = chrono::Utc::now().timestamp() - old_value;
             let updated_value = self.dispatchers_directory.increment(&id, gap).await?;
@@ -1114,6 +1137,7 @@ impl Dispatcher {
 
         self.dispatchers_directory.pop(&id).await?;
         self.dispatchers_directory_finalize.pop(&id).await?;
+        self.dispatchers_queue_layout.pop(&id).await?;
         Ok(())
     }
 
@@ -1130,7 +1154,10 @@ impl Dispatcher {
         // Keep a table of the last recorded status for other dispatchers
         let mut last_seen: HashMap<String, i64> = Default::default();
 
-        while self.core.sleep(Duration::from_secs(GUARD_TIMEOUT as u64 / 4)).await {
+        let check_interval = Duration::from_secs(GUARD_TIMEOUT as u64 / 4);
+        let heartbeat = self.core.health.register("work_thief", check_interval);
+        while self.core.sleep(check_interval).await {
+            heartbeat.beat();
 
             // Load guards
             let finalizing = self.dispatchers_directory_finalize.items().await?;
@@ -1173,6 +1200,7 @@ impl Dispatcher {
         let id = self.instance_id.to_string();
         self.dispatchers_directory.pop(&id).await?;
         self.dispatchers_directory_finalize.pop(&id).await?;
+        self.dispatchers_queue_layout.pop(&id).await?;
         Ok(())
     }
 
@@ -1202,11 +1230,14 @@ impl Dispatcher {
         info!("Finished stealing work from {target}");
         self.dispatchers_directory.pop(target).await?;
         self.dispatchers_directory_finalize.pop(target).await?;
+        self.dispatchers_queue_layout.pop(target).await?;
         Ok(())
     }
 
     async fn handle_commands(self: &Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("commands", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
 
             let command = match self.command_queue.pop_timeout(Duration::from_secs(3)).await? {
                 Some(message) => message,
@@ -1303,7 +1334,9 @@ impl Dispatcher {
     }
 
     async fn timeout_backstop(self: Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("timeout_backstop", GLOBAL_TASK_CHECK_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             // { // timeout_backstop
             //     let dispatcher_instances = self.core.dispatcher_instances().await?;
             //     let mut error_tasks = vec![];
@@ -1453,8 +1486,14 @@ impl Dispatcher {
     // Preconditions:
     //     - File exists in the filestore and file collection in the datastore
     //     - Submission is stored in the datastore
-    #[instrument]
-    async fn dispatch_submission(self: &Arc<Self>, mut task: SubmissionTask) -> Result<()> {
+    async fn dispatch_submission(self: &Arc<Self>, task: SubmissionTask) -> Result<()> {
+        // the parent has to be set before the span is entered for the work in it to join the trace
+        let span = tracing::info_span!("dispatch_submission", sid = %task.submission.sid);
+        crate::telemetry::set_parent(&span, task.trace_context.as_ref());
+        self._dispatch_submission(task).instrument(span).await
+    }
+
+    async fn _dispatch_submission(self: &Arc<Self>, mut task: SubmissionTask) -> Result<()> {
         // let submission = &task.submission;
         let sid = task.submission.sid.to_string();
         let sha256 = task.submission.files[0].sha256.clone();
@@ -1472,7 +1511,8 @@ impl Dispatcher {
         if !self.active_submissions.exists(&sid).await? {
             info!("[{sid}] New submission received");
             task.trace_event("submission_start");
-            self.active_submissions.add(&sid, &SubmissionDispatchMessage::new(task.submission.clone(),  task.completed_queue.clone())).await?;
+            self.active_submissions.add(&sid, &SubmissionDispatchMessage::new(task.submission.clone(), task.completed_queue.clone())
+                .set_trace_context(task.trace_context.clone())).await?;
 
             // Write all new submissions to the traffic queue
             self.traffic_queue.publish(&SubmissionMessage::started((&task.submission).into())).await?;
@@ -1520,7 +1560,8 @@ impl Dispatcher {
         }
 
         // Launch processor
-        tokio::spawn(self.clone().submission_worker(task, receiver));
+        let span = tracing::info_span!("submission", sid);
+        tokio::spawn(self.clone().submission_worker(task, receiver).instrument(span));
         Ok(())
     }
 
@@ -1683,7 +1724,8 @@ impl Dispatcher {
                 },
                 DispatchAction::BadSid(_) => {
                     task.submission.to_be_deleted = true;
-                    self.active_submissions.set(&sid.to_string(), &SubmissionDispatchMessage::new(task.submission.clone(), task.completed_queue.clone())).await?;
+                    self.active_submissions.set(&sid.to_string(), &SubmissionDispatchMessage::new(task.submission.clone(), task.completed_queue.clone())
+                        .set_trace_context(task.trace_context.clone())).await?;
                 },
                 DispatchAction::Check(_) => {
                     info!("[{sid}] checking dispatch status...");
@@ -1957,14 +1999,19 @@ impl Dispatcher {
                     temporary_submission_data: temp_data,
                     deep_scan,
                     priority: task.submission.params.priority as i32,
-                    safelist_config: self.core.config.services.safelist.clone()
+                    safelist_config: self.core.config.services.safelist.clone(),
+                    trace_context: crate::telemetry::current_context(),
                 };
                 service_task.metadata.insert("dispatcher__".to_string(), self.instance_id.clone().into());
                 service_task.metadata.insert("dispatcher_address__".to_string(), self.instance_address.clone().into());
                 service_task.metadata.insert("task_id__".to_string(), service_task.task_id.to_string().into());
+                if let Some(context) = &service_task.trace_context {
+                    service_task.metadata.insert(crate::telemetry::TRACE_CONTEXT_METADATA.to_string(), crate::telemetry::to_metadata(context).into());
+                }
 
                 // Its a new task, send it to the service
-                let queue_key = service_queue.push(service_task.priority as f64, &service_task).await?;
+                let tenant = queue_tenant(self.core.config.core.dispatcher.service_queue_fairness, &task.submission.params);
+                let queue_key = service_queue.push(tenant, service_task.priority as f64, &service_task).await?;
                 task.queue_keys.insert(key.clone(), (service_task, queue_key, Instant::now()));
                 sent.push(service_name);
                 task.service_logs.entry(key).or_default().push(format!("Submitted to queue at {}", chrono::Utc::now()));
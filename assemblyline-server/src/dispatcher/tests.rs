@@ -98,6 +98,38 @@ async fn start_test_dispatcher(core: Core) -> anyhow::Result<Arc<Dispatcher>> {
     Ok(dispatcher)
 }
 
+//MARK: trace context
+#[tokio::test]
+async fn test_dispatch_trace_context() {
+    use crate::telemetry::test::{trace_subscriber, TRACE_ID, TRACE_PARENT};
+    let _subscriber = trace_subscriber();
+    let (core, _guard) = setup().await;
+
+    let mut file: File = rand::rng().random();
+    file.file_type = "unknown".to_string();
+    core.datastore.file.save(&file.sha256, &file, None, None).await.unwrap();
+    let user: User = User::create_test_user();
+    core.datastore.user.save(&user.uname, &user, None, None).await.unwrap();
+
+    let mut sub: Submission = rand::rng().random();
+    sub.to_be_deleted = false;
+    sub.params.classification = ClassificationString::unrestricted(&core.classification_parser);
+    sub.params.submitter = user.uname.clone();
+    sub.files = vec![submission::File{ sha256: file.sha256.clone(), name: "file".to_string(), size: None }];
+
+    // a submission sent as part of a trace produces service tasks in the same trace
+    let disp = start_test_dispatcher(core.clone()).await.unwrap();
+    let mut message = SubmissionDispatchMessage::new(sub.clone(), None);
+    message.trace_context = Some([("traceparent".to_owned(), TRACE_PARENT.to_owned())].into());
+    disp.dispatch_submission(SubmissionTask::new(message, None, &core.services, &core.config)).await.unwrap();
+    disp.get_test_report(sub.sid).await.unwrap();
+
+    let task = core.get_service_queue("extract").blocking_pop(Duration::from_secs(5), false).await.unwrap().unwrap();
+    let traceparent = task.trace_context.unwrap().get("traceparent").cloned().unwrap();
+    assert!(traceparent.starts_with(&format!("00-{TRACE_ID}-")), "{traceparent}");
+    assert!(task.metadata.contains_key(crate::telemetry::TRACE_CONTEXT_METADATA));
+}
+
 //MARK: simple
 #[tokio::test]
 async fn test_simple() {
@@ -679,7 +711,8 @@ async fn test_create_submission_task() {
         file_infos: file_infos.clone(),
         results: results.clone(),
         file_tree: file_tree.clone(),
-        errors: errors.clone() };
+        errors: errors.clone(),
+        trace_context: None };
 
 
     let task = SubmissionTask::new(dispatch_message, None, &core.services, &core.config);
@@ -757,3 +790,11 @@ async fn test_create_submission_task() {
 
 
 }
+
+//MARK: health
+#[tokio::test]
+async fn test_healthz() {
+    let (core, _guard) = setup().await;
+    let dispatcher = start_test_dispatcher(core.clone()).await.unwrap();
+    crate::health::test::check_healthz(&core, super::http::app(dispatcher)).await;
+}
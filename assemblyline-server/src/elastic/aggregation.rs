@@ -0,0 +1,476 @@
+//! Aggregation queries on a collection: facets, histograms, field stats and grouped searches.
+//!
+//! These are the datastore api the statistics views of the ui and api server are built on, the
+//! core daemons don't aggregate anything yet so nothing in this crate calls them outside of tests.
+#![allow(dead_code)]
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+
+use assemblyline_models::Readable;
+
+use super::collection::{build_query_expression, Collection, CollectionType, DEFAULT_ROW_SIZE, DEFAULT_SORT};
+use super::{parse_sort, responses, ElasticError, Index, Request, Result};
+
+/// Name given to the aggregation within the request, the response is read back using the same name
+const AGGREGATION_NAME: &str = "result";
+
+/// Largest number of buckets a histogram may be asked to produce
+pub const MAX_HISTOGRAM_STEPS: u64 = 1000;
+
+/// Parameters describing which documents an aggregation runs over
+struct Scope<'a> {
+    query: &'a str,
+    filters: Vec<String>,
+    access_control: Option<String>,
+    index_type: Index,
+    timeout: Option<Duration>,
+}
+
+impl Scope<'_> {
+    fn new() -> Self {
+        Scope {
+            query: "*",
+            filters: vec![],
+            access_control: None,
+            index_type: Index::Hot,
+            timeout: None,
+        }
+    }
+
+    fn query_expression(&self) -> serde_json::Value {
+        let mut filters = self.filters.clone();
+        if let Some(access_control) = &self.access_control {
+            filters.push(access_control.clone());
+        }
+        build_query_expression(self.query, filters)
+    }
+
+    fn params(&self, size: u64) -> Vec<(&'static str, Cow<'static, str>)> {
+        let mut params: Vec<(&str, Cow<str>)> = vec![("size", size.to_string().into())];
+        if let Some(timeout) = &self.timeout {
+            params.push(("timeout", format!("{}ms", timeout.as_millis()).into()));
+        }
+        params
+    }
+}
+
+/// Setters shared by every aggregation builder for narrowing down the documents considered
+macro_rules! scope_setters {
+    () => {
+        /// Lucene query selecting the documents to run over, defaults to all documents
+        pub fn query(mut self, query: &'a str) -> Self {
+            self.scope.query = query; self
+        }
+
+        /// Add a filter query to reduce the scope of the main query
+        pub fn filter(mut self, filter: &str) -> Self {
+            self.scope.filters.push(filter.to_owned()); self
+        }
+
+        /// Access control query limiting what documents the caller may see
+        pub fn access_control(mut self, access_control: Option<String>) -> Self {
+            self.scope.access_control = access_control; self
+        }
+
+        /// Indices to target, defaults to the hot index
+        pub fn index_type(mut self, index_type: Index) -> Self {
+            self.scope.index_type = index_type; self
+        }
+
+        /// Time limit for elasticsearch to complete the query
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.scope.timeout = Some(timeout); self
+        }
+    };
+}
+
+impl<T: CollectionType> Collection<T> {
+
+    /// Count the number of documents for each of the most common values of a field
+    pub fn facet<'a>(&'a self, field: &'a str) -> FacetBuilder<'a, T> {
+        FacetBuilder { collection: self, field, scope: Scope::new(), rows: 10, mincount: 1 }
+    }
+
+    /// Count the documents falling in evenly sized steps along a numeric field
+    pub fn histogram<'a>(&'a self, field: &'a str, start: f64, end: f64, gap: f64) -> HistogramBuilder<'a, T, f64> {
+        HistogramBuilder { collection: self, field, scope: Scope::new(), start, end, gap, mincount: 1 }
+    }
+
+    /// Count the documents falling in evenly sized time steps along a date field
+    pub fn date_histogram<'a>(&'a self, field: &'a str, start: DateTime<Utc>, end: DateTime<Utc>, gap: TimeDelta) -> HistogramBuilder<'a, T, DateTime<Utc>> {
+        HistogramBuilder { collection: self, field, scope: Scope::new(), start, end, gap, mincount: 1 }
+    }
+
+    /// Compute the count, min, max, average and sum of a numeric field
+    pub fn stats<'a>(&'a self, field: &'a str) -> StatsBuilder<'a, T> {
+        StatsBuilder { collection: self, field, scope: Scope::new() }
+    }
+
+    /// Search for documents, grouping the hits by the value of a field
+    pub fn grouped_search<'a>(&'a self, group_field: &'a str) -> GroupedSearchBuilder<'a, T> {
+        GroupedSearchBuilder {
+            collection: self,
+            group_field,
+            scope: Scope::new(),
+            offset: 0,
+            rows: DEFAULT_ROW_SIZE,
+            limit: 1,
+            sort: DEFAULT_SORT.to_owned(),
+            group_sort: None,
+            field_list: "*".to_owned(),
+        }
+    }
+
+    /// Run a search that only returns the named aggregation
+    async fn run_aggregation<Agg: DeserializeOwned>(&self, scope: &Scope<'_>, aggregation: serde_json::Value) -> Result<Agg> {
+        let index = self.get_joined_index(Some(scope.index_type))?;
+        let request = Request::get_search_on(&self.database.host, &index, scope.params(0))?;
+
+        let body = json!({
+            "query": scope.query_expression(),
+            "aggregations": {AGGREGATION_NAME: aggregation},
+        });
+        let response = self.database.make_request_json(&mut 0, &request, &body).await?;
+        let mut response: responses::AggregationSearch<Agg> = response.json().await?;
+        if response.timed_out {
+            warn!("Aggregation on {} timed out, results may be incomplete", self.name());
+        }
+        response.aggregations.remove(AGGREGATION_NAME)
+            .ok_or_else(|| ElasticError::fatal("Aggregation missing from search response"))
+    }
+}
+
+/// Build a term facet over a single field
+pub struct FacetBuilder<'a, T: CollectionType> {
+    collection: &'a Collection<T>,
+    field: &'a str,
+    scope: Scope<'a>,
+    rows: u64,
+    mincount: u64,
+}
+
+impl<'a, T: CollectionType> FacetBuilder<'a, T> {
+    scope_setters!();
+
+    /// Number of distinct values to return
+    pub fn rows(mut self, rows: u64) -> Self {
+        self.rows = rows; self
+    }
+
+    /// Minimum number of documents a value must appear in to be returned
+    pub fn mincount(mut self, mincount: u64) -> Self {
+        self.mincount = mincount; self
+    }
+
+    /// Return the number of documents for each value of the field
+    pub async fn execute(self) -> Result<HashMap<String, u64>> {
+        let aggregation = json!({"terms": {
+            "field": self.field,
+            "size": self.rows,
+            "min_doc_count": self.mincount,
+        }});
+
+        let result: responses::BucketAggregation = self.collection.run_aggregation(&self.scope, aggregation).await?;
+        Ok(result.buckets.into_iter().map(|bucket| (bucket.key_string(), bucket.doc_count)).collect())
+    }
+}
+
+/// A single step of a histogram
+#[derive(Debug, PartialEq)]
+pub struct HistogramBucket<Key> {
+    /// Start of the step
+    pub key: Key,
+    /// Number of documents in the step
+    pub count: u64,
+}
+
+/// Build a histogram over a numeric or date field
+pub struct HistogramBuilder<'a, T: CollectionType, Key: HistogramKey> {
+    collection: &'a Collection<T>,
+    field: &'a str,
+    scope: Scope<'a>,
+    start: Key,
+    end: Key,
+    gap: Key::Gap,
+    mincount: u64,
+}
+
+impl<'a, T: CollectionType, Key: HistogramKey> HistogramBuilder<'a, T, Key> {
+    scope_setters!();
+
+    /// Minimum number of documents a step must contain to be returned
+    pub fn mincount(mut self, mincount: u64) -> Self {
+        self.mincount = mincount; self
+    }
+
+    /// Return the number of documents in each step between start and end
+    pub async fn execute(self) -> Result<Vec<HistogramBucket<Key>>> {
+        let steps = Key::steps(&self.start, &self.end, &self.gap)
+            .ok_or_else(|| ElasticError::fatal("Histogram gap must be positive and end must come after start"))?;
+        if steps > MAX_HISTOGRAM_STEPS {
+            return Err(ElasticError::fatal(format!("Histogram would have {steps} steps, the maximum is {MAX_HISTOGRAM_STEPS}")))
+        }
+
+        // Only documents within the range are considered
+        let mut scope = self.scope;
+        scope.filters.push(format!("{}:[{} TO {}]", self.field, self.start.query_value(), self.end.query_value()));
+
+        let aggregation = json!({Key::AGGREGATION: {
+            "field": self.field,
+            Key::INTERVAL: Key::gap_value(&self.gap),
+            "min_doc_count": self.mincount,
+            "extended_bounds": {"min": self.start.bound(), "max": self.end.bound()},
+        }});
+
+        let result: responses::BucketAggregation = self.collection.run_aggregation(&scope, aggregation).await?;
+        let mut buckets = vec![];
+        for bucket in result.buckets {
+            let key = Key::from_bucket(&bucket.key)
+                .ok_or_else(|| ElasticError::fatal(format!("Unexpected histogram key: {}", bucket.key)))?;
+            buckets.push(HistogramBucket { key, count: bucket.doc_count });
+        }
+        Ok(buckets)
+    }
+}
+
+/// Types that can be used as the steps of a histogram
+pub trait HistogramKey: Sized {
+    /// Type describing the distance between steps
+    type Gap;
+    /// Elasticsearch aggregation used for this type
+    const AGGREGATION: &'static str;
+    /// Name of the aggregation parameter describing the gap
+    const INTERVAL: &'static str;
+
+    /// Number of steps between start and end, None if the range is invalid
+    fn steps(start: &Self, end: &Self, gap: &Self::Gap) -> Option<u64>;
+    /// Format the value for use in a lucene range query
+    fn query_value(&self) -> String;
+    /// Format the value as a histogram bound
+    fn bound(&self) -> serde_json::Value;
+    /// Format the gap for the aggregation request
+    fn gap_value(gap: &Self::Gap) -> serde_json::Value;
+    /// Read a key from a histogram bucket
+    fn from_bucket(key: &serde_json::Value) -> Option<Self>;
+}
+
+impl HistogramKey for f64 {
+    type Gap = f64;
+    const AGGREGATION: &'static str = "histogram";
+    const INTERVAL: &'static str = "interval";
+
+    fn steps(start: &Self, end: &Self, gap: &Self::Gap) -> Option<u64> {
+        if *gap <= 0.0 || end < start {
+            return None
+        }
+        Some(((end - start) / gap).ceil() as u64)
+    }
+
+    fn query_value(&self) -> String { self.to_string() }
+
+    fn bound(&self) -> serde_json::Value { json!(self) }
+
+    fn gap_value(gap: &Self::Gap) -> serde_json::Value { json!(gap) }
+
+    fn from_bucket(key: &serde_json::Value) -> Option<Self> { key.as_f64() }
+}
+
+impl HistogramKey for DateTime<Utc> {
+    type Gap = TimeDelta;
+    const AGGREGATION: &'static str = "date_histogram";
+    const INTERVAL: &'static str = "fixed_interval";
+
+    fn steps(start: &Self, end: &Self, gap: &Self::Gap) -> Option<u64> {
+        let gap = gap.num_milliseconds();
+        let range = (*end - *start).num_milliseconds();
+        if gap <= 0 || range < 0 {
+            return None
+        }
+        Some(((range + gap - 1) / gap) as u64)
+    }
+
+    fn query_value(&self) -> String {
+        format!("\"{}\"", self.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    }
+
+    fn bound(&self) -> serde_json::Value { json!(self.timestamp_millis()) }
+
+    fn gap_value(gap: &Self::Gap) -> serde_json::Value { json!(format!("{}ms", gap.num_milliseconds())) }
+
+    fn from_bucket(key: &serde_json::Value) -> Option<Self> {
+        DateTime::from_timestamp_millis(key.as_i64()?)
+    }
+}
+
+/// Summary statistics for a numeric field
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct FieldStats {
+    /// Number of documents with a value for the field
+    pub count: u64,
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub avg: Option<f64>,
+    pub sum: f64,
+}
+
+/// Build a stats aggregation over a single field
+pub struct StatsBuilder<'a, T: CollectionType> {
+    collection: &'a Collection<T>,
+    field: &'a str,
+    scope: Scope<'a>,
+}
+
+impl<'a, T: CollectionType> StatsBuilder<'a, T> {
+    scope_setters!();
+
+    /// Return the stats of the field, min/max/avg are None when no documents have a value
+    pub async fn execute(self) -> Result<FieldStats> {
+        let aggregation = json!({"stats": {"field": self.field}});
+        self.collection.run_aggregation(&self.scope, aggregation).await
+    }
+}
+
+/// Build a search where hits are grouped by a field
+pub struct GroupedSearchBuilder<'a, T: CollectionType> {
+    collection: &'a Collection<T>,
+    group_field: &'a str,
+    scope: Scope<'a>,
+    offset: u64,
+    rows: u64,
+    limit: u64,
+    sort: String,
+    group_sort: Option<String>,
+    field_list: String,
+}
+
+impl<'a, T: CollectionType> GroupedSearchBuilder<'a, T> {
+    scope_setters!();
+
+    /// Number of groups to skip
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset; self
+    }
+
+    /// Number of groups to return
+    pub fn rows(mut self, rows: u64) -> Self {
+        self.rows = rows; self
+    }
+
+    /// Number of documents to return within each group
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = limit; self
+    }
+
+    /// Order of the groups
+    pub fn sort(mut self, sort: &str) -> Self {
+        self.sort = sort.to_owned(); self
+    }
+
+    /// Order of the documents within each group, defaults to the group order
+    pub fn group_sort(mut self, sort: &str) -> Self {
+        self.group_sort = Some(sort.to_owned()); self
+    }
+
+    /// Fields of the documents to return
+    pub fn fields(mut self, fields: &str) -> Self {
+        self.field_list = fields.to_owned(); self
+    }
+
+    /// Run the search and return each group with its top documents
+    pub async fn execute<RT: Debug + DeserializeOwned + Readable>(self) -> Result<GroupedSearchResult<RT>> {
+        let source = if self.field_list.is_empty() || self.field_list == "*" {
+            json!(true)
+        } else {
+            json!(self.field_list.split(',').collect::<Vec<&str>>())
+        };
+        let sort = format_sort(&self.sort)?;
+        let group_sort = match &self.group_sort {
+            Some(group_sort) => format_sort(group_sort)?,
+            None => sort.clone(),
+        };
+
+        let body = json!({
+            "query": self.scope.query_expression(),
+            "collapse": {
+                "field": self.group_field,
+                "inner_hits": {
+                    "name": "group",
+                    "_source": source,
+                    "size": self.limit,
+                    "sort": group_sort,
+                },
+            },
+            "sort": sort,
+            "_source": source,
+            "from": self.offset,
+        });
+
+        let collection = self.collection;
+        let index = collection.get_joined_index(Some(self.scope.index_type))?;
+        let request = Request::get_search_on(&collection.database.host, &index, self.scope.params(self.rows))?;
+        let response = collection.database.make_request_json(&mut 0, &request, &body).await?;
+        let response: responses::GroupedSearch<RT> = response.json().await?;
+        if response.timed_out {
+            warn!("Grouped search on {} timed out, results may be incomplete", collection.name());
+        }
+
+        let mut items = vec![];
+        for hit in response.hits.hits {
+            let value = hit.fields.get(self.group_field).and_then(|values| values.first()).cloned().unwrap_or_default();
+            let (total, hits) = match hit.inner_hits.into_iter().next() {
+                Some((_, group)) => (group.hits.total.value, group.hits.hits),
+                None => (0, vec![]),
+            };
+
+            let mut group_items = vec![];
+            for row in hits {
+                if let Some(mut source) = row._source {
+                    source.set_from_archive(collection.is_archive_index(&row._index));
+                    group_items.push(source);
+                }
+            }
+            items.push(Group { value, total, items: group_items });
+        }
+
+        Ok(GroupedSearchResult {
+            offset: self.offset,
+            rows: self.rows,
+            total: response.hits.total.value,
+            items,
+        })
+    }
+}
+
+pub struct GroupedSearchResult<Source> {
+    pub offset: u64,
+    pub rows: u64,
+    /// Number of documents matching the query
+    pub total: u64,
+    pub items: Vec<Group<Source>>,
+}
+
+pub struct Group<Source> {
+    /// Value of the group field shared by the documents in this group
+    pub value: serde_json::Value,
+    /// Number of documents in the group
+    pub total: u64,
+    /// Top documents of the group
+    pub items: Vec<Source>,
+}
+
+fn format_sort(sort: &str) -> Result<serde_json::Value> {
+    let sort = parse_sort(sort)?;
+    Ok(sort.iter().map(|(name, direction)| json!({name: direction.to_string()})).collect())
+}
@@ -22,6 +22,7 @@ use crate::elastic::{responses, DEFAULT_SEARCH_FIELD, KEEP_ALIVE};
 
 use super::bulk::TypedBulkPlan;
 use super::pit::PitGuard;
+use super::task::TaskHandle;
 use super::responses::DeleteResult;
 use super::{parse_sort, CopyMethod, ElasticError, ElasticHelper, Index, Request, Result, SortDirection, Version};
 use super::error::{ElasticErrorInner, WithContext};
@@ -65,6 +66,11 @@ impl<T: CollectionType> Collection<T> {
         Ok(collection)
     }
 
+    /// Name of the hot index for this collection
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     // pub async fn _create_index(&self) -> Result<()> {
 
     /// This function should test if the collection that you are trying to access does indeed exist
@@ -781,22 +787,7 @@ impl<T: CollectionType> Collection<T> {
             filters.push(access_control);
         }
 
-        let mut formatted_filters = vec![];
-        for ff in filters {
-            formatted_filters.push(json!({"query_string": {"query": ff}}))
-        }
-
-        let query_expression = json!({
-            "bool": {
-                "must": {
-                    "query_string": {
-                        "query": query,
-                        "default_field": DEFAULT_SEARCH_FIELD
-                    }
-                },
-                "filter": formatted_filters
-            }
-        });
+        let query_expression = build_query_expression(query, filters);
 
         let sort = parse_sort(DEFAULT_SORT)?;
         let source = if fl.is_empty() || fl == "*" {
@@ -887,6 +878,99 @@ impl<T: CollectionType> Collection<T> {
         return Ok(deleted)
     }
 
+    /// Delete all the documents matching a query.
+    ///
+    /// The deletion runs as a task within elasticsearch, the returned handle can be used to
+    /// check on it or wait for it to finish.
+    ///
+    /// :param query: lucene query selecting the documents to delete
+    /// :param filters: additional queries to run on the original query to reduce the scope
+    /// :param max_docs: Maximum number of documents to delete
+    /// :param index_type: Type of indices to target
+    /// :return: Handle on the deletion task
+    #[instrument]
+    pub async fn delete_by_query(&self, query: &str, filters: Vec<String>, max_docs: Option<u64>, index_type: Option<Index>) -> Result<TaskHandle> {
+        let index = self.get_joined_index(index_type)?;
+        let request = Request::delete_by_query(&self.database.host, &index, false, "proceed", max_docs)?;
+        let task: responses::TaskId = self.make_request_json(&request, &json!({
+            "query": build_query_expression(query, filters),
+        })).await?.json().await?;
+
+        Ok(TaskHandle::new(self.database.clone(), task.task))
+    }
+
+    /// Apply a set of update operations to all the documents matching a query.
+    ///
+    /// :param query: lucene query selecting the documents to update
+    /// :param operations: the operations to apply to each document
+    /// :param filters: additional queries to run on the original query to reduce the scope
+    /// :param max_docs: Maximum number of documents to update
+    /// :param index_type: Type of indices to target
+    /// :return: Handle on the update task
+    #[instrument(skip(operations))]
+    pub async fn update_by_query(&self, query: &str, mut operations: OperationBatch, filters: Vec<String>, max_docs: Option<u64>, index_type: Option<Index>) -> Result<TaskHandle> {
+        operations.validate_operations::<T>()?;
+        let index = self.get_joined_index(index_type)?;
+        let request = Request::update_by_query(&self.database.host, &index, false, "proceed", max_docs)?;
+        let task: responses::TaskId = self.make_request_json(&request, &json!({
+            "query": build_query_expression(query, filters),
+            "script": operations.to_script(),
+        })).await?.json().await?;
+
+        Ok(TaskHandle::new(self.database.clone(), task.task))
+    }
+
+    /// Count the documents matching a query without fetching any of them.
+    ///
+    /// :param query: lucene query to search for
+    /// :param filters: additional queries to run on the original query to reduce the scope
+    /// :param access_control: access control parameters to run the query with
+    /// :param index_type: Type of indices to target
+    /// :return: Number of matching documents
+    #[instrument]
+    pub async fn count(&self, query: &str, mut filters: Vec<String>, access_control: Option<String>, index_type: Option<Index>) -> Result<u64> {
+        if let Some(access_control) = access_control {
+            filters.push(access_control);
+        }
+
+        let index = self.get_joined_index(index_type)?;
+        let response: responses::Count = self.make_request_json(&Request::count(&self.database.host, &index)?, &json!({
+            "query": build_query_expression(query, filters),
+        })).await?.json().await?;
+        Ok(response.count)
+    }
+
+    /// Copy a document from the hot index into the archive.
+    ///
+    /// The archived copy is stamped with an archive_ts and has its expiry removed. The hot copy
+    /// is either stamped with the same archive_ts or deleted when `delete_after` is set.
+    /// Documents already moved to the archive are left as they are, so this can safely be repeated.
+    ///
+    /// :param key: id of the document to archive
+    /// :param delete_after: Remove the document from the hot index once it is archived
+    /// :return: True if the document is now in the archive
+    #[instrument]
+    pub async fn archive(&self, key: &str, delete_after: bool) -> Result<bool> {
+        let (mut data, _) = match self._get_if_exists::<JsonMap>(key, Some(Index::Hot)).await? {
+            Some(data) => data,
+            None => return self.exists(key, Some(Index::Archive)).await,
+        };
+
+        let archive_ts = json!(chrono::Utc::now());
+        data.insert("archive_ts".to_owned(), archive_ts.clone());
+        data.insert("expiry_ts".to_owned(), serde_json::Value::Null);
+        self.save_json(key, &mut data, None, Some(Index::Archive)).await?;
+
+        if delete_after {
+            self.delete(key, Some(Index::Hot)).await?;
+        } else {
+            let mut batch = OperationBatch::default();
+            batch.set("archive_ts".to_owned(), archive_ts);
+            self.update(key, batch, Some(Index::Hot), None).await?;
+        }
+        Ok(true)
+    }
+
     /// Creates a BulkPlan tailored for the current datastore
     ///
     /// :param index_type: Type of indices to target
@@ -965,6 +1049,14 @@ impl OperationBatch {
         self.operations.push((UpdateOperation::Max, field, value))
     }
 
+    pub fn append(&mut self, field: String, value: serde_json::Value) {
+        self.operations.push((UpdateOperation::Append, field, value))
+    }
+
+    pub fn append_if_missing(&mut self, field: String, value: serde_json::Value) {
+        self.operations.push((UpdateOperation::AppendIfMissing, field, value))
+    }
+
     // Validate the different operations received for a partial update
     //
     // TODO: When the field is of type Mapping, the validation/check only works for depth 1. A full recursive
@@ -1183,6 +1275,25 @@ fn describe_value(value: &serde_json::Value) -> &'static str {
     }
 }
 
+/// Build the query section of a search body from a lucene query and a set of filters
+pub (super) fn build_query_expression(query: &str, filters: Vec<String>) -> serde_json::Value {
+    let filters: Vec<serde_json::Value> = filters.into_iter()
+        .map(|filter| json!({"query_string": {"query": filter}}))
+        .collect();
+
+    json!({
+        "bool": {
+            "must": {
+                "query_string": {
+                    "query": query,
+                    "default_field": DEFAULT_SEARCH_FIELD
+                }
+            },
+            "filter": filters
+        }
+    })
+}
+
 pub fn check_type(kind: &struct_metadata::Kind<ElasticMeta>, value: &mut serde_json::Value) -> Result<(), CheckError> {
     match kind {
         struct_metadata::Kind::Struct { name, children } => {
@@ -12,12 +12,14 @@ use log::{debug, error, warn};
 use tracing::instrument;
 
 pub mod responses;
+pub mod aggregation;
 pub mod collection;
 pub mod error;
 pub mod search;
 pub mod bulk;
 pub mod pit;
 pub mod request;
+pub mod task;
 
 #[cfg(test)]
 mod test_datastore;
@@ -30,7 +32,7 @@ use assemblyline_markings::classification::ClassificationParser;
 use assemblyline_models::datastore::filescore::FileScore;
 use assemblyline_models::datastore::user::User;
 use assemblyline_models::types::{ExpandingClassification, JsonMap, ServiceName, Sha256};
-use assemblyline_models::datastore::{EmptyResult, Error as ErrorModel, Result as ResultModel, File, Service, ServiceDelta, Submission};
+use assemblyline_models::datastore::{Alert, CachedFile, EmptyResult, Error as ErrorModel, Result as ResultModel, File, Retrohunt, RetrohuntHit, Service, ServiceDelta, Submission, Workflow};
 use chrono::{DateTime, TimeDelta, Utc};
 use collection::{Collection, OperationBatch};
 use error::{ElasticErrorInner, WithContext};
@@ -42,6 +44,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use self::error::{ElasticError, Result};
 use self::request::Request;
+use self::task::TaskHandle;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Index {
@@ -447,7 +450,7 @@ impl std::fmt::Debug for ElasticHelper {
 }
 
 impl ElasticHelper {
-    async fn connect(url: &str, archive_access: bool, ca_cert: Option<&[u8]>, connect_unsafe: bool) -> Result<Self> {
+    pub async fn connect(url: &str, archive_access: bool, ca_cert: Option<&[u8]>, connect_unsafe: bool) -> Result<Self> {
         let host: url::Url = url.parse()?;
         let mut builder = reqwest::Client::builder()
             .timeout(get_transport_timeout());
@@ -479,6 +482,14 @@ impl ElasticHelper {
         }
     }
 
+    fn change_archive_access(&self, archive_access: bool) -> Self {
+        ElasticHelper{
+            client: self.client.clone(),
+            host: self.host.clone(),
+            archive_access,
+        }
+    }
+
 
     // async fn connection_reset(&self) -> Result<()> {
     //     *self.es.write().await = Self::_create_connection(self.host.clone())?;
@@ -792,6 +803,15 @@ impl ElasticHelper {
         }     
     }
 
+    /// Add a document with a generated id to an index, the index is created if it doesn't exist
+    #[instrument(skip(body))]
+    pub async fn index_document<R: Serialize>(&self, index: &str, body: &R) -> Result<()> {
+        let url = self.host.join(&format!("{index}/_doc"))?;
+        let request = Request::new(Method::POST, url, Some(index.to_owned()));
+        self.make_request_json(&mut 0, &request, body).await?;
+        Ok(())
+    }
+
     /// checking if an index of the given name exists
     #[instrument]
     pub async fn does_index_exist(&self, name: &str) -> Result<bool> {
@@ -982,6 +1002,7 @@ pub struct Elastic {
     es: Arc<ElasticHelper>,
     prefix: String,
 
+    pub alert: Collection<Alert>,
     pub apikey: Collection<Apikey>,
     pub file: Collection<File>,
     pub submission: Collection<Submission>,
@@ -990,6 +1011,12 @@ pub struct Elastic {
     pub safelist: Collection<Safelist>,
     pub badlist: Collection<Badlist>,
     pub heuristic: Collection<Heuristic>,
+    pub workflow: Collection<Workflow>,
+    pub retrohunt: Collection<Retrohunt>,
+    pub retrohunt_hit: Collection<RetrohuntHit>,
+
+    /// Files stored in the cache filestore by components through the CacheStore
+    pub cached_file: Collection<CachedFile>,
 
     pub result: Collection<assemblyline_models::datastore::result::Result>,
     pub emptyresult: Collection<EmptyResult>,
@@ -1026,10 +1053,11 @@ impl Elastic {
 
         Ok(Arc::new(Self {
             es: helper.clone(),
+            alert: collection!("alert"),
             apikey: collection!("apikey"),
             file: collection!(archive, "file"),
             submission: collection!(archive, "submission"),
-            error: collection!("error"),
+            error: collection!(archive, "error"),
             safelist: collection!("safelist"),
             badlist: collection!("badlist"),
             result: collection!(archive, "result"),
@@ -1039,6 +1067,10 @@ impl Elastic {
             service_delta: collection!("service_delta"),
             user: collection!("user"),
             filescore: collection!("filescore"),
+            workflow: collection!("workflow"),
+            retrohunt: collection!("retrohunt"),
+            retrohunt_hit: collection!("retrohunt_hit"),
+            cached_file: collection!("cached_file"),
             prefix: prefix.to_string(),
         }))
     }
@@ -1105,6 +1137,12 @@ impl Elastic {
         Self::setup(helper, &self.prefix).await
     }
 
+    /// Get a view of the same datastore where the archive indices are accessible
+    pub async fn with_archive_access(&self) -> Result<Arc<Elastic>> {
+        let helper = Arc::new(self.es.change_archive_access(true));
+        Self::setup(helper, &self.prefix).await
+    }
+
     pub async fn task_cleanup(&self, deleteable_task_age: Option<chrono::TimeDelta>, max_tasks: Option<u64>) -> Result<u64> {
         let deleteable_task_age = deleteable_task_age.unwrap_or(chrono::TimeDelta::zero());
 
@@ -1119,10 +1157,10 @@ impl Elastic {
         })).await?.json().await?;
 
         // Wait until the tasks deletion task is over
-        let res = self.es.get_task_results(&task.task).await?;
+        let res = TaskHandle::new(self.es.clone(), task.task).wait().await?;
 
         // return the number of deleted items
-        return Ok(res._status.deleted)
+        return Ok(res.deleted)
     }
 
     // pub async fn update_service_delta(&self, name: &str, delta: &JsonMap) -> Result<()> {
@@ -91,6 +91,23 @@ impl Request {
         Ok(Self::new(Method::POST, url, Some(name.to_owned())))
     }
     
+    pub fn update_by_query(host: &reqwest::Url, name: &str, wait_for_completion: bool, conflicts: &str, max_docs: Option<u64>) -> Result<Self> {
+        let mut url = host.join(&format!("/{name}/_update_by_query"))?;
+
+        url.query_pairs_mut()
+            .append_pair("wait_for_completion", &wait_for_completion.to_string().to_lowercase())
+            .append_pair("conflicts", conflicts);
+        if let Some(max_docs) = max_docs {
+            url.query_pairs_mut().append_pair("max_docs", &max_docs.to_string());
+        }
+
+        Ok(Self::new(Method::POST, url, Some(name.to_owned())))
+    }
+
+    pub fn count(host: &reqwest::Url, index: &str) -> Result<Self> {
+        Ok(Self::new(Method::POST, host.join(&format!("{index}/_count"))?, Some(index.to_owned())))
+    }
+
     pub fn post_user(host: &reqwest::Url, name: &str) -> Result<Self> {
         Ok(Self::new(Method::POST, host.join(&format!("_security/user/{name}"))?, None))
     }
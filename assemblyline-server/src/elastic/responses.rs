@@ -234,6 +234,73 @@ pub struct SearchHitItem<FieldType, SourceType> {
     pub fields: FieldType,
 }
 
+/// Response to a search that only requested aggregations
+#[derive(Debug, Deserialize)]
+pub struct AggregationSearch<Aggregation> {
+    /// flag indicating the search timed out rather than completed
+    pub timed_out: bool,
+    /// aggregation results keyed by the name given in the request
+    pub aggregations: HashMap<String, Aggregation>,
+}
+
+/// Result of a bucketing aggregation such as terms or histogram
+#[derive(Debug, Deserialize)]
+pub struct BucketAggregation {
+    pub buckets: Vec<AggregationBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AggregationBucket {
+    /// value the bucket was built for
+    pub key: Value,
+    /// formatted version of the key for keys that are not strings (dates, booleans)
+    pub key_as_string: Option<String>,
+    /// number of documents in the bucket
+    pub doc_count: u64,
+}
+
+impl AggregationBucket {
+    /// Key of the bucket as a string, preferring the formatted version given by elasticsearch
+    pub fn key_string(&self) -> String {
+        match (&self.key_as_string, &self.key) {
+            (Some(key), _) => key.clone(),
+            (None, Value::String(key)) => key.clone(),
+            (None, key) => key.to_string(),
+        }
+    }
+}
+
+/// Response to a search collapsed on a field
+#[derive(Debug, Deserialize)]
+pub struct GroupedSearch<SourceType: Debug> {
+    /// flag indicating the search timed out rather than completed
+    pub timed_out: bool,
+    pub hits: GroupedSearchHits<SourceType>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupedSearchHits<SourceType> {
+    /// number of documents matching the search
+    pub total: SearchHitTotals,
+    /// one entry for each group
+    pub hits: Vec<GroupedSearchHitItem<SourceType>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupedSearchHitItem<SourceType> {
+    /// collapsed field values of the group
+    #[serde(default)]
+    pub fields: HashMap<String, Vec<Value>>,
+    /// documents within the group
+    #[serde(default = "HashMap::new")]
+    pub inner_hits: HashMap<String, InnerHits<SourceType>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InnerHits<SourceType> {
+    pub hits: SearchHits<(), SourceType>,
+}
+
 /// helper function to handle empty source response when SourceType is not Default
 fn default_source<T>() -> Option<T> { None }
 
@@ -329,6 +396,16 @@ pub struct TaskId {
     pub task: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Count {
+    pub count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TaskProgress {
+    pub completed: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TaskBody {
     pub response: TaskResponse, 
@@ -161,9 +161,11 @@ impl<'a, T: Serialize + Readable + Described<ElasticMeta> + Debug> SearchBuilder
         // }
 
         // Load any result fields
+        let mut keys = vec![];
         let mut field_items = vec![];
         let mut source_items = vec![];
         while let Some(row) = response.hits.hits.pop() {
+            keys.push(row._id);
             if let Some(mut source) = row._source {
                 source.set_from_archive(self.collection.is_archive_index(&row._index));
                 source_items.push(source);
@@ -176,6 +178,7 @@ impl<'a, T: Serialize + Readable + Described<ElasticMeta> + Debug> SearchBuilder
             offset: self.offset.unwrap_or_default(),
             rows: self.rows,
             total: response.hits.total.value,
+            keys,
             field_items,
             source_items
         })
@@ -187,6 +190,7 @@ pub struct SearchResult<Field, Source> {
     pub offset: u64,
     pub rows: u64,
     pub total: u64,
+    pub keys: Vec<String>,
     pub field_items: Vec<Field>,
     pub source_items: Vec<Source>
 }
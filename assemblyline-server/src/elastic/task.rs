@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use super::{responses, ElasticHelper, Request, Result};
+
+/// Handle on an asynchronous task running in elasticsearch, like a delete or update by query.
+pub struct TaskHandle {
+    helper: Arc<ElasticHelper>,
+    id: String,
+}
+
+impl TaskHandle {
+    pub (super) fn new(helper: Arc<ElasticHelper>, id: String) -> Self {
+        Self { helper, id }
+    }
+
+    /// Task id assigned by elasticsearch
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Check if the task has finished without waiting on it
+    pub async fn is_completed(&self) -> Result<bool> {
+        let request = Request::get_task(&self.helper.host, &self.id, false, "5s")?;
+        let body: responses::TaskProgress = self.helper.make_request(&mut 0, &request).await?.json().await?;
+        Ok(body.completed)
+    }
+
+    /// Wait for the task to finish and return its final status
+    pub async fn wait(self) -> Result<responses::TaskStatus> {
+        Ok(self.helper.get_task_results(&self.id).await?._status)
+    }
+}
@@ -2,11 +2,13 @@ use std::sync::Arc;
 
 use assemblyline_markings::classification::ClassificationParser;
 use assemblyline_markings::config::ready_classification;
-use assemblyline_models::datastore::{File, Service};
-use assemblyline_models::types::{ServiceName, Sha256};
+use assemblyline_models::datastore::workflow::Priorities;
+use assemblyline_models::datastore::{CachedFile, File, Retrohunt, RetrohuntHit, Service, Workflow};
+use assemblyline_models::types::{ExpandingClassification, ServiceName, Sha256};
 use log::debug;
 use rand::Rng;
 
+use crate::elastic::collection::OperationBatch;
 use crate::elastic::create_empty_result_from_key;
 
 use super::Elastic;
@@ -150,6 +152,189 @@ async fn test_save_or_freshen_file() {
     assert_eq!(freshened_file.classification, ce.unrestricted());
 }
 
+#[tokio::test]
+async fn test_query_operations() {
+    let ds = init().await;
+
+    // Save a handful of files, half of them marked for deletion
+    let mut keys = vec![];
+    for index in 0..6u64 {
+        let data: [u8; 32] = rand::rng().random();
+        let mut file = File::gen_for_sample(&data, &mut rand::rng());
+        file.seen.count = if index % 2 == 0 { 1 } else { 100 };
+        ds.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+        keys.push(file.sha256.to_string());
+    }
+    ds.file.commit(None).await.unwrap();
+
+    assert_eq!(ds.file.count("*", vec![], None, None).await.unwrap(), 6);
+    assert_eq!(ds.file.count("seen.count:1", vec![], None, None).await.unwrap(), 3);
+    assert_eq!(ds.file.count("*", vec!["seen.count:100".to_owned()], Some(format!("sha256:{}", keys[1])), None).await.unwrap(), 1);
+
+    // Update everything matched by a query
+    let mut operations = OperationBatch::default();
+    operations.increment("seen.count".to_owned(), serde_json::json!(10));
+    let status = ds.file.update_by_query("seen.count:1", operations, vec![], None, None).await.unwrap().wait().await.unwrap();
+    assert_eq!(status.updated, 3);
+    ds.file.commit(None).await.unwrap();
+    assert_eq!(ds.file.get(&keys[0], None).await.unwrap().unwrap().seen.count, 11);
+    assert_eq!(ds.file.count("seen.count:11", vec![], None, None).await.unwrap(), 3);
+
+    // Delete with a filter and a limit on the number of documents
+    let task = ds.file.delete_by_query("seen.count:11", vec![format!("NOT sha256:{}", keys[0])], Some(1), None).await.unwrap();
+    assert!(!task.id().is_empty());
+    assert_eq!(task.wait().await.unwrap().deleted, 1);
+    ds.file.commit(None).await.unwrap();
+    assert_eq!(ds.file.count("*", vec![], None, None).await.unwrap(), 5);
+
+    let task = ds.file.delete_by_query("seen.count:11", vec![], None, None).await.unwrap();
+    while !task.is_completed().await.unwrap() {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(task.wait().await.unwrap().deleted, 2);
+    ds.file.commit(None).await.unwrap();
+    assert_eq!(ds.file.count("*", vec![], None, None).await.unwrap(), 3);
+    assert!(!ds.file.exists(&keys[0], None).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_aggregations() {
+    let ds = init().await;
+    let now = chrono::Utc::now();
+
+    // Save files of two types with known sizes and times
+    let mut keys = vec![];
+    for (index, (file_type, size)) in [("text/plain", 10), ("text/plain", 20), ("text/plain", 150), ("image/png", 250)].into_iter().enumerate() {
+        let data: [u8; 32] = rand::rng().random();
+        let mut file = File::gen_for_sample(&data, &mut rand::rng());
+        file.file_type = file_type.to_owned();
+        file.size = size;
+        file.seen.last = now - chrono::TimeDelta::days(index as i64);
+        ds.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+        keys.push(file.sha256.to_string());
+    }
+    ds.file.commit(None).await.unwrap();
+
+    // Term facets
+    let facet = ds.file.facet("type").execute().await.unwrap();
+    assert_eq!(facet, [("text/plain".to_owned(), 3), ("image/png".to_owned(), 1)].into_iter().collect());
+    let facet = ds.file.facet("type").mincount(2).execute().await.unwrap();
+    assert_eq!(facet.len(), 1);
+    let facet = ds.file.facet("type").filter("size:[100 TO *]").execute().await.unwrap();
+    assert_eq!(facet.get("text/plain"), Some(&1));
+    let facet = ds.file.facet("type").access_control(Some(format!("sha256:{}", keys[3]))).execute().await.unwrap();
+    assert_eq!(facet, [("image/png".to_owned(), 1)].into_iter().collect());
+
+    // Numeric histogram, empty steps are included when mincount is zero
+    let histogram = ds.file.histogram("size", 0.0, 300.0, 100.0).mincount(0).execute().await.unwrap();
+    let counts: Vec<(f64, u64)> = histogram.iter().map(|bucket| (bucket.key, bucket.count)).collect();
+    assert_eq!(counts, vec![(0.0, 2), (100.0, 1), (200.0, 1), (300.0, 0)]);
+    let histogram = ds.file.histogram("size", 0.0, 300.0, 100.0).query("type:\"text/plain\"").execute().await.unwrap();
+    assert_eq!(histogram.len(), 2);
+    assert!(ds.file.histogram("size", 0.0, 1.0, 0.0).execute().await.is_err());
+    assert!(ds.file.histogram("size", 0.0, 1e9, 1.0).execute().await.is_err());
+
+    // Date histogram over the last week
+    let histogram = ds.file.date_histogram("seen.last", now - chrono::TimeDelta::days(7), now, chrono::TimeDelta::days(1)).execute().await.unwrap();
+    assert_eq!(histogram.iter().map(|bucket| bucket.count).sum::<u64>(), 4);
+    assert!(histogram.iter().all(|bucket| bucket.count > 0));
+
+    // Field stats
+    let stats = ds.file.stats("size").execute().await.unwrap();
+    assert_eq!(stats.count, 4);
+    assert_eq!(stats.min, Some(10.0));
+    assert_eq!(stats.max, Some(250.0));
+    assert_eq!(stats.sum, 430.0);
+    let stats = ds.file.stats("size").query("type:missing").execute().await.unwrap();
+    assert_eq!(stats.count, 0);
+    assert_eq!(stats.avg, None);
+
+    // Grouped search
+    let grouped = ds.file.grouped_search("type").limit(5).execute::<File>().await.unwrap();
+    assert_eq!(grouped.total, 4);
+    assert_eq!(grouped.items.len(), 2);
+    assert_eq!(grouped.items.iter().map(|group| group.total).sum::<u64>(), 4);
+    for group in &grouped.items {
+        assert_eq!(group.items.len() as u64, group.total);
+        assert!(group.items.iter().all(|file| serde_json::json!(file.file_type) == group.value));
+    }
+
+    let grouped = ds.file.grouped_search("type").sort("type desc").rows(1).offset(1).execute::<File>().await.unwrap();
+    assert_eq!(grouped.offset, 1);
+    assert_eq!(grouped.items.len(), 1);
+    assert_eq!(grouped.items[0].value, serde_json::json!("image/png"));
+    assert_eq!(grouped.items[0].items.len(), 1);
+}
+
+#[tokio::test]
+async fn test_workflow_retrohunt_cached_file_collections() {
+    let ds = init().await;
+    let ce = Arc::new(ClassificationParser::new(assemblyline_markings::classification::sample_config()).unwrap());
+    assemblyline_models::types::classification::set_global_classification(ce.clone());
+    let now = chrono::Utc::now();
+
+    // every collection has an index created for it on connect
+    let indices = ds.list_indices().await.unwrap();
+    for name in ["workflow", "retrohunt", "retrohunt_hit", "cached_file"] {
+        assert!(indices.iter().any(|index| index.ends_with(&format!("{name}_hot"))), "{name} missing from {indices:?}");
+    }
+
+    let workflow: Workflow = serde_json::from_value(serde_json::json!({
+        "classification": ce.unrestricted(),
+        "creation_date": now,
+        "creator": "admin",
+        "edited_by": "admin",
+        "labels": ["phishing"],
+        "last_edit": now,
+        "name": "Label phishing",
+        "priority": "HIGH",
+        "query": "al.score:>=1000",
+        "workflow_id": "7pKYFBnGcHgWQbOS0ZOxMl",
+    })).unwrap();
+    ds.workflow.save("7pKYFBnGcHgWQbOS0ZOxMl", &workflow, None, None).await.unwrap();
+    let saved = ds.workflow.get("7pKYFBnGcHgWQbOS0ZOxMl", None).await.unwrap().unwrap();
+    assert_eq!(saved.labels, vec!["phishing".to_owned()]);
+    assert_eq!(saved.priority, Some(Priorities::High));
+    assert!(saved.enabled);
+
+    let retrohunt: Retrohunt = serde_json::from_value(serde_json::json!({
+        "indices": "hot",
+        "classification": ce.unrestricted(),
+        "search_classification": ce.unrestricted(),
+        "creator": "admin",
+        "description": "find things",
+        "start_group": 1,
+        "end_group": 2,
+        "created_time": now,
+        "started_time": now,
+        "key": "search-key",
+        "raw_query": "{}",
+        "yara_signature": "rule a { condition: true }",
+        "errors": [],
+        "warnings": [],
+        "finished": false,
+        "truncated": false,
+    })).unwrap();
+    ds.retrohunt.save(&retrohunt.key, &retrohunt, None, None).await.unwrap();
+    assert_eq!(ds.retrohunt.get("search-key", None).await.unwrap().unwrap().creator, "admin");
+
+    let hit = RetrohuntHit {
+        key: "search-key_hit".to_owned(),
+        classification: ExpandingClassification::new(ce.unrestricted().to_owned(), &ce).unwrap(),
+        sha256: "a123".repeat(16).parse().unwrap(),
+        expiry_ts: None,
+        search: "search-key".to_owned(),
+    };
+    ds.retrohunt_hit.save(&hit.key, &hit, None, None).await.unwrap();
+    assert_eq!(ds.retrohunt_hit.get(&hit.key, None).await.unwrap().unwrap(), hit);
+
+    let cached = CachedFile { component: "test".to_owned(), expiry_ts: now };
+    ds.cached_file.save("test_item", &cached, None, None).await.unwrap();
+    ds.cached_file.commit(None).await.unwrap();
+    assert_eq!(ds.cached_file.get("test_item", None).await.unwrap().unwrap().component, "test");
+    assert_eq!(ds.cached_file.count("component:test", vec![], None, None).await.unwrap(), 1);
+}
+
 // import hashlib
 // from assemblyline.common.isotime import now_as_iso
 // from assemblyline.odm.models.file import File
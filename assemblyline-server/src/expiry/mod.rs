@@ -0,0 +1,292 @@
+//! The expiry daemon removes documents from the hot indices once their expiry_ts has passed.
+//!
+//! Each collection with an expiry_ts field is cleaned with a delete-by-query. Expired files also
+//! have their blobs removed from the filestore, as do supplementary files of expired results that
+//! no longer have a file document keeping them alive. Expired cached_file documents take their
+//! blob in the cache filestore with them. In dry-run mode nothing is deleted, the
+//! number of documents that would have been removed is only logged.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use assemblyline_filestore::FileStore;
+use assemblyline_models::messages::expiry_heartbeat::Metrics;
+use assemblyline_models::Readable;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use redis_objects::{increment, AutoExportingMetrics};
+use serde::Deserialize;
+
+use crate::constants::METRICS_CHANNEL;
+use crate::elastic::collection::{Collection, CollectionType};
+use crate::elastic::task::TaskHandle;
+use crate::elastic::Index;
+use crate::Core;
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+
+pub async fn main(core: Core, dry_run: bool) -> Result<()> {
+    let expiry = Expiry::new(core, dry_run).await?;
+    expiry.core.running.install_terminate_handler(false)?;
+
+    if dry_run {
+        info!("Expiry running in dry-run mode, nothing will be deleted");
+    }
+
+    while expiry.core.is_running() {
+        if let Err(err) = expiry.run_expiry_once().await {
+            error!("Error in expiry: {err:?}");
+            expiry.core.sleep(ERROR_BACKOFF).await;
+            continue
+        }
+        expiry.core.sleep(Duration::from_secs(expiry.core.config.core.expiry.sleep_time as u64)).await;
+    }
+    info!("Expiry stopped");
+    Ok(())
+}
+
+/// The fields of a result needed to find its supplementary files
+#[derive(Deserialize, Debug)]
+struct PartialResult {
+    #[serde(default)]
+    response: PartialResponse,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PartialResponse {
+    #[serde(default)]
+    supplementary: Vec<PartialFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PartialFile {
+    sha256: String,
+}
+
+impl Readable for PartialResult {
+    fn set_from_archive(&mut self, _from_archive: bool) { }
+}
+
+impl Readable for PartialFile {
+    fn set_from_archive(&mut self, _from_archive: bool) { }
+}
+
+pub struct Expiry {
+    core: Core,
+    dry_run: bool,
+    cache: Arc<FileStore>,
+    counter: AutoExportingMetrics<Metrics>,
+}
+
+impl Expiry {
+    pub async fn new(core: Core, dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            dry_run,
+            cache: FileStore::open(&core.config.filestore.cache).await.context("initializing cache filestore")?,
+            counter: core.redis_metrics.auto_exporting_metrics(METRICS_CHANNEL.to_owned(), "expiry".to_owned())
+                .counter_name("expiry".to_owned())
+                .export_interval(Duration::from_secs(core.config.core.metrics.export_interval as u64))
+                .start(),
+            core,
+        })
+    }
+
+    /// Everything with an expiry_ts before this date should be removed
+    fn final_date(&self) -> DateTime<Utc> {
+        let config = &self.core.config.core.expiry;
+        let final_date = Utc::now() - TimeDelta::hours(config.delay as i64);
+        if config.batch_delete {
+            // Round down to the start of the day so deletes are grouped together
+            final_date.date_naive().and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+        } else {
+            final_date
+        }
+    }
+
+    /// Run through every expirable collection once
+    pub async fn run_expiry_once(&self) -> Result<()> {
+        let final_date = self.final_date().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let query = format!("expiry_ts:[* TO \"{final_date}\"]");
+        let datastore = &self.core.datastore;
+
+        // Results go first so their supplementary files can be checked against the remaining file documents
+        let deleted = self.expire_results(&query).await?;
+        increment!(self.counter, result, deleted as u32);
+        let deleted = self.expire_files(&query).await?;
+        increment!(self.counter, file, deleted as u32);
+        let deleted = self.expire_cached_files(&query).await?;
+        increment!(self.counter, cached_file, deleted as u32);
+
+        macro_rules! expire {
+            ($($name:ident),*) => {$(
+                if !self.core.is_running() {
+                    return Ok(())
+                }
+                let deleted = self.expire_collection(&datastore.$name, &query).await?;
+                increment!(self.counter, $name, deleted as u32);
+            )*}
+        }
+
+        expire!(alert, badlist, emptyresult, error, filescore, retrohunt_hit, safelist, submission);
+        Ok(())
+    }
+
+    /// Remove the expired documents of a collection that has no data outside of elasticsearch
+    async fn expire_collection<T: CollectionType>(&self, collection: &Collection<T>, query: &str) -> Result<u64> {
+        if self.dry_run {
+            let count = collection.count(query, vec![], None, Some(Index::Hot)).await?;
+            info!("[dry-run] {count} documents would be removed from {}", collection.name());
+            return Ok(0)
+        }
+
+        let task = collection.delete_by_query(query, vec![], None, Some(Index::Hot)).await?;
+        let deleted = self.wait_for_delete(task).await?;
+        if deleted > 0 {
+            info!("Removed {deleted} expired documents from {}", collection.name());
+        }
+        Ok(deleted)
+    }
+
+    /// Remove expired file documents along with their blobs in the filestore
+    async fn expire_files(&self, query: &str) -> Result<u64> {
+        let collection = &self.core.datastore.file;
+        if !self.core.config.core.expiry.delete_storage {
+            return self.expire_collection(collection, query).await
+        }
+
+        let batch_size = self.core.config.core.expiry.delete_batch_size.max(1) as usize;
+        let mut cursor = collection.stream_search::<PartialFile>(query, "sha256".to_owned(), vec![], None, Some(1000), Some(Index::Hot)).await?;
+
+        let mut deleted = 0;
+        let mut batch = vec![];
+        loop {
+            let item = cursor.next().await?;
+            if let Some(file) = item {
+                batch.push(file.sha256);
+                if batch.len() < batch_size {
+                    continue
+                }
+            }
+
+            if batch.is_empty() {
+                break
+            }
+
+            if self.dry_run {
+                info!("[dry-run] {} files and their blobs would be removed", batch.len());
+            } else {
+                // Remove the blobs first, a file document without a blob will be cleaned up on the next run
+                self.delete_blobs(&self.core.filestore, &batch).await;
+                let sha256s = batch.join(" OR ");
+                let task = collection.delete_by_query(&format!("sha256:({sha256s})"), vec![], None, Some(Index::Hot)).await?;
+                deleted += self.wait_for_delete(task).await?;
+            }
+            batch.clear();
+        }
+
+        if deleted > 0 {
+            info!("Removed {deleted} expired files from {}", collection.name());
+        }
+        Ok(deleted)
+    }
+
+    /// Remove expired cached_file documents along with their blobs in the cache filestore
+    async fn expire_cached_files(&self, query: &str) -> Result<u64> {
+        let collection = &self.core.datastore.cached_file;
+        if self.dry_run || !self.core.config.core.expiry.delete_storage {
+            return self.expire_collection(collection, query).await
+        }
+
+        // The blobs are named by the document ids, which are fetched a batch at a time until none are left
+        let batch_size = self.core.config.core.expiry.delete_batch_size.max(1) as u64;
+        let mut deleted = 0;
+        while self.core.is_running() {
+            let batch = collection.search(query).rows(batch_size).fields("component").execute::<()>().await?.keys;
+            if batch.is_empty() {
+                break
+            }
+
+            self.delete_blobs(&self.cache, &batch).await;
+            let task = collection.delete_by_query(&format!("_id:({})", batch.join(" OR ")), vec![], None, Some(Index::Hot)).await?;
+            deleted += self.wait_for_delete(task).await?;
+            collection.commit(Some(Index::Hot)).await?;
+
+            if (batch.len() as u64) < batch_size {
+                break
+            }
+        }
+
+        if deleted > 0 {
+            info!("Removed {deleted} expired files from {}", collection.name());
+        }
+        Ok(deleted)
+    }
+
+    /// Remove expired results, along with the blobs of supplementary files no longer described by a file document
+    async fn expire_results(&self, query: &str) -> Result<u64> {
+        let collection = &self.core.datastore.result;
+        if !self.core.config.core.expiry.delete_storage {
+            return self.expire_collection(collection, query).await
+        }
+
+        let mut supplementary = HashSet::new();
+        let mut cursor = collection.stream_search::<PartialResult>(query, "response.supplementary.sha256".to_owned(), vec![], None, Some(1000), Some(Index::Hot)).await?;
+        while let Some(result) = cursor.next().await? {
+            supplementary.extend(result.response.supplementary.into_iter().map(|file| file.sha256));
+        }
+
+        let deleted = self.expire_collection(collection, query).await?;
+
+        // Files that still have a document are removed when that document expires
+        let mut orphaned = vec![];
+        for sha256 in supplementary {
+            if !self.core.datastore.file.exists(&sha256, Some(Index::Hot)).await? {
+                orphaned.push(sha256);
+            }
+        }
+
+        if self.dry_run {
+            info!("[dry-run] {} supplementary blobs would be removed", orphaned.len());
+        } else {
+            for batch in orphaned.chunks(self.core.config.core.expiry.delete_batch_size.max(1) as usize) {
+                self.delete_blobs(&self.core.filestore, batch).await;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Wait for a delete task to finish, returning how many documents it removed.
+    ///
+    /// If the daemon is stopped the task is left to finish within elasticsearch on its own.
+    async fn wait_for_delete(&self, task: TaskHandle) -> Result<u64> {
+        debug!("Waiting on delete task {}", task.id());
+        while !task.is_completed().await? {
+            if !self.core.sleep(Duration::from_secs(1)).await {
+                return Ok(0)
+            }
+        }
+        Ok(task.wait().await?.deleted)
+    }
+
+    /// Delete a batch of blobs using several concurrent requests.
+    ///
+    /// Failures are only logged, the blob will be left behind rather than keeping the document alive.
+    async fn delete_blobs(&self, storage: &Arc<FileStore>, batch: &[String]) {
+        let workers = self.core.config.core.expiry.delete_workers.max(1) as usize;
+        let mut deletes = futures::stream::iter(batch)
+            .map(|name| async move { (name, storage.delete(name).await) })
+            .buffer_unordered(workers);
+
+        while let Some((name, result)) = deletes.next().await {
+            if let Err(err) = result {
+                warn!("Could not remove {name} from the filestore: {err}");
+            }
+        }
+    }
+}
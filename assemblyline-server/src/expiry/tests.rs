@@ -0,0 +1,129 @@
+use assemblyline_filestore::FileStore;
+use assemblyline_models::datastore::{File, Submission};
+use assemblyline_models::datastore::Result as ResultModel;
+use assemblyline_models::datastore::result::File as ResultFile;
+use bytes::Bytes;
+use chrono::{TimeDelta, Utc};
+use rand::Rng;
+
+use crate::cachestore::CacheStore;
+use crate::elastic::Index;
+use crate::Core;
+
+use super::Expiry;
+
+/// Write a file with a blob and a result carrying a supplementary file, both with the given expiry
+async fn save_file(core: &Core, expiry_days: i64) -> (File, String, String) {
+    let expiry_ts = Some(Utc::now() + TimeDelta::days(expiry_days));
+
+    let data: [u8; 64] = rand::rng().random();
+    let mut file = File::gen_for_sample(&data, &mut rand::rng());
+    file.expiry_ts = expiry_ts;
+    core.filestore.put(&file.sha256.to_string(), &Bytes::copy_from_slice(&data)).await.unwrap();
+    core.datastore.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+
+    // a supplementary file whose only reference is the result
+    let data: [u8; 64] = rand::rng().random();
+    let supplementary = File::gen_for_sample(&data, &mut rand::rng());
+    let supplementary_sha256 = supplementary.sha256.to_string();
+    core.filestore.put(&supplementary_sha256, &Bytes::copy_from_slice(&data)).await.unwrap();
+
+    let mut result: ResultModel = rand::rng().random();
+    result.sha256 = file.sha256.clone();
+    result.expiry_ts = expiry_ts;
+    result.response.supplementary = vec![ResultFile::new(supplementary.sha256.clone(), "supplementary".to_owned())];
+    let result_key = result.build_key(None).unwrap();
+    core.datastore.result.save(&result_key, &result, None, None).await.unwrap();
+    (file, result_key, supplementary_sha256)
+}
+
+async fn commit(core: &Core) {
+    core.datastore.file.commit(None).await.unwrap();
+    core.datastore.result.commit(None).await.unwrap();
+    core.datastore.submission.commit(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_expire_documents_and_blobs() {
+    let (core, _guard) = Core::test_setup().await;
+    let (expired, expired_result, _) = save_file(&core, -1).await;
+    let (kept, kept_result, _) = save_file(&core, 1).await;
+
+    let mut submission: Submission = rand::rng().random();
+    submission.expiry_ts = Some(Utc::now() - TimeDelta::days(1));
+    let sid = submission.sid.to_string();
+    core.datastore.submission.save(&sid, &submission, None, None).await.unwrap();
+    commit(&core).await;
+
+    let expiry = Expiry::new(core.clone(), false).await.unwrap();
+    expiry.run_expiry_once().await.unwrap();
+    commit(&core).await;
+
+    // expired documents and their blobs are gone
+    let datastore = &core.datastore;
+    assert!(!datastore.file.exists(&expired.sha256.to_string(), Some(Index::Hot)).await.unwrap());
+    assert!(!core.filestore.exists(&expired.sha256.to_string()).await.unwrap());
+    assert!(!datastore.result.exists(&expired_result, Some(Index::Hot)).await.unwrap());
+    assert!(!datastore.submission.exists(&sid, Some(Index::Hot)).await.unwrap());
+
+    // everything else is left alone
+    assert!(datastore.file.exists(&kept.sha256.to_string(), Some(Index::Hot)).await.unwrap());
+    assert!(core.filestore.exists(&kept.sha256.to_string()).await.unwrap());
+    assert!(datastore.result.exists(&kept_result, Some(Index::Hot)).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_expire_supplementary_blobs() {
+    let (core, _guard) = Core::test_setup().await;
+    let (_, result_key, supplementary) = save_file(&core, -1).await;
+    commit(&core).await;
+
+    // the blob is removed along with the only result referencing it
+    Expiry::new(core.clone(), false).await.unwrap().run_expiry_once().await.unwrap();
+    assert!(!core.datastore.result.exists(&result_key, Some(Index::Hot)).await.unwrap());
+    assert!(!core.filestore.exists(&supplementary).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_expire_cached_files() {
+    let (core, _guard) = Core::test_setup().await;
+    let cache_store = FileStore::open(&core.config.filestore.cache).await.unwrap();
+    let cache = CacheStore::new("expiry".to_owned(), core.datastore.clone(), cache_store.clone()).unwrap();
+    cache.save("expired", &Bytes::from_static(b"expired"), Some(TimeDelta::days(-1))).await.unwrap();
+    cache.save("kept", &Bytes::from_static(b"kept"), None).await.unwrap();
+    core.datastore.cached_file.commit(None).await.unwrap();
+
+    Expiry::new(core.clone(), false).await.unwrap().run_expiry_once().await.unwrap();
+    assert!(!core.datastore.cached_file.exists("expiry_expired", Some(Index::Hot)).await.unwrap());
+    assert!(!cache_store.exists("expiry_expired").await.unwrap());
+    assert!(core.datastore.cached_file.exists("expiry_kept", Some(Index::Hot)).await.unwrap());
+    assert!(cache_store.exists("expiry_kept").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    let (core, _guard) = Core::test_setup().await;
+    let (expired, expired_result, _) = save_file(&core, -1).await;
+    commit(&core).await;
+
+    let expiry = Expiry::new(core.clone(), true).await.unwrap();
+    expiry.run_expiry_once().await.unwrap();
+    commit(&core).await;
+
+    // nothing is removed in dry-run mode
+    assert!(core.datastore.file.exists(&expired.sha256.to_string(), Some(Index::Hot)).await.unwrap());
+    assert!(core.filestore.exists(&expired.sha256.to_string()).await.unwrap());
+    assert!(core.datastore.result.exists(&expired_result, Some(Index::Hot)).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_batch_delete_date() {
+    let (core, _guard) = Core::test_custom_setup(|config| {
+        config.core.expiry.batch_delete = true;
+        config.core.expiry.delay = 24;
+    }).await;
+
+    let final_date = Expiry::new(core, true).await.unwrap().final_date();
+    assert_eq!(final_date.time(), chrono::NaiveTime::MIN);
+    assert!(final_date <= Utc::now() - TimeDelta::hours(24));
+}
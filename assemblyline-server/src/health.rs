@@ -0,0 +1,268 @@
+//! Liveness and readiness of a core component.
+//!
+//! The worker loops of a component register with the `Health` table on the core and check in every time
+//! they go around. A component is live while none of its loops have stalled, and ready while it is live
+//! and the elasticsearch, redis and filestore it depends on can be reached. Both are served under
+//! `/healthz` and the configured heartbeat file is touched whenever the component is ready, so container
+//! health checks fail when a component stops making progress rather than only when it exits.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use parking_lot::Mutex;
+use poem::http::StatusCode;
+use poem::web::{Data, Json};
+use poem::{get, handler, Endpoint, EndpointExt, Route};
+use serde::Serialize;
+
+use crate::Core;
+
+/// How often the heartbeat file is refreshed while the component is ready
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time a loop may run over its expected interval before it is considered stalled
+const STALL_GRACE: Duration = Duration::from_secs(60);
+
+/// File probed to check that the filestore can be reached, it doesn't matter if it exists
+const FILESTORE_PROBE: &str = "healthcheck";
+
+/// Last check in of every worker loop running in this process
+#[derive(Default)]
+pub struct Health {
+    /// Loops keyed by the id of their heartbeat, copies of a loop share a name but are tracked on their own
+    loops: Mutex<HashMap<u64, LoopState>>,
+    next_id: AtomicU64,
+}
+
+struct LoopState {
+    name: String,
+    last_beat: Instant,
+    /// Longest the loop is expected to go between beats
+    interval: Duration,
+}
+
+impl Health {
+    /// Start tracking a worker loop that should beat at least once every interval
+    ///
+    /// The loop stops being tracked when the heartbeat is dropped.
+    pub fn register(self: &Arc<Self>, name: &str, interval: Duration) -> Heartbeat {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.loops.lock().insert(id, LoopState {
+            name: name.to_owned(),
+            last_beat: Instant::now(),
+            interval,
+        });
+        Heartbeat { health: self.clone(), id }
+    }
+
+    /// Names of the loops with any copy that hasn't checked in within its interval
+    pub fn stalled(&self) -> Vec<String> {
+        let mut stalled: Vec<String> = self.loops.lock().values()
+            .filter(|state| state.last_beat.elapsed() > state.interval + STALL_GRACE)
+            .map(|state| state.name.clone())
+            .collect();
+        stalled.sort_unstable();
+        stalled.dedup();
+        stalled
+    }
+}
+
+/// Handle a worker loop uses to report that it is making progress
+pub struct Heartbeat {
+    health: Arc<Health>,
+    id: u64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(state) = self.health.loops.lock().get_mut(&self.id) {
+            state.last_beat = Instant::now();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.health.loops.lock().remove(&self.id);
+    }
+}
+
+/// Result of checking everything a component needs to do its work
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Readiness {
+    pub elasticsearch: bool,
+    pub redis: bool,
+    pub filestore: bool,
+    /// Filestore transports whose latest operation failed, the filestore may still be usable through the others
+    pub degraded_filestores: Vec<String>,
+    /// Worker loops that have stopped making progress
+    pub stalled: Vec<String>,
+}
+
+impl Readiness {
+    pub fn is_live(&self) -> bool {
+        self.stalled.is_empty()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_live() && self.elasticsearch && self.redis && self.filestore
+    }
+}
+
+impl Core {
+    /// Check the connections of this component and the progress of its worker loops
+    pub async fn readiness(&self) -> Readiness {
+        let (elasticsearch, persistant, volatile, metrics, filestore) = tokio::join!(
+            self.datastore.ping(),
+            self.redis_persistant.ping(),
+            self.redis_volatile.ping(),
+            self.redis_metrics.ping(),
+            self.filestore.exists(FILESTORE_PROBE),
+        );
+
+        let mut redis = true;
+        for result in [persistant, volatile, metrics] {
+            if let Err(err) = result {
+                warn!("Redis health check failed: {err}");
+                redis = false;
+            }
+        }
+        if let Err(err) = &filestore {
+            warn!("Filestore health check failed: {err}");
+        }
+
+        let degraded_filestores: Vec<String> = self.filestore.health().into_iter()
+            .filter(|status| status.is_degraded())
+            .map(|status| status.transport)
+            .collect();
+        if !degraded_filestores.is_empty() {
+            warn!("Filestore transports degraded: {degraded_filestores:?}");
+        }
+
+        Readiness {
+            elasticsearch,
+            redis,
+            filestore: filestore.is_ok(),
+            degraded_filestores,
+            stalled: self.health.stalled(),
+        }
+    }
+}
+
+/// Touch the configured heartbeat file for as long as the component is ready
+pub async fn heartbeat_file(core: Core) {
+    let Some(path) = core.config.logging.heartbeat_file.clone() else { return };
+    while core.is_running() {
+        let readiness = core.readiness().await;
+        if readiness.is_ready() {
+            if let Err(err) = tokio::fs::write(&path, chrono::Utc::now().to_rfc3339()).await {
+                error!("Could not write heartbeat file {path}: {err}");
+            }
+        } else {
+            warn!("Not ready, heartbeat file not updated: {readiness:?}");
+        }
+        core.sleep(HEARTBEAT_INTERVAL).await;
+    }
+}
+
+/// Routes serving the liveness and readiness of the component, meant to be nested under `/healthz`
+pub fn api(core: Core) -> impl Endpoint {
+    Route::new()
+        .at("/live", get(get_liveness))
+        .at("/ready", get(get_readiness))
+        .data(core)
+}
+
+/// Live while none of the worker loops have stalled
+#[handler]
+fn get_liveness(Data(core): Data<&Core>) -> (StatusCode, Json<Vec<String>>) {
+    let stalled = core.health.stalled();
+    let status = if stalled.is_empty() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(stalled))
+}
+
+/// Ready while live and every connection can be reached
+#[handler]
+async fn get_readiness(Data(core): Data<&Core>) -> (StatusCode, Json<Readiness>) {
+    let readiness = core.readiness().await;
+    let status = if readiness.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use poem::listener::{Acceptor, TcpAcceptor};
+    use poem::{Endpoint, Server};
+    use reqwest::StatusCode;
+
+    use crate::Core;
+
+    use super::Health;
+
+    /// Serve the routes of a component and check its health endpoints follow the state of its loops
+    pub(crate) async fn check_healthz(core: &Core, app: impl Endpoint + 'static) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let acceptor = TcpAcceptor::from_tokio(listener).unwrap();
+        let port = acceptor.local_addr()[0].as_socket_addr().unwrap().port();
+        let server = tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+        let client = reqwest::Client::new();
+        let live = format!("http://localhost:{port}/healthz/live");
+        let ready = format!("http://localhost:{port}/healthz/ready");
+
+        let response = client.get(&live).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.json::<Vec<String>>().await.unwrap().is_empty());
+        let response = client.get(&ready).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let readiness: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(readiness["elasticsearch"], true);
+        assert_eq!(readiness["redis"], true);
+
+        // a loop that stops checking in fails both
+        let heartbeat = core.health.register("stuck", Duration::ZERO);
+        for state in core.health.loops.lock().values_mut().filter(|state| state.name == "stuck") {
+            state.last_beat -= super::STALL_GRACE * 2;
+        }
+        let response = client.get(&live).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.json::<Vec<String>>().await.unwrap(), ["stuck"]);
+        let response = client.get(&ready).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(heartbeat);
+        server.abort();
+    }
+
+    #[test]
+    fn stalled_loops() {
+        let health = Arc::new(Health::default());
+        let first = health.register("ingest", Duration::ZERO);
+        let second = health.register("ingest", Duration::ZERO);
+        let other = health.register("complete", Duration::from_secs(3600));
+        assert!(health.stalled().is_empty());
+
+        // pretend the loops last checked in before the grace period
+        for state in health.loops.lock().values_mut() {
+            state.last_beat -= super::STALL_GRACE * 2;
+        }
+        assert_eq!(health.stalled(), vec!["ingest".to_owned()]);
+
+        // a beat from one copy doesn't hide another that has stalled
+        first.beat();
+        assert_eq!(health.stalled(), vec!["ingest".to_owned()]);
+        second.beat();
+        assert!(health.stalled().is_empty());
+
+        // loops stop being tracked once their heartbeat is dropped
+        drop(first);
+        drop(second);
+        drop(other);
+        assert!(health.loops.lock().is_empty());
+    }
+}
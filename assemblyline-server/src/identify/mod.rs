@@ -5,6 +5,7 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -140,6 +141,9 @@ struct Magic(pub magic::Cookie<magic::cookie::Load>);
 /// and AFAIK the _kind_ of thread unsafeness libmagic has can be handled with that.
 unsafe impl Send for Magic {}
 
+/// Number of custom configuration files identify loads from the cache
+const CUSTOM_FILE_COUNT: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+
 pub struct Identify {
     file_type: Mutex<Magic>,
     mime_type: Mutex<Magic>,
@@ -155,6 +159,8 @@ pub struct Identify {
 
 impl Identify {
     pub async fn new_with_cache(cache: CacheStore, redis_volatile: Arc<RedisObjects>) -> Result<Arc<Self>> {
+        // Keep the custom files in memory so reloads only fetch the ones that changed
+        let cache = cache.with_memory_cache(CUSTOM_FILE_COUNT);
         Self::new(Some(cache), Some(redis_volatile)).await
     }
     
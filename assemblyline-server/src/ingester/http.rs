@@ -11,7 +11,8 @@ use log::{error, info};
 use poem::listener::{Listener, OpensslTlsConfig, TcpListener};
 use poem::web::{Data, Json};
 use assemblyline_models::messages::submission::Submission as MessageSubmission;
-use poem::{get, handler, post, EndpointExt, Route, Server};
+use poem::http::StatusCode;
+use poem::{get, handler, post, Endpoint, EndpointExt, Route, Server};
 
 /// API endpoint for null status that is always available
 #[handler]
@@ -19,10 +20,14 @@ async fn get_status() -> Result<()> {
     return Ok(())
 }
 
+/// Ingest a submission, refusing it with 429 if it exceeds a rate limit
 #[handler]
-async fn start_ingest(ingester: Data<&Arc<Ingester>>, submission: Json<MessageSubmission>) -> Result<()> {
-    let task = Box::new(IngestTask::new(submission.0));
-    ingester.spawn_ingest(task).await?;
+async fn start_ingest(ingester: Data<&Arc<Ingester>>, submission: Json<MessageSubmission>) -> poem::Result<()> {
+    let mut task = Box::new(IngestTask::new(submission.0));
+    if let Some(reason) = ingester.refuse_rate_limited(&mut task).await? {
+        return Err(poem::Error::from_string(reason, StatusCode::TOO_MANY_REQUESTS))
+    }
+    ingester.spawn_admitted(task).await.map_err(anyhow::Error::from)??;
     return Ok(())
 }
 
@@ -33,12 +38,19 @@ pub async fn start(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, i
 }
 
 
-async fn _start(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, ingester: Arc<Ingester>) -> Result<()> {
-    let app = Route::new()
+/// Routes served by the ingester
+pub(super) fn app(ingester: Arc<Ingester>) -> impl Endpoint {
+    Route::new()
         .at("/alive", get(get_status))
         .at("/ingest", post(start_ingest))
-        .data(ingester.clone())
-        .with(LoggerMiddleware);
+        .nest("/healthz", crate::health::api(ingester.core.clone()))
+        .data(ingester)
+        .with(LoggerMiddleware)
+        .with(crate::telemetry::TraceContextMiddleware)
+}
+
+async fn _start(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, ingester: Arc<Ingester>) -> Result<()> {
+    let app = app(ingester.clone());
 
     let listener = TcpListener::bind(bind_address);
     let tls_config = match tls {
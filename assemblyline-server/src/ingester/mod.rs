@@ -5,11 +5,12 @@
 //! be created.
 
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use redis_objects::quota::UserQuotaTracker;
 use strum::IntoEnumIterator;
 
@@ -17,23 +18,26 @@ use assemblyline_models::config::Priority;
 use assemblyline_models::datastore::filescore::FileScore;
 use assemblyline_models::datastore::submission::{SubmissionParams, SubmissionState};
 use assemblyline_models::datastore::user::User;
-use assemblyline_models::types::{ExpandingClassification, Sha256, Sid, UpperString};
+use assemblyline_models::types::{ClassificationString, ExpandingClassification, Sha256, Sid, UpperString, Wildcard};
 use assemblyline_models::datastore::alert::ExtendedScanValues;
 use assemblyline_models::messages::submission::{Submission as MessageSubmission, SubmissionMessage};
 use assemblyline_models::datastore::submission::Submission as DatabaseSubmission;
+use assemblyline_filestore::errors::TooLargeError;
 use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 use rand::Rng;
 use redis_objects::queue::MultiQueue;
-use redis_objects::{increment, AutoExportingMetrics, Hashmap, PriorityQueue, Publisher, Queue};
+use redis_objects::{increment, AutoExportingMetrics, Hashmap, PriorityQueue, Publisher, Queue, SlidingWindow};
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
+use tracing::{instrument, Instrument};
 
 use crate::common::metrics::CPUTracker;
 use crate::constants::{COMPLETE_QUEUE_NAME, INGEST_QUEUE_NAME, METRICS_CHANNEL};
 use crate::postprocessing::ActionWorker;
 use crate::submit::SubmitManager;
+use crate::work_queue::WorkQueue;
 use crate::Core;
 
 mod http;
@@ -46,6 +50,10 @@ const _MAX_RETRIES: u32 = 10;
 const DEFAULT_RETRY_DELAY: chrono::Duration = chrono::Duration::minutes(4); // Wait 4 minutes to retry
 const DEFAULT_MAX_TIME: chrono::Duration = chrono::Duration::days(2); // Wait 2 days for responses.
 const HOUR_IN_SECONDS: i64 = 60 * 60;
+/// Longest the worker loops go between iterations when there is nothing to do
+const LOOP_INTERVAL: Duration = Duration::from_secs(5);
+/// Time between checks for submissions that went missing in the dispatcher
+const MISSING_INTERVAL: Duration = Duration::from_secs(900);
 pub const SCANNING_TABLE_NAME: &str = "m-scanning-table";
 
 fn read_env_size(name: &str, default: usize) -> Result<usize> {
@@ -152,7 +160,7 @@ pub struct Ingester {
     complete_queue: Queue<DatabaseSubmission>,
 
     // Input. An external process places submission requests on this queue.
-    ingest_queue: Queue<MessageSubmission>,
+    ingest_queue: WorkQueue<MessageSubmission>,
 
     // Metrics gathering factory
     counter: AutoExportingMetrics<assemblyline_models::messages::ingest_heartbeat::Metrics>,
@@ -179,6 +187,10 @@ pub struct Ingester {
     // Async Submission quota tracker
     async_submission_tracker: UserQuotaTracker,
 
+    // Submission counts over the last hour and day, for enforcing rate limits
+    hourly_limits: SlidingWindow,
+    daily_limits: SlidingWindow,
+
     #[cfg(test)]
     pub test_hook_fail_submit: Mutex<usize>,
 }
@@ -230,7 +242,7 @@ impl Ingester {
             timeout_queue: core.redis_volatile.priority_queue("m-timeout".to_owned()),
             timeout_delay: DEFAULT_MAX_TIME,
             complete_queue: core.redis_volatile.queue(COMPLETE_QUEUE_NAME.to_owned(), None),
-            ingest_queue: core.redis_persistant.queue(INGEST_QUEUE_NAME.to_owned(), None),
+            ingest_queue: WorkQueue::open(&core.redis_persistant, INGEST_QUEUE_NAME, "ingester", core.config.core.ingester.durable_queue),
             counter: core.redis_metrics.auto_exporting_metrics(METRICS_CHANNEL.to_owned(), "ingester".to_owned())
                 .counter_name("ingester".to_owned())
                 .export_interval(Duration::from_secs(core.config.core.metrics.export_interval as u64))
@@ -245,6 +257,8 @@ impl Ingester {
             submit_manager: SubmitManager::new(&core),
             async_submission_tracker: core.redis_persistant.user_quota_tracker("async_submissions".to_owned())
                 .set_timeout(chrono::Duration::days(1).to_std().unwrap()),
+            hourly_limits: core.redis_persistant.sliding_window("ingest-rate-hourly".to_owned(), Duration::from_secs(60 * 60)),
+            daily_limits: core.redis_persistant.sliding_window("ingest-rate-daily".to_owned(), Duration::from_secs(24 * 60 * 60)),
             core,
             #[cfg(test)]
             test_hook_fail_submit: Mutex::new(0),
@@ -321,9 +335,12 @@ impl Ingester {
         // While there are entries in the ingest queue we consume chunk_size
         // entries at a time and move unique entries to uniqueq / queued and
         // duplicates to their own queues / waiting.
+        let heartbeat = self.core.health.register("ingest", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             while !self.core.is_active() {
                 // Ingester is disabled... waiting for it to be reactivated
+                heartbeat.beat();
                 self.core.sleep(Duration::from_millis(100)).await;
             }
 
@@ -343,7 +360,7 @@ impl Ingester {
         };
 
         // continue if there has been a timeout
-        let message = match message {
+        let (message, receipt) = match message {
             Some(message) => message,
             None => return Ok(()),
         };
@@ -354,12 +371,24 @@ impl Ingester {
         // Reset to new random uuid
         // task.submission.sid = rand::rng().random();
 
-        self.spawn_ingest(task);
+        // the message is only released from the ingest queue once it has been handled
+        let handle = self.spawn_ingest(task);
+        let this = self.clone();
+        tokio::spawn(async move {
+            // a failed message stays pending, to be delivered again or dead lettered by the queue
+            if let Ok(Ok(())) = handle.await {
+                if let Err(err) = this.ingest_queue.ack(receipt).await {
+                    error!("Could not acknowledge ingest submission: {err}");
+                }
+            }
+        });
         Ok(())
     }
 
     async fn handle_complete(self: &Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("complete", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             let result = match self.complete_queue.pop_timeout(Duration::from_secs(3)).await? {
                 Some(result) => result,
                 None => continue
@@ -384,7 +413,9 @@ impl Ingester {
     }
 
     async fn handle_submit(self: &Arc<Self>, block_on_redis: bool) -> Result<()> {
+        let heartbeat = self.core.health.register("submit", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             self.submit_once(block_on_redis).await?;
         }
         Ok(())
@@ -488,14 +519,17 @@ impl Ingester {
     }
 
     async fn handle_retries(self: &Arc<Ingester>) -> Result<()> {
+        let heartbeat = self.core.health.register("retries", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             let now = chrono::Utc::now().timestamp();
             let tasks = self.retry_queue.dequeue_range(None, Some(now), None, Some(100)).await?;
             let task_count = tasks.len();
 
+            // retries were admitted by the rate limits when they were first ingested
             for task in tasks {
                 increment!(self.counter, retries);
-                self.spawn_ingest(Box::new(task));
+                self.spawn_admitted(Box::new(task));
             }
 
             if task_count == 0 {
@@ -506,7 +540,9 @@ impl Ingester {
     }
 
     async fn handle_timeouts(self: Arc<Self>) -> Result<()> {
+        let heartbeat = self.core.health.register("timeouts", LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             let now = chrono::Utc::now().timestamp();
             let timeouts = self.timeout_queue.dequeue_range(None, Some(now), None, Some(100)).await?;
             let timeouts_count = timeouts.len();
@@ -562,7 +598,9 @@ impl Ingester {
     async fn handle_missing(self: &Arc<Self>) -> Result<()> {
         let mut last_round: HashSet<Sid> = Default::default();
 
+        let heartbeat = self.core.health.register("missing", MISSING_INTERVAL + LOOP_INTERVAL);
         while self.core.is_running() {
+            heartbeat.beat();
             // Get the current set of outstanding tasks
             let mut outstanding = self.scanning.items().await?;
 
@@ -611,7 +649,7 @@ impl Ingester {
 
             // wait a few minutes before checking again
             if last_round.is_empty() {
-                self.core.sleep(Duration::from_secs(900)).await;
+                self.core.sleep(MISSING_INTERVAL).await;
             } else {
                 self.core.sleep(Duration::from_secs(300)).await;
             }
@@ -619,19 +657,107 @@ impl Ingester {
         Ok(())
     }
 
-    fn spawn_ingest(self: &Arc<Self>, task: Box<IngestTask>) -> tokio::task::JoinHandle<()> {
-        // spawn a task to process this
+    /// Spawn the processing of a task, errors are logged and also returned through the handle
+    fn spawn_ingest(self: &Arc<Self>, task: Box<IngestTask>) -> tokio::task::JoinHandle<Result<()>> {
         let this = self.clone();
+        Self::spawn_logged(async move { this.ingest(task).await })
+    }
+
+    /// Spawn the processing of a task that has already been checked against the rate limits
+    fn spawn_admitted(self: &Arc<Self>, task: Box<IngestTask>) -> tokio::task::JoinHandle<Result<()>> {
+        let this = self.clone();
+        Self::spawn_logged(async move { this.ingest_admitted(task).await })
+    }
+
+    fn spawn_logged(work: impl Future<Output=Result<()>> + Send + 'static) -> tokio::task::JoinHandle<Result<()>> {
+        // keep the span of the caller, such as an http request continuing the trace of its sender
         tokio::spawn(async move {
-            if let Err(err) = this.ingest(task).await {
+            let result = work.await;
+            if let Err(err) = &result {
                 error!("Error while ingesting a file: {err}");
             }
-        })
+            result
+        }.in_current_span())
     }
 
     async fn ingest(self: &Arc<Self>, mut task: Box<IngestTask>) -> Result<()> {
+        if self.refuse_rate_limited(&mut task).await?.is_some() {
+            return Ok(())
+        }
+        self.ingest_admitted(task).await
+    }
+
+    /// Check a task against the configured rate limits, dropping it with a notification if any are exceeded.
+    /// Returns the reason the task was refused.
+    ///
+    /// A refused task doesn't count against any of the limits, those it was already counted against are released.
+    async fn refuse_rate_limited(&self, task: &mut IngestTask) -> Result<Option<String>> {
+        let limits = &self.core.config.core.ingester.rate_limits;
+        self.assign_groups(task).await?;
+        let params = task.params();
+
+        let mut checks = vec![];
+        let user_limit = limits.users.get(&params.submitter).unwrap_or(&limits.per_user);
+        checks.push(("user", params.submitter.clone(), *user_limit));
+        if let Some(limit) = limits.ingest_types.get(&params.submission_type) {
+            checks.push(("type", params.submission_type.clone(), *limit));
+        }
+        for group in &params.groups {
+            if let Some((name, limit)) = limits.groups.iter().find(|(name, _)| name.eq_ignore_ascii_case(group)) {
+                checks.push(("group", name.clone(), *limit));
+            }
+        }
+
+        let token = format!("{}-{:016x}", task.ingest_id, rand::random::<u64>());
+        let mut acquired = vec![];
+        for (kind, name, limit) in checks {
+            let windows = [("hour", &self.hourly_limits, limit.hourly), ("day", &self.daily_limits, limit.daily)];
+            for (period, limiter, maximum) in windows {
+                let Some(maximum) = maximum else { continue };
+                let key = format!("{kind}-{name}");
+                let decision = limiter.acquire_as(&key, maximum, 1, &token).await?;
+                if decision.allowed {
+                    acquired.push((limiter, key));
+                } else {
+                    for (limiter, key) in acquired {
+                        limiter.release(&key, 1, &token).await?;
+                    }
+                    let reason = format!("Rate limit exceeded: {kind} {name} is limited to {maximum} submissions per {period}, retry in {} seconds", decision.retry_after.as_secs().max(1));
+                    task.failure.clone_from(&reason);
+                    warn!("[{} :: {}] {reason}", task.ingest_id, task.sha256());
+                    self._notify_drop(task).await?;
+                    increment!(self.counter, rate_limited);
+                    return Ok(Some(reason))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Set the groups from the user, if they aren't already set
+    async fn assign_groups(&self, task: &mut IngestTask) -> Result<()> {
+        if task.params().groups.is_empty() {
+            let classification_string = task.params().classification.as_str().to_string();
+            for g in self.get_groups_from_user(&task.params().submitter).await? {
+                if classification_string.contains(g.deref()) {
+                    task.submission.params.groups.push(g);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn ingest_admitted(self: &Arc<Self>, mut task: Box<IngestTask>) -> Result<()> {
         info!("[{} :: {}] Task received for processing", task.ingest_id, task.sha256());
 
+        // Replace CaRT files with their content before anything is recorded about the submission
+        if self.core.config.core.ingester.unwrap_cart && !self.unwrap_cart(&mut task).await? {
+            self._notify_drop(&mut task).await?;
+            increment!(self.counter, error);
+            error!("[{} :: {}] {}", task.ingest_id, task.sha256(), task.failure);
+            return Ok(())
+        }
+
         // Write all input to the traffic queue
         self.traffic_queue.publish(&SubmissionMessage::ingested(task.submission.clone())).await?;
         debug!("[{} :: {}] posted to traffic channel", task.ingest_id, task.sha256());
@@ -667,14 +793,7 @@ impl Ingester {
         }
 
         // Set the groups from the user, if they aren't already set
-        if task.params().groups.is_empty() {
-            let classification_string = task.params().classification.as_str().to_string();
-            for g in self.get_groups_from_user(&task.params().submitter).await? {
-                if classification_string.contains(g.deref()) {
-                    task.submission.params.groups.push(g);
-                }
-            }
-        }
+        self.assign_groups(&mut task).await?;
 
         // Check if this file is already being processed
         debug!("[{} :: {}] checking cache? {}", task.ingest_id, task.sha256(), !task.params().ignore_cache);
@@ -773,6 +892,74 @@ impl Ingester {
         Ok(())
     }
 
+    /// Swap a CaRT file given for ingestion for the file packed inside it.
+    /// The name, description and classification in the CaRT header update the submission,
+    /// the rest of the header is flattened into the submission metadata.
+    ///
+    /// Returns false, with the failure set on the task, when the packed file is over the size limit.
+    async fn unwrap_cart(&self, task: &mut IngestTask) -> Result<bool> {
+        let max_file_size = self.core.config.submission.max_file_size;
+        let limit = if task.params().ignore_size || task.params().never_drop { u64::MAX } else { max_file_size };
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let mut header = match self.core.filestore.unpack_cart(task.sha256(), temp_file.path(), limit).await {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(true),
+            Err(err) if err.is::<TooLargeError>() => {
+                task.failure = format!("File too large (unpacked CaRT content > {max_file_size})");
+                return Ok(false)
+            }
+            Err(err) => {
+                // submit the container as it is, it will be reported as a corrupted CaRT file
+                warn!("[{} :: {}] Could not unpack CaRT file: {err:#}", task.ingest_id, task.sha256());
+                return Ok(true)
+            }
+        };
+
+        let fileinfo = self.core.identify.fileinfo(temp_file.path().to_path_buf(), true, None, None).await?;
+        let Some(sha256) = fileinfo.sha256.clone() else {
+            bail!("Expected hash not found")
+        };
+
+        if let Some(serde_json::Value::String(classification)) = header.remove("classification") {
+            let parser = &self.core.classification_parser;
+            let classification = parser.max_classification(&classification, &task.submission.params.classification, None)?;
+            task.submission.params.classification = ClassificationString::new(classification, parser)?;
+        }
+        if let Some(serde_json::Value::String(description)) = header.remove("description") {
+            task.submission.params.description = Some(description.into());
+        }
+        let name = header.remove("name");
+
+        // Store the unpacked file like any other uploaded file
+        let expiry = if task.params().ttl > 0 {
+            Some(Utc::now() + chrono::Duration::days(task.params().ttl.into()))
+        } else {
+            None
+        };
+        let serde_json::Value::Object(fileinfo) = serde_json::to_value(&fileinfo)? else {
+            bail!("Unusable file info");
+        };
+        let classification = task.params().classification.to_string();
+        self.core.datastore.save_or_freshen_file(&sha256, fileinfo, expiry, classification, &self.core.classification_parser).await?;
+        self.core.filestore.upload(temp_file.path(), &sha256).await?;
+
+        // Metadata given with the submission takes priority over what was packed with the file
+        let mut metadata = vec![];
+        flatten_metadata("", serde_json::Value::Object(header), &mut metadata);
+        for (key, value) in metadata {
+            task.submission.metadata.entry(key).or_insert(value);
+        }
+
+        info!("[{} :: {}] Unpacked CaRT file to {sha256}", task.ingest_id, task.sha256());
+        let file = &mut task.submission.files[0];
+        if let Some(serde_json::Value::String(name)) = name {
+            file.name = name;
+        }
+        file.size = Some(temp_file.as_file().metadata()?.len());
+        file.sha256 = sha256;
+        Ok(true)
+    }
+
     /// Invoked when notified that a submission has completed.
     async fn completed(self: &Arc<Self>, sub: DatabaseSubmission) -> Result<String> {
         // There is only one file in the submissions we have made
@@ -1076,6 +1263,7 @@ impl Ingester {
         Ok(())
     }
 
+    #[instrument(skip_all, fields(sid = %task.submission.sid, sha256 = %task.submission.files[0].sha256))]
     async fn submit(&self, scan_key: String, task: Box<IngestTask>) -> Result<()> {
         let sha = task.submission.files[0].sha256.clone();
 
@@ -1226,3 +1414,18 @@ fn drop_chance(length: u64, maximum: i64) -> f64 {
 fn current_hour() -> i64 {
     Utc::now().timestamp()/HOUR_IN_SECONDS
 }
+
+/// Turn nested metadata into dotted keys with string values
+fn flatten_metadata(prefix: &str, value: serde_json::Value, output: &mut Vec<(String, Wildcard)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() { key } else { format!("{prefix}.{key}") };
+                flatten_metadata(&key, value, output);
+            }
+        },
+        serde_json::Value::String(value) => output.push((prefix.to_owned(), value.into())),
+        serde_json::Value::Null => {},
+        value => output.push((prefix.to_owned(), value.to_string().into())),
+    }
+}
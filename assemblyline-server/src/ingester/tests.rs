@@ -15,15 +15,22 @@ use assemblyline_models::messages::submission::Submission as MessageSubmission;
 use assemblyline_models::types::{ClassificationString, ExpandingClassification, JsonMap, Sha256, Sid, UpperString, Wildcard};
 use itertools::Itertools;
 use rand::Rng;
+use redis_objects::queue::RawQueue;
 use serde_json::json;
 use tokio::sync::mpsc;
 
-use crate::constants::METRICS_CHANNEL;
+use crate::constants::{INGEST_QUEUE_NAME, METRICS_CHANNEL};
 use crate::ingester::IngestTask;
+use crate::work_queue::WorkQueue;
 use crate::Core;
 
 use super::Ingester;
 
+/// The list other processes push ingest messages to, picked up whether or not the ingester reads through a stream
+fn ingest_list(core: &Core) -> RawQueue {
+    core.redis_persistant.queue::<MessageSubmission>(INGEST_QUEUE_NAME.to_owned(), None).raw()
+}
+
 fn time_limit() -> Duration {
     Duration::from_secs(120)
 }
@@ -155,6 +162,16 @@ fn uniform_string(value: char, length: usize) -> String {
 async fn test_ingest_simple() {
     // setup the test environment
     let (core, _redis_lock) = Core::test_setup().await;
+    check_ingest_simple(core).await
+}
+
+#[tokio::test]
+async fn test_ingest_simple_memory() {
+    let (core, _redis_lock) = Core::test_memory_setup(|_| {}).await;
+    check_ingest_simple(core).await
+}
+
+async fn check_ingest_simple(core: Core) {
     let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
     let mut metrics = core.redis_metrics.subscribe(METRICS_CHANNEL.to_owned()).await;
 
@@ -165,7 +182,7 @@ async fn test_ingest_simple() {
     core.datastore.user.save("user", &user, None, None).await.unwrap();
 
     // Send a message with a garbled sha, this should be dropped
-    let in_queue = ingester.ingest_queue.raw();
+    let in_queue = ingest_list(&core);
     in_queue.push(&MakeMessage::new(core.classification_parser.clone()).files(json!({
         "sha256": uniform_string('0', 10)
     })).build()).await.unwrap();
@@ -203,6 +220,38 @@ async fn test_ingest_simple() {
     assert_eq!(ingester.ingest_queue.length().await.unwrap(), 0);
 }
 
+//MARK: durable queue
+#[tokio::test]
+async fn test_ingest_durable_queue() {
+    let (core, _redis_lock) = Core::test_custom_setup(|config| {
+        config.core.ingester.durable_queue = true;
+    }).await;
+    let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
+    let WorkQueue::Stream { stream, .. } = &ingester.ingest_queue else { panic!("expected a stream") };
+
+    // messages pushed to the list are moved to the stream, and only leave it once handled
+    let in_queue = ingest_list(&core);
+    in_queue.push(&MakeMessage::new(core.classification_parser.clone()).build()).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+    let task = ingester.unique_queue.blocking_pop(Duration::from_secs(2), false).await.unwrap().unwrap();
+    assert_eq!(task.submission.files[0].sha256.to_string(), uniform_string('0', 64));
+    let start = std::time::Instant::now();
+    while ingester.ingest_queue.length().await.unwrap() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(10), "message was not acknowledged");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(stream.pending(10).await.unwrap().is_empty());
+
+    // a garbled message is set aside rather than lost or retried
+    in_queue.push(&MakeMessage::new(core.classification_parser.clone()).files(json!({
+        "sha256": uniform_string('0', 10)
+    })).build()).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+    assert_eq!(ingester.ingest_queue.length().await.unwrap(), 0);
+    assert_eq!(stream.dead_letters(10).await.unwrap().len(), 1);
+    assert_eq!(ingester.unique_queue.length().await.unwrap(), 0);
+}
+
 //MARK: stale score exists
 #[tokio::test]
 async fn test_ingest_stale_score_exists() {
@@ -225,7 +274,7 @@ async fn test_ingest_stale_score_exists() {
     core.datastore.filescore.save(&key, &filescore_cache, None, None).await.unwrap();
 
     // Process a message that hits the stale score
-    ingester.ingest_queue.raw().push(&MakeMessage::new(core.classification_parser.clone()).files(json!({"sha256": sha256})).build()).await.unwrap();
+    ingest_list(&core).push(&MakeMessage::new(core.classification_parser.clone()).files(json!({"sha256": sha256})).build()).await.unwrap();
     ingester.ingest_once().await.unwrap();
 
     // The stale filescore was retrieved but expired
@@ -262,7 +311,7 @@ async fn test_ingest_score_exists() {
     core.datastore.filescore.save(&key, &filescore_cache, None, None).await.unwrap();
 
     // Ingest a file
-    ingester.ingest_queue.raw().push(&MakeMessage::new(core.classification_parser.clone()).files(json!({"sha256": sha256})).build()).await.unwrap();
+    ingest_list(&core).push(&MakeMessage::new(core.classification_parser.clone()).files(json!({"sha256": sha256})).build()).await.unwrap();
     ingester.ingest_once().await.unwrap();
 
     // wait for metrics
@@ -288,7 +337,7 @@ async fn test_ingest_groups_custom() {
     core.datastore.user.save("test_ingest_groups_custom", &user, None, None).await.unwrap();
 
     // process a message
-    ingester.ingest_queue.raw().push(&MakeMessage::new(core.classification_parser.clone())
+    ingest_list(&core).push(&MakeMessage::new(core.classification_parser.clone())
         .params(json!({"submitter": "test_ingest_groups_custom", "groups": ["group_b"]})).build()
     ).await.unwrap();
     ingester.ingest_once().await.unwrap();
@@ -324,7 +373,7 @@ async fn test_ingest_size_error() {
     // datastore.file.save(submission['files'][0]['sha256'], fo)
 
     // process the message
-    ingester.ingest_queue.raw().push(&submission).await.unwrap();
+    ingest_list(&core).push(&submission).await.unwrap();
     ingester.ingest_once().await.unwrap();
 
     // wait for the error to be sent
@@ -340,6 +389,119 @@ async fn test_ingest_size_error() {
     assert!(!task.failure.is_empty());
 }
 
+//MARK: cart
+#[tokio::test]
+async fn test_ingest_cart() {
+    let (core, _redis_lock) = Core::test_custom_setup(|config| {
+        config.core.ingester.unwrap_cart = true;
+    }).await;
+    let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
+
+    // pack a file with metadata into a CaRT container and store that
+    let body = b"packed file content ".repeat(100);
+    let mut header = JsonMap::new();
+    header.insert("name".to_owned(), json!("packed.txt"));
+    header.insert("source".to_owned(), json!({"feed": "abc", "count": 3}));
+    header.insert("small".to_owned(), json!("from cart"));
+    let mut encoder = assemblyline_filestore::cart::CartEncoder::new(Some(header), None).unwrap();
+    let mut packed = encoder.update(&body).unwrap();
+    packed.extend(encoder.finish().unwrap());
+    let packed_sha = crate::common::sha256_data(&packed);
+    core.filestore.put(&packed_sha, &packed.into()).await.unwrap();
+
+    ingest_list(&core).push(&MakeMessage::new(core.classification_parser.clone())
+        .files(json!({"sha256": packed_sha}))
+        .metadata(json!({"small": "100"}))
+        .build()
+    ).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+
+    // the content of the container is submitted in its place
+    let task = ingester.unique_queue.blocking_pop(std::time::Duration::from_secs(2), false).await.unwrap().unwrap();
+    let sha256 = crate::common::sha256_data(&body);
+    assert_eq!(task.submission.files[0].sha256.to_string(), sha256);
+    assert_eq!(task.submission.files[0].size, Some(body.len() as u64));
+    assert_eq!(task.submission.files[0].name, "packed.txt");
+    assert!(core.filestore.exists(&sha256).await.unwrap());
+    assert!(core.datastore.file.exists(&sha256, None).await.unwrap());
+
+    // the header becomes metadata without replacing what was submitted
+    assert_eq!(task.submission.metadata.get("source.feed"), Some(&"abc".into()));
+    assert_eq!(task.submission.metadata.get("source.count"), Some(&"3".into()));
+    assert_eq!(task.submission.metadata.get("small"), Some(&"100".into()));
+}
+
+#[tokio::test]
+async fn test_ingest_cart_too_large() {
+    let (core, _redis_lock) = Core::test_custom_setup(|config| {
+        config.core.ingester.unwrap_cart = true;
+        config.submission.max_file_size = 1000;
+    }).await;
+    let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
+    let mut metrics = core.redis_metrics.subscribe(METRICS_CHANNEL.to_owned()).await;
+
+    // a small container holding a file over the size limit
+    let body = vec![0u8; 100_000];
+    let mut encoder = assemblyline_filestore::cart::CartEncoder::new(None, None).unwrap();
+    let mut packed = encoder.update(&body).unwrap();
+    packed.extend(encoder.finish().unwrap());
+    assert!(packed.len() < 1000);
+    let packed_sha = crate::common::sha256_data(&packed);
+    core.filestore.put(&packed_sha, &packed.into()).await.unwrap();
+
+    ingest_list(&core).push(&MakeMessage::new(core.classification_parser.clone())
+        .files(json!({"sha256": packed_sha, "size": 500}))
+        .params(json!({"ignore_size": false, "never_drop": false}))
+        .message(json!({"notification": {"queue": "test_ingest_cart_too_large"}}))
+        .build()
+    ).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+
+    // the task is failed instead of being submitted
+    assert_metrics(&mut metrics, &[("error", 1)]).await;
+    assert_eq!(ingester.unique_queue.length().await.unwrap(), 0);
+    let queue = core.notification_queue("test_ingest_cart_too_large");
+    let task = queue.pop_timeout(std::time::Duration::from_secs(2)).await.unwrap().unwrap();
+    assert!(task.failure.contains("too large"), "{}", task.failure);
+}
+
+//MARK: rate limits
+#[tokio::test]
+async fn test_ingest_rate_limits() {
+    let (core, _redis_lock) = Core::test_custom_setup(|config| {
+        config.core.ingester.rate_limits.per_user.hourly = Some(1);
+        config.core.ingester.rate_limits.groups.insert("other".to_owned(), assemblyline_models::config::RateLimit { hourly: None, daily: Some(0) });
+    }).await;
+    let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
+    let mut metrics = core.redis_metrics.subscribe(METRICS_CHANNEL.to_owned()).await;
+
+    let message = |sha: char| MakeMessage::new(core.classification_parser.clone())
+        .files(json!({"sha256": uniform_string(sha, 64)}))
+        .message(json!({"notification": {"queue": "test_ingest_rate_limits"}}));
+
+    // the first submission from the user goes through, the next is over the hourly limit
+    ingest_list(&core).push(&message('1').build()).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+    ingest_list(&core).push(&message('2').build()).await.unwrap();
+    ingester.ingest_once().await.unwrap();
+    assert_metrics(&mut metrics, &[("submissions_ingested", 1), ("rate_limited", 1)]).await;
+    assert_eq!(ingester.unique_queue.length().await.unwrap(), 1);
+
+    // the refusal is reported on the notification queue
+    let queue = core.notification_queue("test_ingest_rate_limits");
+    let task = queue.pop_timeout(std::time::Duration::from_secs(2)).await.unwrap().unwrap();
+    assert!(task.failure.starts_with("Rate limit exceeded: user user"));
+
+    // group limits apply on top of the user limits
+    let mut task = IngestTask::new(serde_json::from_slice(&message('3').params(json!({"submitter": "another", "groups": ["OTHER"]})).build()).unwrap());
+    let reason = ingester.refuse_rate_limited(&mut task).await.unwrap().unwrap();
+    assert!(reason.contains("group other"));
+
+    // the refused task isn't counted against the user limit it passed
+    let mut task = IngestTask::new(serde_json::from_slice(&message('4').params(json!({"submitter": "another", "groups": ["USERS"]})).build()).unwrap());
+    assert!(ingester.refuse_rate_limited(&mut task).await.unwrap().is_none());
+}
+
 //MARK: always create submission
 #[tokio::test]
 async fn test_ingest_always_create_submission() {
@@ -381,7 +543,7 @@ async fn test_ingest_always_create_submission() {
         .files(json!({"sha256": sha256.to_string()}))
         .metadata(json!({"blah": "blah"}))
         .build();
-    ingester.ingest_queue.raw().push(&submission_msg).await.unwrap();
+    ingest_list(&core).push(&submission_msg).await.unwrap();
     ingester.ingest_once().await.unwrap();
 
     // No file has made it into the internal buffer => cache hit and drop
@@ -453,7 +615,7 @@ async fn test_submit_simple() {
     ingester.submit_once(true).await.unwrap();
 
     // The task has been passed to the submit tool and there are no other submissions
-    ingester.submit_manager.dispatch_submission_queue.pop().await.unwrap();
+    ingester.submit_manager.dispatch_submission_queue.pop_timeout(Duration::from_secs(1)).await.unwrap().unwrap();
     assert_eq!(ingester.unique_queue.length().await.unwrap(), 0);
 }
 
@@ -572,7 +734,8 @@ async fn test_submit_bundle() {
 
 
     // The task has been passed to the submit tool and there are no other submissions
-    let dispatcher_message = ingester.submit_manager.dispatch_submission_queue.pop().await.unwrap();
+    let dispatcher_message = ingester.submit_manager.dispatch_submission_queue.pop_timeout(Duration::from_secs(1)).await.unwrap()
+        .map(|(message, _receipt)| message);
 
 
     // The dispatch message should have error, metadata, file, file_tree
@@ -661,3 +824,11 @@ async fn test_existing_score() {
     let queue = core.notification_queue("test_existing_score");
     assert_eq!(queue.length().await.unwrap(), 1);
 }
+
+//MARK: health
+#[tokio::test]
+async fn test_healthz() {
+    let (core, _redis_lock) = Core::test_setup().await;
+    let ingester = Arc::new(Ingester::new(core.clone()).await.unwrap());
+    crate::health::test::check_healthz(&core, super::http::app(ingester)).await;
+}
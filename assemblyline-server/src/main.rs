@@ -15,17 +15,17 @@
 
 use std::{path::PathBuf, process::ExitCode, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use assemblyline_markings::classification::ClassificationParser;
 use assemblyline_markings::config::{ready_classification, ClassificationConfig};
-use assemblyline_filestore::FileStore;
-use assemblyline_models::config::Config;
+use assemblyline_filestore::{FileStore, ReplicationPolicy};
+use assemblyline_models::config::{Config, RedisServer};
 use cachestore::CacheStore;
 use clap::{Parser, Subcommand};
 use common::flag::Flag;
 use elastic::Elastic;
 use identify::Identify;
-use redis_objects::RedisObjects;
+use redis_objects::{RedisObjects, TlsCertificates};
 use log::{error, info};
 use services::ServiceHelper;
 use tracing_subscriber::layer::SubscriberExt;
@@ -56,6 +56,16 @@ mod string_utils;
 mod plumber;
 mod service_api;
 mod common;
+mod alerter;
+mod archiver;
+mod expiry;
+mod workflow;
+mod scaler;
+mod updater;
+mod work_queue;
+mod metrics_aggregator;
+mod telemetry;
+mod health;
 
 #[cfg(test)]
 mod tests;
@@ -73,7 +83,7 @@ struct Args {
     #[arg(short, long)]
     secure_connections: bool,
 
-    /// Enable APM exporting
+    /// Enable trace exporting to the configured APM and OTLP servers
     #[arg(short, long)]
     enable_apm: bool,
 
@@ -94,7 +104,35 @@ enum Commands {
     },
     ServiceAPI {
 
-    }
+    },
+    Alerter {
+
+    },
+    Archiver {
+
+    },
+    Expiry {
+        /// Only report what would be deleted without removing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Workflow {
+
+    },
+    Scaler {
+        /// Path of the Docker or Podman API socket, defaults to DOCKER_HOST or /var/run/docker.sock
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Network service containers are attached to
+        #[arg(long)]
+        network: Option<String>,
+    },
+    Updater {
+
+    },
+    Metrics {
+
+    },
 }
 
 impl Commands {
@@ -104,6 +142,13 @@ impl Commands {
             Commands::Dispatcher { .. } => "dispatcher",
             Commands::Plumber { .. } => "plumber",
             Commands::ServiceAPI { .. } => "service_server",
+            Commands::Alerter { .. } => "alerter",
+            Commands::Archiver { .. } => "archiver",
+            Commands::Expiry { .. } => "expiry",
+            Commands::Workflow { .. } => "workflow",
+            Commands::Scaler { .. } => "scaler",
+            Commands::Updater { .. } => "updater",
+            Commands::Metrics { .. } => "metrics",
         }
     }
 }
@@ -122,15 +167,16 @@ async fn main() -> ExitCode {
     let _log_manager = configure_logging(&config).expect("Could not configure logging");
     info!("Configuration loaded from: {}", config_path.to_string_lossy());
 
-    // Configure APM
+    // Configure trace exporting, APM and OTLP can be used on their own or together
+    telemetry::install_propagator();
+    let mut apm_layer = None;
+    let mut otlp_provider = None;
     if let Some(url) = &config.core.metrics.apm_server.server_url {
         if args.enable_apm {
             let config = tracing_elastic_apm::config::Config::new(url.to_string())
                 .allow_invalid_certificates(true);
 
-            let layer = tracing_elastic_apm::new_layer(args.command.label().to_string(), config).expect("Could not initialize APM");
-
-            tracing_subscriber::registry().with(layer).init();
+            apm_layer = Some(tracing_elastic_apm::new_layer(args.command.label().to_string(), config).expect("Could not initialize APM"));
             info!("APM exporter configured and enabled");
         } else {
             info!("APM exporter configured but disabled");
@@ -138,6 +184,21 @@ async fn main() -> ExitCode {
     } else {
         info!("APM collection not configured");
     }
+    if config.core.metrics.otlp_server.server_url.is_some() {
+        if args.enable_apm {
+            otlp_provider = telemetry::otlp_provider(&config.core.metrics.otlp_server, args.command.label()).expect("Could not initialize OTLP exporter");
+            info!("OTLP exporter configured and enabled");
+        } else {
+            info!("OTLP exporter configured but disabled");
+        }
+    }
+    if apm_layer.is_some() || otlp_provider.is_some() {
+        let otlp_layer = otlp_provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(args.command.label().to_string()))
+        });
+        tracing_subscriber::registry().with(apm_layer).with(otlp_layer).init();
+    }
 
     // Connect to all the supporting components
     let core = match Core::setup(config, "", args.secure_connections).await {
@@ -148,6 +209,9 @@ async fn main() -> ExitCode {
         }
     };
 
+    // tell the container environment this component is healthy for as long as it is
+    tokio::spawn(health::heartbeat_file(core.clone()));
+
     // pick the module to launch
     let result = match args.command {
         Commands::Ingester {  } => {
@@ -162,8 +226,36 @@ async fn main() -> ExitCode {
         Commands::ServiceAPI { } => {  
             crate::service_api::main(core).await
         }
+        Commands::Alerter { } => {
+            crate::alerter::main(core).await
+        }
+        Commands::Archiver { } => {
+            crate::archiver::main(core).await
+        }
+        Commands::Expiry { dry_run } => {
+            crate::expiry::main(core, dry_run).await
+        }
+        Commands::Workflow { } => {
+            crate::workflow::main(core).await
+        }
+        Commands::Scaler { socket, network } => {
+            crate::scaler::main(core, socket, network).await
+        }
+        Commands::Updater { } => {
+            crate::updater::main(core).await
+        }
+        Commands::Metrics { } => {
+            crate::metrics_aggregator::main(core).await
+        }
     };
 
+    // send any spans still waiting to be exported
+    if let Some(provider) = otlp_provider {
+        if let Err(err) = provider.shutdown() {
+            error!("Error flushing OTLP exporter: {err}");
+        }
+    }
+
     // log if the module failed
     match result {
         Ok(_) => ExitCode::SUCCESS,
@@ -213,31 +305,22 @@ struct Core {
 
     // interface to request service information
     pub services: ServiceHelper,
+
+    // progress of the worker loops in this process
+    pub health: Arc<health::Health>,
 }
 
 impl Core {
     /// Initialize connections to resources that everything uses
     pub async fn setup(config: Arc<Config>, elastic_prefix: &str, secure: bool) -> Result<Self> {
         // connect to redis one
-        let redis_persistant = if secure {
-            RedisObjects::open_host_native_tls(&config.core.redis.persistent.host, config.core.redis.persistent.port, config.core.redis.persistent.db)?            
-        } else {
-            RedisObjects::open_host(&config.core.redis.persistent.host, config.core.redis.persistent.port, config.core.redis.persistent.db)?
-        };
+        let redis_persistant = open_redis(&config.core.redis.persistent, secure).await.context("connecting to persistent redis")?;
 
         // connect to redis two
-        let redis_volatile = if secure { 
-            RedisObjects::open_host_native_tls(&config.core.redis.nonpersistent.host, config.core.redis.nonpersistent.port, config.core.redis.nonpersistent.db)?
-        } else {
-            RedisObjects::open_host(&config.core.redis.nonpersistent.host, config.core.redis.nonpersistent.port, config.core.redis.nonpersistent.db)?
-        };
+        let redis_volatile = open_redis(&config.core.redis.nonpersistent, secure).await.context("connecting to volatile redis")?;
 
         // connect to redis three
-        let redis_metrics = if secure {
-            RedisObjects::open_host_native_tls(&config.core.metrics.redis.host, config.core.metrics.redis.port, config.core.metrics.redis.db)?
-        } else {
-            RedisObjects::open_host(&config.core.metrics.redis.host, config.core.metrics.redis.port, config.core.metrics.redis.db)?
-        };
+        let redis_metrics = open_redis(&config.core.metrics.redis, secure).await.context("connecting to metrics redis")?;
 
         // connect to elastic
         let datastore_ca = get_datastore_ca().await?;
@@ -246,7 +329,11 @@ impl Core {
         let datastore = Elastic::connect(&config.datastore.hosts[0], false, datastore_ca, !datastore_verify, elastic_prefix).await?;
 
         // connect to filestore
-        let filestore = FileStore::open(&config.filestore.storage).await.context("initializing filestore")?;
+        let replication = ReplicationPolicy {
+            write_quorum: config.filestore.replication.write_quorum,
+            backfill_queue: config.filestore.replication.backfill_queue,
+        };
+        let filestore = FileStore::open_with_policy(&config.filestore.storage, replication).await.context("initializing filestore")?;
 
         //
         let file_cache = FileStore::open(&config.filestore.cache).await.context("initializing cache filestore")?;
@@ -289,24 +376,37 @@ impl Core {
             filestore,
             classification_parser,
             identify,
+            health: Default::default(),
         })
     }
     
     #[cfg(test)]
     pub async fn test_custom_setup(callback: impl Fn(&mut Config)) -> (Self, TestGuard) {
+        Self::test_setup_on("localhost", callback).await
+    }
+
+    /// Produce a set of core resources with redis held in the memory of this process
+    #[cfg(test)]
+    pub async fn test_memory_setup(callback: impl Fn(&mut Config)) -> (Self, TestGuard) {
+        Self::test_setup_on("memory://test", callback).await
+    }
+
+    #[cfg(test)]
+    async fn test_setup_on(host: &str, callback: impl Fn(&mut Config)) -> (Self, TestGuard) {
         use rand::Rng;
         let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug).try_init();
 
         static USED_DB: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(1);
         let db = USED_DB.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        let host = "localhost";
         let port = 6379;
-        println!("Redis connection");
-        let redis = RedisObjects::open_host(host, port, db).unwrap();
-        println!("Redis wipe");
-        redis.wipe().await.unwrap();
-    
+        // stores held in memory start out empty for each db
+        if !host.starts_with("memory://") {
+            println!("Redis connection");
+            let redis = RedisObjects::open_host(host, port, db).unwrap();
+            println!("Redis wipe");
+            redis.wipe().await.unwrap();
+        }
 
         let filestore = tempfile::TempDir::new().unwrap();
 
@@ -406,15 +506,53 @@ fn get_datastore_verify() -> Result<bool> {
 }
 
 
-// async fn get_redis_cert(kind: &str) -> Result<Option<Vec<u8>>> {
-//     let path = match std::env::var(format!("REDIS_{name}_CERT_PATH")) {
-//         Ok(path) => path,
-//         Err(std::env::VarError::NotPresent) => return Ok(None),
-//         Err(err) => return Err(err.into())
-//     };
+async fn get_redis_cert(kind: &str) -> Result<Option<Vec<u8>>> {
+    let path = match std::env::var(format!("REDIS_{kind}_PATH")) {
+        Ok(path) => path,
+        Err(std::env::VarError::NotPresent) => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+
+    Ok(Some(tokio::fs::read(&path).await.with_context(|| format!("reading redis certificate {path}"))?))
+}
+
+/// Open a connection to a redis deployment, using sentinels or a cluster if configured,
+/// or a store in the memory of this process if the host is given as `memory://<name>`
+async fn open_redis(server: &RedisServer, secure: bool) -> Result<Arc<RedisObjects>> {
+    let certificates = if secure {
+        Some(TlsCertificates {
+            client_cert: get_redis_cert("CLIENT_CERT").await?,
+            client_key: get_redis_cert("CLIENT_KEY").await?,
+            root_cert: get_redis_cert("ROOT_CA").await?,
+        })
+    } else {
+        None
+    };
+
+    if let Some(name) = server.host.strip_prefix("memory://") {
+        return Ok(RedisObjects::open_memory(&format!("{name}/{}", server.db)))
+    }
+
+    if let Some(sentinel) = &server.sentinel {
+        let hosts: Vec<(String, u16)> = sentinel.hosts.iter().map(|node| (node.host.clone(), node.port)).collect();
+        return Ok(RedisObjects::open_sentinel(&hosts, &sentinel.master_name, server.db, certificates.as_ref())?)
+    }
 
-//     Ok(Some(tokio::fs::read(path).await?))
-// }
+    if !server.cluster_nodes.is_empty() {
+        if server.db != 0 {
+            bail!("redis cluster only supports db 0");
+        }
+        let nodes: Vec<(String, u16)> = server.cluster_nodes.iter().map(|node| (node.host.clone(), node.port)).collect();
+        return Ok(RedisObjects::open_cluster(&nodes, certificates.as_ref())?)
+    }
+
+    Ok(match certificates {
+        Some(certificates) if certificates.client_cert.is_some() || certificates.root_cert.is_some() => 
+            RedisObjects::open_host_tls(&server.host, server.port, server.db, &certificates)?,
+        Some(_) => RedisObjects::open_host_native_tls(&server.host, server.port, server.db)?,
+        None => RedisObjects::open_host(&server.host, server.port, server.db)?,
+    })
+}
 
 
 /// While this struct is held prevent temporary core resources from being collected
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use crate::config::TLSConfig;
+use crate::logging::LoggerMiddleware;
+
+use super::MetricsAggregator;
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use poem::listener::{Listener, OpensslTlsConfig, TcpListener};
+use poem::web::Data;
+use poem::{get, handler, EndpointExt, IntoResponse, Route, Server};
+
+/// API endpoint for null status that is always available
+#[handler]
+async fn get_status() -> Result<()> {
+    return Ok(())
+}
+
+/// Counter totals in the Prometheus text exposition format
+#[handler]
+async fn get_metrics(aggregator: Data<&Arc<MetricsAggregator>>) -> impl IntoResponse {
+    aggregator.render()
+        .with_content_type("text/plain; version=0.0.4; charset=utf-8")
+}
+
+pub async fn start(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, aggregator: Arc<MetricsAggregator>) {
+    while let Err(err) = _start(bind_address, tls.clone(), aggregator.clone()).await {
+        error!("Error with http interface: {err} {}", err.root_cause());
+    }
+}
+
+
+async fn _start(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, aggregator: Arc<MetricsAggregator>) -> Result<()> {
+    let app = Route::new()
+        .at("/alive", get(get_status))
+        .at("/metrics", get(get_metrics))
+        .data(aggregator.clone())
+        .with(LoggerMiddleware);
+
+    let listener = TcpListener::bind(bind_address);
+    let tls_config = match tls {
+        Some(tls) => {
+            OpensslTlsConfig::new()
+                .cert_from_data(tls.certificate_pem)
+                .key_from_data(tls.key_pem)
+        },
+        None => crate::config::generate_certificate()?
+    };
+    let listener = listener.openssl_tls(tls_config);
+
+    Server::new(listener)
+        .run_with_graceful_shutdown(app, aggregator.core.running.wait_for(false), None)
+        .await.context("Error in server runtime.")?;
+    info!("HTTP interface stopped");
+    Ok(())
+}
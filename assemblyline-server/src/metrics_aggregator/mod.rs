@@ -0,0 +1,247 @@
+//! A daemon that collects the counters every component publishes on the metrics channel.
+//!
+//! Components export their counters as JSON messages tagged with their type, name and host every export
+//! interval. The aggregator keeps a running total of every counter per type, name and host and serves them
+//! on `/metrics` in the Prometheus text format, so any number of aggregators can be scraped independently.
+//!
+//! When an elasticsearch cluster is configured for metrics the counts received over each export interval
+//! are also summed per component type and name and written to the `al_metrics_{type}` index. Only the
+//! aggregator elected leader writes these rollups so they aren't duplicated.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::constants::METRICS_CHANNEL;
+use crate::elastic::ElasticHelper;
+use crate::Core;
+
+mod http;
+#[cfg(test)]
+mod tests;
+
+/// How long leadership of the rollup writer lasts without being renewed
+const LEADER_TTL: Duration = Duration::from_secs(30);
+
+/// Fields of a metrics message describing where it came from rather than counting anything
+const IDENTITY_FIELDS: [&str; 3] = ["type", "name", "host"];
+
+pub async fn main(core: Core) -> Result<()> {
+    let mut tasks = tokio::task::JoinSet::new();
+    let aggregator = MetricsAggregator::new(core).await?;
+    aggregator.core.running.install_terminate_handler(false)?;
+    aggregator.start(&mut tasks).await?;
+    while let Some(task) = tasks.join_next().await {
+        task?;
+    }
+    Ok(())
+}
+
+/// Counters summed per component over one export interval
+#[derive(Debug, Default, PartialEq)]
+pub struct Rollup {
+    /// Hosts that reported during the interval
+    pub hosts: BTreeSet<String>,
+    pub counters: BTreeMap<String, f64>,
+}
+
+/// Document written to elasticsearch for each component every export interval
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RollupDocument {
+    pub timestamp: DateTime<Utc>,
+    #[serde(rename = "type")]
+    pub counter_type: String,
+    pub name: String,
+    /// Number of hosts that reported during the interval
+    pub instances: usize,
+    #[serde(flatten)]
+    pub counters: BTreeMap<String, f64>,
+}
+
+/// Counters received so far, kept separate from the daemon so it can be used without any connections
+#[derive(Debug, Default)]
+pub struct MetricsState {
+    /// Running totals by prometheus metric name, then by (name, host)
+    totals: BTreeMap<String, BTreeMap<(String, String), f64>>,
+    /// Counts since the last rollup by (type, name)
+    rollups: BTreeMap<(String, String), Rollup>,
+}
+
+impl MetricsState {
+    /// Add the counters in a message from the metrics channel, returns false if it wasn't a metrics message
+    pub fn record(&mut self, message: &Value) -> bool {
+        let Some(fields) = message.as_object() else { return false };
+        let Some(counter_type) = fields.get("type").and_then(Value::as_str) else { return false };
+        let name = fields.get("name").and_then(Value::as_str).unwrap_or(counter_type);
+        let host = fields.get("host").and_then(Value::as_str).unwrap_or("unknown");
+
+        let rollup = self.rollups.entry((counter_type.to_owned(), name.to_owned())).or_default();
+        rollup.hosts.insert(host.to_owned());
+
+        for (field, value) in fields {
+            if IDENTITY_FIELDS.contains(&field.as_str()) {
+                continue
+            }
+            // counters are numbers, anything else in the message is left out
+            let Some(value) = value.as_f64() else { continue };
+            *self.totals.entry(metric_name(counter_type, field)).or_default()
+                .entry((name.to_owned(), host.to_owned())).or_default() += value;
+            *rollup.counters.entry(field.clone()).or_default() += value;
+        }
+        true
+    }
+
+    /// Write the running totals in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for (metric, series) in &self.totals {
+            let _ = writeln!(output, "# TYPE {metric} counter");
+            for ((name, host), value) in series {
+                let _ = writeln!(output, "{metric}{{name=\"{}\",host=\"{}\"}} {value}", escape_label(name), escape_label(host));
+            }
+        }
+        output
+    }
+
+    /// Take the counts collected since the last call
+    pub fn take_rollups(&mut self) -> BTreeMap<(String, String), Rollup> {
+        std::mem::take(&mut self.rollups)
+    }
+}
+
+/// Build the prometheus name for a counter, only letters, digits and underscores are allowed
+fn metric_name(counter_type: &str, field: &str) -> String {
+    let name = format!("assemblyline_{counter_type}_{field}_total");
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+/// Escape a label value for the prometheus text format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub struct MetricsAggregator {
+    core: Core,
+    state: Mutex<MetricsState>,
+    /// Cluster rollups are written to, if one is configured
+    rollup_store: Option<ElasticHelper>,
+    rollup_interval: Duration,
+}
+
+impl MetricsAggregator {
+    pub async fn new(core: Core) -> Result<Arc<Self>> {
+        let config = &core.config.core.metrics;
+        let rollup_store = match config.elasticsearch.hosts.as_ref().and_then(|hosts| hosts.first()) {
+            Some(host) => {
+                let ca = config.elasticsearch.host_certificates.as_ref().map(|cert| cert.as_bytes());
+                Some(ElasticHelper::connect(host, false, ca, false).await.context("connecting to metrics elasticsearch")?)
+            },
+            None => None,
+        };
+
+        Ok(Arc::new(Self {
+            rollup_interval: Duration::from_secs(config.export_interval.max(1) as u64),
+            core,
+            state: Default::default(),
+            rollup_store,
+        }))
+    }
+
+    pub async fn start(self: &Arc<Self>, pool: &mut tokio::task::JoinSet<()>) -> Result<()> {
+        // Launch the http interface
+        let bind_address = crate::config::load_bind_address()?;
+        let tls_config = crate::config::TLSConfig::load().await?;
+        pool.spawn(http::start(bind_address, tls_config, self.clone()));
+
+        // subscribe before returning so no message sent after start is missed
+        let messages = self.core.redis_metrics.subscribe_json::<Value>(METRICS_CHANNEL.to_owned()).await;
+        let this = self.clone();
+        pool.spawn(async move {
+            if let Err(err) = this.collect(messages).await {
+                error!("Error in metrics collection: {err}");
+            }
+        });
+
+        if self.rollup_store.is_some() {
+            let this = self.clone();
+            pool.spawn(async move {
+                while let Err(err) = this.run_rollups().await {
+                    error!("Error writing metrics rollups: {err}");
+                    this.core.sleep(this.rollup_interval).await;
+                }
+            });
+        } else {
+            info!("No elasticsearch configured for metrics, rollups will not be written");
+        }
+        Ok(())
+    }
+
+    /// Read the current totals in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.state.lock().render()
+    }
+
+    async fn collect(&self, mut messages: tokio::sync::mpsc::Receiver<Option<Value>>) -> Result<()> {
+        loop {
+            let message = tokio::select! {
+                message = messages.recv() => message,
+                _ = self.core.running.wait_for(false) => return Ok(()),
+            };
+
+            match message {
+                Some(Some(message)) => {
+                    if !self.state.lock().record(&message) {
+                        warn!("Ignoring message on metrics channel without a counter type");
+                    }
+                },
+                // a reconnect or message that couldn't be parsed, already logged by the listener
+                Some(None) => continue,
+                None => anyhow::bail!("Metrics channel subscription closed"),
+            }
+        }
+    }
+
+    async fn run_rollups(&self) -> Result<()> {
+        let election = self.core.redis_volatile.leader_election("metrics-rollups".to_owned(), LEADER_TTL);
+        tokio::select! {
+            result = election.lead(|_| self.rollup_loop()) => result?,
+            _ = self.core.running.wait_for(false) => Ok(()),
+        }
+    }
+
+    async fn rollup_loop(&self) -> Result<()> {
+        // counts gathered while another aggregator was writing have already been covered by it
+        self.state.lock().take_rollups();
+        while self.core.sleep(self.rollup_interval).await {
+            self.write_rollups().await?;
+        }
+        Ok(())
+    }
+
+    /// Write the counts collected since the last call to elasticsearch
+    async fn write_rollups(&self) -> Result<()> {
+        let Some(store) = &self.rollup_store else { return Ok(()) };
+        let rollups = self.state.lock().take_rollups();
+        let timestamp = Utc::now();
+        for ((counter_type, name), rollup) in rollups {
+            let index = format!("al_metrics_{counter_type}").to_lowercase();
+            let document = RollupDocument {
+                timestamp,
+                instances: rollup.hosts.len(),
+                counters: rollup.counters,
+                counter_type,
+                name,
+            };
+            store.index_document(&index, &document).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use assemblyline_models::messages::dispatcher_heartbeat::Metrics as DispatcherMetrics;
+use serde_json::json;
+
+use crate::metrics_aggregator::{MetricsAggregator, MetricsState};
+use crate::Core;
+
+#[test]
+fn test_prometheus_render() {
+    let mut state = MetricsState::default();
+    assert!(state.record(&json!({"type": "ingester", "name": "ingester", "host": "a", "submissions_ingested": 3, "cpu_seconds": 0.5})));
+    assert!(state.record(&json!({"type": "ingester", "name": "ingester", "host": "a", "submissions_ingested": 2, "cpu_seconds": 0.25})));
+    assert!(state.record(&json!({"type": "ingester", "name": "ingester", "host": "b", "submissions_ingested": 1, "status": "ok"})));
+    assert!(state.record(&json!({"type": "service", "name": "Extract\"or", "host": null, "execute": 4})));
+    assert!(!state.record(&json!({"name": "untyped", "execute": 1})));
+    assert!(!state.record(&json!([1, 2, 3])));
+
+    assert_eq!(state.render(), concat!(
+        "# TYPE assemblyline_ingester_cpu_seconds_total counter\n",
+        "assemblyline_ingester_cpu_seconds_total{name=\"ingester\",host=\"a\"} 0.75\n",
+        "# TYPE assemblyline_ingester_submissions_ingested_total counter\n",
+        "assemblyline_ingester_submissions_ingested_total{name=\"ingester\",host=\"a\"} 5\n",
+        "assemblyline_ingester_submissions_ingested_total{name=\"ingester\",host=\"b\"} 1\n",
+        "# TYPE assemblyline_service_execute_total counter\n",
+        "assemblyline_service_execute_total{name=\"Extract\\\"or\",host=\"unknown\"} 4\n",
+    ));
+}
+
+#[test]
+fn test_rollups() {
+    let mut state = MetricsState::default();
+    state.record(&json!({"type": "service", "name": "a", "host": "1", "execute": 4, "cache_hit": 1}));
+    state.record(&json!({"type": "service", "name": "a", "host": "2", "execute": 2}));
+    state.record(&json!({"type": "service", "name": "b", "host": "1", "execute": 1}));
+
+    let rollups = state.take_rollups();
+    assert_eq!(rollups.len(), 2);
+    let a = &rollups[&("service".to_owned(), "a".to_owned())];
+    assert_eq!(a.hosts.len(), 2);
+    assert_eq!(a.counters, [("cache_hit".to_owned(), 1.0), ("execute".to_owned(), 6.0)].into());
+
+    // rollups restart every interval while the totals keep going
+    assert!(state.take_rollups().is_empty());
+    state.record(&json!({"type": "service", "name": "a", "host": "1", "execute": 1}));
+    assert_eq!(state.take_rollups()[&("service".to_owned(), "a".to_owned())].counters, [("execute".to_owned(), 1.0)].into());
+    assert!(state.render().contains("assemblyline_service_execute_total{name=\"a\",host=\"1\"} 5\n"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_collect_published_metrics() {
+    let (core, _guard) = Core::test_setup().await;
+    let aggregator = MetricsAggregator::new(core.clone()).await.unwrap();
+    let mut pool = tokio::task::JoinSet::new();
+    aggregator.start(&mut pool).await.unwrap();
+
+    let metrics = DispatcherMetrics { submissions_completed: 3, ..Default::default() };
+    core.export_metrics_once("dispatcher", &metrics, Some("host-1"), None).await.unwrap();
+
+    let expected = "assemblyline_dispatcher_submissions_completed_total{name=\"dispatcher\",host=\"host-1\"} 3\n";
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !aggregator.render().contains(expected) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.unwrap();
+}
@@ -9,7 +9,7 @@ use super::Plumber;
 use anyhow::{Context, Result};
 use log::{error, info};
 use poem::listener::{Listener, OpensslTlsConfig, TcpListener};
-use poem::{get, handler, EndpointExt, Route, Server};
+use poem::{get, handler, Endpoint, EndpointExt, Route, Server};
 
 /// API endpoint for null status that is always available
 #[handler]
@@ -24,11 +24,17 @@ pub async fn start<Dispatch: DispatchCapable>(bind_address: std::net::SocketAddr
 }
 
 
-async fn _start<Dispatch: DispatchCapable>(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, plumber: Arc<Plumber<Dispatch>>) -> Result<()> {
-    let app = Route::new()
+/// Routes served by the plumber
+pub(super) fn app<Dispatch: DispatchCapable>(plumber: Arc<Plumber<Dispatch>>) -> impl Endpoint {
+    Route::new()
         .at("/alive", get(get_status))
-        .data(plumber.clone())
-        .with(LoggerMiddleware);
+        .nest("/healthz", crate::health::api(plumber.core.clone()))
+        .data(plumber)
+        .with(LoggerMiddleware)
+}
+
+async fn _start<Dispatch: DispatchCapable>(bind_address: std::net::SocketAddr, tls: Option<TLSConfig>, plumber: Arc<Plumber<Dispatch>>) -> Result<()> {
+    let app = app(plumber.clone());
 
     let listener = TcpListener::bind(bind_address);
     let tls_config = match tls {
@@ -4,8 +4,12 @@
 //! emptied. The status of all the services will be periodically checked and any service that is found to be
 //! disabled or deleted for which a service queue exists, the dispatcher will be informed that the task(s)
 //! had an error.
+//!
+//! Several plumbers can run at once. Each of the cleanup loops is only run by whichever plumber is elected
+//! leader for that loop, the others take over if the leader stops.
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
 use std::time::Duration;
@@ -14,6 +18,7 @@ use anyhow::Result;
 use assemblyline_models::datastore::apikey::{Apikey, get_apikey_id};
 use assemblyline_models::datastore::user::{AclCatagory, UserRole, UserType, load_roles, load_roles_form_acls};
 use assemblyline_models::Readable;
+use assemblyline_models::config::QueueFairness;
 use assemblyline_models::datastore::Service;
 use assemblyline_models::messages::changes::ServiceChange;
 use assemblyline_models::types::{JsonMap, ServiceName};
@@ -24,6 +29,7 @@ use serde::Deserialize;
 use tokio::task::JoinHandle;
 
 use crate::constants::{service_queue_name, ServiceStage, SERVICE_QUEUE_PREFIX};
+use crate::core_dispatcher::ServiceQueue;
 use crate::dispatcher::client::{DispatchCapable, DispatchClient};
 use crate::elastic::collection::OperationBatch;
 use crate::elastic::Elastic;
@@ -36,6 +42,11 @@ mod tests;
 const DAY: TimeDelta = TimeDelta::days(1);
 const TASK_DELETE_CHUNK: u64 = 10000;
 
+/// How long leadership of a cleanup loop lasts without being renewed
+const LEADER_TTL: Duration = Duration::from_secs(30);
+/// Tenant given to tasks moved between service queue layouts, the submitter is not kept on the task
+const MIGRATED_TENANT: &str = "migrated";
+
 pub async fn main(core: Core) -> Result<()> {
     let mut tasks = tokio::task::JoinSet::new();
     let plumber = Plumber::new(core, None, None).await?;
@@ -61,6 +72,16 @@ struct ServiceWorker {
     task: JoinHandle<()>,
 }
 
+struct StopWorkers<'a>(&'a Mutex<HashMap<ServiceName, ServiceWorker>>);
+
+impl Drop for StopWorkers<'_> {
+    fn drop(&mut self) {
+        for (_, worker) in self.0.lock().drain() {
+            worker.stop.set(true);
+        }
+    }
+}
+
 impl Plumber<DispatchClient> {
     pub async fn new(core: Core, delay: Option<Duration>, user: Option<&str>) -> Result<Arc<Self>> {
         Ok(Arc::new(Self {
@@ -97,11 +118,21 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         let tls_config = crate::config::TLSConfig::load().await?;
         pool.spawn(http::start(bind_address, tls_config, self.clone()));
 
-        // launch a cleanup task to fix old user api keys
+        // launch a cleanup task to fix old user api keys, only one plumber needs to do this
         let this = self.clone();
         pool.spawn(async move {
-            if let Err(err) = this.user_apikey_cleanup().await {
-                error!("Error in API key cleanup: {err}");
+            let lock = this.core.redis_volatile.lock("plumber-apikey-cleanup".to_owned(), LEADER_TTL);
+            match lock.try_acquire().await {
+                Ok(Some(guard)) => {
+                    if let Err(err) = this.user_apikey_cleanup().await {
+                        error!("Error in API key cleanup: {err}");
+                    }
+                    if let Err(err) = guard.release().await {
+                        error!("Error releasing API key cleanup lock: {err}");
+                    }
+                },
+                Ok(None) => info!("API key cleanup is being run by another plumber"),
+                Err(err) => error!("Error in API key cleanup: {err}"),
             }
         });
 
@@ -125,7 +156,7 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         // Start a task cleanup thread
         let this = self.clone();
         pool.spawn(async move {
-            while let Err(err) = this.cleanup_old_tasks().await {
+            while let Err(err) = this.run_as_leader("task-cleanup", |_| this.cleanup_old_tasks()).await {
                 error!("Error in datastore task cleanup: {err}");
             }
         });
@@ -133,26 +164,51 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         // Start a notification queue cleanup thread
         let this = self.clone();
         pool.spawn(async move {
-            while let Err(err) = this.cleanup_notification_queues().await {
+            while let Err(err) = this.run_as_leader("notification-queues", |_| this.cleanup_notification_queues()).await {
                 error!("Error in redis notification queue cleanup: {err}");
             }
         });
 
+        // Copy blobs to the filestore transports that are missing them
+        if self.core.config.core.plumber.filestore_reconcile_interval > 0 {
+            let this = self.clone();
+            pool.spawn(async move {
+                while let Err(err) = this.run_as_leader("filestore-reconcile", |_| this.reconcile_filestore()).await {
+                    error!("Error in filestore reconcile: {err}");
+                }
+            });
+        }
+
         // Whatch for service queues that can be managed
         let this = self.clone();
         pool.spawn(async move {
-            while let Err(err) = this.service_queue_plumbing().await {
+            while let Err(err) = this.run_as_leader("service-queues", |_| this.service_queue_plumbing()).await {
                 error!("Error in service queue cleanup: {err}");
             }
         });
         Ok(())
     }
 
+    /// Run a loop only while this plumber is the leader for it, returns when the plumber stops
+    async fn run_as_leader<Fut: Future<Output=Result<()>>>(&self, name: &str, job: impl FnMut(u64) -> Fut) -> Result<()> {
+        let election = self.core.redis_volatile.leader_election(format!("plumber-{name}"), LEADER_TTL);
+        tokio::select! {
+            result = election.lead(job) => result?,
+            _ = self.core.running.wait_for(false) => Ok(()),
+        }
+    }
+
     async fn service_queue_plumbing(self: &Arc<Self>) -> Result<()> {
         info!("Starting service queue plumbing.");
-        // Get an initial list of all the service queues
+        // the queue workers belong to this run of the loop, stop them if it ends or loses leadership
+        let _workers = StopWorkers(&self.flush_tasks);
+        let heartbeat = self.core.health.register("service_queue_plumbing", self.delay);
+
+        // Get an initial list of all the service queues, in either layout
         let mut service_queues: HashMap<ServiceName, Option<Service>> = Default::default();
-        for queue_name in self.core.redis_volatile.keys(&service_queue_name("*")).await? {
+        let mut queue_names = self.core.redis_volatile.keys(&service_queue_name("*")).await?;
+        queue_names.extend(self.core.redis_volatile.fair_queue_names(&service_queue_name("*")).await?);
+        for queue_name in queue_names {
             if let Some(name) = queue_name.strip_prefix(SERVICE_QUEUE_PREFIX) {
                 service_queues.insert(name.into(), None);
             }
@@ -162,6 +218,7 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         let service_stage_hash = self.core.services.get_service_stage_hash().clone();
 
         while self.core.running.read() {
+            heartbeat.beat();
             // Reset the status of the service queues
             for row in service_queues.values_mut() {
                 *row = None;
@@ -172,7 +229,18 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
                 service_queues.insert(service.name, Some(service));
             }
 
+            // tasks queued by dispatchers still using the other layout are only found by them where they are
+            let drain = !self.inactive_layout_in_use().await?;
+
             for (service_name, service) in service_queues.iter() {
+                if drain {
+                    let moved = self.drain_inactive_layout(service_name).await?;
+                    if moved > 0 {
+                        info!("Moved {moved} tasks for {service_name} left in the queue layout used before fairness was changed");
+                    }
+                }
+                self.sync_queue_weights(service_name).await?;
+
                 // For disabled or othewise unavailable services purge the queue
                 let current_stage = service_stage_hash.get(service_name).await?.unwrap_or(ServiceStage::Running);
                 let disabled = service.as_ref().map(|s|!s.enabled).unwrap_or(true); // either enabled is false or the service info is none
@@ -234,9 +302,64 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         Ok(())
     }
 
+    /// Check if any running dispatcher uses a different service queue layout than the one configured.
+    /// Dispatchers that haven't recorded their layout yet are counted as using the other one.
+    async fn inactive_layout_in_use(&self) -> Result<bool> {
+        let fair = ServiceQueue::is_fair(self.core.config.core.dispatcher.service_queue_fairness);
+        let layouts = self.core.dispatcher_queue_layouts().items().await?;
+        for instance in self.core.dispatcher_instances().await? {
+            match layouts.get(&instance) {
+                Some(layout) if ServiceQueue::is_fair(*layout) == fair => {},
+                _ => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Give tenants the share of a fair service queue set in the configuration, returning any others to the default
+    async fn sync_queue_weights(&self, service_name: &str) -> Result<()> {
+        let ServiceQueue::Fair(queue) = self.core.get_service_queue(service_name) else { return Ok(()) };
+        let weights = &self.core.config.core.dispatcher.service_queue_weights;
+        let current = queue.weights().await?;
+        for tenant in current.keys() {
+            if !weights.contains_key(tenant) {
+                queue.clear_weight(tenant).await?;
+            }
+        }
+        for (tenant, weight) in weights {
+            if current.get(tenant) != Some(weight) {
+                queue.set_weight(tenant, *weight).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move tasks from the service queue layout that isn't configured into the one that is.
+    /// Tasks are left in the other layout when queue fairness is turned on or off, nothing would read them otherwise.
+    /// Only called once no dispatcher uses the other layout, moved tasks get new keys that only the new layout knows.
+    async fn drain_inactive_layout(&self, service_name: &str) -> Result<u64> {
+        let inactive = match self.core.config.core.dispatcher.service_queue_fairness {
+            QueueFairness::Disabled => QueueFairness::Submitter,
+            _ => QueueFairness::Disabled,
+        };
+        let inactive = ServiceQueue::open(&self.core.redis_volatile, service_name, inactive);
+        let active = self.core.get_service_queue(service_name);
+
+        let mut moved = 0;
+        while let Some(task) = inactive.pop(false).await? {
+            // the submitter isn't kept on the task, moved tasks share a turn between them
+            active.push(MIGRATED_TENANT, task.priority as f64, &task).await?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
     async fn cleanup_notification_queues(&self) -> Result<()> {
         info!("Cleaning up notification queues for old messages...");
+        let interval = Duration::from_secs(self.core.config.core.plumber.notification_queue_interval);
+        let heartbeat = self.core.health.register("notification_queues", interval);
         while self.core.is_running() {
+            heartbeat.beat();
             // Finding all possible notification queues
             let keys = self.core.redis_persistant.keys("nq-*").await?;
             if keys.is_empty() {
@@ -315,7 +438,7 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
             }
 
             // wait for next run
-            self.core.sleep(Duration::from_secs(self.core.config.core.plumber.notification_queue_interval)).await;
+            self.core.sleep(interval).await;
         }
         info!("Done cleaning up notification queues");
         Ok(())
@@ -323,7 +446,9 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
 
     async fn cleanup_old_tasks(&self) -> Result<()> {
         info!("Cleaning up task index for old completed tasks...");
+        let heartbeat = self.core.health.register("task_cleanup", self.delay);
         while self.core.running.read() {
+            heartbeat.beat();
             let deleted = self.datastore.task_cleanup(Some(DAY), Some(TASK_DELETE_CHUNK)).await?;
             if deleted == 0 {
                 self.core.sleep(self.delay).await;
@@ -335,6 +460,27 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
         Ok(())
     }
 
+    /// Compare the transports of the filestore now and then, copying blobs to those missing them.
+    /// A filestore with a single transport has nothing to compare.
+    async fn reconcile_filestore(&self) -> Result<()> {
+        if self.core.filestore.health().len() < 2 {
+            return Ok(())
+        }
+        let interval = Duration::from_secs(self.core.config.core.plumber.filestore_reconcile_interval);
+        let heartbeat = self.core.health.register("filestore_reconcile", interval);
+        while self.core.is_running() {
+            heartbeat.beat();
+            let report = self.core.filestore.reconcile().await?;
+            for (name, error) in &report.failed {
+                warn!("Could not copy [{name}] to every filestore transport: {error}");
+            }
+            // the time spent reconciling doesn't count against the wait for the next one
+            heartbeat.beat();
+            self.core.sleep(interval).await;
+        }
+        Ok(())
+    }
+
     async fn watch_service(self: Arc<Self>, service_name: ServiceName, stop_signal: Arc<Flag>, limit: Arc<AtomicU32>) {
         if let Err(err) = self._watch_service(service_name, stop_signal, limit).await {
             error!("service watch queue crashed with: {err}");
@@ -479,9 +625,14 @@ impl<Dispatch: DispatchCapable + Send + Sync> Plumber<Dispatch> {
             // Commit changes made to indices
             self.datastore.apikey.commit(None).await?;
 
-            // // Update permissions for API keys based on submission customization
-            // self.datastore.apikey.update_by_query('roles:"submission_create" AND NOT roles:"submission_customize"',
-            //                                       [(self.datastore.apikey.UPDATE_APPEND, 'roles', 'submission_customize')])
+            // Update permissions for API keys based on submission customization
+            let mut operations = OperationBatch::default();
+            operations.append("roles".to_owned(), serde_json::json!(UserRole::SubmissionCustomize));
+            let task = self.datastore.apikey.update_by_query(r#"roles:"submission_create" AND NOT roles:"submission_customize""#, operations, vec![], None, None).await?;
+            let status = task.wait().await?;
+            if status.updated > 0 {
+                info!("Added submission customization to {} API keys", status.updated);
+            }
         }
 
         Ok(())
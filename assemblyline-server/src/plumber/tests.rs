@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use assemblyline_models::config::QueueFairness;
 use assemblyline_models::messages::task::Task;
 use assemblyline_models::types::{JsonMap, ServiceName};
 use reqwest::Method;
@@ -9,9 +10,74 @@ use crate::elastic::collection::Collection;
 use crate::elastic::request::Request;
 use crate::elastic::responses;
 use crate::plumber::Plumber;
-use crate::services::test::{dummy_service, setup_services_and_core};
+use crate::core_dispatcher::ServiceQueue;
+use crate::services::test::{dummy_service, setup_core_with_config, setup_services, setup_services_and_core};
 
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fair_service_queues() {
+    // Setup so that service 'a' is the only one, with fair service queues
+    let name = ServiceName::from("a");
+    let services = [
+        (name, dummy_service("a", "core", None, None, None, None))
+    ].into();
+    let (core, guard) = setup_core_with_config(|config| {
+        config.services.stages = vec!["pre".to_string(), "core".to_string(), "post".to_string()];
+        config.core.dispatcher.service_queue_fairness = QueueFairness::Submitter;
+        config.core.dispatcher.service_queue_weights = [("heavy".to_owned(), 2.0)].into();
+    }).await;
+    let (core, _guard) = setup_services(core, guard, services).await;
+
+    // a task left in the plain layout from before fairness was enabled
+    let waiting: Task = rand::random();
+    let plain = ServiceQueue::open(&core.redis_volatile, "a", QueueFairness::Disabled);
+    plain.push("test", 0.0, &waiting).await.unwrap();
+
+    // a fair queue for a service that isn't service a
+    let task: Task = rand::random();
+    let queue = core.get_service_queue("not-service-a");
+    queue.push("test", 0.0, &task).await.unwrap();
+
+    // a dispatcher started before fairness was enabled still holds keys into the plain layout
+    let directory = core.dispatcher_instances_table();
+    directory.set("old-dispatcher", &chrono::Utc::now().timestamp()).await.unwrap();
+    core.dispatcher_queue_layouts().set("old-dispatcher", &QueueFairness::Disabled).await.unwrap();
+
+    // start the plumber
+    let active = core.get_service_queue("a");
+    let plumber = Plumber::new_mocked(core, Some(Duration::from_millis(100)), Some("plumber_fair")).await.unwrap();
+    let mut pool = tokio::task::JoinSet::new();
+    plumber.start(&mut pool).await.unwrap();
+
+    // the fair queue is found and its task cancelled
+    let failed = plumber.dispatch_client.failed().await;
+    assert_eq!(queue.length().await.unwrap(), 0);
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].sid, task.sid);
+
+    // give the plumber a few passes over the services
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // the configured weights are given to the tenants
+    let ServiceQueue::Fair(fair) = &active else { panic!("service queue should be fair") };
+    assert_eq!(fair.weights().await.unwrap(), [("heavy".to_owned(), 2.0)].into());
+
+    // the waiting task stays put until the old dispatcher is gone
+    assert_eq!(plain.length().await.unwrap(), 1);
+    assert_eq!(active.length().await.unwrap(), 0);
+    directory.pop("old-dispatcher").await.unwrap();
+
+    // then it is moved where the service will look for it
+    let start = std::time::Instant::now();
+    while active.length().await.unwrap() == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(plain.length().await.unwrap(), 0);
+    assert_eq!(active.length().await.unwrap(), 1);
+    assert_eq!(active.pop(false).await.unwrap().unwrap().sid, waiting.sid);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_expire_missing_service() {
     // Setup so that service 'a' is the only one
@@ -24,7 +90,7 @@ async fn test_expire_missing_service() {
     // enqueue a task for a service that isn't service a
     let task: Task = rand::random();
     let queue = core.get_service_queue("not-service-a");
-    queue.push(0.0, &task).await.unwrap();
+    queue.push("test", 0.0, &task).await.unwrap();
 
     // start the plumber
     let plumber = Plumber::new_mocked(core, Some(Duration::from_millis(100)), Some("plumber_1")).await.unwrap();
@@ -38,6 +104,37 @@ async fn test_expire_missing_service() {
     assert_eq!(failed[0].sid, task.sid);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wait_for_leadership() {
+    let name = ServiceName::from("a");
+    let services = [
+        (name, dummy_service("a", "core", None, None, None, None))
+    ].into();
+    let (core, _guard) = setup_services_and_core(services).await;
+
+    // another plumber is already handling the service queues
+    let election = core.redis_volatile.leader_election("plumber-service-queues".to_owned(), Duration::from_secs(30));
+    let leader = election.campaign().await.unwrap();
+
+    let task: Task = rand::random();
+    let queue = core.get_service_queue("not-service-a");
+    queue.push("test", 0.0, &task).await.unwrap();
+
+    let plumber = Plumber::new_mocked(core, Some(Duration::from_millis(100)), Some("plumber_4")).await.unwrap();
+    let mut pool = tokio::task::JoinSet::new();
+    plumber.start(&mut pool).await.unwrap();
+
+    // the queue is left alone until the other plumber steps down
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(queue.length().await.unwrap(), 1);
+    leader.release().await.unwrap();
+
+    let failed = plumber.dispatch_client.failed().await;
+    assert_eq!(queue.length().await.unwrap(), 0);
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].sid, task.sid);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_flush_paused_queues() {
     // Setup so that service 'a' is the only one
@@ -50,7 +147,7 @@ async fn test_flush_paused_queues() {
     // enqueue a task for a service that isn't service a
     let task: Task = rand::random();
     let queue = core.get_service_queue("a");
-    queue.push(0.0, &task).await.unwrap();
+    queue.push("test", 0.0, &task).await.unwrap();
 
     // start the plumber
     let plumber = Plumber::new_mocked(core.clone(), Some(Duration::from_millis(100)), Some("plumber_2")).await.unwrap();
@@ -180,4 +277,10 @@ async fn test_flush_paused_queues() {
     //     # Full service spec should be preserved in default profile (along with others by default if there's no restricted parameters)
     //     assert settings['service_spec'] == {'AVClass': {'include_malpedia_dataset': False}}
     //     assert settings['ttl'] == 0
-// }
\ No newline at end of file
+// }
+#[tokio::test]
+async fn test_healthz() {
+    let (core, _guard) = setup_services_and_core(Default::default()).await;
+    let plumber = Plumber::new_mocked(core.clone(), None, Some("plumber_health")).await.unwrap();
+    crate::health::test::check_healthz(&core, super::http::app(plumber)).await;
+}
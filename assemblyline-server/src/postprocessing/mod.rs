@@ -36,7 +36,7 @@ mod search;
 #[cfg(test)]
 mod tests;
 
-pub use self::search::{Query, parse};
+pub use self::search::{Query, parse, parse_for};
 
 
 const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
@@ -161,6 +161,17 @@ impl Query {
         }
     }
 
+    /// Check if the query contains terms not tied to a field, these are matched against the
+    /// full text fields of a submission.
+    pub fn uses_unqualified_search(&self) -> bool {
+        match self {
+            Query::And(parts) | Query::Or(parts) => parts.iter().any(|part| part.uses_unqualified_search()),
+            Query::Not(part) => part.uses_unqualified_search(),
+            Query::MatchAny(_) | Query::RegexAny(_) => true,
+            Query::MatchField(..) | Query::FieldExists(_) => false,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn test(&self, data: &serde_json::Value) -> Result<bool> {
         match self {
@@ -707,6 +718,18 @@ fn check_field(field: &[String], options: &[struct_metadata::Entry<ElasticMeta>]
 
 
 pub fn parse(query: &str) -> Result<Query, ParsingError> {
+    parse_with_fields(query, submission_fields())
+}
+
+/// Parse a query with its fields checked against a model other than submissions
+pub fn parse_for<Model: Described<ElasticMeta>>(query: &str) -> Result<Query, ParsingError> {
+    let struct_metadata::Kind::Struct { children, .. } = Model::metadata().kind else {
+        return Err(ParsingError::SubmissionFilterUsesUnknownFields(vec![]))
+    };
+    parse_with_fields(query, &children)
+}
+
+fn parse_with_fields(query: &str, model_fields: &[struct_metadata::Entry<ElasticMeta>]) -> Result<Query, ParsingError> {
     // jsut make sure we can parse the query at all
     let (remain, query) = match super::parsing::expression(query) {
         Ok(row) => row,
@@ -718,7 +741,6 @@ pub fn parse(query: &str) -> Result<Query, ParsingError> {
 
     // compare the query against our field maps to see if its valid
     let mut extra_fields = vec![];
-    let submission_fields = submission_fields();
     'fields: for field in query.list_fields() {
         // check if its in a special field, TODO replace this with actual tag layout when available
         if let Some(root) = field.first() {
@@ -727,8 +749,8 @@ pub fn parse(query: &str) -> Result<Query, ParsingError> {
             }
         }
 
-        // check against submission structure
-        if check_field(&field, submission_fields) {
+        // check against the model structure
+        if check_field(&field, model_fields) {
             continue 'fields
         }
 
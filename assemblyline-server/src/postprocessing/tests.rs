@@ -9,7 +9,7 @@ use rand::Rng;
 
 use crate::{postprocessing::{search::CacheAvailabilityStatus, ActionWorker, ParsingError, SubmissionFilter}, Core};
 
-use super::parse;
+use super::{parse, parse_for};
 
 
 #[test]
@@ -142,6 +142,21 @@ fn test_bad_field_detection() {
     assert_eq!(parse("max_score_pain:found").unwrap_err(), ParsingError::SubmissionFilterUsesUnknownFields(vec!["max_score_pain".into()]));
 }
 
+#[test]
+fn test_other_model_fields() {
+    use assemblyline_models::datastore::Alert;
+    assert!(parse("al.score:>=500").is_err());
+
+    let fltr = parse_for::<Alert>("al.score:>=500 AND NOT status:*").unwrap();
+    assert!(fltr.test(&json!({"al": {"score": 600}})).unwrap());
+    assert!(!fltr.test(&json!({"al": {"score": 600}, "status": "TRIAGE"})).unwrap());
+    assert!(!fltr.test(&json!({"al": {"score": 100}})).unwrap());
+    assert!(!fltr.uses_unqualified_search());
+
+    assert!(parse_for::<Alert>("al.score:>=500 AND things").unwrap().uses_unqualified_search());
+    assert_eq!(parse_for::<Alert>("max_score:>100").unwrap_err(), ParsingError::SubmissionFilterUsesUnknownFields(vec!["max_score".into()]));
+}
+
 #[test]
 fn test_tag_filters() {
     let sub = json!({"max_score": 100});
@@ -0,0 +1,40 @@
+//! Interface between the scaler and whatever system is actually running the service containers.
+
+use std::collections::HashMap;
+use std::future::Future;
+
+use anyhow::Result;
+use assemblyline_models::datastore::service::{DependencyConfig, DockerConfig, EnvironmentVariable};
+use assemblyline_models::types::ServiceName;
+
+/// Everything a controller needs to know to launch containers for a service
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceDeployment {
+    /// Name of the service
+    pub name: ServiceName,
+    /// Container the service itself runs in
+    pub container: DockerConfig,
+    /// Additional containers the service needs to be running before it is started
+    pub dependencies: HashMap<String, DependencyConfig>,
+    /// Environment variables added to every container on top of the ones in their configuration
+    pub environment: Vec<EnvironmentVariable>,
+    /// Labels added to every container on top of the ones in their configuration
+    pub labels: Vec<EnvironmentVariable>,
+}
+
+pub trait Controller: 'static + Send + Sync {
+    /// Make sure the dependency containers of a service are running
+    fn start_dependencies(&self, deployment: &ServiceDeployment) -> impl Future<Output=Result<()>> + Send;
+
+    /// Number of containers currently running for a service, not counting dependencies
+    fn get_running(&self, service_name: ServiceName) -> impl Future<Output=Result<u32>> + Send;
+
+    /// Start or stop containers until the given number of containers is running for a service
+    fn set_running(&self, deployment: &ServiceDeployment, count: u32) -> impl Future<Output=Result<()>> + Send;
+
+    /// Stop all the containers of a service, including its dependencies
+    fn stop(&self, service_name: ServiceName) -> impl Future<Output=Result<()>> + Send;
+
+    /// Names of all services that have containers managed by this controller
+    fn list_services(&self) -> impl Future<Output=Result<Vec<ServiceName>>> + Send;
+}
@@ -0,0 +1,288 @@
+//! Controller running service containers through the API socket of a local Docker or Podman daemon.
+//!
+//! Every container created is labelled with the service it belongs to, so the state of the
+//! deployment is read back from the container runtime rather than kept in memory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use assemblyline_models::datastore::service::{DockerConfig, PersistentVolume};
+use assemblyline_models::types::ServiceName;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::UnixStream;
+
+use super::controller::{Controller, ServiceDeployment};
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// Marks the containers created by the scaler
+const MANAGED_LABEL: &str = "assemblyline.scaler";
+/// Name of the service a container belongs to
+const SERVICE_LABEL: &str = "assemblyline.service";
+/// Name of the dependency a container is running, absent on the containers of the service itself
+const DEPENDENCY_LABEL: &str = "assemblyline.dependency";
+
+/// Seconds a service container is given to finish its current task when being stopped
+const STOP_TIMEOUT: u32 = 30;
+
+/// Summary of a container as returned when listing containers
+#[derive(Deserialize, Debug)]
+struct ContainerSummary {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "State", default)]
+    state: String,
+    #[serde(rename = "Labels", default)]
+    labels: HashMap<String, String>,
+}
+
+impl ContainerSummary {
+    fn is_running(&self) -> bool {
+        self.state == "running"
+    }
+
+    fn dependency(&self) -> Option<&str> {
+        self.labels.get(DEPENDENCY_LABEL).map(|name| name.as_str())
+    }
+}
+
+pub struct DockerController {
+    socket: PathBuf,
+    network: Option<String>,
+}
+
+impl DockerController {
+    /// Connect to the socket given, or the one named by DOCKER_HOST, and attach containers to `network`
+    pub fn new(socket: Option<PathBuf>, network: Option<String>) -> Self {
+        let socket = socket.unwrap_or_else(|| match std::env::var("DOCKER_HOST") {
+            Ok(host) => PathBuf::from(host.strip_prefix("unix://").unwrap_or(&host)),
+            Err(_) => PathBuf::from(DEFAULT_SOCKET),
+        });
+        Self { socket, network }
+    }
+
+    /// Send a single request to the container runtime, returning the body of successful responses
+    async fn request(&self, method: Method, path: &str, headers: &[(&str, String)], body: Option<serde_json::Value>) -> Result<Bytes> {
+        let stream = UnixStream::connect(&self.socket).await
+            .with_context(|| format!("connecting to container runtime at {}", self.socket.to_string_lossy()))?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("Container runtime connection closed: {err}");
+            }
+        });
+
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(path)
+            .header(hyper::header::HOST, "localhost");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(hyper::header::CONTENT_TYPE, "application/json");
+                Full::new(Bytes::from(serde_json::to_vec(&body)?))
+            },
+            None => Full::new(Bytes::new()),
+        };
+
+        let response = sender.send_request(request.body(body)?).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        // not modified is returned when starting or stopping a container already in that state
+        if !status.is_success() && status != hyper::StatusCode::NOT_MODIFIED {
+            bail!("{method} {path} failed with {status}: {}", String::from_utf8_lossy(&body).trim())
+        }
+        Ok(body)
+    }
+
+    /// List the containers the scaler created, optionally limited to a single service
+    async fn list_containers(&self, service_name: Option<ServiceName>) -> Result<Vec<ContainerSummary>> {
+        let mut labels = vec![format!("{MANAGED_LABEL}=true")];
+        if let Some(service_name) = service_name {
+            labels.push(format!("{SERVICE_LABEL}={service_name}"));
+        }
+        let filters = json!({"label": labels}).to_string();
+        let filters: String = url::form_urlencoded::byte_serialize(filters.as_bytes()).collect();
+        let body = self.request(Method::GET, &format!("/containers/json?all=true&filters={filters}"), &[], None).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Fetch the image of a container, using the registry credentials it was configured with
+    async fn pull_image(&self, config: &DockerConfig) -> Result<()> {
+        let mut headers = vec![];
+        if let Some(username) = &config.registry_username {
+            let auth = json!({
+                "username": username,
+                "password": config.registry_password.clone().unwrap_or_default(),
+            });
+            headers.push(("X-Registry-Auth", base64::engine::general_purpose::URL_SAFE.encode(auth.to_string())));
+        }
+
+        let image: String = url::form_urlencoded::byte_serialize(config.image.as_bytes()).collect();
+        let body = self.request(Method::POST, &format!("/images/create?fromImage={image}"), &headers, None).await?;
+
+        // Pull progress is streamed as json lines, failures are reported as a line with an error
+        for line in body.split(|byte| *byte == b'\n') {
+            if let Ok(progress) = serde_json::from_slice::<serde_json::Value>(line) {
+                if let Some(error) = progress.get("error").and_then(|error| error.as_str()) {
+                    bail!("Could not pull {}: {error}", config.image)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create and start a container
+    async fn launch(&self, name: &str, deployment: &ServiceDeployment, config: &DockerConfig, dependency: Option<(&str, &HashMap<String, PersistentVolume>)>) -> Result<()> {
+        let mut labels: BTreeMap<String, String> = BTreeMap::new();
+        for label in deployment.labels.iter().chain(config.labels.iter()) {
+            labels.insert(label.name.clone(), label.value.clone());
+        }
+        labels.insert(MANAGED_LABEL.to_owned(), "true".to_owned());
+        labels.insert(SERVICE_LABEL.to_owned(), deployment.name.to_string());
+
+        let mut binds = vec![];
+        if let Some((dependency_name, volumes)) = dependency {
+            labels.insert(DEPENDENCY_LABEL.to_owned(), dependency_name.to_owned());
+            for (volume_name, volume) in volumes {
+                binds.push(format!("{name}_{volume_name}:{}", volume.mount_path));
+            }
+        }
+
+        let environment: Vec<String> = deployment.environment.iter().chain(config.environment.iter())
+            .map(|variable| format!("{}={}", variable.name, variable.value))
+            .collect();
+
+        let mut body = json!({
+            "Image": config.image,
+            "Env": environment,
+            "Labels": labels,
+            "HostConfig": {
+                "Memory": config.ram_mb as i64 * 1024 * 1024,
+                "MemoryReservation": config.ram_mb_min as i64 * 1024 * 1024,
+                "NanoCpus": (config.cpu_cores as f64 * 1e9) as i64,
+                "Binds": binds,
+                "RestartPolicy": {"Name": "on-failure"},
+            },
+        });
+        if let Some(command) = &config.command {
+            body["Cmd"] = json!(command);
+        }
+        if let Some(network) = &self.network {
+            body["HostConfig"]["NetworkMode"] = json!(network);
+            // dependencies are reached by the service using their name
+            if let Some((dependency_name, _)) = dependency {
+                body["NetworkingConfig"] = json!({"EndpointsConfig": {network: {"Aliases": [dependency_name]}}});
+            }
+        }
+
+        let created = self.request(Method::POST, &format!("/containers/create?name={name}"), &[], Some(body)).await?;
+        let id = serde_json::from_slice::<serde_json::Value>(&created)?
+            .get("Id").and_then(|id| id.as_str()).unwrap_or(name).to_owned();
+        self.request(Method::POST, &format!("/containers/{id}/start"), &[], None).await?;
+        Ok(())
+    }
+
+    /// Stop a container and remove it
+    async fn remove(&self, container: &ContainerSummary) -> Result<()> {
+        if container.is_running() {
+            self.request(Method::POST, &format!("/containers/{}/stop?t={STOP_TIMEOUT}", container.id), &[], None).await?;
+        }
+        self.request(Method::DELETE, &format!("/containers/{}?force=true", container.id), &[], None).await?;
+        Ok(())
+    }
+}
+
+/// Container names only allow a limited set of characters
+fn container_name(service_name: ServiceName, suffix: &str) -> String {
+    let name: String = service_name.to_lowercase().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("{name}_{suffix}")
+}
+
+impl Controller for DockerController {
+    async fn start_dependencies(&self, deployment: &ServiceDeployment) -> Result<()> {
+        let containers = self.list_containers(Some(deployment.name)).await?;
+        for (dependency_name, dependency) in &deployment.dependencies {
+            let existing = containers.iter().find(|container| container.dependency() == Some(dependency_name.as_str()));
+            match existing {
+                Some(container) if container.is_running() => continue,
+                Some(container) => {
+                    self.request(Method::POST, &format!("/containers/{}/start", container.id), &[], None).await?;
+                },
+                None => {
+                    info!("Starting dependency {dependency_name} of {}", deployment.name);
+                    self.pull_image(&dependency.container).await?;
+                    let name = container_name(deployment.name, dependency_name);
+                    self.launch(&name, deployment, &dependency.container, Some((dependency_name, &dependency.volumes))).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_running(&self, service_name: ServiceName) -> Result<u32> {
+        let containers = self.list_containers(Some(service_name)).await?;
+        Ok(containers.iter().filter(|container| container.dependency().is_none() && container.is_running()).count() as u32)
+    }
+
+    async fn set_running(&self, deployment: &ServiceDeployment, count: u32) -> Result<()> {
+        let mut running = vec![];
+        for container in self.list_containers(Some(deployment.name)).await? {
+            if container.dependency().is_some() {
+                continue
+            }
+            if container.is_running() {
+                running.push(container);
+            } else {
+                // containers that have exited are replaced rather than restarted
+                if let Err(err) = self.remove(&container).await {
+                    warn!("Could not remove stopped container {}: {err}", container.id);
+                }
+            }
+        }
+
+        let count = count as usize;
+        if running.len() < count {
+            self.pull_image(&deployment.container).await?;
+            for _ in running.len()..count {
+                let suffix = format!("{:08x}", rand::rng().random::<u32>());
+                self.launch(&container_name(deployment.name, &suffix), deployment, &deployment.container, None).await?;
+            }
+        } else {
+            for container in &running[count..] {
+                self.remove(container).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&self, service_name: ServiceName) -> Result<()> {
+        for container in self.list_containers(Some(service_name)).await? {
+            self.remove(&container).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_services(&self) -> Result<Vec<ServiceName>> {
+        let mut services: Vec<ServiceName> = self.list_containers(None).await?.iter()
+            .filter_map(|container| container.labels.get(SERVICE_LABEL))
+            .map(|name| ServiceName::from(name.as_str()))
+            .collect();
+        services.sort_unstable();
+        services.dedup();
+        Ok(services)
+    }
+}
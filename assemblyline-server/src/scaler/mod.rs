@@ -0,0 +1,255 @@
+//! The scaler decides how many instances of each service should be running and has a
+//! controller make it so.
+//!
+//! Each enabled service gets its dependency containers started, then its instance count is
+//! adjusted based on the length of its queue and how busy its instances report themselves to be
+//! in the service status table. The number of instances is kept between the service's
+//! `min_instances` and its `licence_count`. Disabled services have all their containers stopped.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use assemblyline_models::config::ScalerServiceDefaults;
+use assemblyline_models::datastore::service::{EnvironmentVariable, Service};
+use assemblyline_models::types::ServiceName;
+use log::{debug, error, info};
+
+use crate::constants::{ServiceStage, ServiceStatus, SERVICE_STATE_HASH};
+use crate::Core;
+
+use self::controller::{Controller, ServiceDeployment};
+use self::docker::DockerController;
+
+pub mod controller;
+pub mod docker;
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+const SCALE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest interval counted towards the scaling pressure, so a stalled pass doesn't cause a jump
+const MAX_DELTA: f64 = 60.0;
+
+pub async fn main(core: Core, socket: Option<PathBuf>, network: Option<String>) -> Result<()> {
+    let mut scaler = Scaler::new(core, DockerController::new(socket, network));
+    scaler.core.running.install_terminate_handler(false)?;
+
+    while scaler.core.is_running() {
+        if let Err(err) = scaler.run_once().await {
+            error!("Error in scaler: {err:?}");
+            scaler.core.sleep(ERROR_BACKOFF).await;
+            continue
+        }
+        scaler.core.sleep(SCALE_INTERVAL).await;
+    }
+    info!("Scaler stopped");
+    Ok(())
+}
+
+/// Scaling state of a single service.
+///
+/// A queue backlog builds up pressure to add instances while idle instances build up pressure to
+/// remove them. Pressure slowly leaks away on its own so short bursts don't cause any change.
+#[derive(Debug)]
+pub struct ServiceProfile {
+    min_instances: u32,
+    /// Upper bound on instances, None for no limit
+    max_instances: Option<u32>,
+    desired_instances: u32,
+    pressure: f64,
+    growth_threshold: f64,
+    shrink_threshold: f64,
+    backlog: f64,
+    target_duty_cycle: f64,
+    leak_rate: f64,
+}
+
+impl ServiceProfile {
+    pub fn new(service: &Service, defaults: &ScalerServiceDefaults) -> Self {
+        let mut profile = Self {
+            min_instances: 0,
+            max_instances: None,
+            desired_instances: 0,
+            pressure: 0.0,
+            growth_threshold: defaults.growth.max(1) as f64,
+            shrink_threshold: -(defaults.shrink.max(1) as f64),
+            backlog: defaults.backlog.max(1) as f64,
+            target_duty_cycle: 0.9,
+            leak_rate: 0.1,
+        };
+        profile.configure(service, defaults);
+        profile.desired_instances = profile.min_instances;
+        profile
+    }
+
+    /// Apply the instance limits of a service, the configuration may have changed since the last pass
+    pub fn configure(&mut self, service: &Service, defaults: &ScalerServiceDefaults) {
+        self.max_instances = if service.licence_count > 0 { Some(service.licence_count) } else { None };
+        self.min_instances = service.min_instances.unwrap_or(defaults.min_instances);
+        if let Some(max_instances) = self.max_instances {
+            self.min_instances = self.min_instances.min(max_instances);
+        }
+    }
+
+    fn clamp(&self, instances: u32, min_instances: u32) -> u32 {
+        let instances = instances.max(min_instances);
+        match self.max_instances {
+            Some(max_instances) => instances.min(max_instances),
+            None => instances,
+        }
+    }
+
+    /// Take in the state of the service over the last `delta` seconds and return how many instances should be running
+    pub fn update(&mut self, delta: f64, backlog: u64, duty_cycle: f64) -> u32 {
+        // Run at least one instance while there is work waiting, even if the minimum is zero
+        let min_instances = self.clamp(self.min_instances.max(u32::from(backlog > 0)), 0);
+        self.desired_instances = self.clamp(self.desired_instances, min_instances);
+
+        // Grow because of the backlog, shrink if some of the instances are idle
+        self.pressure += delta * (backlog as f64 / self.backlog).sqrt();
+        self.pressure -= delta * (self.target_duty_cycle - duty_cycle) / self.target_duty_cycle;
+
+        // Let the pressure drift back towards zero
+        let leak = (self.leak_rate * delta).min(self.pressure.abs());
+        self.pressure = (self.pressure.abs() - leak).copysign(self.pressure);
+
+        // Don't build up pressure that can't be acted on
+        if self.desired_instances == min_instances {
+            self.pressure = self.pressure.max(0.0);
+        }
+        if Some(self.desired_instances) == self.max_instances {
+            self.pressure = self.pressure.min(0.0);
+        }
+
+        if self.pressure >= self.growth_threshold {
+            self.desired_instances = self.clamp(self.desired_instances + 1, min_instances);
+            self.pressure = 0.0;
+        }
+        if self.pressure <= self.shrink_threshold {
+            self.desired_instances = self.clamp(self.desired_instances.saturating_sub(1), min_instances);
+            self.pressure = 0.0;
+        }
+        self.desired_instances
+    }
+}
+
+pub struct Scaler<C: Controller> {
+    core: Core,
+    controller: C,
+    profiles: HashMap<ServiceName, ServiceProfile>,
+    last_pass: Option<Instant>,
+}
+
+impl<C: Controller> Scaler<C> {
+    pub fn new(core: Core, controller: C) -> Self {
+        Self { core, controller, profiles: Default::default(), last_pass: None }
+    }
+
+    /// Build the description of the containers a service needs
+    fn deployment(&self, service: &Service) -> ServiceDeployment {
+        let config = &self.core.config.core.scaler;
+        let mut environment = config.service_defaults.environment.clone();
+        environment.push(EnvironmentVariable { name: "AL_SERVICE_NAME".to_owned(), value: service.name.to_string() });
+
+        let labels = config.additional_labels.iter().flatten()
+            .filter_map(|label| label.split_once('='))
+            .map(|(name, value)| EnvironmentVariable { name: name.to_owned(), value: value.to_owned() })
+            .collect();
+
+        ServiceDeployment {
+            name: service.name,
+            container: service.docker_config.clone(),
+            dependencies: service.dependencies.clone(),
+            environment,
+            labels,
+        }
+    }
+
+    /// Count the busy and idle instances of each service from the status table
+    async fn duty_cycles(&self) -> Result<HashMap<ServiceName, f64>> {
+        let status_table = self.core.redis_volatile.hashmap::<(ServiceName, ServiceStatus, f64)>(SERVICE_STATE_HASH.to_owned(), None);
+        let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+
+        let mut counts: HashMap<ServiceName, (u32, u32)> = HashMap::new();
+        for (service_name, status, expiry) in status_table.items().await?.into_values() {
+            if expiry < now {
+                continue
+            }
+            let (busy, idle) = counts.entry(service_name).or_default();
+            match status {
+                ServiceStatus::Running => *busy += 1,
+                ServiceStatus::Idle => *idle += 1,
+            }
+        }
+
+        Ok(counts.into_iter()
+            .map(|(service_name, (busy, idle))| (service_name, busy as f64 / (busy + idle) as f64))
+            .collect())
+    }
+
+    /// Bring every service to the number of instances it should have
+    pub async fn run_once(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let delta = match self.last_pass.replace(now) {
+            Some(last_pass) => now.duration_since(last_pass).as_secs_f64().min(MAX_DELTA),
+            None => 0.0,
+        };
+
+        let services = self.core.services.list_all();
+        let stages = self.core.services.get_service_stage_hash();
+        let duty_cycles = self.duty_cycles().await?;
+
+        for (service_name, service) in &services {
+            if !service.enabled {
+                if self.profiles.remove(service_name).is_some() || self.controller.get_running(*service_name).await? > 0 {
+                    info!("Stopping disabled service {service_name}");
+                }
+                self.controller.stop(*service_name).await?;
+                stages.set(service_name, &ServiceStage::Off).await?;
+                continue
+            }
+
+            let deployment = self.deployment(service);
+            self.controller.start_dependencies(&deployment).await?;
+
+            // Once dependencies are up the service can be updated, services without updates can run right away
+            let mut stage = stages.get(service_name).await?.unwrap_or(ServiceStage::Off);
+            if stage == ServiceStage::Off {
+                stage = if service.update_config.is_some() { ServiceStage::Update } else { ServiceStage::Running };
+                stages.set(service_name, &stage).await?;
+            }
+
+            let defaults = &self.core.config.core.scaler.service_defaults;
+            let profile = self.profiles.entry(*service_name).or_insert_with(|| ServiceProfile::new(service, defaults));
+            profile.configure(service, defaults);
+
+            let target = if stage == ServiceStage::Running {
+                let backlog = self.core.get_service_queue(service_name).length().await?;
+                let duty_cycle = duty_cycles.get(service_name).copied().unwrap_or_default();
+                profile.update(delta, backlog, duty_cycle)
+            } else {
+                0
+            };
+
+            let running = self.controller.get_running(*service_name).await?;
+            if running != target {
+                debug!("Scaling {service_name} from {running} to {target} instances");
+                self.controller.set_running(&deployment, target).await?;
+            }
+        }
+
+        // Clean up containers of services that have been removed entirely
+        for service_name in self.controller.list_services().await? {
+            if !services.contains_key(&service_name) {
+                info!("Stopping containers of removed service {service_name}");
+                self.profiles.remove(&service_name);
+                self.controller.stop(service_name).await?;
+            }
+        }
+        Ok(())
+    }
+}
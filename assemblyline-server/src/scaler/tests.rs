@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use assemblyline_models::config::ScalerServiceDefaults;
+use assemblyline_models::datastore::service::{DependencyConfig, Service};
+use assemblyline_models::types::ServiceName;
+use parking_lot::Mutex;
+
+use crate::constants::{service_queue_name, ServiceStage};
+use crate::services::test::{dummy_service, setup_services_and_core};
+
+use super::controller::{Controller, ServiceDeployment};
+use super::{Scaler, ServiceProfile};
+
+/// Controller that only records what it has been asked to run
+#[derive(Default)]
+struct MockController {
+    running: Mutex<HashMap<ServiceName, u32>>,
+    dependencies: Mutex<HashSet<(ServiceName, String)>>,
+}
+
+impl Controller for MockController {
+    async fn start_dependencies(&self, deployment: &ServiceDeployment) -> Result<()> {
+        let mut dependencies = self.dependencies.lock();
+        for name in deployment.dependencies.keys() {
+            dependencies.insert((deployment.name, name.clone()));
+        }
+        Ok(())
+    }
+
+    async fn get_running(&self, service_name: ServiceName) -> Result<u32> {
+        Ok(self.running.lock().get(&service_name).copied().unwrap_or_default())
+    }
+
+    async fn set_running(&self, deployment: &ServiceDeployment, count: u32) -> Result<()> {
+        self.running.lock().insert(deployment.name, count);
+        Ok(())
+    }
+
+    async fn stop(&self, service_name: ServiceName) -> Result<()> {
+        self.running.lock().remove(&service_name);
+        self.dependencies.lock().retain(|(name, _)| *name != service_name);
+        Ok(())
+    }
+
+    async fn list_services(&self) -> Result<Vec<ServiceName>> {
+        let mut services: Vec<ServiceName> = self.running.lock().keys().copied().collect();
+        services.extend(self.dependencies.lock().iter().map(|(name, _)| *name));
+        services.sort_unstable();
+        services.dedup();
+        Ok(services)
+    }
+}
+
+fn service(min_instances: Option<u32>, licence_count: u32) -> Service {
+    let mut service = dummy_service("extract", "core", None, None, None, None);
+    service.min_instances = min_instances;
+    service.licence_count = licence_count;
+    service
+}
+
+#[test]
+fn test_profile_growth() {
+    let defaults = ScalerServiceDefaults::default();
+    let mut profile = ServiceProfile::new(&service(None, 0), &defaults);
+
+    // nothing to do, nothing running
+    assert_eq!(profile.update(10.0, 0, 0.0), 0);
+
+    // any backlog brings up a first instance right away
+    assert_eq!(profile.update(1.0, 10, 0.0), 1);
+
+    // a large backlog with busy instances grows once the pressure passes the growth threshold
+    let mut instances = 1;
+    for _ in 0..20 {
+        instances = profile.update(5.0, 10_000, 1.0);
+    }
+    assert!(instances > 1);
+
+    // idle instances are removed down to the minimum
+    for _ in 0..100 {
+        instances = profile.update(5.0, 0, 0.0);
+    }
+    assert_eq!(instances, 0);
+}
+
+#[test]
+fn test_profile_limits() {
+    let defaults = ScalerServiceDefaults::default();
+
+    // the licence count caps the number of instances
+    let mut profile = ServiceProfile::new(&service(None, 2), &defaults);
+    let mut instances = 0;
+    for _ in 0..100 {
+        instances = profile.update(5.0, 100_000, 1.0);
+    }
+    assert_eq!(instances, 2);
+
+    // the minimum is kept even when idle, but never above the licence count
+    let mut profile = ServiceProfile::new(&service(Some(3), 0), &defaults);
+    assert_eq!(profile.update(100.0, 0, 0.0), 3);
+    let mut profile = ServiceProfile::new(&service(Some(3), 1), &defaults);
+    assert_eq!(profile.update(100.0, 0, 0.0), 1);
+}
+
+#[tokio::test]
+async fn test_scaler_with_mock_controller() {
+    let mut extract = service(Some(1), 0);
+    extract.dependencies.insert("database".to_owned(), DependencyConfig {
+        container: extract.docker_config.clone(),
+        volumes: Default::default(),
+        run_as_core: false,
+    });
+    let mut disabled = dummy_service("disabled", "core", None, None, None, None);
+    disabled.enabled = false;
+
+    let (core, _guard) = setup_services_and_core([
+        ("extract".into(), extract),
+        ("disabled".into(), disabled),
+    ].into_iter().collect()).await;
+
+    let controller = MockController::default();
+    controller.running.lock().insert("disabled".into(), 2);
+    controller.running.lock().insert("removed".into(), 1);
+
+    let mut scaler = Scaler::new(core.clone(), controller);
+    scaler.run_once().await.unwrap();
+
+    // dependencies and minimum instances are started
+    let extract: ServiceName = "extract".into();
+    assert!(scaler.controller.dependencies.lock().contains(&(extract, "database".to_owned())));
+    assert_eq!(scaler.controller.get_running(extract).await.unwrap(), 1);
+
+    // disabled and unknown services are stopped
+    assert_eq!(scaler.controller.get_running("disabled".into()).await.unwrap(), 0);
+    assert_eq!(scaler.controller.get_running("removed".into()).await.unwrap(), 0);
+    assert_eq!(core.services.get_service_stage_hash().get("disabled").await.unwrap(), Some(ServiceStage::Off));
+
+    // a backlog with busy instances grows the service
+    let queue = core.redis_volatile.priority_queue::<serde_json::Value>(service_queue_name("extract"));
+    for index in 0..1000 {
+        queue.push(1.0, &serde_json::json!({"task": index})).await.unwrap();
+    }
+    for _ in 0..10 {
+        scaler.last_pass = Some(std::time::Instant::now() - std::time::Duration::from_secs(60));
+        scaler.run_once().await.unwrap();
+    }
+    assert!(scaler.controller.get_running(extract).await.unwrap() > 1);
+}
@@ -23,6 +23,7 @@ use parking_lot::Mutex;
 use redis_objects::{increment, AutoExportingMetrics, Hashmap, RedisObjects};
 use serde_json::{json, Value};
 use thiserror::Error;
+use tracing::Instrument;
 
 use crate::service_api::v1::task::models::{Result as ApiResult};
 use crate::common::heuristics::{HeuristicHandler, InvalidHeuristicException};
@@ -615,6 +616,13 @@ impl TaskingClient {
 #[error("The service you're asking task for does not exist, try later")]
 pub struct ServiceMissing;
 
+/// Span the outcome of a task is handled in, continuing the trace of its submission
+fn task_span(task: &Task) -> tracing::Span {
+    let span = tracing::info_span!("task_finished", sid = %task.sid, service = %task.service_name);
+    crate::telemetry::set_parent(&span, task.trace_context.as_ref());
+    span
+}
+
 /// Some fields of the task object are new, in order to make the new code
 /// compatable with older code those fields are coppied to metadata.
 /// Here we will copy them back if they are missing
@@ -647,6 +655,15 @@ fn finish_parsing_task(mut data: JsonMap) -> Result<Task> {
             }
         }
     }
+    if !data.contains_key("trace_context") {
+        if let Some(Value::Object(metadata)) = data.get("metadata") {
+            if let Some(context) = metadata.get(crate::telemetry::TRACE_CONTEXT_METADATA).and_then(Value::as_str) {
+                if let Some(context) = crate::telemetry::from_metadata(context) {
+                    data.insert("trace_context".to_string(), json!(context));
+                }
+            }
+        }
+    }
     serde_json::from_value(Value::Object(data)).context("Couldn't parse Task")
 }
 
@@ -658,7 +675,9 @@ impl TaskingClient {
                 let task = finish_parsing_task(success.task)?;
                 success.task = Default::default();
 
-                let missing_files = self._handle_task_result(task, success, client_id, service_name).await.context("_handle_task_result")?;
+                let span = task_span(&task);
+                let missing_files = self._handle_task_result(task, success, client_id, service_name)
+                    .instrument(span).await.context("_handle_task_result")?;
                 if !missing_files.is_empty() {
                     return Ok(json!({"success": false, "missing_files": missing_files}))
                 }
@@ -667,7 +686,8 @@ impl TaskingClient {
             FinishedBody::Error { task, exec_time, error } => {
                 let task = finish_parsing_task(task)?;
                 // let error = service_task['error']
-                self._handle_task_error(exec_time, task, error, client_id, service_name).await?;
+                let span = task_span(&task);
+                self._handle_task_error(exec_time, task, error, client_id, service_name).instrument(span).await?;
                 return Ok(json!({"success": true}))    
             },
             FinishedBody::Other { content } => {
@@ -26,6 +26,7 @@ pub async fn api(core: Arc<Core>) -> Result<impl Endpoint> {
         .nest("/healthz", v1::health::api(core.clone()))
         .data(tasking_client)
         .with(LoggerMiddleware)
+        .with(crate::telemetry::TraceContextMiddleware)
         .with(NormalizePath::new(poem::middleware::TrailingSlash::Trim))
     )
 }
@@ -57,6 +57,30 @@ async fn test_download_file() {
     assert_eq!(response.status().as_u16(), 404);
 }
 
+#[tokio::test]
+async fn test_download_file_cart() {
+    let (client, core, _guard, address) = setup(headers()).await;
+    let body = b"MZ".repeat(5000);
+    let hash = setup_file(&core, &body).await;
+
+    // the file comes back neutered, described by its file record
+    let response = client.get(format!("{address}/api/v1/file/{hash}/?encoding=cart")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let packed = response.bytes().await.unwrap();
+    assert!(packed.starts_with(b"CART"));
+    let mut output = vec![];
+    let (header, _) = cart_container::unpack_stream(packed.as_ref(), &mut output, None).unwrap();
+    assert_eq!(output, body);
+    let header = header.unwrap();
+    assert_eq!(header["name"], hash);
+    let file = core.datastore.file.get(&hash, None).await.unwrap().unwrap();
+    assert_eq!(header["al"]["type"], file.file_type);
+
+    // unknown encodings are refused
+    let response = client.get(format!("{address}/api/v1/file/{hash}/?encoding=zip")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
 #[tokio::test]
 async fn test_upload_new_file() {
     let (client, core, _guard, address) = setup(headers()).await;
@@ -201,7 +201,7 @@ async fn test_task_ignored_then_timeout() {
 
     // put a task for that service in a queue
     let queue = core.get_service_queue(&service.name);
-    queue.push(0.0, &task).await.unwrap();
+    queue.push("test", 0.0, &task).await.unwrap();
 
     // add an empty result for that task to cache hit on
     let result_key = assemblyline_models::datastore::Result::help_build_key(
@@ -249,7 +249,7 @@ async fn test_task_dispatch() {
 
     // put a task for that service in a queue
     let queue = core.get_service_queue(&service.name);
-    queue.push(0.0, &task).await.unwrap();
+    queue.push("test", 0.0, &task).await.unwrap();
 
     // ask for a task
     let response = client.get(format!("{address}/api/v1/task/")).send().await.unwrap();
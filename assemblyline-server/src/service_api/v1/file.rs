@@ -13,11 +13,13 @@
 
 use std::sync::Arc;
 
+use assemblyline_filestore::cart::JsonMap;
 use assemblyline_models::types::Sha256;
 use log::{error, info, warn};
 use poem::http::{HeaderMap, StatusCode};
-use poem::web::{Data, Multipart, Path};
+use poem::web::{Data, Multipart, Path, Query};
 use poem::{get, handler, put, Body, Endpoint, EndpointExt, Result, Response, Route};
+use serde::Deserialize;
 use serde_json::json;
 use tokio_stream::wrappers::ReceiverStream;
 
@@ -45,32 +47,54 @@ pub fn api(core: Arc<Core>) -> impl Endpoint {
 /// sha256       => A resource locator for the file (sha256)
 ///
 /// Arguments:
-/// None
+/// encoding     => Encoding to apply to the file: raw (default) or cart
 ///
 /// Data Block:
 /// None
 ///
 /// API call example:
-/// GET /api/v1/file/123456...654321/
+/// GET /api/v1/file/123456...654321/?encoding=cart
 ///
 /// Result example:
 /// <THE FILE BINARY>
 #[handler]
-async fn download_file(Path(sha256): Path<String>, client_info: Data<&ClientInfo>, core: Data<&Arc<Core>>) -> Result<Response> {
+async fn download_file(Path(sha256): Path<String>, Query(query): Query<DownloadQuery>, client_info: Data<&ClientInfo>, core: Data<&Arc<Core>>) -> Result<Response> {
     let sha256: Sha256 = match sha256.parse() {
         Ok(sha) => sha,
         Err(_) => return Err(make_empty_api_error(StatusCode::BAD_REQUEST, "A sha256 must be provided")),
     };
-    match core.filestore.stream(&sha256).await {
-        Ok((size, stream)) => {
-            let body = Body::from_bytes_stream(ReceiverStream::new(stream));
-            let filename = format!("UTF-8''{}", urlencoding::encode(&sha256));
-            Ok(Response::builder()
+
+    let response = match query.encoding.as_deref() {
+        None | Some("raw") => core.filestore.stream(&sha256).await.map(|(size, stream)| {
+            Response::builder()
                 .content_type("application/octet-stream")
                 .header("Content-Length", size.to_string())
-                .header("Content-Disposition", format!("attachment; filename=file.bin; filename*={filename}"))
-                .body(body))
-        }, 
+                .header("Content-Disposition", format!("attachment; filename=file.bin; filename*=UTF-8''{}", urlencoding::encode(&sha256)))
+                .body(Body::from_bytes_stream(ReceiverStream::new(stream)))
+        }),
+        Some("cart") => {
+            // describe the file in the CaRT header when the datastore knows about it
+            let mut header = JsonMap::new();
+            header.insert("name".to_owned(), json!(sha256));
+            match core.datastore.file.get(&sha256, None).await {
+                Ok(Some(file)) => {
+                    header.insert("al".to_owned(), json!({"type": file.file_type, "classification": file.classification.as_str()}));
+                },
+                Ok(None) => {},
+                Err(err) => warn!("[{}] {} couldn't load file record for {sha256}: {err}", client_info.client_id, client_info.service_name),
+            }
+            core.filestore.stream_cart(&sha256, Some(header)).await.map(|stream| {
+                Response::builder()
+                    .content_type("application/octet-stream")
+                    .header("Content-Disposition", format!("attachment; filename=file.cart; filename*=UTF-8''{}.cart", urlencoding::encode(&sha256)))
+                    .body(Body::from_bytes_stream(ReceiverStream::new(stream)))
+            })
+        },
+        Some(encoding) => return Err(make_empty_api_error(StatusCode::BAD_REQUEST, &format!("Unknown file encoding: {encoding}"))),
+    };
+
+    match response {
+        Ok(response) => Ok(response),
         Err(err) => {
             error!("[{}] {} couldn't find file {sha256} requested by service: {err}", 
                 client_info.client_id, client_info.service_name);
@@ -79,6 +103,11 @@ async fn download_file(Path(sha256): Path<String>, client_info: Data<&ClientInfo
     }
 }
 
+#[derive(Deserialize)]
+struct DownloadQuery {
+    encoding: Option<String>,
+}
+
 /// Upload a single file.
 ///
 /// Variables:
@@ -33,8 +33,12 @@ pub fn api(core: Arc<Core>) -> impl Endpoint {
 /// Result example:
 /// OK or FAIL
 #[handler]
-fn liveness() -> &'static str {
-    "OK"
+fn liveness(Data(core): Data<&Arc<Core>>) -> (StatusCode, &'static str) {
+    if core.health.stalled().is_empty() {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "FAIL")
+    }
 }
 
 
@@ -53,7 +57,7 @@ fn liveness() -> &'static str {
 /// OK or FAIL
 #[handler]
 async fn readyness(Data(core): Data<&Arc<Core>>) -> (StatusCode, &'static str) {
-    if core.datastore.ping().await {
+    if core.readiness().await.is_ready() {
         (StatusCode::OK, "OK")
     } else {
         (StatusCode::SERVICE_UNAVAILABLE, "FAIL")
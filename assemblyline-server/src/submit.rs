@@ -7,14 +7,14 @@ use assemblyline_markings::classification::ClassificationParser;
 use assemblyline_models::config::Config;
 use assemblyline_models::types::{ExpandingClassification, Sid};
 use chrono::{Utc, Duration};
+use tracing::instrument;
 
 use assemblyline_models::messages::submission::Submission as MessageSubmission;
 use assemblyline_models::datastore::submission::{Submission as DatastoreSubmission, SubmissionState};
 use assemblyline_models::messages::dispatching::SubmissionDispatchMessage;
-use redis_objects::Queue;
-
 use crate::constants::SUBMISSION_QUEUE;
 use crate::elastic::Elastic;
+use crate::work_queue::WorkQueue;
 use crate::Core;
 
 
@@ -22,14 +22,14 @@ pub struct SubmitManager {
     datastore: Arc<Elastic>,
     config: Arc<Config>,
     classification_parser: Arc<ClassificationParser>,
-    pub dispatch_submission_queue: Queue<SubmissionDispatchMessage>,
+    pub dispatch_submission_queue: WorkQueue<SubmissionDispatchMessage>,
 }
 
 impl SubmitManager {
 
     pub fn new(core: &Core) -> Self {
         Self {
-            dispatch_submission_queue: core.redis_volatile.queue(SUBMISSION_QUEUE.to_owned(), None),
+            dispatch_submission_queue: WorkQueue::open(&core.redis_volatile, SUBMISSION_QUEUE, "dispatcher", core.config.core.dispatcher.durable_queue),
             config: core.config.clone(),
             classification_parser: core.classification_parser.clone(),
             datastore: core.datastore.clone(),
@@ -38,6 +38,7 @@ impl SubmitManager {
     }
 
     /// Start a submission that has already been prepared
+    #[instrument(skip_all, fields(sid = %submission_obj.sid))]
     pub async fn submit_prepared(&self, mut submission_obj: MessageSubmission, completed_queue: Option<String>) -> Result<()> {
         // Figure out the expiry for the submission
         // Enforce maximum DTL
@@ -84,10 +85,13 @@ impl SubmitManager {
 
         self.datastore.submission.save(&sub.sid.to_string(), &sub, None, None).await?;
 
-        self.dispatch_submission_queue.push(&SubmissionDispatchMessage::new(sub, completed_queue)).await?;
+        let message = SubmissionDispatchMessage::new(sub, completed_queue)
+            .set_trace_context(crate::telemetry::current_context());
+        self.dispatch_submission_queue.push(&message).await?;
         Ok(())
     }
 
+    #[instrument(skip_all, fields(sid = %submission_obj.sid))]
     pub async fn submit_bundle(&self, submission_obj: MessageSubmission, completed_queue: Option<String>) -> Result<()> {
         let sid : Sid = submission_obj.sid;
         let sub_data = self.datastore.submission.get(&sid.to_string(), None).await?;
@@ -99,7 +103,8 @@ impl SubmitManager {
                 .set_file_infos(submission_obj.file_infos)
                 .set_file_tree(submission_obj.file_tree)
                 .set_results(submission_obj.results)
-                .set_errors(submission_obj.errors);
+                .set_errors(submission_obj.errors)
+                .set_trace_context(crate::telemetry::current_context());
                 self.dispatch_submission_queue.push(&dispatch_message).await?;
                 return Ok(());
             },
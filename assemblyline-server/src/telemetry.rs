@@ -0,0 +1,183 @@
+//! Trace export over OTLP and propagation of trace context between components.
+//!
+//! Spans are exported by the layers configured in `main`. To follow a submission across processes the
+//! W3C trace context of the current span is attached to the messages queued between components
+//! (`SubmissionDispatchMessage`, `Task`) and to the headers of http calls between them. The receiving
+//! side makes that context the parent of the span the work is handled in.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use assemblyline_models::config::OTLPServer;
+use assemblyline_models::messages::TraceContext;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use poem::{Endpoint, Middleware, Request};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Metadata key the trace context is copied to for services that only pass metadata through
+pub const TRACE_CONTEXT_METADATA: &str = "trace_context__";
+
+/// Use W3C trace context headers for propagation, this is done even when no exporter is
+/// configured so that traces started elsewhere are passed along
+pub fn install_propagator() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+/// Build the provider exporting spans to the configured OTLP collector, if there is one
+pub fn otlp_provider(config: &OTLPServer, component: &str) -> Result<Option<SdkTracerProvider>> {
+    let Some(url) = &config.server_url else { return Ok(None) };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(url)
+        .with_headers(config.headers.clone())
+        .build()
+        .context("building OTLP exporter")?;
+
+    let resource = Resource::builder()
+        .with_service_name(format!("assemblyline-{component}"))
+        .build();
+
+    Ok(Some(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build()))
+}
+
+/// Capture the trace context of the current span, None when it isn't part of an exported trace
+pub fn current_context() -> Option<TraceContext> {
+    let mut carrier = TraceContext::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    if carrier.is_empty() {
+        None
+    } else {
+        Some(carrier)
+    }
+}
+
+/// Continue the trace described by a message in the given span
+pub fn set_parent(span: &tracing::Span, context: Option<&TraceContext>) {
+    let Some(carrier) = context else { return };
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    let _ = span.set_parent(parent);
+}
+
+/// Headers carrying a trace context to another component, the current span's if none is given
+pub fn trace_headers(context: Option<&TraceContext>) -> HeaderMap {
+    let context = match context {
+        Some(context) => Some(context.clone()),
+        None => current_context(),
+    };
+    let mut headers = HeaderMap::new();
+    for (name, value) in context.unwrap_or_default() {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// Encode a trace context for the task metadata
+pub fn to_metadata(context: &TraceContext) -> String {
+    serde_json::to_string(context).unwrap_or_default()
+}
+
+/// Read a trace context back from the task metadata
+pub fn from_metadata(value: &str) -> Option<TraceContext> {
+    serde_json::from_str::<HashMap<String, String>>(value).ok()
+}
+
+/// Read propagation headers from an incoming request
+struct HeaderExtractor<'a>(&'a poem::http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Middleware handling each request in a span continuing the trace of the caller
+pub struct TraceContextMiddleware;
+
+impl<E: Endpoint> Middleware<E> for TraceContextMiddleware {
+    type Output = TraceContextMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        TraceContextMiddlewareImpl { ep }
+    }
+}
+
+/// Endpoint wrapper that implements the details of `TraceContextMiddleware`
+pub struct TraceContextMiddlewareImpl<E> {
+    /// Inner endpoint wrapped by this object
+    ep: E,
+}
+
+impl<E: Endpoint> Endpoint for TraceContextMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let span = tracing::info_span!("http_request", method = %req.method(), path = %req.uri().path());
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+        let _ = span.set_parent(parent);
+        self.ep.call(req).instrument(span).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use assemblyline_models::messages::TraceContext;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use poem::{Endpoint, EndpointExt, Request};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{current_context, from_metadata, install_propagator, to_metadata, TraceContextMiddleware};
+
+    pub(crate) const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    pub(crate) const TRACE_PARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    /// Record spans on this thread with an opentelemetry layer so that trace contexts are tracked
+    pub(crate) fn trace_subscriber() -> tracing::subscriber::DefaultGuard {
+        install_propagator();
+        let tracer = SdkTracerProvider::builder().build().tracer("test");
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let context: TraceContext = [("traceparent".to_owned(), TRACE_PARENT.to_owned())].into();
+        assert_eq!(from_metadata(&to_metadata(&context)), Some(context));
+        assert_eq!(from_metadata("not json"), None);
+    }
+
+    #[tokio::test]
+    async fn middleware_continues_trace() {
+        let _subscriber = trace_subscriber();
+        let endpoint = poem::endpoint::make_sync(|_| {
+            current_context().and_then(|context| context.get("traceparent").cloned()).unwrap_or_default()
+        }).with(TraceContextMiddleware);
+
+        // the request is handled in a span that is part of the caller's trace
+        let request = Request::builder().header("traceparent", TRACE_PARENT).finish();
+        let traceparent = endpoint.call(request).await.unwrap();
+        assert!(traceparent.starts_with(&format!("00-{TRACE_ID}-")), "{traceparent}");
+
+        // without the header a new trace is started
+        let traceparent = endpoint.call(Request::builder().finish()).await.unwrap();
+        assert!(!traceparent.is_empty());
+        assert!(!traceparent.contains(TRACE_ID));
+    }
+}
@@ -12,7 +12,6 @@ use assemblyline_models::datastore::submission::{SubmissionParams, SubmissionSta
 use assemblyline_models::datastore::user::User;
 use assemblyline_models::datastore::{Service, Submission};
 use assemblyline_models::messages::changes::ServiceChange;
-use assemblyline_models::messages::task::Task;
 use assemblyline_models::types::{ClassificationString, ExpandingClassification, JsonMap, ServiceName, Sha256, Sid};
 use log::{debug, error, info};
 use parking_lot::Mutex;
@@ -43,7 +42,7 @@ const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
 /// MARK: MockService
 struct MockService {
     service_name: String,
-    service_queue: redis_objects::PriorityQueue<Task>,
+    service_queue: crate::core_dispatcher::ServiceQueue,
     server_address: String,
     classification_engine: Arc<ClassificationParser>,
 
@@ -127,7 +126,7 @@ impl MockService {
 
             if let Some(hold) = instructions.get("hold") {
                 if let Some(hold) = hold.as_i64() {
-                    self.service_queue.push(task.priority as f64, &task).await.unwrap();
+                    self.service_queue.push("test", task.priority as f64, &task).await.unwrap();
                     info!("{} Requeued task in {}, holding for {hold}", self.service_name, self.service_queue.name());
                     _ = tokio::time::timeout(Duration::from_secs(hold as u64), self.signal.notified()).await;
                     info!("{} Resuming from hold", self.service_name);
@@ -0,0 +1,316 @@
+//! The updater keeps the signatures of services up to date with their external sources.
+//!
+//! Every source listed in the update configuration of a service is fetched on its own interval.
+//! The files fetched are split into individual signatures using the delimiter configured for the
+//! service and deduplicated by sha256. The resulting bundle is stored in the cachestore under
+//! `{service}.{source}` and, when its content changed, a signature change is published on
+//! `changes.signatures.{service}` so running instances reload it. Services waiting in the update
+//! stage are moved to running once all their sources have been fetched.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use assemblyline_models::datastore::service::{FetchMethods, Service, SignatureDelimiter, UpdateConfig, UpdateSource};
+use assemblyline_models::messages::changes::{Operation, SignatureChange};
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, warn};
+use redis_objects::Hashmap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cachestore::CacheStore;
+use crate::constants::ServiceStage;
+use crate::Core;
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Name of the hash tracking the last fetch of every source
+pub const UPDATER_STATE_HASH: &str = "service-updates";
+
+/// Cachestore component the signature bundles are stored under
+pub const SIGNATURE_CACHE_COMPONENT: &str = "signatures";
+
+/// How long a bundle is kept after the last time its source was fetched
+const BUNDLE_TTL: TimeDelta = TimeDelta::days(7);
+
+pub async fn main(core: Core) -> Result<()> {
+    let updater = Updater::new(core).await?;
+    updater.core.running.install_terminate_handler(false)?;
+
+    while updater.core.is_running() {
+        if let Err(err) = updater.run_once().await {
+            error!("Error in updater: {err:?}");
+            updater.core.sleep(ERROR_BACKOFF).await;
+            continue
+        }
+        updater.core.sleep(UPDATE_CHECK_INTERVAL).await;
+    }
+    info!("Updater stopped");
+    Ok(())
+}
+
+/// Result of the last successful fetch of a source
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceState {
+    /// Hash of the signature bundle built from the source
+    pub sha256: String,
+    /// Number of distinct signatures in the bundle
+    pub signatures: usize,
+    pub last_fetch: DateTime<Utc>,
+}
+
+/// Signatures of a single source as stored in the cachestore
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignatureBundle {
+    pub source: String,
+    pub sha256: String,
+    /// Distinct signatures, ordered by their sha256
+    pub signatures: Vec<String>,
+}
+
+impl SignatureBundle {
+    /// Deduplicate signatures and build the bundle for a source
+    pub fn new(source: &str, signatures: impl IntoIterator<Item=String>) -> Self {
+        let signatures: BTreeMap<String, String> = signatures.into_iter()
+            .map(|signature| (hex::encode(Sha256::digest(signature.as_bytes())), signature))
+            .collect();
+
+        let mut hasher = Sha256::new();
+        for sha256 in signatures.keys() {
+            hasher.update(sha256.as_bytes());
+        }
+
+        Self {
+            source: source.to_owned(),
+            sha256: hex::encode(hasher.finalize()),
+            signatures: signatures.into_values().collect(),
+        }
+    }
+}
+
+/// Split the content of a file into signatures according to the update configuration of a service
+pub fn split_signatures(content: &str, config: &UpdateConfig) -> Vec<String> {
+    let delimiter = match config.signature_delimiter {
+        SignatureDelimiter::File | SignatureDelimiter::None => None,
+        SignatureDelimiter::Custom => config.custom_delimiter.clone().filter(|delimiter| !delimiter.is_empty()),
+        other => Some(other.token()),
+    };
+
+    let signatures: Vec<&str> = match &delimiter {
+        Some(delimiter) => content.split(delimiter.as_str()).collect(),
+        None => vec![content],
+    };
+
+    signatures.into_iter()
+        .map(str::trim)
+        .filter(|signature| !signature.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Cache keys only allow a limited set of characters
+fn cache_key(service: &str, source: &str) -> String {
+    format!("{service}.{source}").chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+pub struct Updater {
+    core: Core,
+    cache: CacheStore,
+    state: Hashmap<SourceState>,
+}
+
+impl Updater {
+    pub async fn new(core: Core) -> Result<Self> {
+        let file_cache = assemblyline_filestore::FileStore::open(&core.config.filestore.cache).await.context("initializing cache filestore")?;
+        let cache = CacheStore::new(SIGNATURE_CACHE_COMPONENT.to_owned(), core.datastore.clone(), file_cache)?;
+        let state = core.redis_persistant.hashmap(UPDATER_STATE_HASH.to_owned(), None);
+        Ok(Self { core, cache, state })
+    }
+
+    /// Fetch every source that is due and release services waiting on their first update
+    pub async fn run_once(&self) -> Result<()> {
+        let stages = self.core.services.get_service_stage_hash();
+
+        for (service_name, service) in self.core.services.list_all() {
+            let Some(config) = &service.update_config else { continue };
+            if !service.enabled {
+                continue
+            }
+
+            let mut ready = true;
+            for source in config.sources.iter().filter(|source| source.enabled) {
+                if let Err(err) = self.update_source(&service, config, source).await {
+                    warn!("Could not update source {} of {service_name}: {err:?}", source.name);
+                }
+                ready &= self.state.exists(&cache_key(&service_name, &source.name)).await?;
+            }
+
+            // Services that don't wait for their signatures are released even if some sources failed
+            if stages.get(&service_name).await? == Some(ServiceStage::Update) && (ready || !config.wait_for_update) {
+                info!("Signatures ready for {service_name}, moving to running stage");
+                stages.set(&service_name, &ServiceStage::Running).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch a single source if its interval has passed, storing and announcing its signatures if they changed
+    async fn update_source(&self, service: &Service, config: &UpdateConfig, source: &UpdateSource) -> Result<()> {
+        let key = cache_key(&service.name, &source.name);
+        let previous = self.state.get(&key).await?;
+
+        let interval = match source.update_interval {
+            Some(interval) => interval.get() as i64,
+            None => config.update_interval_seconds.max(1) as i64,
+        };
+        let now = Utc::now();
+        if let Some(previous) = &previous {
+            if previous.last_fetch + TimeDelta::seconds(interval) > now {
+                return Ok(())
+            }
+        }
+
+        let since = if source.ignore_cache { None } else { previous.as_ref().map(|state| state.last_fetch) };
+        let files = match self.fetch(config, source, since).await? {
+            Some(files) => files,
+            None => {
+                debug!("Source {} of {} not modified", source.name, service.name);
+                if self.refresh(&key, &previous, now).await? {
+                    return Ok(())
+                }
+                // the stored bundle is gone, so the source has to be downloaded in full again
+                match self.fetch(config, source, None).await? {
+                    Some(files) => files,
+                    None => bail!("source {} reported no change to an unconditional fetch", source.name),
+                }
+            }
+        };
+
+        let signatures = files.iter().flat_map(|(_, content)| split_signatures(&String::from_utf8_lossy(content), config));
+        let bundle = SignatureBundle::new(&source.name, signatures);
+
+        if previous.as_ref().is_some_and(|state| state.sha256 == bundle.sha256) && !source.ignore_cache {
+            debug!("Source {} of {} unchanged", source.name, service.name);
+            if self.refresh(&key, &previous, now).await? {
+                return Ok(())
+            }
+        }
+
+        info!("Storing {} signatures from source {} of {}", bundle.signatures.len(), source.name, service.name);
+        self.cache.save(&key, &Bytes::from(serde_json::to_vec(&bundle)?), Some(BUNDLE_TTL)).await?;
+        self.state.set(&key, &SourceState { sha256: bundle.sha256.clone(), signatures: bundle.signatures.len(), last_fetch: now }).await?;
+
+        let signature_type = service.name.to_lowercase();
+        self.core.redis_volatile.publish_json(&format!("changes.signatures.{signature_type}"), &SignatureChange {
+            signature_id: "*".to_owned(),
+            signature_type,
+            source: source.name.clone(),
+            operation: Operation::Modified,
+        }).await?;
+        Ok(())
+    }
+
+    /// Record a fetch that didn't change anything, keeping the existing bundle alive.
+    /// Returns false if there is no stored bundle left to keep, in which case it must be stored again.
+    async fn refresh(&self, key: &str, previous: &Option<SourceState>, now: DateTime<Utc>) -> Result<bool> {
+        let Some(state) = previous else { return Ok(false) };
+        if let Err(err) = self.cache.touch(key, Some(BUNDLE_TTL)).await {
+            warn!("Could not refresh signature bundle {key}: {err}");
+            return Ok(false)
+        }
+        self.state.set(key, &SourceState { last_fetch: now, ..state.clone() }).await?;
+        Ok(true)
+    }
+
+    /// Download the files of a source, returns None if the source reports no change since the given time
+    async fn fetch(&self, config: &UpdateConfig, source: &UpdateSource, since: Option<DateTime<Utc>>) -> Result<Option<Vec<(String, Vec<u8>)>>> {
+        let pattern = source.pattern.as_deref().unwrap_or(config.default_pattern.as_str());
+        let pattern = Regex::new(pattern).with_context(|| format!("invalid pattern for source {}", source.name))?;
+
+        if source.fetch_method == FetchMethods::Git {
+            bail!("git sources are not supported")
+        }
+
+        let uri = url::Url::parse(&source.uri).with_context(|| format!("invalid uri for source {}", source.name))?;
+        match uri.scheme() {
+            "file" => {
+                let path = uri.to_file_path().map_err(|_| anyhow::anyhow!("invalid file path: {uri}"))?;
+                Ok(Some(read_local(&path, &pattern).await?))
+            },
+            "http" | "https" => fetch_http(source, uri, since).await,
+            scheme => bail!("unsupported scheme: {scheme}"),
+        }
+    }
+}
+
+/// Read a local file, or all the files in a directory whose relative path matches the pattern
+async fn read_local(path: &Path, pattern: &Regex) -> Result<Vec<(String, Vec<u8>)>> {
+    if !tokio::fs::metadata(path).await?.is_dir() {
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        return Ok(vec![(name, tokio::fs::read(path).await?)])
+    }
+
+    let mut files = vec![];
+    let mut pending: Vec<PathBuf> = vec![path.to_owned()];
+    while let Some(directory) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let entry_path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry_path);
+                continue
+            }
+            let name = entry_path.strip_prefix(path)?.to_string_lossy().to_string();
+            if pattern.is_match(&name) {
+                files.push((name, tokio::fs::read(&entry_path).await?));
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Download a source over http, returns None if the server reports it hasn't changed
+async fn fetch_http(source: &UpdateSource, uri: url::Url, since: Option<DateTime<Utc>>) -> Result<Option<Vec<(String, Vec<u8>)>>> {
+    let mut client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(source.ssl_ignore_errors);
+    if let Some(proxy) = &source.proxy {
+        client = client.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(ca_cert) = &source.ca_cert {
+        client = client.add_root_certificate(reqwest::Certificate::from_pem(ca_cert.as_bytes())?);
+    }
+    let client = client.build()?;
+
+    let name = uri.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default().to_owned();
+    let mut request = match source.fetch_method {
+        FetchMethods::Post => client.post(uri).body(source.data.as_ref().map(|data| data.to_string()).unwrap_or_default()),
+        _ => client.get(uri),
+    };
+    for header in &source.headers {
+        request = request.header(&header.name, &header.value);
+    }
+    if let Some(username) = &source.username {
+        request = request.basic_auth(username, source.password.as_ref());
+    }
+    if let Some(since) = since {
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, since.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+    }
+
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(None)
+    }
+    let response = response.error_for_status()?;
+    Ok(Some(vec![(name, response.bytes().await?.to_vec())]))
+}
@@ -0,0 +1,87 @@
+use assemblyline_filestore::FileStore;
+use assemblyline_models::datastore::service::{Service, UpdateConfig};
+use assemblyline_models::messages::changes::SignatureChange;
+use chrono::TimeDelta;
+use serde_json::json;
+
+use crate::constants::ServiceStage;
+use crate::services::test::{dummy_service, setup_services_and_core};
+
+use super::{cache_key, split_signatures, SignatureBundle, Updater, SIGNATURE_CACHE_COMPONENT};
+
+fn update_config(delimiter: &str, sources: serde_json::Value) -> UpdateConfig {
+    serde_json::from_value(json!({
+        "update_interval_seconds": 3600,
+        "signature_delimiter": delimiter,
+        "custom_delimiter": "%%",
+        "wait_for_update": true,
+        "sources": sources,
+    })).unwrap()
+}
+
+/// Load the signature bundle the updater stored for a source
+async fn stored_bundle(updater: &Updater, service: &str, source: &str) -> SignatureBundle {
+    serde_json::from_slice(&updater.cache.get(&cache_key(service, source)).await.unwrap().unwrap()).unwrap()
+}
+
+#[test]
+fn test_split_signatures() {
+    let content = "rule a {}\n\nrule b {}\n\n\n\nrule a {}\n";
+    assert_eq!(split_signatures(content, &update_config("double_new_line", json!([]))), vec!["rule a {}", "rule b {}", "rule a {}"]);
+    assert_eq!(split_signatures(content, &update_config("file", json!([]))), vec![content.trim()]);
+    assert_eq!(split_signatures("a%%b%%", &update_config("custom", json!([]))), vec!["a", "b"]);
+    assert_eq!(split_signatures("a,b, c", &update_config("comma", json!([]))), vec!["a", "b", "c"]);
+
+    // duplicates are dropped and the order of the input doesn't matter
+    let bundle = SignatureBundle::new("source", ["b".to_owned(), "a".to_owned(), "b".to_owned()]);
+    assert_eq!(bundle.signatures.len(), 2);
+    assert_eq!(bundle.sha256, SignatureBundle::new("source", ["a".to_owned(), "b".to_owned()]).sha256);
+}
+
+#[tokio::test]
+async fn test_update_local_source() {
+    let directory = tempfile::tempdir().unwrap();
+    std::fs::create_dir(directory.path().join("nested")).unwrap();
+    std::fs::write(directory.path().join("first.yar"), "rule a {}\n\nrule b {}").unwrap();
+    std::fs::write(directory.path().join("nested/second.yar"), "rule b {}\n\nrule c {}").unwrap();
+    std::fs::write(directory.path().join("readme.txt"), "not a signature").unwrap();
+
+    let mut service: Service = dummy_service("yara", "core", None, None, None, None);
+    service.update_config = Some(update_config("double_new_line", json!([{
+        "name": "local",
+        "uri": format!("file://{}", directory.path().to_string_lossy()),
+        "pattern": ".*\\.yar$",
+    }])));
+    let (core, _guard) = setup_services_and_core([("yara".into(), service)].into_iter().collect()).await;
+    core.services.get_service_stage_hash().set("yara", &ServiceStage::Update).await.unwrap();
+    let mut changes = core.redis_volatile.subscribe_json::<SignatureChange>("changes.signatures.yara".to_owned()).await;
+
+    let updater = Updater::new(core.clone()).await.unwrap();
+    updater.run_once().await.unwrap();
+
+    // the signatures of all matching files are stored without duplicates
+    let bundle = stored_bundle(&updater, "yara", "local").await;
+    assert_eq!(bundle.signatures.len(), 3);
+    assert!(!bundle.signatures.contains(&"not a signature".to_owned()));
+
+    // services are told to reload and released from the update stage
+    let change = tokio::time::timeout(std::time::Duration::from_secs(10), changes.recv()).await.unwrap().unwrap().unwrap();
+    assert_eq!(change.source, "local");
+    assert_eq!(change.signature_type, "yara");
+    assert_eq!(core.services.get_service_stage_hash().get("yara").await.unwrap(), Some(ServiceStage::Running));
+
+    // the source isn't fetched again before its interval has passed
+    std::fs::write(directory.path().join("third.yar"), "rule d {}").unwrap();
+    updater.run_once().await.unwrap();
+    assert_eq!(stored_bundle(&updater, "yara", "local").await.sha256, bundle.sha256);
+
+    // an unchanged source whose bundle has expired is stored again
+    std::fs::remove_file(directory.path().join("third.yar")).unwrap();
+    let key = cache_key("yara", "local");
+    FileStore::open(&core.config.filestore.cache).await.unwrap().delete(&format!("{SIGNATURE_CACHE_COMPONENT}_{key}")).await.unwrap();
+    let mut state = updater.state.get(&key).await.unwrap().unwrap();
+    state.last_fetch -= TimeDelta::days(1);
+    updater.state.set(&key, &state).await.unwrap();
+    updater.run_once().await.unwrap();
+    assert_eq!(stored_bundle(&updater, "yara", "local").await.sha256, bundle.sha256);
+}
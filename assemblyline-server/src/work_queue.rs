@@ -0,0 +1,114 @@
+//! Queues handing work between components, kept either in a redis list or a redis stream.
+//!
+//! With a list an item is gone as soon as it is popped, so it is lost if the process taking it stops
+//! before the item has been recorded anywhere else. With a stream the item stays pending until it is
+//! acknowledged and is handed to another consumer if that doesn't happen in time.
+//!
+//! A key can't be both a list and a stream, so the stream is kept under a different name. Processes
+//! outside of this crate may still push to the list, in durable mode anything found there is moved into
+//! the stream before reading from it. The move is done in one step on the server, so the stream name
+//! uses the list name as its hash tag to keep both keys on the same node of a redis cluster. Items
+//! that can't be parsed are moved to the dead letter stream when they are read.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use redis_objects::{Queue, RedisObjects, StreamQueue};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// How many items left on the list are moved to the stream at a time
+const LIST_TRANSFER_BATCH: usize = 100;
+
+/// Acknowledgement for an item taken from a work queue, pass it to `WorkQueue::ack` once the item is safe
+#[derive(Debug)]
+#[must_use]
+pub struct Receipt(Option<String>);
+
+/// A queue that is either a plain list or a durable stream depending on configuration
+pub enum WorkQueue<T: Serialize + DeserializeOwned> {
+    /// Items are removed as soon as they are popped
+    List(Queue<T>),
+    /// Items are kept until acknowledged, `list` holds items pushed by processes not aware of the stream
+    Stream {
+        stream: StreamQueue<T>,
+        list: Queue<T>,
+    },
+}
+
+impl<T: Serialize + DeserializeOwned> WorkQueue<T> {
+    /// Open the queue with the given name, in durable mode it is read through the named consumer group
+    pub fn open(store: &Arc<RedisObjects>, name: &str, group: &str, durable: bool) -> Self {
+        let list = store.queue(name.to_owned(), None);
+        if durable {
+            Self::Stream { stream: store.stream_queue(format!("{{{name}}}-stream"), group.to_owned()), list }
+        } else {
+            Self::List(list)
+        }
+    }
+
+    /// enqueue a single item
+    pub async fn push(&self, data: &T) -> Result<()> {
+        match self {
+            Self::List(queue) => queue.push(data).await?,
+            Self::Stream { stream, .. } => { stream.push(data).await?; },
+        }
+        Ok(())
+    }
+
+    /// Put an item back to be taken again, at the front of the queue if it is a list
+    pub async fn unpop(&self, data: &T) -> Result<()> {
+        match self {
+            Self::List(queue) => queue.unpop(data).await?,
+            Self::Stream { stream, .. } => { stream.push(data).await?; },
+        }
+        Ok(())
+    }
+
+    /// Take the next item, waiting up to the timeout for one to arrive
+    pub async fn pop_timeout(&self, timeout: Duration) -> Result<Option<(T, Receipt)>> {
+        match self {
+            Self::List(queue) => Ok(queue.pop_timeout(timeout).await?.map(|item| (item, Receipt(None)))),
+            Self::Stream { stream, list } => {
+                stream.transfer_from_list(list.name(), LIST_TRANSFER_BATCH).await?;
+                loop {
+                    match stream.pop(timeout).await {
+                        Ok(delivery) => return Ok(delivery.map(|delivery| (delivery.message, Receipt(Some(delivery.id))))),
+                        // the item has already been moved to the dead letter stream, move on to the next
+                        Err(err) if err.is_serialize_error() => continue,
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            },
+        }
+    }
+
+    /// Confirm an item has been handled and doesn't need to be delivered again
+    pub async fn ack(&self, receipt: Receipt) -> Result<()> {
+        if let (Self::Stream { stream, .. }, Receipt(Some(id))) = (self, receipt) {
+            stream.ack(&id).await?;
+        }
+        Ok(())
+    }
+
+    /// Read the number of items in the queue, including those not acknowledged yet
+    pub async fn length(&self) -> Result<usize> {
+        Ok(match self {
+            Self::List(queue) => queue.length().await?,
+            Self::Stream { stream, list } => stream.length().await? + list.length().await?,
+        })
+    }
+
+    /// Load the entire content of the queue into memory
+    pub async fn content(&self) -> Result<Vec<T>> {
+        Ok(match self {
+            Self::List(queue) => queue.content().await?,
+            Self::Stream { stream, list } => {
+                let mut content = stream.content().await?;
+                content.extend(list.content().await?);
+                content
+            },
+        })
+    }
+}
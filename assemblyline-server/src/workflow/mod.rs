@@ -0,0 +1,329 @@
+//! The workflow component applies user defined workflows to newly created alerts.
+//!
+//! Every pass loads the enabled workflows and the alerts that haven't been through them yet.
+//! Workflow queries the postprocessing query parser understands are evaluated in memory against
+//! each alert, any other query is run in elasticsearch restricted to the alerts of the batch.
+//! Labels, priority and status of matching workflows are applied through update operations,
+//! the alert is marked as completed, and the hit statistics of the workflows are updated.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use assemblyline_models::datastore::alert::{Alert, EntityType, Event};
+use assemblyline_models::datastore::workflow::{Statuses, Workflow};
+use assemblyline_models::types::{ExpandingClassification, JsonMap};
+use assemblyline_models::Readable;
+use chrono::Utc;
+use itertools::Itertools;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::elastic::collection::OperationBatch;
+use crate::elastic::Index;
+use crate::postprocessing::{parse_for, Query};
+use crate::Core;
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+const PASS_INTERVAL: Duration = Duration::from_secs(30);
+const BATCH_SIZE: usize = 1000;
+
+/// Alerts pending workflows are only looked for within this window
+const LOOKBACK: &str = "now-1d";
+
+/// Id of the built in workflow triaging alerts no other workflow gave a status to
+const DEFAULT_WORKFLOW_ID: &str = "DEFAULT";
+
+pub async fn main(core: Core) -> Result<()> {
+    let engine = WorkflowEngine::new(core);
+    engine.core.running.install_terminate_handler(false)?;
+
+    while engine.core.is_running() {
+        if let Err(err) = engine.run_once().await {
+            error!("Error applying workflows: {err:?}");
+            engine.core.sleep(ERROR_BACKOFF).await;
+            continue
+        }
+        engine.core.sleep(PASS_INTERVAL).await;
+    }
+    info!("Workflow stopped");
+    Ok(())
+}
+
+/// The id of an alert matched by a workflow query run in elasticsearch
+#[derive(Deserialize, Debug)]
+struct PartialAlert {
+    alert_id: String,
+}
+
+impl Readable for PartialAlert {
+    fn set_from_archive(&mut self, _from_archive: bool) { }
+}
+
+/// A workflow prepared to be run against alerts
+struct ActiveWorkflow {
+    workflow: Workflow,
+    /// Parsed query when it can be evaluated in memory
+    matcher: Option<Query>,
+}
+
+impl ActiveWorkflow {
+    fn new(workflow: Workflow) -> Self {
+        let matcher = match parse_for::<Alert>(&workflow.query) {
+            // unqualified terms are only understood by the evaluator for submissions
+            Ok(query) if query.uses_unqualified_search() => {
+                debug!("Workflow {} will be evaluated by elasticsearch: query uses unqualified search", workflow.name);
+                None
+            },
+            Ok(query) => Some(query),
+            Err(err) => {
+                debug!("Workflow {} will be evaluated by elasticsearch: {err}", workflow.name);
+                None
+            }
+        };
+        Self { workflow, matcher }
+    }
+
+    fn id(&self) -> String {
+        match &self.workflow.workflow_id {
+            Some(id) => id.to_string(),
+            None => DEFAULT_WORKFLOW_ID.to_owned(),
+        }
+    }
+
+    /// Check if any of the labels, priority or status this workflow sets is missing from the alert.
+    ///
+    /// A workflow that doesn't set anything always applies, it still records an event on the alert.
+    fn changes(&self, alert: &serde_json::Value) -> bool {
+        let labels = alert.get("label").and_then(|labels| labels.as_array());
+        let missing_label = self.workflow.labels.iter()
+            .any(|label| !labels.is_some_and(|labels| labels.iter().any(|item| item.as_str() == Some(label))));
+        let is_set = |field: &str| alert.get(field).is_some_and(|value| !value.is_null());
+
+        let sets_anything = !self.workflow.labels.is_empty() || self.workflow.priority.is_some() || self.workflow.status.is_some();
+        !sets_anything
+            || missing_label
+            || (self.workflow.priority.is_some() && !is_set("priority"))
+            || (self.workflow.status.is_some() && !is_set("status"))
+    }
+
+    /// Add the operations for this workflow to the alert's batch and reflect them on the local copy
+    /// of the alert so workflows that come after see the changes.
+    fn apply(&self, alert: &mut serde_json::Value, operations: &mut OperationBatch) -> Result<()> {
+        let workflow = &self.workflow;
+        for label in &workflow.labels {
+            operations.append_if_missing("label".to_owned(), json!(label));
+            if let Some(labels) = alert.get_mut("label").and_then(|labels| labels.as_array_mut()) {
+                if !labels.iter().any(|item| item.as_str() == Some(label)) {
+                    labels.push(json!(label));
+                }
+            } else if let Some(alert) = alert.as_object_mut() {
+                alert.insert("label".to_owned(), json!([label]));
+            }
+        }
+
+        // the first workflow to set the priority or status wins, later ones only add labels
+        let is_set = |field: &str| alert.get(field).is_some_and(|value| !value.is_null());
+        let priority = workflow.priority.filter(|_| !is_set("priority"));
+        let status = workflow.status.filter(|_| !is_set("status"));
+        if let Some(priority) = priority {
+            operations.set("priority".to_owned(), serde_json::to_value(priority)?);
+        }
+        if let Some(status) = status {
+            operations.set("status".to_owned(), serde_json::to_value(status)?);
+        }
+        if let Some(alert) = alert.as_object_mut() {
+            if let Some(priority) = priority {
+                alert.insert("priority".to_owned(), serde_json::to_value(priority)?);
+            }
+            if let Some(status) = status {
+                alert.insert("status".to_owned(), serde_json::to_value(status)?);
+            }
+        }
+
+        let event = Event {
+            entity_type: EntityType::Workflow,
+            entity_id: self.id(),
+            entity_name: workflow.name.clone(),
+            ts: Utc::now(),
+            labels: workflow.labels.clone(),
+            labels_removed: vec![],
+            status,
+            priority,
+        };
+        operations.append("events".to_owned(), serde_json::to_value(event)?);
+        Ok(())
+    }
+}
+
+/// Workflows run in order of the status they apply, the first workflow to set a field wins
+fn status_rank(status: Option<Statuses>) -> u8 {
+    match status {
+        Some(Statuses::Malicious) => 0,
+        Some(Statuses::NonMalicious) => 1,
+        Some(Statuses::Assess) => 2,
+        Some(Statuses::Triage) => 3,
+        None => 4,
+    }
+}
+
+/// Workflow setting the triage status on alerts left without a status
+fn default_workflow(core: &Core) -> Workflow {
+    let now = Utc::now();
+    Workflow {
+        classification: ExpandingClassification::unrestricted(&core.classification_parser),
+        creation_date: now,
+        creator: "SYSTEM".to_owned(),
+        edited_by: "SYSTEM".to_owned(),
+        enabled: true,
+        first_seen: None,
+        hit_count: 0,
+        labels: vec![],
+        last_edit: now,
+        last_seen: None,
+        name: "Triage all with no status".to_owned(),
+        origin: None,
+        priority: None,
+        query: "NOT status:*".to_owned(),
+        status: Some(Statuses::Triage),
+        workflow_id: None,
+    }
+}
+
+pub struct WorkflowEngine {
+    core: Core,
+}
+
+impl WorkflowEngine {
+    pub fn new(core: Core) -> Self {
+        Self { core }
+    }
+
+    /// Load the enabled workflows in the order they should be applied
+    async fn load_workflows(&self) -> Result<Vec<ActiveWorkflow>> {
+        let mut workflows = vec![];
+        let mut cursor = self.core.datastore.workflow.stream_search::<Workflow>("enabled:true", "*".to_owned(), vec![], None, Some(1000), Some(Index::Hot)).await?;
+        while let Some(workflow) = cursor.next().await? {
+            workflows.push(workflow);
+        }
+        workflows.sort_by_key(|workflow| status_rank(workflow.status));
+        workflows.push(default_workflow(&self.core));
+        Ok(workflows.into_iter().map(ActiveWorkflow::new).collect())
+    }
+
+    /// Apply the workflows to every alert waiting on them
+    pub async fn run_once(&self) -> Result<()> {
+        let workflows = self.load_workflows().await?;
+        let mut hits = vec![0; workflows.len()];
+
+        // Alerts waiting on an extended scan will be processed once it completes
+        let query = format!("reporting_ts:[{LOOKBACK} TO *] AND NOT workflows_completed:true AND NOT extended_scan:submitted");
+        let mut cursor = self.core.datastore.alert.stream_search::<JsonMap>(&query, "*".to_owned(), vec![], None, Some(1000), Some(Index::Hot)).await?;
+
+        let mut processed = 0;
+        let mut batch = vec![];
+        loop {
+            let item = cursor.next().await?;
+            if let Some(alert) = item {
+                batch.push(serde_json::Value::Object(alert));
+                if batch.len() < BATCH_SIZE {
+                    continue
+                }
+            }
+
+            if batch.is_empty() {
+                break
+            }
+
+            processed += batch.len();
+            self.process_batch(&workflows, std::mem::take(&mut batch), &mut hits).await?;
+        }
+
+        if processed > 0 {
+            info!("Applied workflows to {processed} alerts");
+        }
+        self.update_statistics(&workflows, &hits).await
+    }
+
+    /// Run every workflow over a batch of alerts and save the resulting changes
+    async fn process_batch(&self, workflows: &[ActiveWorkflow], mut alerts: Vec<serde_json::Value>, hits: &mut [u64]) -> Result<()> {
+        let ids: Vec<String> = alerts.iter()
+            .map(|alert| alert.get("alert_id").and_then(|id| id.as_str()).unwrap_or_default().to_owned())
+            .collect();
+        let mut operations: Vec<OperationBatch> = ids.iter().map(|_| OperationBatch::default()).collect();
+
+        for (workflow, hits) in workflows.iter().zip(hits.iter_mut()) {
+            let matches = self.find_matches(workflow, &ids, &alerts).await;
+            for (index, alert) in alerts.iter_mut().enumerate() {
+                if matches[index] && workflow.changes(alert) {
+                    workflow.apply(alert, &mut operations[index])?;
+                    *hits += 1;
+                }
+            }
+        }
+
+        for (id, mut operations) in ids.into_iter().zip(operations) {
+            operations.set("workflows_completed".to_owned(), json!(true));
+            self.core.datastore.alert.update(&id, operations, None, Some(3)).await?;
+        }
+        Ok(())
+    }
+
+    /// Find which of the alerts in a batch match the query of a workflow.
+    ///
+    /// A workflow whose query can't be run is logged and treated as matching nothing so it
+    /// doesn't hold back the others.
+    async fn find_matches(&self, workflow: &ActiveWorkflow, ids: &[String], alerts: &[serde_json::Value]) -> Vec<bool> {
+        if let Some(query) = &workflow.matcher {
+            return alerts.iter().map(|alert| match query.test(alert) {
+                Ok(hit) => hit,
+                Err(err) => {
+                    warn!("Could not evaluate workflow {}: {err}", workflow.workflow.name);
+                    false
+                }
+            }).collect()
+        }
+
+        let filter = format!("alert_id:({})", ids.iter().map(|id| format!("\"{id}\"")).join(" OR "));
+        match self.search_alert_ids(&workflow.workflow.query, filter).await {
+            Ok(found) => ids.iter().map(|id| found.contains(id)).collect(),
+            Err(err) => {
+                warn!("Could not run query for workflow {}: {err}", workflow.workflow.name);
+                vec![false; ids.len()]
+            }
+        }
+    }
+
+    async fn search_alert_ids(&self, query: &str, filter: String) -> Result<HashSet<String>> {
+        let mut found = HashSet::new();
+        let mut cursor = self.core.datastore.alert.stream_search::<PartialAlert>(query, "alert_id".to_owned(), vec![filter], None, Some(1000), Some(Index::Hot)).await?;
+        while let Some(alert) = cursor.next().await? {
+            found.insert(alert.alert_id);
+        }
+        Ok(found)
+    }
+
+    /// Record the hits of this pass on the workflow documents
+    async fn update_statistics(&self, workflows: &[ActiveWorkflow], hits: &[u64]) -> Result<()> {
+        let seen = Utc::now();
+        for (workflow, hits) in workflows.iter().zip(hits) {
+            let workflow_id = match &workflow.workflow.workflow_id {
+                Some(id) if *hits > 0 => id.to_string(),
+                _ => continue,
+            };
+
+            let mut operations = OperationBatch::default();
+            operations.increment("hit_count".to_owned(), json!(hits));
+            operations.set("last_seen".to_owned(), json!(seen));
+            if workflow.workflow.first_seen.is_none() {
+                operations.set("first_seen".to_owned(), json!(seen));
+            }
+            self.core.datastore.workflow.update(&workflow_id, operations, None, Some(3)).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use assemblyline_models::datastore::alert::ExtendedScanValues;
+use assemblyline_models::datastore::submission::SubmissionState;
+use assemblyline_models::datastore::workflow::{Priorities, Statuses, Workflow};
+use assemblyline_models::datastore::{File, Submission};
+use assemblyline_models::messages::submission::Submission as MessageSubmission;
+use assemblyline_models::types::ExpandingClassification;
+use chrono::Utc;
+use rand::Rng;
+
+use crate::alerter::{AlertMessage, Alerter};
+use crate::Core;
+
+use super::WorkflowEngine;
+
+/// Create an alert through the alerter for a new submission with the given score
+async fn create_alert(core: &Core, score: i32) -> String {
+    let data: [u8; 32] = rand::rng().random();
+    let file = File::gen_for_sample(&data, &mut rand::rng());
+    core.datastore.file.save(&file.sha256.to_string(), &file, None, None).await.unwrap();
+
+    let mut submission: Submission = rand::rng().random();
+    submission.state = SubmissionState::Completed;
+    submission.params.psid = None;
+    submission.files[0].sha256 = file.sha256.clone();
+    submission.results = vec![];
+    core.datastore.submission.save(&submission.sid.to_string(), &submission, None, None).await.unwrap();
+
+    let alert_id = submission.sid.to_string();
+    let message = AlertMessage {
+        submission: MessageSubmission::from(&submission),
+        score,
+        extended_scan: ExtendedScanValues::Skipped,
+        ingest_id: Some(alert_id.clone()),
+        alert_retries: 0,
+    };
+    Alerter::new(core.clone()).process_alert_message(&message).await.unwrap();
+    alert_id
+}
+
+async fn save_workflow(core: &Core, id: &str, query: &str, labels: Vec<String>, priority: Option<Priorities>, status: Option<Statuses>) {
+    let now = Utc::now();
+    let workflow = Workflow {
+        classification: ExpandingClassification::unrestricted(&core.classification_parser),
+        creation_date: now,
+        creator: "admin".to_owned(),
+        edited_by: "admin".to_owned(),
+        enabled: true,
+        first_seen: None,
+        hit_count: 0,
+        labels,
+        last_edit: now,
+        last_seen: None,
+        name: format!("workflow {id}"),
+        origin: None,
+        priority,
+        query: query.to_owned(),
+        status,
+        workflow_id: Some(id.parse().unwrap()),
+    };
+    core.datastore.workflow.save(id, &workflow, None, None).await.unwrap();
+}
+
+async fn commit(core: &Core) {
+    core.datastore.alert.commit(None).await.unwrap();
+    core.datastore.workflow.commit(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_apply_workflows() {
+    let (core, _guard) = Core::test_setup().await;
+    let high = create_alert(&core, 1000).await;
+    let low = create_alert(&core, 10).await;
+
+    // one query the in-memory evaluator handles, one it leaves to elasticsearch
+    let malicious = "0b9d8f0e-5c8e-4f43-9c57-5d5b1cf1a001";
+    let labelled = "0b9d8f0e-5c8e-4f43-9c57-5d5b1cf1a002";
+    save_workflow(&core, malicious, "al.score:>=1000", vec!["HIGH_SCORE".to_owned()], Some(Priorities::High), Some(Statuses::Malicious)).await;
+    save_workflow(&core, labelled, "al.score:[0 TO 100] AND NOT unmatchedterm", vec!["LOW_SCORE".to_owned()], None, None).await;
+    commit(&core).await;
+
+    let engine = WorkflowEngine::new(core.clone());
+    engine.run_once().await.unwrap();
+    commit(&core).await;
+
+    let alert = core.datastore.alert.get(&high, None).await.unwrap().unwrap();
+    assert_eq!(alert.label, vec!["HIGH_SCORE".to_owned()]);
+    assert_eq!(alert.priority, Some(Priorities::High));
+    assert_eq!(alert.status, Some(Statuses::Malicious));
+    assert!(alert.workflows_completed);
+    assert_eq!(alert.events.len(), 1);
+
+    // the default workflow triages alerts left without a status
+    let alert = core.datastore.alert.get(&low, None).await.unwrap().unwrap();
+    assert_eq!(alert.label, vec!["LOW_SCORE".to_owned()]);
+    assert_eq!(alert.priority, None);
+    assert_eq!(alert.status, Some(Statuses::Triage));
+    assert!(alert.workflows_completed);
+    assert_eq!(alert.events.len(), 2);
+
+    let workflow = core.datastore.workflow.get(malicious, None).await.unwrap().unwrap();
+    assert_eq!(workflow.hit_count, 1);
+    assert!(workflow.first_seen.is_some());
+    assert!(workflow.last_seen.is_some());
+
+    // completed alerts are not processed again
+    engine.run_once().await.unwrap();
+    let workflow = core.datastore.workflow.get(malicious, None).await.unwrap().unwrap();
+    assert_eq!(workflow.hit_count, 1);
+}
+
+#[tokio::test]
+async fn test_first_workflow_sets_priority() {
+    let (core, _guard) = Core::test_setup().await;
+    let alert_id = create_alert(&core, 500).await;
+
+    // both match, the malicious workflow runs first and later ones only add their labels
+    save_workflow(&core, "0b9d8f0e-5c8e-4f43-9c57-5d5b1cf1a003", "al.score:>=100", vec!["FIRST".to_owned()], Some(Priorities::High), Some(Statuses::Malicious)).await;
+    save_workflow(&core, "0b9d8f0e-5c8e-4f43-9c57-5d5b1cf1a004", "al.score:>=100", vec!["SECOND".to_owned()], Some(Priorities::Low), None).await;
+    commit(&core).await;
+
+    WorkflowEngine::new(core.clone()).run_once().await.unwrap();
+    commit(&core).await;
+
+    let alert = core.datastore.alert.get(&alert_id, None).await.unwrap().unwrap();
+    assert_eq!(alert.label, vec!["FIRST".to_owned(), "SECOND".to_owned()]);
+    assert_eq!(alert.priority, Some(Priorities::High));
+    assert_eq!(alert.status, Some(Statuses::Malicious));
+    assert_eq!(alert.events.len(), 2);
+    assert_eq!(alert.events[1].priority, None);
+}
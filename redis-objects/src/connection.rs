@@ -0,0 +1,403 @@
+//! Connection managers for connections redis-rs can't make on its own.
+//!
+//! Connections secured with a client certificate or a private certificate authority are made here,
+//! over native tls like the rest of the redis connections, and handed to redis-rs once established.
+//!
+//! A sentinel deployment is reached through the sentinels, which are asked for the address of the
+//! current master every time a connection is made. This way connections that break during a
+//! failover are replaced with connections to the newly promoted master.
+//!
+//! A cluster deployment spreads keys over its nodes by hash slot. Commands on a single key are
+//! routed to the right node, but commands and scripts touching several keys at once only work
+//! when all of those keys are in the same slot. Names that get used together (like the queues
+//! given to `MultiQueue` or `select`) need to share a hash tag, `{tag}queue-a` and `{tag}queue-b`.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use deadpool::managed::{self, Metrics, RecycleResult};
+use redis::aio::{MultiplexedConnection, PubSub};
+use redis::cluster::{ClusterClient, ClusterClientBuilder};
+use redis::cluster_async::ClusterConnection;
+use redis::{ConnectionAddr, ErrorKind, RedisConnectionInfo, RedisError, RedisResult, TlsMode};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Certificates used to secure connections to redis, all values are PEM encoded.
+#[derive(Default, Clone)]
+pub struct TlsCertificates {
+    /// Certificate presented by this client, used only when the key is also given
+    pub client_cert: Option<Vec<u8>>,
+    /// PKCS #8 private key for the client certificate
+    pub client_key: Option<Vec<u8>>,
+    /// Certificate authorities the servers are verified against instead of the system trust store
+    pub root_cert: Option<Vec<u8>>,
+}
+
+impl TlsCertificates {
+    /// Whether anything beyond the system trust store is needed to connect
+    pub (crate) fn is_custom(&self) -> bool {
+        self.client_cert.is_some() || self.client_key.is_some() || self.root_cert.is_some()
+    }
+}
+
+/// Address of a node, with tls if certificates are configured
+pub (crate) fn node_address(host: &str, port: u16, tls: bool) -> ConnectionAddr {
+    if tls {
+        ConnectionAddr::TcpTls { host: host.to_owned(), port, insecure: false, tls_params: None }
+    } else {
+        ConnectionAddr::Tcp(host.to_owned(), port)
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Opens connections to redis servers, over tls when certificates are given
+#[derive(Clone)]
+pub (crate) struct Connector {
+    tls: Option<tokio_native_tls::TlsConnector>,
+}
+
+impl Connector {
+    pub (crate) fn new(certificates: Option<&TlsCertificates>) -> RedisResult<Self> {
+        let Some(certificates) = certificates else {
+            return Ok(Self { tls: None })
+        };
+
+        let mut builder = native_tls::TlsConnector::builder();
+        if let (Some(cert), Some(key)) = (&certificates.client_cert, &certificates.client_key) {
+            builder.identity(native_tls::Identity::from_pkcs8(cert, key)?);
+        }
+        if let Some(root_cert) = &certificates.root_cert {
+            builder.disable_built_in_roots(true);
+            for cert in pem_certificates(root_cert) {
+                builder.add_root_certificate(native_tls::Certificate::from_pem(cert)?);
+            }
+        }
+        Ok(Self { tls: Some(builder.build()?.into()) })
+    }
+
+    async fn stream(&self, host: &str, port: u16) -> RedisResult<Box<dyn Stream>> {
+        let stream = tokio::net::TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        Ok(match &self.tls {
+            Some(tls) => Box::new(tls.connect(host, stream).await?),
+            None => Box::new(stream),
+        })
+    }
+
+    /// Open a connection to a server, its driver runs on a task of its own
+    pub (crate) async fn connect(&self, host: &str, port: u16, info: &RedisConnectionInfo) -> RedisResult<MultiplexedConnection> {
+        let (connection, driver) = MultiplexedConnection::new(info, self.stream(host, port).await?).await?;
+        tokio::spawn(driver);
+        Ok(connection)
+    }
+
+    /// Open a connection to a server for subscribing to channels
+    pub (crate) async fn pubsub(&self, host: &str, port: u16, info: &RedisConnectionInfo) -> RedisResult<PubSub> {
+        PubSub::new(info, self.stream(host, port).await?).await
+    }
+}
+
+/// Split a PEM bundle into its certificates
+fn pem_certificates(pem: &[u8]) -> Vec<&[u8]> {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut certificates = vec![];
+    let mut rest = pem;
+    while let Some(end) = rest.windows(END.len()).position(|window| window == END) {
+        certificates.push(&rest[..end + END.len()]);
+        rest = &rest[end + END.len()..];
+    }
+    certificates
+}
+
+/// Check a pooled connection still answers, with a fresh value each time so stale replies are caught
+async fn ping(conn: &mut impl redis::aio::ConnectionLike, ping_number: &AtomicUsize) -> RecycleResult<RedisError> {
+    let ping_number = ping_number.fetch_add(1, Ordering::Relaxed).to_string();
+    let pong: String = redis::cmd("PING").arg(&ping_number).query_async(conn).await?;
+    if pong == ping_number {
+        Ok(())
+    } else {
+        Err(managed::RecycleError::message("Invalid PING response"))
+    }
+}
+
+/// Pool of connections to a single server made through a `Connector`
+pub (crate) type ServerPool = managed::Pool<ServerManager>;
+
+/// Pool of connections to the master of a sentinel deployment
+pub (crate) type SentinelPool = managed::Pool<SentinelManager>;
+
+/// Pool of connections to a redis cluster
+pub (crate) type ClusterPool = managed::Pool<ClusterManager>;
+
+/// Creates connections to a single server
+pub (crate) struct ServerManager {
+    connector: Connector,
+    host: String,
+    port: u16,
+    info: RedisConnectionInfo,
+    ping_number: AtomicUsize,
+}
+
+impl ServerManager {
+    pub (crate) fn new(host: &str, port: u16, db: i64, certificates: Option<&TlsCertificates>) -> RedisResult<Self> {
+        Ok(Self {
+            connector: Connector::new(certificates)?,
+            host: host.to_owned(),
+            port,
+            info: RedisConnectionInfo { db, ..Default::default() },
+            ping_number: AtomicUsize::new(0),
+        })
+    }
+
+    pub (crate) async fn pubsub(&self) -> RedisResult<PubSub> {
+        self.connector.pubsub(&self.host, self.port, &self.info).await
+    }
+}
+
+impl managed::Manager for ServerManager {
+    type Type = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<MultiplexedConnection, RedisError> {
+        self.connector.connect(&self.host, self.port, &self.info).await
+    }
+
+    async fn recycle(&self, conn: &mut MultiplexedConnection, _: &Metrics) -> RecycleResult<RedisError> {
+        ping(conn, &self.ping_number).await
+    }
+}
+
+/// Creates connections to whichever server the sentinels currently report as master
+pub (crate) struct SentinelManager {
+    connector: Connector,
+    sentinels: Vec<(String, u16)>,
+    master: String,
+    info: RedisConnectionInfo,
+    ping_number: AtomicUsize,
+}
+
+impl SentinelManager {
+    pub (crate) fn new(sentinels: &[(String, u16)], master: &str, db: i64, tls: Option<&TlsCertificates>) -> RedisResult<Self> {
+        if sentinels.is_empty() {
+            return Err((ErrorKind::InvalidClientConfig, "No sentinels given").into())
+        }
+        Ok(Self {
+            connector: Connector::new(tls)?,
+            sentinels: sentinels.to_vec(),
+            master: master.to_owned(),
+            info: RedisConnectionInfo { db, ..Default::default() },
+            ping_number: AtomicUsize::new(0),
+        })
+    }
+
+    /// Ask the sentinels in turn for the address of the current master
+    pub (crate) async fn master(&self) -> RedisResult<(String, u16)> {
+        let mut last_error = None;
+        for (host, port) in &self.sentinels {
+            let address = async {
+                let mut sentinel = self.connector.connect(host, *port, &Default::default()).await?;
+                let address: Option<(String, u16)> = redis::cmd("SENTINEL").arg("get-master-addr-by-name").arg(&self.master)
+                    .query_async(&mut sentinel).await?;
+                address.ok_or_else(|| RedisError::from((ErrorKind::ResponseError, "Sentinel doesn't know the master", self.master.clone())))
+            }.await;
+            match address {
+                Ok(address) => return Ok(address),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| (ErrorKind::InvalidClientConfig, "No sentinels given").into()))
+    }
+
+    pub (crate) async fn pubsub(&self) -> RedisResult<PubSub> {
+        let (host, port) = self.master().await?;
+        self.connector.pubsub(&host, port, &self.info).await
+    }
+}
+
+impl managed::Manager for SentinelManager {
+    type Type = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<MultiplexedConnection, RedisError> {
+        let (host, port) = self.master().await?;
+        self.connector.connect(&host, port, &self.info).await
+    }
+
+    async fn recycle(&self, conn: &mut MultiplexedConnection, _: &Metrics) -> RecycleResult<RedisError> {
+        // a connection to a server that has been demoted still answers pings, so check the role as well
+        ping(conn, &self.ping_number).await?;
+        let role: Vec<redis::Value> = redis::cmd("ROLE").query_async(conn).await?;
+        match role.first() {
+            Some(redis::Value::BulkString(role)) if role == b"master" => Ok(()),
+            _ => Err(managed::RecycleError::message("Server is no longer the master")),
+        }
+    }
+}
+
+/// Creates connections to a redis cluster, each connection routes commands to all of the nodes
+pub (crate) struct ClusterManager {
+    client: ClusterClient,
+    /// Clients for the individual nodes, used for pubsub
+    nodes: Vec<redis::Client>,
+    next_node: AtomicUsize,
+    ping_number: AtomicUsize,
+}
+
+impl ClusterManager {
+    /// Tls to a cluster is made by redis-rs itself, which only verifies against the system trust store
+    pub (crate) fn new(nodes: &[(String, u16)], tls: Option<&TlsCertificates>) -> RedisResult<Self> {
+        if tls.is_some_and(TlsCertificates::is_custom) {
+            return Err((ErrorKind::InvalidClientConfig, "Client certificates and certificate authorities aren't supported with redis cluster").into())
+        }
+
+        let addresses: Vec<redis::ConnectionInfo> = nodes.iter()
+            .map(|(host, port)| redis::ConnectionInfo { addr: node_address(host, *port, tls.is_some()), redis: Default::default() })
+            .collect();
+
+        let mut builder = ClusterClientBuilder::new(addresses.clone());
+        if tls.is_some() {
+            builder = builder.tls(TlsMode::Secure);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            nodes: addresses.into_iter().map(redis::Client::open).collect::<RedisResult<Vec<_>>>()?,
+            next_node: AtomicUsize::new(0),
+            ping_number: AtomicUsize::new(0),
+        })
+    }
+
+    /// Open a pubsub connection to one of the nodes, rotating through them on every call.
+    /// Published messages are forwarded to every node of the cluster so any of them can be used to subscribe.
+    pub (crate) async fn pubsub(&self) -> RedisResult<PubSub> {
+        let index = self.next_node.fetch_add(1, Ordering::Relaxed);
+        self.nodes[index % self.nodes.len()].get_async_pubsub().await
+    }
+}
+
+impl managed::Manager for ClusterManager {
+    type Type = ClusterConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<ClusterConnection, RedisError> {
+        self.client.get_async_connection().await
+    }
+
+    async fn recycle(&self, conn: &mut ClusterConnection, _: &Metrics) -> RecycleResult<RedisError> {
+        ping(conn, &self.ping_number).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use parking_lot::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::RedisObjects;
+
+    type Handler = Arc<dyn Fn(&[String]) -> Vec<u8> + Send + Sync>;
+
+    fn bulk(value: &str) -> Vec<u8> {
+        format!("${}\r\n{value}\r\n", value.len()).into_bytes()
+    }
+
+    /// Serve the redis protocol on a local port, answering each command with the handler
+    async fn fake_server(handler: Handler) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut read = BufReader::new(read);
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if read.read_line(&mut line).await.unwrap_or(0) == 0 { return }
+                        let count: usize = line.trim_end()[1..].parse().unwrap();
+                        let mut command = vec![];
+                        for _ in 0..count {
+                            line.clear();
+                            read.read_line(&mut line).await.unwrap();
+                            let length: usize = line.trim_end()[1..].parse().unwrap();
+                            let mut value = vec![0; length + 2];
+                            read.read_exact(&mut value).await.unwrap();
+                            value.truncate(length);
+                            command.push(String::from_utf8(value).unwrap());
+                        }
+                        if write.write_all(&handler(&command)).await.is_err() { return }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    /// A server holding string values that reports itself as master while the flag is set
+    async fn fake_master(values: Arc<Mutex<HashMap<String, String>>>, master: Arc<Mutex<bool>>) -> u16 {
+        fake_server(Arc::new(move |command: &[String]| {
+            match command[0].to_uppercase().as_str() {
+                "PING" => bulk(command.get(1).map(String::as_str).unwrap_or("PONG")),
+                "ROLE" => {
+                    let role = if *master.lock() { "master" } else { "slave" };
+                    [b"*1\r\n".to_vec(), bulk(role)].concat()
+                },
+                "SET" => {
+                    values.lock().insert(command[1].clone(), command[2].clone());
+                    b"+OK\r\n".to_vec()
+                },
+                "GET" => match values.lock().get(&command[1]) {
+                    Some(value) => bulk(value),
+                    None => b"$-1\r\n".to_vec(),
+                },
+                _ => b"+OK\r\n".to_vec(),
+            }
+        })).await
+    }
+
+    #[tokio::test]
+    async fn sentinel_round_trip() {
+        let first_values = Arc::new(Mutex::new(HashMap::new()));
+        let first_master = Arc::new(Mutex::new(true));
+        let first = fake_master(first_values.clone(), first_master.clone()).await;
+        let second_values = Arc::new(Mutex::new(HashMap::new()));
+        let second = fake_master(second_values.clone(), Arc::new(Mutex::new(true))).await;
+
+        let current = Arc::new(AtomicU16::new(first));
+        let sentinel = fake_server(Arc::new({
+            let current = current.clone();
+            move |command: &[String]| {
+                if command[0] == "SENTINEL" && command[2] == "mymaster" {
+                    [b"*2\r\n".to_vec(), bulk("127.0.0.1"), bulk(&current.load(Ordering::SeqCst).to_string())].concat()
+                } else {
+                    b"+OK\r\n".to_vec()
+                }
+            }
+        })).await;
+
+        let redis = RedisObjects::open_sentinel(&[("127.0.0.1".to_owned(), sentinel)], "mymaster", 0, None).unwrap();
+        {
+            let mut conn = redis.pool.get().await.unwrap();
+            let _: () = redis::cmd("SET").arg("key").arg("first").query_async(&mut conn).await.unwrap();
+            let value: String = redis::cmd("GET").arg("key").query_async(&mut conn).await.unwrap();
+            assert_eq!(value, "first");
+        }
+        assert_eq!(first_values.lock().get("key").unwrap(), "first");
+
+        // after a failover the pooled connection is dropped and the new master is used
+        *first_master.lock() = false;
+        current.store(second, Ordering::SeqCst);
+        {
+            let mut conn = redis.pool.get().await.unwrap();
+            let _: () = redis::cmd("SET").arg("key").arg("second").query_async(&mut conn).await.unwrap();
+        }
+        assert_eq!(first_values.lock().get("key").unwrap(), "first");
+        assert_eq!(second_values.lock().get("key").unwrap(), "second");
+    }
+}
@@ -1,6 +1,6 @@
 //! Objects and helpers for publishing metrics in an efficent manner. 
 
-use std::{borrow::BorrowMut, sync::Arc};
+use std::{borrow::BorrowMut, collections::HashMap, sync::Arc};
 use std::marker::PhantomData;
 use std::time::Duration;
 
@@ -11,7 +11,7 @@ use serde::Serialize;
 use parking_lot::Mutex;
 use serde_json::json;
 
-use crate::{retry_call, ErrorTypes, RedisObjects};
+use crate::{retry_call, ErrorTypes, Hashmap, RedisObjects};
 
 /// Trait for metric messages being exported 
 pub trait MetricMessage: Serialize + Default + Send + Sync + 'static {}
@@ -266,6 +266,103 @@ impl<M: MetricMessage> Drop for AutoExportingMetrics<M> {
 }
 
 
+/// A set of named counters sharing a common prefix that can be incremented and decremented.
+///
+/// When tracking is enabled the time each counter was last incremented is kept in a
+/// hash under the counter name (or a tracking id given with the increment) until it is
+/// decremented again, this makes it possible to find work that was started but never finished.
+#[derive(Debug, Clone)]
+pub struct Counters {
+    prefix: String,
+    store: Arc<RedisObjects>,
+    tracker: Option<Hashmap<f64>>,
+}
+
+impl Counters {
+    pub (crate) fn new(prefix: String, store: Arc<RedisObjects>, track_counters: bool) -> Self {
+        let tracker = if track_counters {
+            Some(store.hashmap(format!("c-tracker-{prefix}"), None))
+        } else {
+            None
+        };
+        Self { prefix, store, tracker }
+    }
+
+    fn counter_name(&self, name: &str) -> String {
+        format!("{}-{name}", self.prefix)
+    }
+
+    /// Access the hash holding the time tracked counters were last incremented
+    pub fn tracker(&self) -> Option<&Hashmap<f64>> {
+        self.tracker.as_ref()
+    }
+
+    /// Increase the named counter by the given value, returning the new value
+    pub async fn inc(&self, name: &str, value: i64, track_id: Option<&str>) -> Result<i64, ErrorTypes> {
+        if let Some(tracker) = &self.tracker {
+            let now = chrono::Utc::now().timestamp_micros() as f64 / 1_000_000.0;
+            tracker.add(track_id.unwrap_or(name), &now).await?;
+        }
+        retry_call!(self.store.pool, incr, self.counter_name(name), value)
+    }
+
+    /// Decrease the named counter by the given value, returning the new value
+    pub async fn dec(&self, name: &str, value: i64, track_id: Option<&str>) -> Result<i64, ErrorTypes> {
+        if let Some(tracker) = &self.tracker {
+            tracker.pop(track_id.unwrap_or(name)).await?;
+        }
+        retry_call!(self.store.pool, decr, self.counter_name(name), value)
+    }
+
+    /// List the full names of all the counters under this prefix
+    pub async fn get_queues(&self) -> Result<Vec<String>, ErrorTypes> {
+        self.store.keys(&format!("{}-*", self.prefix)).await
+    }
+
+    /// Read the value of every counter under this prefix
+    pub async fn get_queues_sizes(&self) -> Result<HashMap<String, i64>, ErrorTypes> {
+        let names = self.get_queues().await?;
+        if names.is_empty() {
+            return Ok(HashMap::new())
+        }
+        let mut pipe = redis::pipe();
+        for name in &names {
+            pipe.get(name);
+        }
+        let values: Vec<Option<i64>> = retry_call!(method, self.store.pool, pipe, query_async)?;
+        Ok(names.into_iter().zip(values).map(|(name, value)| (name, value.unwrap_or_default())).collect())
+    }
+
+    /// Set every counter under this prefix back to zero and forget any tracked increments
+    pub async fn reset_queues(&self) -> Result<(), ErrorTypes> {
+        if let Some(tracker) = &self.tracker {
+            tracker.delete().await?;
+        }
+        let names = self.get_queues().await?;
+        if names.is_empty() {
+            return Ok(())
+        }
+        let mut pipe = redis::pipe();
+        for name in &names {
+            pipe.set(name, 0).ignore();
+        }
+        retry_call!(method, self.store.pool, pipe, query_async)
+    }
+
+    /// Remove every counter under this prefix along with the tracker
+    pub async fn delete(&self) -> Result<(), ErrorTypes> {
+        if let Some(tracker) = &self.tracker {
+            tracker.delete().await?;
+        }
+        let names = self.get_queues().await?;
+        if names.is_empty() {
+            return Ok(())
+        }
+        retry_call!(self.store.pool, del, &names)
+    }
+}
+
+
 #[cfg(test)]
 fn init() {
     let _ = env_logger::builder().filter_level(log::LevelFilter::Debug).is_test(true).try_init();
@@ -333,48 +430,81 @@ async fn auto_exporting_counter() {
     assert_eq!(result.unwrap().unwrap(), MetricKind{started: 0, finished: 6});
 }
 
-    
-    // # noinspection PyShadowingNames
-    // def test_basic_counters(redis_connection):
-    //     if redis_connection:
-    //         from assemblyline.remote.datatypes.counters import Counters
-    //         with Counters('test-counter') as ct:
-    //             ct.delete()
-    
-    //             for x in range(10):
-    //                 ct.inc('t1')
-    //             for x in range(20):
-    //                 ct.inc('t2', value=2)
-    //             ct.dec('t1')
-    //             ct.dec('t2')
-    //             assert sorted(ct.get_queues()) == ['test-counter-t1',
-    //                                                'test-counter-t2']
-    //             assert ct.get_queues_sizes() == {'test-counter-t1': 9,
-    //                                              'test-counter-t2': 39}
-    //             ct.reset_queues()
-    //             assert ct.get_queues_sizes() == {'test-counter-t1': 0,
-    //                                              'test-counter-t2': 0}
-    
-    
-    // # noinspection PyShadowingNames
-    // def test_tracked_counters(redis_connection):
-    //     if redis_connection:
-    //         from assemblyline.remote.datatypes.counters import Counters
-    //         with Counters('tracked-test-counter', track_counters=True) as ct:
-    //             ct.delete()
-    
-    //             for x in range(10):
-    //                 ct.inc('t1')
-    //             for x in range(20):
-    //                 ct.inc('t2', value=2)
-    //             assert ct.tracker.keys() == ['t1', 't2']
-    //             ct.dec('t1')
-    //             ct.dec('t2')
-    //             assert ct.tracker.keys() == []
-    //             assert sorted(ct.get_queues()) == ['tracked-test-counter-t1',
-    //                                                'tracked-test-counter-t2']
-    //             assert ct.get_queues_sizes() == {'tracked-test-counter-t1': 9,
-    //                                              'tracked-test-counter-t2': 39}
-    //             ct.reset_queues()
-    //             assert ct.get_queues_sizes() == {'tracked-test-counter-t1': 0,
-    //                                              'tracked-test-counter-t2': 0}
\ No newline at end of file
+#[cfg(test)]
+async fn check_basic_counters(connection: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+    let ct = connection.counters("test-counter".to_owned(), false);
+    ct.delete().await?;
+
+    for _ in 0..10 {
+        ct.inc("t1", 1, None).await?;
+    }
+    for _ in 0..20 {
+        ct.inc("t2", 2, None).await?;
+    }
+    ct.dec("t1", 1, None).await?;
+    ct.dec("t2", 1, None).await?;
+    let mut queues = ct.get_queues().await?;
+    queues.sort();
+    assert_eq!(queues, ["test-counter-t1", "test-counter-t2"]);
+    assert_eq!(ct.get_queues_sizes().await?, [("test-counter-t1".to_owned(), 9), ("test-counter-t2".to_owned(), 39)].into());
+    ct.reset_queues().await?;
+    assert_eq!(ct.get_queues_sizes().await?, [("test-counter-t1".to_owned(), 0), ("test-counter-t2".to_owned(), 0)].into());
+    ct.delete().await?;
+    assert!(ct.get_queues().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn basic_counters() -> Result<(), ErrorTypes> {
+    check_basic_counters(crate::test::redis_connection().await).await
+}
+
+#[tokio::test]
+async fn basic_counters_memory() -> Result<(), ErrorTypes> {
+    check_basic_counters(crate::test::memory_connection("basic_counters")).await
+}
+
+#[cfg(test)]
+async fn check_tracked_counters(connection: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+    let ct = connection.counters("tracked-test-counter".to_owned(), true);
+    ct.delete().await?;
+    let tracker = ct.tracker().unwrap();
+
+    for _ in 0..10 {
+        ct.inc("t1", 1, None).await?;
+    }
+    for _ in 0..20 {
+        ct.inc("t2", 2, None).await?;
+    }
+    let mut tracked = tracker.keys().await?;
+    tracked.sort();
+    assert_eq!(tracked, ["t1", "t2"]);
+    ct.dec("t1", 1, None).await?;
+    ct.dec("t2", 1, None).await?;
+    assert!(tracker.keys().await?.is_empty());
+
+    // increments can be tracked under their own id
+    ct.inc("t1", 1, Some("task-a")).await?;
+    assert_eq!(tracker.keys().await?, ["task-a"]);
+    ct.dec("t1", 1, Some("task-a")).await?;
+    assert!(tracker.keys().await?.is_empty());
+
+    let mut queues = ct.get_queues().await?;
+    queues.sort();
+    assert_eq!(queues, ["tracked-test-counter-t1", "tracked-test-counter-t2"]);
+    assert_eq!(ct.get_queues_sizes().await?, [("tracked-test-counter-t1".to_owned(), 9), ("tracked-test-counter-t2".to_owned(), 39)].into());
+    ct.reset_queues().await?;
+    assert_eq!(ct.get_queues_sizes().await?, [("tracked-test-counter-t1".to_owned(), 0), ("tracked-test-counter-t2".to_owned(), 0)].into());
+    ct.delete().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn tracked_counters() -> Result<(), ErrorTypes> {
+    check_tracked_counters(crate::test::redis_connection().await).await
+}
+
+#[tokio::test]
+async fn tracked_counters_memory() -> Result<(), ErrorTypes> {
+    check_tracked_counters(crate::test::memory_connection("tracked_counters")).await
+}
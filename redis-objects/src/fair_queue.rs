@@ -0,0 +1,404 @@
+//! A priority queue shared fairly between tenants.
+//!
+//! Each tenant (a user, group, or anything else work should be balanced between) has its own sorted set
+//! ordered by priority. Tenants with items waiting are kept in a round robin list and items are taken
+//! from them by deficit round robin: every time a tenant comes to the front of the list its weight is
+//! added to its credit, it is served while it has at least one credit, then it moves to the back. With
+//! the default weight of one every tenant with work waiting gets one item per round no matter how much
+//! it has queued, so a single bulk submitter can't starve everyone else.
+//!
+//! Priority is only respected between the items of one tenant. All the keys of a queue share a hash
+//! tag so the scripts keep working on a redis cluster.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::queue::SORTING_KEY_LEN;
+use crate::{retry_call, ErrorTypes, RedisObjects};
+
+/// Add an item to the queue of a tenant, putting the tenant into rotation if it had nothing queued
+///
+/// keys: active tenant list, queue of the tenant, wakeup signal
+/// args: tenant, score, item
+pub (crate) const FAIR_PUSH_SCRIPT: &str = r#"
+if redis.call('zcard', KEYS[2]) == 0 then
+    redis.call('lrem', KEYS[1], 0, ARGV[1])
+    redis.call('rpush', KEYS[1], ARGV[1])
+end
+redis.call('zadd', KEYS[2], ARGV[2], ARGV[3])
+redis.call('lpush', KEYS[3], 1)
+redis.call('ltrim', KEYS[3], 0, 0)
+return 1
+"#;
+
+/// Take the next item by deficit round robin
+///
+/// keys: active tenant list, credit hash, weight hash, wakeup signal
+/// args: prefix of the tenant queue keys, '1' to take the lowest priority item of the tenant
+///
+/// The tenant queue keys are built from the prefix here rather than passed in, the tenant isn't known
+/// until the script runs. This is only safe on a redis cluster because every key of a queue, the
+/// prefix included, carries the same `{name}` hash tag, so they all live in the slot of the keys
+/// that are declared. Any change to the key layout has to keep that tag.
+pub (crate) const FAIR_POP_SCRIPT: &str = r#"
+local prefix = ARGV[1]
+local low_priority = ARGV[2] == '1'
+
+-- weights are at least 0.01 so every tenant gets a turn within this many visits
+local visits = redis.call('llen', KEYS[1]) * 100 + 1
+for _ = 1, visits do
+    local tenant = redis.call('lindex', KEYS[1], 0)
+    if not tenant then return nil end
+    local queue = prefix .. tenant
+
+    if redis.call('zcard', queue) == 0 then
+        redis.call('lpop', KEYS[1])
+        redis.call('hdel', KEYS[2], tenant)
+    else
+        local credit = tonumber(redis.call('hget', KEYS[2], tenant)) or 0
+        if credit < 1 then
+            credit = credit + (tonumber(redis.call('hget', KEYS[3], tenant)) or 1)
+        end
+
+        if credit < 1 then
+            redis.call('hset', KEYS[2], tenant, credit)
+            redis.call('rpush', KEYS[1], redis.call('lpop', KEYS[1]))
+        else
+            local popped
+            if low_priority then
+                popped = redis.call('zpopmax', queue)
+            else
+                popped = redis.call('zpopmin', queue)
+            end
+            credit = credit - 1
+
+            if redis.call('zcard', queue) == 0 then
+                redis.call('lpop', KEYS[1])
+                redis.call('hdel', KEYS[2], tenant)
+            else
+                redis.call('hset', KEYS[2], tenant, credit)
+                if credit < 1 then
+                    redis.call('rpush', KEYS[1], redis.call('lpop', KEYS[1]))
+                end
+            end
+
+            -- pass the wakeup along to other consumers while there is work left
+            if redis.call('llen', KEYS[1]) > 0 then
+                redis.call('lpush', KEYS[4], 1)
+                redis.call('ltrim', KEYS[4], 0, 0)
+            end
+            return popped[1]
+        end
+    end
+end
+return nil
+"#;
+
+/// Smallest weight a tenant can be given
+const MIN_WEIGHT: f64 = 0.01;
+
+/// Longest a blocking pop waits on the wakeup signal before checking the queue again
+const SIGNAL_WAIT: Duration = Duration::from_secs(1);
+
+/// Separates the tenant from the data in the items of the queue
+const TENANT_SEPARATOR: u8 = 0;
+
+/// Every key of a queue is wrapped around its name like this one, the braces make the name the hash tag
+fn active_key_name(name: &str) -> String {
+    format!("fair-queue-{{{name}}}-active")
+}
+
+/// Find the names of the fair queues matching a key pattern that have items waiting
+pub (crate) async fn list_active(store: &RedisObjects, pattern: &str) -> Result<Vec<String>, ErrorTypes> {
+    let keys = store.keys(&active_key_name(pattern)).await?;
+    Ok(keys.iter()
+        .filter_map(|key| key.strip_prefix("fair-queue-{")?.strip_suffix("}-active"))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// A priority queue that takes items from each tenant in turn
+pub struct FairQueue<T> {
+    name: String,
+    store: Arc<RedisObjects>,
+    push_script: redis::Script,
+    pop_script: redis::Script,
+    _data: PhantomData<T>,
+}
+
+impl<T> std::fmt::Debug for FairQueue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FairQueue").field("name", &self.name).field("store", &self.store).finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> FairQueue<T> {
+    pub (crate) fn new(name: String, store: Arc<RedisObjects>) -> Self {
+        Self {
+            name,
+            store,
+            push_script: redis::Script::new(FAIR_PUSH_SCRIPT),
+            pop_script: redis::Script::new(FAIR_POP_SCRIPT),
+            _data: PhantomData,
+        }
+    }
+
+    /// get the name this queue was opened with
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn active_key(&self) -> String {
+        active_key_name(&self.name)
+    }
+
+    fn credit_key(&self) -> String {
+        format!("fair-queue-{{{}}}-credit", self.name)
+    }
+
+    fn weight_key(&self) -> String {
+        format!("fair-queue-{{{}}}-weight", self.name)
+    }
+
+    fn signal_key(&self) -> String {
+        format!("fair-queue-{{{}}}-signal", self.name)
+    }
+
+    fn tenant_prefix(&self) -> String {
+        format!("fair-queue-{{{}}}-tenant-", self.name)
+    }
+
+    fn tenant_key(&self, tenant: &str) -> String {
+        self.tenant_prefix() + tenant
+    }
+
+    /// Items carry the same sorting prefix as a priority queue, followed by the tenant they belong to
+    fn encode(tenant: &str, item: &T) -> Result<Vec<u8>, ErrorTypes> {
+        let now = chrono::Utc::now().timestamp_micros();
+        let mut value = format!("9{now:020}{tenant}").into_bytes();
+        value.push(TENANT_SEPARATOR);
+        serde_json::to_writer(&mut value, item)?;
+        Ok(value)
+    }
+
+    /// Split an item into its tenant and data
+    fn split(raw: &[u8]) -> Option<(&[u8], &[u8])> {
+        let body = raw.get(SORTING_KEY_LEN..)?;
+        let separator = body.iter().position(|byte| *byte == TENANT_SEPARATOR)?;
+        Some((&body[..separator], &body[separator + 1..]))
+    }
+
+    fn decode(raw: &[u8]) -> Result<T, ErrorTypes> {
+        let Some((_, data)) = Self::split(raw) else { return Err(ErrorTypes::UnknownRedisError) };
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// Place an item into the queue of a tenant, returns the raw item for use with `rank` and `remove`
+    ///
+    /// Tenant names can't contain a null byte.
+    #[instrument(skip(data))]
+    pub async fn push(&self, tenant: &str, priority: f64, data: &T) -> Result<Vec<u8>, ErrorTypes> {
+        let value = Self::encode(tenant, data)?;
+        let mut call = self.push_script.key(self.active_key());
+        let call = call.key(self.tenant_key(tenant)).key(self.signal_key())
+            .arg(tenant).arg(-priority).arg(&value);
+        let _: i64 = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(value)
+    }
+
+    /// Take the next item, the highest priority item of whichever tenant's turn it is
+    ///
+    /// With `low_priority` the lowest priority item of that tenant is taken instead.
+    #[instrument]
+    pub async fn pop(&self, low_priority: bool) -> Result<Option<T>, ErrorTypes> {
+        let mut call = self.pop_script.key(self.active_key());
+        let call = call.key(self.credit_key()).key(self.weight_key()).key(self.signal_key())
+            .arg(self.tenant_prefix()).arg(if low_priority { "1" } else { "0" });
+        let result: Option<Vec<u8>> = retry_call!(method, self.store.pool, call, invoke_async)?;
+        result.map(|raw| Self::decode(&raw)).transpose()
+    }
+
+    /// Take the next item, waiting up to the timeout for one to arrive
+    #[instrument]
+    pub async fn blocking_pop(&self, timeout: Duration, low_priority: bool) -> Result<Option<T>, ErrorTypes> {
+        let deadline = Instant::now() + timeout;
+        let signal = self.signal_key();
+        loop {
+            if let Some(item) = self.pop(low_priority).await? {
+                return Ok(Some(item))
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None)
+            }
+            // a zero timeout would block forever, the wait is always at least a millisecond
+            let wait = remaining.min(SIGNAL_WAIT).max(Duration::from_millis(1));
+            let _: Option<(String, Vec<u8>)> = retry_call!(self.store.pool, blpop, &signal, wait.as_secs_f64())?;
+        }
+    }
+
+    /// Given a raw item returned by `push` get its position among the items of its tenant
+    #[instrument(skip(raw_value))]
+    pub async fn rank(&self, raw_value: &[u8]) -> Result<Option<u64>, ErrorTypes> {
+        let Some((tenant, _)) = Self::split(raw_value) else { return Ok(None) };
+        let key = self.tenant_key(&String::from_utf8_lossy(tenant));
+        retry_call!(self.store.pool, zrank, &key, raw_value)
+    }
+
+    /// Remove a specific item from the queue based on the raw item returned by `push`
+    #[instrument(skip(raw_value))]
+    pub async fn remove(&self, raw_value: &[u8]) -> Result<bool, ErrorTypes> {
+        let Some((tenant, _)) = Self::split(raw_value) else { return Ok(false) };
+        let key = self.tenant_key(&String::from_utf8_lossy(tenant));
+        let count: i32 = retry_call!(self.store.pool, zrem, &key, raw_value)?;
+        Ok(count >= 1)
+    }
+
+    /// Set the share of the queue a tenant gets relative to the others, tenants default to a weight of one
+    #[instrument]
+    pub async fn set_weight(&self, tenant: &str, weight: f64) -> Result<(), ErrorTypes> {
+        retry_call!(self.store.pool, hset, &self.weight_key(), tenant, weight.max(MIN_WEIGHT))
+    }
+
+    /// Return a tenant to the default weight
+    #[instrument]
+    pub async fn clear_weight(&self, tenant: &str) -> Result<(), ErrorTypes> {
+        retry_call!(self.store.pool, hdel, &self.weight_key(), tenant)
+    }
+
+    /// Get the tenants that have been given a weight
+    #[instrument]
+    pub async fn weights(&self) -> Result<HashMap<String, f64>, ErrorTypes> {
+        retry_call!(self.store.pool, hgetall, &self.weight_key())
+    }
+
+    /// Get the number of items queued by each tenant with anything waiting
+    #[instrument]
+    pub async fn tenant_lengths(&self) -> Result<Vec<(String, u64)>, ErrorTypes> {
+        let tenants: Vec<String> = retry_call!(self.store.pool, lrange, &self.active_key(), 0, -1)?;
+        if tenants.is_empty() {
+            return Ok(vec![])
+        }
+
+        let mut pipe = redis::pipe();
+        for tenant in &tenants {
+            pipe.zcard(self.tenant_key(tenant));
+        }
+        let lengths: Vec<u64> = retry_call!(method, self.store.pool, pipe, query_async)?;
+        Ok(tenants.into_iter().zip(lengths).filter(|(_, length)| *length > 0).collect())
+    }
+
+    /// Get the number of items in the queue across all tenants
+    #[instrument]
+    pub async fn length(&self) -> Result<u64, ErrorTypes> {
+        Ok(self.tenant_lengths().await?.into_iter().map(|(_, length)| length).sum())
+    }
+
+    /// Remove all the data from this queue, tenant weights included
+    #[instrument]
+    pub async fn delete(&self) -> Result<(), ErrorTypes> {
+        let tenants: Vec<String> = retry_call!(self.store.pool, lrange, &self.active_key(), 0, -1)?;
+        let mut keys: Vec<String> = tenants.iter().map(|tenant| self.tenant_key(tenant)).collect();
+        keys.extend([self.active_key(), self.credit_key(), self.weight_key(), self.signal_key()]);
+        retry_call!(self.store.pool, del, &keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::test::{memory_connection, redis_connection};
+    use crate::{ErrorTypes, RedisObjects};
+
+    #[tokio::test]
+    async fn fair_queue() -> Result<(), ErrorTypes> {
+        check_fair_queue(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn fair_queue_memory() -> Result<(), ErrorTypes> {
+        check_fair_queue(memory_connection("fair_queue")).await
+    }
+
+    async fn check_fair_queue(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        let queue = redis.fair_queue::<String>("test-fair-queue".to_owned());
+        queue.delete().await?;
+
+        // a tenant with a lot queued gets the same share as one with a little
+        for index in 0..6 {
+            queue.push("bulk", 1.0, &format!("bulk-{index}")).await?;
+        }
+        queue.push("user", 1.0, &"user-0".to_owned()).await?;
+        queue.push("user", 5.0, &"user-1".to_owned()).await?;
+        assert_eq!(queue.length().await?, 8);
+        assert_eq!(queue.tenant_lengths().await?, [("bulk".to_owned(), 6), ("user".to_owned(), 2)]);
+        assert_eq!(redis.fair_queue_names("test-fair-*").await?, ["test-fair-queue"]);
+
+        // priority is followed within a tenant
+        let mut order = vec![];
+        for _ in 0..5 {
+            order.push(queue.pop(false).await?.unwrap());
+        }
+        assert_eq!(order, ["bulk-0", "user-1", "bulk-1", "user-0", "bulk-2"]);
+        assert_eq!(queue.tenant_lengths().await?, [("bulk".to_owned(), 3)]);
+
+        // weights change the share of each round a tenant gets
+        queue.delete().await?;
+        queue.set_weight("heavy", 2.0).await?;
+        queue.set_weight("light", 0.5).await?;
+        for index in 0..6 {
+            queue.push("heavy", 1.0, &format!("heavy-{index}")).await?;
+            queue.push("light", 1.0, &format!("light-{index}")).await?;
+            queue.push("normal", 1.0, &format!("normal-{index}")).await?;
+        }
+        let mut order = vec![];
+        for _ in 0..7 {
+            order.push(queue.pop(false).await?.unwrap());
+        }
+        assert_eq!(order, ["heavy-0", "heavy-1", "normal-0", "heavy-2", "heavy-3", "light-0", "normal-1"]);
+        assert_eq!(queue.weights().await?, [("heavy".to_owned(), 2.0), ("light".to_owned(), 0.5)].into());
+        queue.clear_weight("heavy").await?;
+        assert_eq!(queue.weights().await?, [("light".to_owned(), 0.5)].into());
+        assert_eq!(queue.pop(false).await?.unwrap(), "heavy-4");
+        assert_eq!(queue.pop(false).await?.unwrap(), "normal-2");
+
+        // items can be found and removed by the raw value returned when they were added
+        queue.delete().await?;
+        let first = queue.push("user", 1.0, &"first".to_owned()).await?;
+        let second = queue.push("user", 1.0, &"second".to_owned()).await?;
+        assert_eq!(queue.rank(&second).await?, Some(1));
+        assert!(queue.remove(&first).await?);
+        assert!(!queue.remove(&first).await?);
+        assert_eq!(queue.rank(&first).await?, None);
+        assert_eq!(queue.rank(&second).await?, Some(0));
+        assert_eq!(queue.pop(true).await?.unwrap(), "second");
+        assert!(queue.pop(false).await?.is_none());
+
+        // a tenant emptied by removal rejoins the rotation once
+        let only = queue.push("user", 1.0, &"only".to_owned()).await?;
+        assert!(queue.remove(&only).await?);
+        queue.push("user", 1.0, &"again".to_owned()).await?;
+        assert_eq!(queue.tenant_lengths().await?, [("user".to_owned(), 1)]);
+        assert_eq!(queue.pop(false).await?.unwrap(), "again");
+
+        // waiting pops are woken by new items
+        assert!(queue.blocking_pop(Duration::from_millis(50), false).await?.is_none());
+        let writer = redis.fair_queue::<String>("test-fair-queue".to_owned());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            writer.push("late", 1.0, &"late".to_owned()).await.unwrap();
+        });
+        assert_eq!(queue.blocking_pop(Duration::from_secs(5), false).await?.unwrap(), "late");
+        queue.delete().await?;
+        Ok(())
+    }
+}
@@ -8,14 +8,14 @@ use tracing::instrument;
 
 use crate::{RedisObjects, ErrorTypes, retry_call};
 
-const POP_SCRIPT: &str = r#"
-local result = redis.call('hget', ARGV[1], ARGV[2])
-if result then redis.call('hdel', ARGV[1], ARGV[2]) end
+pub (crate) const POP_SCRIPT: &str = r#"
+local result = redis.call('hget', KEYS[1], ARGV[1])
+if result then redis.call('hdel', KEYS[1], ARGV[1]) end
 return result
 "#;
 
 
-const CONDITIONAL_REMOVE_SCRIPT: &str = r#"
+pub (crate) const CONDITIONAL_REMOVE_SCRIPT: &str = r#"
 local hash_name = KEYS[1]
 local key_in_hash = ARGV[1]
 local expected_value = ARGV[2]
@@ -39,6 +39,83 @@ return 0
 // return nil
 // "#;
 
+pub (crate) const EXPIRING_SET_SCRIPT: &str = r#"
+local t = redis.call('time')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+
+local hash_name = KEYS[1]
+local expiry_name = KEYS[2]
+local key_in_hash = ARGV[1]
+local value = ARGV[2]
+local ttl = tonumber(ARGV[3])
+local only_new = ARGV[4] == '1'
+
+local expired = redis.call('zrangebyscore', expiry_name, '-inf', now)
+-- unpack is limited in how many values it can return, remove the keys in chunks
+for index = 1, #expired, 1000 do
+    redis.call('hdel', hash_name, unpack(expired, index, math.min(index + 999, #expired)))
+end
+if #expired > 0 then
+    redis.call('zremrangebyscore', expiry_name, '-inf', now)
+end
+if only_new and redis.call('hexists', hash_name, key_in_hash) == 1 then
+    return 0
+end
+
+local added = redis.call('hset', hash_name, key_in_hash, value)
+redis.call('zadd', expiry_name, now + ttl, key_in_hash)
+local last = redis.call('zrevrangebyscore', expiry_name, '+inf', '-inf', 'WITHSCORES', 'LIMIT', 0, 1)
+local remaining = tonumber(last[2]) - now
+redis.call('pexpire', hash_name, remaining)
+redis.call('pexpire', expiry_name, remaining)
+return added
+"#;
+
+pub (crate) const EXPIRING_GET_SCRIPT: &str = r#"
+local t = redis.call('time')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+
+local hash_name = KEYS[1]
+local expiry_name = KEYS[2]
+local key_in_hash = ARGV[1]
+local remove = ARGV[2] == '1'
+
+local expiry = tonumber(redis.call('zscore', expiry_name, key_in_hash))
+if expiry == nil then
+    return false
+end
+if expiry <= now then
+    redis.call('hdel', hash_name, key_in_hash)
+    redis.call('zrem', expiry_name, key_in_hash)
+    return false
+end
+
+local result = redis.call('hget', hash_name, key_in_hash)
+if remove then
+    redis.call('hdel', hash_name, key_in_hash)
+    redis.call('zrem', expiry_name, key_in_hash)
+end
+return result
+"#;
+
+pub (crate) const EXPIRING_READ_SCRIPT: &str = r#"
+local t = redis.call('time')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+
+local hash_name = KEYS[1]
+local expiry_name = KEYS[2]
+
+local expired = redis.call('zrangebyscore', expiry_name, '-inf', now)
+-- unpack is limited in how many values it can return, remove the keys in chunks
+for index = 1, #expired, 1000 do
+    redis.call('hdel', hash_name, unpack(expired, index, math.min(index + 999, #expired)))
+end
+if #expired > 0 then
+    redis.call('zremrangebyscore', expiry_name, '-inf', now)
+end
+return redis.call(ARGV[1], hash_name)
+"#;
+
 
 /// Hashmap opened by `RedisObjects::hashmap`
 #[derive(Clone)]
@@ -193,7 +270,7 @@ impl<T: Serialize + DeserializeOwned> Hashmap<T> {
     /// Remove and return the item in the hash if found
     #[instrument]
     pub async fn pop(&self, key: &str) -> Result<Option<T>, ErrorTypes> {
-        let item: Option<Vec<u8>>  = retry_call!(method, self.store.pool, self.pop_script.arg(&self.name).arg(key), invoke_async)?;
+        let item: Option<Vec<u8>>  = retry_call!(method, self.store.pool, self.pop_script.key(&self.name).arg(key), invoke_async)?;
         Ok(match item {
             Some(data) => Some(serde_json::from_slice(&data)?),
             None => None,
@@ -225,15 +302,164 @@ impl<T: Serialize + DeserializeOwned> Hashmap<T> {
 }
 
 
+/// Hash opened by `RedisObjects::expiring_hash` where each key ages out on its own.
+///
+/// The expiry time of every key is kept in a sorted set next to the hash, expired keys are never
+/// returned and are removed by the next write or listing, so no scan is needed to clear them.
+#[derive(Clone)]
+pub struct ExpiringHash<T> {
+    name: String,
+    store: Arc<RedisObjects>,
+    ttl: Duration,
+    set_script: redis::Script,
+    get_script: redis::Script,
+    read_script: redis::Script,
+    _data: PhantomData<T>
+}
+
+impl<T> std::fmt::Debug for ExpiringHash<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExpiringHash").field("store", &self.store).field("name", &self.name).field("ttl", &self.ttl).finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> ExpiringHash<T> {
+    pub (crate) fn new(name: String, store: Arc<RedisObjects>, ttl: Duration) -> Self {
+        Self {
+            name,
+            store,
+            ttl,
+            set_script: redis::Script::new(EXPIRING_SET_SCRIPT),
+            get_script: redis::Script::new(EXPIRING_GET_SCRIPT),
+            read_script: redis::Script::new(EXPIRING_READ_SCRIPT),
+            _data: PhantomData,
+        }
+    }
+
+    // both keys share a hash tag so the scripts can use them together on a cluster
+    fn hash_name(&self) -> String {
+        format!("expiring-hash-{{{}}}", self.name)
+    }
+
+    fn expiry_name(&self) -> String {
+        format!("expiring-hash-{{{}}}-expiry", self.name)
+    }
+
+    async fn write(&self, key: &str, value: &T, ttl: Duration, only_new: bool) -> Result<bool, ErrorTypes> {
+        let data = serde_json::to_vec(value)?;
+        let ttl = (ttl.as_millis() as u64).max(1);
+        let mut call = self.set_script.key(self.hash_name());
+        let call = call.key(self.expiry_name()).arg(key).arg(&data).arg(ttl).arg(if only_new { "1" } else { "0" });
+        let added: i64 = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(added == 1)
+    }
+
+    async fn read<R: redis::FromRedisValue>(&self, command: &str) -> Result<R, ErrorTypes> {
+        let mut call = self.read_script.key(self.hash_name());
+        let call = call.key(self.expiry_name()).arg(command);
+        retry_call!(method, self.store.pool, call, invoke_async)
+    }
+
+    async fn fetch(&self, key: &str, remove: bool) -> Result<Option<T>, ErrorTypes> {
+        let mut call = self.get_script.key(self.hash_name());
+        let call = call.key(self.expiry_name()).arg(key).arg(if remove { "1" } else { "0" });
+        let item: Option<Vec<u8>> = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(match item {
+            Some(data) => Some(serde_json::from_slice(&data)?),
+            None => None,
+        })
+    }
+
+    /// Add the (key, value) pair to the hash if the key isn't already present.
+    /// Returns true if key has been added to the table, False otherwise.
+    #[instrument(skip(value))]
+    pub async fn add(&self, key: &str, value: &T) -> Result<bool, ErrorTypes> {
+        self.write(key, value, self.ttl, true).await
+    }
+
+    /// Write the value at the given key, restarting its time to live.
+    /// Returns true if the key was not already present.
+    #[instrument(skip(value))]
+    pub async fn set(&self, key: &str, value: &T) -> Result<bool, ErrorTypes> {
+        self.write(key, value, self.ttl, false).await
+    }
+
+    /// Write the value at the given key with a time to live other than the default for this hash
+    #[instrument(skip(value))]
+    pub async fn set_with_ttl(&self, key: &str, value: &T, ttl: Duration) -> Result<bool, ErrorTypes> {
+        self.write(key, value, ttl, false).await
+    }
+
+    /// Test if a given key is defined and hasn't expired
+    #[instrument]
+    pub async fn exists(&self, key: &str) -> Result<bool, ErrorTypes> {
+        let mut call = self.get_script.key(self.hash_name());
+        let call = call.key(self.expiry_name()).arg(key).arg("0");
+        let item: Option<Vec<u8>> = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(item.is_some())
+    }
+
+    /// Read the value stored at the given key
+    #[instrument]
+    pub async fn get(&self, key: &str) -> Result<Option<T>, ErrorTypes> {
+        self.fetch(key, false).await
+    }
+
+    /// Remove and return the item in the hash if found
+    #[instrument]
+    pub async fn pop(&self, key: &str) -> Result<Option<T>, ErrorTypes> {
+        self.fetch(key, true).await
+    }
+
+    /// Load all keys that haven't expired
+    #[instrument]
+    pub async fn keys(&self) -> Result<Vec<String>, ErrorTypes> {
+        self.read("hkeys").await
+    }
+
+    /// Read the number of keys that haven't expired
+    #[instrument]
+    pub async fn length(&self) -> Result<u64, ErrorTypes> {
+        self.read("hlen").await
+    }
+
+    /// Download all the items that haven't expired into memory
+    #[instrument]
+    pub async fn items(&self) -> Result<HashMap<String, T>, ErrorTypes> {
+        let items: Vec<(String, Vec<u8>)> = self.read("hgetall").await?;
+        let mut out = HashMap::new();
+        for (key, data) in items {
+            out.insert(key, serde_json::from_slice(&data)?);
+        }
+        Ok(out)
+    }
+
+    /// Clear the content of this hash
+    #[instrument]
+    pub async fn delete(&self) -> Result<(), ErrorTypes> {
+        retry_call!(self.store.pool, del, &[self.hash_name(), self.expiry_name()])
+    }
+}
+
+
 #[cfg(test)]
 mod test {
-    use crate::test::redis_connection;
-    use crate::ErrorTypes;
+    use crate::test::{memory_connection, redis_connection};
+    use crate::{ErrorTypes, RedisObjects};
+    use std::sync::Arc;
     use std::time::Duration;
 
     #[tokio::test]
     async fn hash() -> Result<(), ErrorTypes> {
-        let redis = redis_connection().await;
+        check_hash(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn hash_memory() -> Result<(), ErrorTypes> {
+        check_hash(memory_connection("hash")).await
+    }
+
+    async fn check_hash(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
         let h = redis.hashmap("test-hashmap".to_string(), None);
         h.delete().await?;
 
@@ -287,7 +513,15 @@ mod test {
 
     #[tokio::test] 
     async fn expiring_hash() -> Result<(), ErrorTypes> {
-        let redis = redis_connection().await;
+        check_expiring_hash(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn expiring_hash_memory() -> Result<(), ErrorTypes> {
+        check_expiring_hash(memory_connection("expiring_hash")).await
+    }
+
+    async fn check_expiring_hash(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
         let eh = redis.hashmap("test-expiring-hashmap".to_string(), Duration::from_secs(1).into());
         eh.delete().await?;
         assert!(eh.add("key", &"value".to_owned()).await?);
@@ -297,4 +531,56 @@ mod test {
         Ok(())
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn field_expiring_hash() -> Result<(), ErrorTypes> {
+        check_field_expiring_hash(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn field_expiring_hash_memory() -> Result<(), ErrorTypes> {
+        check_field_expiring_hash(memory_connection("field_expiring_hash")).await
+    }
+
+    async fn check_field_expiring_hash(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        let eh = redis.expiring_hash::<u64>("test-field-expiring-hash".to_string(), Duration::from_millis(500));
+        eh.delete().await?;
+
+        assert!(eh.add("a", &1).await?);
+        assert!(!eh.add("a", &2).await?);
+        assert!(!eh.set("a", &3).await?);
+        assert!(eh.set_with_ttl("b", &4, Duration::from_secs(5)).await?);
+        assert!(eh.exists("a").await?);
+        assert_eq!(eh.get("a").await?, Some(3));
+        assert_eq!(eh.length().await?, 2);
+        assert_eq!(eh.items().await?, [("a".to_owned(), 3), ("b".to_owned(), 4)].into_iter().collect());
+
+        // keys age out individually
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(!eh.exists("a").await?);
+        assert_eq!(eh.get("a").await?, None);
+        assert_eq!(eh.keys().await?, ["b"]);
+        assert_eq!(eh.length().await?, 1);
+
+        // an expired key can be added again and popped keys are gone
+        assert!(eh.add("a", &5).await?);
+        assert_eq!(eh.pop("a").await?, Some(5));
+        assert_eq!(eh.pop("a").await?, None);
+        assert_eq!(eh.length().await?, 1);
+
+        eh.delete().await?;
+        assert_eq!(eh.length().await?, 0);
+
+        // more keys can expire at once than lua can unpack in one call
+        let many = redis.expiring_hash::<u64>("test-field-expiring-hash-many".to_string(), Duration::from_millis(500));
+        many.delete().await?;
+        for index in 0..2500 {
+            many.set(&index.to_string(), &index).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(many.set_with_ttl("last", &0, Duration::from_secs(5)).await?);
+        assert_eq!(many.keys().await?, ["last"]);
+        many.delete().await?;
+        Ok(())
+    }
+
+}
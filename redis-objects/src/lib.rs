@@ -22,24 +22,34 @@ use tokio::sync::mpsc;
 use tracing::instrument;
 
 pub use self::queue::PriorityQueue;
+pub use self::fair_queue::FairQueue;
 pub use self::queue::Queue;
 // pub use self::quota::QuotaGuard;
-pub use self::hashmap::Hashmap;
-pub use self::counters::{AutoExportingMetrics, AutoExportingMetricsBuilder, MetricMessage};
+pub use self::hashmap::{ExpiringHash, Hashmap};
+pub use self::counters::{AutoExportingMetrics, AutoExportingMetricsBuilder, Counters, MetricMessage};
 pub use self::pubsub::{JsonListenerBuilder, ListenerBuilder, Publisher};
 pub use self::set::Set;
+pub use self::connection::TlsCertificates;
+pub use self::lock::{LeaderElection, Lock, LockGuard};
+pub use self::stream::StreamQueue;
+pub use self::rate_limit::{RateDecision, SlidingWindow, TokenBucket};
 
 pub mod queue;
+pub mod fair_queue;
 pub mod quota;
 pub mod hashmap;
 pub mod counters;
 pub mod pubsub;
 pub mod set;
+pub mod lock;
+pub mod stream;
+pub mod rate_limit;
+mod connection;
+mod memory;
 
 /// Handle for a pool of connections to a redis server.
 pub struct RedisObjects {
-    pool: deadpool_redis::Pool,
-    client: redis::Client,
+    pool: Pool,
     hostname: String,
 }
 
@@ -77,36 +87,111 @@ impl RedisObjects {
         })
     }
 
+    /// Open a connection using tls, with a client certificate and certificate authority if given
+    pub fn open_host_tls(host: &str, port: u16, db: i64, certificates: &TlsCertificates) -> Result<Arc<Self>, ErrorTypes> {
+        debug!("Create redis tls connection pool.");
+        let manager = connection::ServerManager::new(host, port, db, Some(certificates))?;
+        let pool = connection::ServerPool::builder(manager)
+            .config(pool_config())
+            .runtime(deadpool_redis::Runtime::Tokio1)
+            .build()
+            .map_err(deadpool_redis::CreatePoolError::Build)?;
+        Ok(Arc::new(Self {
+            pool: Pool::Tls(pool),
+            hostname: format!("{host}:{port}"),
+        }))
+    }
+
     /// Open a connection pool
     pub fn open(config: redis::ConnectionInfo) -> Result<Arc<Self>, ErrorTypes> {
         debug!("Create redis connection pool.");
-        // configuration for the pool manager itself
         let hostname = config.addr.to_string();
-        let mut pool_cfg = deadpool_redis::PoolConfig::new(1024);
-        pool_cfg.timeouts.wait = Some(Duration::from_secs(5));
 
         // load redis configuration and create the pool
         let mut cfg = deadpool_redis::Config::from_connection_info(config.clone());
-        cfg.pool = Some(pool_cfg);
+        cfg.pool = Some(pool_config());
         let pool = cfg.create_pool(Some(deadpool_redis::Runtime::Tokio1))?;
         let client = redis::Client::open(config)?;
         Ok(Arc::new(Self{ 
-            pool,
-            client,
+            pool: Pool::Server { pool, client },
             hostname
         }))
     }
 
+    /// Open a connection pool to the master of a sentinel deployment.
+    /// 
+    /// The sentinels are asked for the current master whenever a new connection is needed, 
+    /// connections to a server that is no longer the master are dropped when they are returned to the pool.
+    pub fn open_sentinel(sentinels: &[(String, u16)], master: &str, db: i64, certificates: Option<&TlsCertificates>) -> Result<Arc<Self>, ErrorTypes> {
+        debug!("Create redis sentinel connection pool.");
+        let manager = connection::SentinelManager::new(sentinels, master, db, certificates)?;
+        let pool = connection::SentinelPool::builder(manager)
+            .config(pool_config())
+            .runtime(deadpool_redis::Runtime::Tokio1)
+            .build()
+            .map_err(deadpool_redis::CreatePoolError::Build)?;
+        let hosts: Vec<String> = sentinels.iter().map(|(host, port)| format!("{host}:{port}")).collect();
+        Ok(Arc::new(Self {
+            pool: Pool::Sentinel(pool),
+            hostname: format!("sentinel://{master}@{}", hosts.join(",")),
+        }))
+    }
+
+    /// Open a connection pool to a redis cluster.
+    /// 
+    /// Names of objects that are used together in a single call (such as the queues passed to `select`) 
+    /// must share a hash tag so that they are stored on the same node, see the redis cluster documentation.
+    /// Tls connections to a cluster are verified against the system trust store, client certificates and 
+    /// custom certificate authorities are rejected.
+    pub fn open_cluster(nodes: &[(String, u16)], certificates: Option<&TlsCertificates>) -> Result<Arc<Self>, ErrorTypes> {
+        debug!("Create redis cluster connection pool.");
+        let manager = connection::ClusterManager::new(nodes, certificates)?;
+        let pool = connection::ClusterPool::builder(manager)
+            .config(pool_config())
+            .runtime(deadpool_redis::Runtime::Tokio1)
+            .build()
+            .map_err(deadpool_redis::CreatePoolError::Build)?;
+        let hosts: Vec<String> = nodes.iter().map(|(host, port)| format!("{host}:{port}")).collect();
+        Ok(Arc::new(Self {
+            pool: Pool::Cluster(pool),
+            hostname: format!("cluster://{}", hosts.join(",")),
+        }))
+    }
+
+    /// Open a store held in the memory of this process rather than on a redis server.
+    /// All handles opened with the same name share the same data.
+    pub fn open_memory(name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            pool: Pool::Memory(memory::MemoryServer::open(name)),
+            hostname: format!("memory://{name}"),
+        })
+    }
+
     /// Open a priority queue under the given key
     pub fn priority_queue<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String) -> PriorityQueue<T> {
         PriorityQueue::new(name, self.clone())
     }
 
+    /// Open a priority queue shared fairly between tenants under the given name
+    pub fn fair_queue<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String) -> FairQueue<T> {
+        FairQueue::new(name, self.clone())
+    }
+
+    /// List the names of the fair queues matching a pattern that currently have items waiting
+    pub async fn fair_queue_names(&self, pattern: &str) -> Result<Vec<String>, ErrorTypes> {
+        fair_queue::list_active(self, pattern).await
+    }
+
     /// Open a FIFO queue under the given key
     pub fn queue<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String, ttl: Option<Duration>) -> Queue<T> {
         Queue::new(name, self.clone(), ttl)
     }
 
+    /// Open a durable queue on a stream, read through the named consumer group
+    pub fn stream_queue<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String, group: String) -> StreamQueue<T> {
+        StreamQueue::new(name, group, self.clone())
+    }
+
     /// an object that represents a set of queues with a common prefix
     pub fn multiqueue<T: Serialize + DeserializeOwned>(self: &Arc<Self>, prefix: String) -> MultiQueue<T> {
         MultiQueue::new(prefix, self.clone())
@@ -117,6 +202,16 @@ impl RedisObjects {
         Hashmap::new(name, self.clone(), ttl)
     }
 
+    /// Open a hash map under the given key where every entry expires on its own after the ttl
+    pub fn expiring_hash<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String, ttl: Duration) -> ExpiringHash<T> {
+        ExpiringHash::new(name, self.clone(), ttl)
+    }
+
+    /// Open a set of named counters sharing the given prefix
+    pub fn counters(self: &Arc<Self>, prefix: String, track_counters: bool) -> Counters {
+        Counters::new(prefix, self.clone(), track_counters)
+    }
+
     /// Create a sink to publish messages to a named channel
     pub fn publisher(self: &Arc<Self>, channel: String) -> Publisher {
         Publisher::new(self.clone(), channel)
@@ -170,6 +265,16 @@ impl RedisObjects {
         UserQuotaTracker::new(self.clone(), prefix)
     }
 
+    /// Open a rate limiter counting events over a trailing window, one counter per key under the prefix
+    pub fn sliding_window(self: &Arc<Self>, prefix: String, window: Duration) -> SlidingWindow {
+        SlidingWindow::new(self.clone(), prefix, window)
+    }
+
+    /// Open a token bucket rate limiter, one bucket per key under the prefix
+    pub fn token_bucket(self: &Arc<Self>, prefix: String) -> TokenBucket {
+        TokenBucket::new(self.clone(), prefix)
+    }
+
     /// Open a set of values
     pub fn set<T: Serialize + DeserializeOwned>(self: &Arc<Self>, name: String) -> Set<T> {
         Set::new(name, self.clone(), None)
@@ -181,6 +286,23 @@ impl RedisObjects {
         Set::new(name, self.clone(), Some(ttl))
     }
 
+    /// Open a lock that expires if not renewed within the given time
+    pub fn lock(self: &Arc<Self>, name: String, ttl: Duration) -> Lock {
+        Lock::new(name, self.clone(), ttl)
+    }
+
+    /// Open a leader election, leadership expires if not renewed within the given time
+    pub fn leader_election(self: &Arc<Self>, name: String, ttl: Duration) -> LeaderElection {
+        LeaderElection::new(name, self.clone(), ttl)
+    }
+
+    /// Check that the redis server can be reached, this is not retried
+    pub async fn ping(&self) -> Result<(), ErrorTypes> {
+        let mut con = self.pool.get().await?;
+        let _: () = redis::cmd("PING").query_async(&mut con).await?;
+        Ok(())
+    }
+
     /// Erase all data on the redis server
     pub async fn wipe(&self) -> Result<(), ErrorTypes> {
         let mut con = self.pool.get().await?;
@@ -196,6 +318,95 @@ impl RedisObjects {
 
 }
 
+/// configuration for the pool manager itself
+fn pool_config() -> deadpool_redis::PoolConfig {
+    let mut pool_cfg = deadpool_redis::PoolConfig::new(1024);
+    pool_cfg.timeouts.wait = Some(Duration::from_secs(5));
+    pool_cfg
+}
+
+/// Where the connections used by a `RedisObjects` handle come from
+pub (crate) enum Pool {
+    /// Connections to a redis server
+    Server {
+        pool: deadpool_redis::Pool,
+        client: redis::Client,
+    },
+    /// Connections to a redis server secured with custom certificates
+    Tls(connection::ServerPool),
+    /// Connections to the master of a sentinel deployment
+    Sentinel(connection::SentinelPool),
+    /// Connections to a redis cluster
+    Cluster(connection::ClusterPool),
+    /// Store in the memory of this process
+    Memory(Arc<memory::MemoryServer>),
+}
+
+impl Pool {
+    /// Get a connection, only the server pool can fail to provide one
+    pub (crate) async fn get(&self) -> Result<Connection, deadpool_redis::PoolError> {
+        match self {
+            Pool::Server { pool, .. } => Ok(Connection::Server(pool.get().await?)),
+            Pool::Tls(pool) => Ok(Connection::Tls(pool.get().await?)),
+            Pool::Sentinel(pool) => Ok(Connection::Sentinel(pool.get().await?)),
+            Pool::Cluster(pool) => Ok(Connection::Cluster(pool.get().await?)),
+            Pool::Memory(server) => Ok(Connection::Memory(memory::MemoryConnection::new(server.clone()))),
+        }
+    }
+
+    /// Open a connection to a server that can be used for pubsub, the memory store has none
+    pub (crate) async fn pubsub(&self) -> redis::RedisResult<redis::aio::PubSub> {
+        match self {
+            Pool::Server { client, .. } => client.get_async_pubsub().await,
+            Pool::Tls(pool) => pool.manager().pubsub().await,
+            Pool::Sentinel(pool) => pool.manager().pubsub().await,
+            Pool::Cluster(pool) => pool.manager().pubsub().await,
+            Pool::Memory(_) => Err((redis::ErrorKind::ClientError, "The memory store doesn't use a redis client").into()),
+        }
+    }
+}
+
+/// A connection taken from a `Pool`
+pub (crate) enum Connection {
+    Server(deadpool_redis::Connection),
+    Tls(deadpool::managed::Object<connection::ServerManager>),
+    Sentinel(deadpool::managed::Object<connection::SentinelManager>),
+    Cluster(deadpool::managed::Object<connection::ClusterManager>),
+    Memory(memory::MemoryConnection),
+}
+
+impl redis::aio::ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> redis::RedisFuture<'a, redis::Value> {
+        match self {
+            Connection::Server(connection) => connection.req_packed_command(cmd),
+            Connection::Tls(connection) => connection.req_packed_command(cmd),
+            Connection::Sentinel(connection) => connection.req_packed_command(cmd),
+            Connection::Cluster(connection) => connection.req_packed_command(cmd),
+            Connection::Memory(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a redis::Pipeline, offset: usize, count: usize) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            Connection::Server(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Tls(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Sentinel(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Memory(connection) => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Server(connection) => connection.get_db(),
+            Connection::Tls(connection) => connection.get_db(),
+            Connection::Sentinel(connection) => connection.get_db(),
+            Connection::Cluster(connection) => connection.get_db(),
+            Connection::Memory(connection) => connection.get_db(),
+        }
+    }
+}
+
 /// Enumeration over all possible errors
 #[derive(Debug)]
 pub enum ErrorTypes {
@@ -339,6 +550,7 @@ pub (crate) use retry_call;
 #[cfg(test)]
 pub (crate) mod test {
     use std::sync::Arc;
+    use std::time::Duration;
 
     use redis::ConnectionInfo;
 
@@ -352,6 +564,11 @@ pub (crate) mod test {
         }).unwrap()
     }
 
+    /// A fresh in-memory store for a single test
+    pub (crate) fn memory_connection(name: &str) -> Arc<RedisObjects> {
+        RedisObjects::open_memory(&format!("test-{name}"))
+    }
+
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
@@ -375,11 +592,39 @@ pub (crate) mod test {
     //         println!("{msg:?}");
     //     }
     // }
-    
+
+    #[tokio::test]
+    async fn open_deployments() {
+        init();
+        // none of these connect until a connection is taken from the pool
+        let nodes = vec![("localhost".to_string(), 26379), ("localhost".to_string(), 26380)];
+        let sentinel = RedisObjects::open_sentinel(&nodes, "mymaster", 1, None).unwrap();
+        assert_eq!(format!("{sentinel:?}"), r#"Redis { host: "sentinel://mymaster@localhost:26379,localhost:26380" }"#);
+        RedisObjects::open_sentinel(&nodes, "mymaster", 0, Some(&Default::default())).unwrap();
+
+        let cluster = RedisObjects::open_cluster(&nodes, None).unwrap();
+        assert_eq!(format!("{cluster:?}"), r#"Redis { host: "cluster://localhost:26379,localhost:26380" }"#);
+        RedisObjects::open_cluster(&nodes, Some(&Default::default())).unwrap();
+        assert!(RedisObjects::open_cluster(&[], None).is_err());
+        let custom = crate::TlsCertificates { root_cert: Some(b"not a certificate".to_vec()), ..Default::default() };
+        assert!(RedisObjects::open_cluster(&nodes, Some(&custom)).is_err());
+
+        let server = RedisObjects::open_host_tls("localhost", 6379, 0, &Default::default()).unwrap();
+        assert_eq!(format!("{server:?}"), r#"Redis { host: "localhost:6379" }"#);
+    }
+
     #[tokio::test]
     async fn test_sets() {
         init();
-        let redis = redis_connection().await;
+        check_sets(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn test_sets_memory() {
+        check_sets(memory_connection("sets")).await
+    }
+
+    async fn check_sets(redis: Arc<RedisObjects>) {
         let s = redis.set::<String>("test-set".to_owned());
 
         s.delete().await.unwrap();
@@ -460,7 +705,15 @@ pub (crate) mod test {
     
     #[tokio::test]
     async fn priority_queue() -> Result<(), ErrorTypes> {
-        let redis = redis_connection().await;
+        check_priority_queue(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn priority_queue_memory() -> Result<(), ErrorTypes> {
+        check_priority_queue(memory_connection("priority_queue")).await
+    }
+
+    async fn check_priority_queue(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
         let pq = redis.priority_queue("test-priority-queue".to_string());
         pq.delete().await?;
 
@@ -567,7 +820,15 @@ pub (crate) mod test {
     
     #[tokio::test]
     async fn named_queue() {
-        let redis = redis_connection().await;
+        check_named_queue(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn named_queue_memory() {
+        check_named_queue(memory_connection("named_queue")).await
+    }
+
+    async fn check_named_queue(redis: Arc<RedisObjects>) {
 
         let nq = redis.queue("test-named-queue".to_owned(), None);
         nq.delete().await.unwrap();
@@ -611,6 +872,14 @@ pub (crate) mod test {
 
         assert_eq!(Queue::select(&[&nq1, &nq2], None).await.unwrap().unwrap(), ("test-named-queue-1".to_owned(), 1));
         assert_eq!(Queue::select(&[&nq1, &nq2], None).await.unwrap().unwrap(), ("test-named-queue-2".to_owned(), 2));
+
+        // items moved between queues are never missing from both
+        nq1.push_batch(&[3, 4]).await.unwrap();
+        assert_eq!(nq1.pop_into(&nq2).await.unwrap(), Some(3));
+        assert_eq!(nq1.pop_into_timeout(&nq2, Duration::from_secs(1)).await.unwrap(), Some(4));
+        assert_eq!(nq1.pop_into_timeout(&nq2, Duration::from_millis(100)).await.unwrap(), None);
+        assert_eq!(nq1.pop_into(&nq2).await.unwrap(), None);
+        assert_eq!(nq2.content().await.unwrap(), [3, 4]);
     }
 
     // # noinspection PyShadowingNames
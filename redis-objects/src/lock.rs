@@ -0,0 +1,345 @@
+//! Locks held by a single process at a time, and leader election built on them.
+//!
+//! A lock is a key holding a random token of its current owner. It expires on its own if the owner
+//! stops renewing it, so a crashed process can't hold a lock forever. Every time a lock is taken a
+//! counter next to it is incremented, this fencing token can be passed along with any writes made
+//! while holding the lock so that a previous owner that has stalled past its expiry can be detected.
+//!
+//! Both keys share a hash tag so they stay on the same node of a cluster.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use tracing::instrument;
+
+use crate::{retry_call, ErrorTypes, RedisObjects};
+
+pub (crate) const ACQUIRE_SCRIPT: &str = r#"
+if redis.call('set', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return redis.call('incr', KEYS[2])
+end
+return false
+"#;
+
+pub (crate) const RENEW_SCRIPT: &str = r#"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('pexpire', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+pub (crate) const RELEASE_SCRIPT: &str = r#"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('del', KEYS[1])
+end
+return 0
+"#;
+
+/// A named lock that only one holder can have at a time
+#[derive(Clone)]
+pub struct Lock {
+    name: String,
+    store: Arc<RedisObjects>,
+    ttl: Duration,
+    acquire_script: redis::Script,
+    renew_script: redis::Script,
+    release_script: redis::Script,
+}
+
+impl std::fmt::Debug for Lock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lock").field("name", &self.name).field("store", &self.store).finish()
+    }
+}
+
+impl Lock {
+    pub (crate) fn new(name: String, store: Arc<RedisObjects>, ttl: Duration) -> Self {
+        Self {
+            name,
+            store,
+            ttl: ttl.max(Duration::from_millis(100)),
+            acquire_script: redis::Script::new(ACQUIRE_SCRIPT),
+            renew_script: redis::Script::new(RENEW_SCRIPT),
+            release_script: redis::Script::new(RELEASE_SCRIPT),
+        }
+    }
+
+    fn key(&self) -> String {
+        format!("lock-{{{}}}", self.name)
+    }
+
+    fn fence_key(&self) -> String {
+        format!("lock-{{{}}}-fence", self.name)
+    }
+
+    fn ttl_ms(&self) -> u64 {
+        self.ttl.as_millis() as u64
+    }
+
+    /// Take the lock if nobody holds it.
+    /// The lock is renewed in the background until the guard returned is released or dropped.
+    #[instrument]
+    pub async fn try_acquire(&self) -> Result<Option<LockGuard>, ErrorTypes> {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let started = Instant::now();
+        let fence: Option<u64> = retry_call!(method, self.store.pool,
+            self.acquire_script.key(self.key()).key(self.fence_key()).arg(&token).arg(self.ttl_ms()), invoke_async)?;
+        let Some(fence) = fence else { return Ok(None) };
+
+        let (held_sender, held) = watch::channel(true);
+        let state = Arc::new(GuardState { valid_until: Mutex::new(started + self.ttl), held: held_sender });
+        let renewal = tokio::spawn(self.clone().renew(token.clone(), state.clone()));
+        Ok(Some(LockGuard { lock: self.clone(), token, fence, state, held, renewal }))
+    }
+
+    /// Wait until the lock can be taken
+    #[instrument]
+    pub async fn acquire(&self) -> Result<LockGuard, ErrorTypes> {
+        let retry = (self.ttl / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        loop {
+            if let Some(guard) = self.try_acquire().await? {
+                return Ok(guard)
+            }
+            tokio::time::sleep(retry).await;
+        }
+    }
+
+    /// Keep extending the expiry of the lock until it is found to have been lost
+    async fn renew(self, token: String, state: Arc<GuardState>) {
+        let interval = self.ttl / 3;
+        loop {
+            tokio::time::sleep(interval).await;
+            let started = Instant::now();
+            let result: Result<bool, ErrorTypes> = retry_call!(method, self.store.pool,
+                self.renew_script.key(self.key()).arg(&token).arg(self.ttl_ms()), invoke_async);
+            match result {
+                Ok(true) => *state.valid_until.lock() = started + self.ttl,
+                Ok(false) => {
+                    warn!("Lock {} was lost", self.name);
+                    break
+                },
+                Err(err) => {
+                    warn!("Could not renew lock {}: {err}", self.name);
+                    break
+                }
+            }
+        }
+        state.held.send_replace(false);
+    }
+}
+
+struct GuardState {
+    /// Point after which the lock may have expired without being renewed
+    valid_until: Mutex<Instant>,
+    held: watch::Sender<bool>,
+}
+
+/// Proof of holding a lock, the lock is released when this is dropped
+pub struct LockGuard {
+    lock: Lock,
+    token: String,
+    fence: u64,
+    state: Arc<GuardState>,
+    held: watch::Receiver<bool>,
+    renewal: tokio::task::JoinHandle<()>,
+}
+
+impl std::fmt::Debug for LockGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockGuard").field("lock", &self.lock).field("fence", &self.fence).finish()
+    }
+}
+
+impl LockGuard {
+    /// Fencing token for this acquisition, larger than that of any previous holder of the lock
+    pub fn fence(&self) -> u64 {
+        self.fence
+    }
+
+    /// Check if the lock is still held.
+    /// This turns false as soon as the lock can't be confirmed, even if the connection is only interrupted.
+    pub fn is_held(&self) -> bool {
+        *self.held.borrow() && Instant::now() < *self.state.valid_until.lock()
+    }
+
+    /// Wait until the lock is lost
+    pub async fn lost(&self) {
+        let mut held = self.held.clone();
+        loop {
+            let valid_until = *self.state.valid_until.lock();
+            tokio::select! {
+                _ = held.wait_for(|held| !held) => return,
+                // the lock may have been renewed in the meantime
+                _ = tokio::time::sleep_until(valid_until.into()) => if !self.is_held() { return },
+            }
+        }
+    }
+
+    /// Give up the lock, returns false if it had already been lost
+    #[instrument]
+    pub async fn release(mut self) -> Result<bool, ErrorTypes> {
+        self.renewal.abort();
+        let token = std::mem::take(&mut self.token);
+        let lock = &self.lock;
+        retry_call!(method, lock.store.pool, lock.release_script.key(lock.key()).arg(&token), invoke_async)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        // the token is cleared once released, and there is nothing to release once the lock is lost
+        if self.token.is_empty() || self.renewal.is_finished() {
+            return
+        }
+        self.renewal.abort();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let lock = self.lock.clone();
+            let token = std::mem::take(&mut self.token);
+            runtime.spawn(async move {
+                let result: Result<bool, ErrorTypes> = retry_call!(method, lock.store.pool,
+                    lock.release_script.key(lock.key()).arg(&token), invoke_async);
+                if let Err(err) = result {
+                    warn!("Could not release lock {}: {err}", lock.name);
+                }
+            });
+        }
+    }
+}
+
+/// Pick a single leader among all the processes campaigning under the same name
+#[derive(Debug, Clone)]
+pub struct LeaderElection {
+    lock: Lock,
+}
+
+impl LeaderElection {
+    pub (crate) fn new(name: String, store: Arc<RedisObjects>, ttl: Duration) -> Self {
+        Self { lock: Lock::new(name, store, ttl) }
+    }
+
+    /// Wait until this process is elected, leadership lasts until the guard is dropped or the lock is lost
+    pub async fn campaign(&self) -> Result<LockGuard, ErrorTypes> {
+        self.lock.acquire().await
+    }
+
+    /// Run a task whenever this process is the leader.
+    ///
+    /// The task is called with the fencing token of the term it is running in. If leadership is lost
+    /// the task is cancelled and restarted once this process is elected again. Returns the output of
+    /// the first run of the task to finish, stepping down as leader when it does.
+    pub async fn lead<T, Fut: Future<Output=T>>(&self, mut task: impl FnMut(u64) -> Fut) -> Result<T, ErrorTypes> {
+        loop {
+            let guard = self.campaign().await?;
+            info!("Elected leader for {} (term {})", self.lock.name, guard.fence());
+            tokio::select! {
+                output = task(guard.fence()) => {
+                    guard.release().await?;
+                    return Ok(output)
+                },
+                _ = guard.lost() => {
+                    warn!("Lost leadership for {}", self.lock.name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::test::{memory_connection, redis_connection};
+    use crate::{ErrorTypes, RedisObjects};
+
+    #[tokio::test]
+    async fn lock() -> Result<(), ErrorTypes> {
+        check_lock(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn lock_memory() -> Result<(), ErrorTypes> {
+        check_lock(memory_connection("lock")).await
+    }
+
+    async fn check_lock(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        redis.wipe().await?;
+        let lock = redis.lock("test-lock".to_owned(), Duration::from_millis(300));
+
+        // only one holder at a time
+        let first = lock.try_acquire().await?.unwrap();
+        assert!(lock.try_acquire().await?.is_none());
+        assert!(first.is_held());
+
+        // the lock is kept alive past its ttl while the guard is held
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(first.is_held());
+        assert!(lock.try_acquire().await?.is_none());
+
+        // fencing tokens grow with every acquisition
+        let fence = first.fence();
+        assert!(first.release().await?);
+        let second = lock.try_acquire().await?.unwrap();
+        assert!(second.fence() > fence);
+
+        // losing the key is noticed by the holder
+        redis.wipe().await?;
+        tokio::time::timeout(Duration::from_secs(2), second.lost()).await.unwrap();
+        assert!(!second.is_held());
+        assert!(!second.release().await?);
+
+        // dropping a guard releases the lock
+        let third = lock.acquire().await?;
+        drop(third);
+        tokio::time::timeout(Duration::from_secs(2), lock.acquire()).await.unwrap()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leader_election() -> Result<(), ErrorTypes> {
+        check_leader_election(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn leader_election_memory() -> Result<(), ErrorTypes> {
+        check_leader_election(memory_connection("leader_election")).await
+    }
+
+    async fn check_leader_election(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        redis.wipe().await?;
+        let first = redis.leader_election("test-election".to_owned(), Duration::from_millis(300));
+        let second = redis.leader_election("test-election".to_owned(), Duration::from_millis(300));
+
+        // the second candidate waits for the first to step down
+        let leader = first.campaign().await?;
+        let waiting = tokio::spawn(async move { second.lead(|term| async move { term }).await });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!waiting.is_finished());
+        assert!(leader.release().await?);
+
+        let term = tokio::time::timeout(Duration::from_secs(2), waiting).await.unwrap().unwrap()?;
+        assert_eq!(term, 2);
+
+        // a task is restarted after leadership is lost
+        let runs = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let output = first.lead(|term| {
+            let redis = redis.clone();
+            let runs = runs.clone();
+            async move {
+                if runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    redis.wipe().await.unwrap();
+                    std::future::pending::<()>().await;
+                }
+                term
+            }
+        }).await?;
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 2);
+        // the wipe also cleared the fencing counter so the count starts over
+        assert_eq!(output, 1);
+        Ok(())
+    }
+}
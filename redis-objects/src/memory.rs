@@ -0,0 +1,1705 @@
+//! An in-process stand in for a redis server.
+//!
+//! The memory backend answers the commands the rest of this crate sends to redis, so every object
+//! works unchanged on top of it. Lua can't be evaluated here, instead the scripts used by this
+//! crate are recognized by their hash and run as native functions with the same behaviour.
+//! Data is shared between all handles opened under the same name and is dropped along with the
+//! last of those handles.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, LazyLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use rand::seq::IteratorRandom;
+use redis::{Cmd, ErrorKind, Msg, RedisError, RedisFuture, RedisResult, Value};
+use tokio::sync::{mpsc, Notify};
+
+/// Native implementation of a lua script, called with the keys and arguments of the invocation
+type ScriptFunction = fn(&mut Keyspace, &[Vec<u8>], &[Vec<u8>]) -> RedisResult<Value>;
+
+/// Every memory server currently open, by name
+static SERVERS: LazyLock<Mutex<HashMap<String, Weak<MemoryServer>>>> = LazyLock::new(Default::default);
+
+/// Data and subscriptions shared by every handle opened with the same name
+pub(crate) struct MemoryServer {
+    keyspace: Mutex<Keyspace>,
+    scripts: HashMap<String, ScriptFunction>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Woken after every command so blocking pops can check for new data
+    changed: Notify,
+}
+
+/// A pubsub listener along with what it is subscribed to
+struct Subscriber {
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    sender: mpsc::UnboundedSender<Msg>,
+}
+
+impl MemoryServer {
+    /// Get the server with the given name, starting a new one if none is open
+    pub(crate) fn open(name: &str) -> Arc<Self> {
+        let mut servers = SERVERS.lock();
+        if let Some(server) = servers.get(name).and_then(Weak::upgrade) {
+            return server
+        }
+        servers.retain(|_, server| server.strong_count() > 0);
+
+        let server = Arc::new(Self::new());
+        servers.insert(name.to_owned(), Arc::downgrade(&server));
+        server
+    }
+
+    fn new() -> Self {
+        let scripts: [(&str, ScriptFunction); 17] = [
+            (crate::queue::PQ_DEQUEUE_RANGE_SCRIPT, dequeue_range_script),
+            (crate::hashmap::POP_SCRIPT, hash_pop_script),
+            (crate::hashmap::CONDITIONAL_REMOVE_SCRIPT, conditional_remove_script),
+            (crate::hashmap::EXPIRING_SET_SCRIPT, expiring_set_script),
+            (crate::hashmap::EXPIRING_GET_SCRIPT, expiring_get_script),
+            (crate::hashmap::EXPIRING_READ_SCRIPT, expiring_read_script),
+            (crate::set::DROP_CARD_SCRIPT, drop_card_script),
+            (crate::set::LIMITED_ADD, limited_add_script),
+            (crate::quota::BEGIN_SCRIPT, quota_begin_script),
+            (crate::lock::ACQUIRE_SCRIPT, lock_acquire_script),
+            (crate::lock::RENEW_SCRIPT, lock_renew_script),
+            (crate::lock::RELEASE_SCRIPT, lock_release_script),
+            (crate::rate_limit::SLIDING_WINDOW_SCRIPT, sliding_window_script),
+            (crate::rate_limit::TOKEN_BUCKET_SCRIPT, token_bucket_script),
+            (crate::fair_queue::FAIR_PUSH_SCRIPT, fair_push_script),
+            (crate::fair_queue::FAIR_POP_SCRIPT, fair_pop_script),
+            (crate::stream::TRANSFER_SCRIPT, stream_transfer_script),
+        ];
+
+        Self {
+            keyspace: Default::default(),
+            scripts: scripts.into_iter()
+                .map(|(code, function)| (redis::Script::new(code).get_hash().to_owned(), function))
+                .collect(),
+            subscribers: Default::default(),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Register a pubsub listener, messages are delivered until the receiver is dropped
+    pub(crate) fn subscribe(&self, channels: Vec<String>, patterns: Vec<String>) -> mpsc::UnboundedReceiver<Msg> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.lock().push(Subscriber { channels, patterns, sender });
+        receiver
+    }
+
+    /// Deliver a message to every matching subscription, returning how many received it
+    fn publish(&self, channel: &[u8], payload: &[u8]) -> i64 {
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+
+        let mut received = 0;
+        for subscriber in subscribers.iter() {
+            for name in &subscriber.channels {
+                if name.as_bytes() == channel {
+                    let message = [bulk("message"), bulk(channel), bulk(payload)];
+                    received += i64::from(send_message(&subscriber.sender, message.into()));
+                }
+            }
+            for pattern in &subscriber.patterns {
+                if glob_match(pattern.as_bytes(), channel) {
+                    let message = [bulk("pmessage"), bulk(pattern), bulk(channel), bulk(payload)];
+                    received += i64::from(send_message(&subscriber.sender, message.into()));
+                }
+            }
+        }
+        received
+    }
+
+    /// Run a single command
+    async fn execute(&self, cmd: &Cmd) -> RedisResult<Value> {
+        let mut args: Vec<Vec<u8>> = vec![];
+        for arg in cmd.args_iter() {
+            match arg {
+                redis::Arg::Simple(data) => args.push(data.to_vec()),
+                redis::Arg::Cursor => return Err(error("cursors are not supported by the memory backend")),
+            }
+        }
+        let Some((command, args)) = args.split_first() else {
+            return Err(error("empty command"))
+        };
+        let command = String::from_utf8_lossy(command).to_uppercase();
+
+        let result = match command.as_str() {
+            "PUBLISH" => {
+                arity(args, 2, 2)?;
+                Ok(Value::Int(self.publish(&args[0], &args[1])))
+            },
+            "BLPOP" | "BZPOPMIN" | "BZPOPMAX" => return self.blocking_pop(&command, args).await,
+            "BLMOVE" => return self.blocking_move(args).await,
+            "XREADGROUP" => return self.read_group(args).await,
+            "EVALSHA" => {
+                arity(args, 2, usize::MAX)?;
+                let hash = String::from_utf8_lossy(&args[0]).to_lowercase();
+                self.run_script(&hash, &args[1..])
+            },
+            "EVAL" => {
+                arity(args, 2, usize::MAX)?;
+                let hash = redis::Script::new(&String::from_utf8_lossy(&args[0])).get_hash().to_owned();
+                self.run_script(&hash, &args[1..])
+            },
+            "SCRIPT" => {
+                arity(args, 2, 2)?;
+                let hash = redis::Script::new(&String::from_utf8_lossy(&args[1])).get_hash().to_owned();
+                match self.scripts.contains_key(&hash) {
+                    true => Ok(bulk(hash)),
+                    false => Err(error("only the scripts built into this crate can run on the memory backend")),
+                }
+            },
+            _ => self.keyspace.lock().execute(&command, args),
+        };
+        self.changed.notify_waiters();
+        result
+    }
+
+    /// Run one of the known scripts given its hash, number of keys, keys, and arguments
+    fn run_script(&self, hash: &str, args: &[Vec<u8>]) -> RedisResult<Value> {
+        let Some(script) = self.scripts.get(hash) else {
+            return Err(RedisError::from((ErrorKind::NoScriptError, "No matching script")))
+        };
+        let key_count = integer(&args[0])? as usize;
+        if key_count > args.len() - 1 {
+            return Err(error("Number of keys can't be greater than number of args"))
+        }
+        let (keys, argv) = args[1..].split_at(key_count);
+        script(&mut self.keyspace.lock(), keys, argv)
+    }
+
+    /// Pop from the first of the given keys that has data, waiting until the timeout for any to have some
+    async fn blocking_pop(&self, command: &str, args: &[Vec<u8>]) -> RedisResult<Value> {
+        arity(args, 2, usize::MAX)?;
+        let (timeout, keys) = args.split_last().unwrap();
+        let timeout = float(timeout)?;
+        // as in redis a timeout of zero blocks forever
+        let deadline = if timeout > 0.0 {
+            Some(tokio::time::Instant::now() + Duration::from_secs_f64(timeout))
+        } else {
+            None
+        };
+
+        loop {
+            // start listening for changes before checking so none are missed
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut keyspace = self.keyspace.lock();
+                for key in keys {
+                    let popped = if command == "BLPOP" {
+                        keyspace.lpop(key, 1)?.into_iter().next().map(|value| vec![bulk(key), bulk(value)])
+                    } else {
+                        keyspace.zpop(key, 1, command == "BZPOPMAX")?.into_iter().next()
+                            .map(|(member, score)| vec![bulk(key), bulk(member), bulk(format_score(score))])
+                    };
+                    if let Some(popped) = popped {
+                        return Ok(Value::Array(popped))
+                    }
+                }
+            }
+
+            match deadline {
+                Some(deadline) => if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    return Ok(Value::Nil)
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Move an item between lists, waiting until the timeout for the source to have one
+    async fn blocking_move(&self, args: &[Vec<u8>]) -> RedisResult<Value> {
+        arity(args, 5, 5)?;
+        let timeout = float(&args[4])?;
+        let deadline = if timeout > 0.0 {
+            Some(tokio::time::Instant::now() + Duration::from_secs_f64(timeout))
+        } else {
+            None
+        };
+
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(value) = self.keyspace.lock().lmove(&args[..4])? {
+                self.changed.notify_waiters();
+                return Ok(bulk(value))
+            }
+
+            match deadline {
+                Some(deadline) => if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    return Ok(Value::Nil)
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Read from streams through a consumer group, waiting for new entries if BLOCK is given
+    async fn read_group(&self, args: &[Vec<u8>]) -> RedisResult<Value> {
+        let request = ReadGroup::parse(args)?;
+        let deadline = match request.block {
+            Some(0) | None => None,
+            Some(block) => Some(tokio::time::Instant::now() + Duration::from_millis(block)),
+        };
+
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let result = self.keyspace.lock().xreadgroup(&request)?;
+            if result != Value::Nil || request.block.is_none() {
+                return Ok(result)
+            }
+
+            match deadline {
+                Some(deadline) => if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    return Ok(Value::Nil)
+                },
+                None => notified.await,
+            }
+        }
+    }
+}
+
+/// Connection to a memory server, usable anywhere a redis connection is
+#[derive(Clone)]
+pub(crate) struct MemoryConnection {
+    server: Arc<MemoryServer>,
+}
+
+impl MemoryConnection {
+    pub(crate) fn new(server: Arc<MemoryServer>) -> Self {
+        Self { server }
+    }
+}
+
+impl redis::aio::ConnectionLike for MemoryConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(self.server.execute(cmd))
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, pipeline: &'a redis::Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let mut results = vec![];
+            for cmd in pipeline.cmd_iter() {
+                results.push(self.server.execute(cmd).await?);
+            }
+            // transactions skip the replies to the queued commands and only read the result of EXEC
+            if offset > 0 {
+                return Ok(vec![Value::Array(results)])
+            }
+            Ok(results.into_iter().take(count).collect())
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+/// Score of a sorted set member, ordered so that it can be used in a btree
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool { self.0.total_cmp(&other.0).is_eq() }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering { self.0.total_cmp(&other.0) }
+}
+
+/// One end of a range of scores, as given to commands like ZCOUNT
+#[derive(Debug, Clone, Copy)]
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(data: &[u8]) -> RedisResult<Self> {
+        match data.strip_prefix(b"(") {
+            Some(value) => Ok(Self { value: float(value)?, exclusive: true }),
+            None => Ok(Self { value: float(data)?, exclusive: false }),
+        }
+    }
+
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive { self.value < score } else { self.value <= score }
+    }
+
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive { self.value > score } else { self.value >= score }
+    }
+}
+
+/// Members ordered by score, then by their content
+#[derive(Default)]
+struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let added = match self.scores.insert(member.clone(), score) {
+            Some(old) => { self.order.remove(&(Score(old), member.clone())); false },
+            None => true,
+        };
+        self.order.insert((Score(score), member));
+        added
+    }
+
+    fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.order.remove(&(Score(score), member.to_vec())),
+            None => false,
+        }
+    }
+
+    fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(self.order.range(..(Score(score), member.to_vec())).count())
+    }
+
+    fn pop(&mut self, highest: bool) -> Option<(Vec<u8>, f64)> {
+        let (score, member) = if highest { self.order.pop_last()? } else { self.order.pop_first()? };
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    fn range(&self, min: ScoreBound, max: ScoreBound) -> impl Iterator<Item=&(Score, Vec<u8>)> {
+        self.order.iter().filter(move |(score, _)| min.below(score.0) && max.above(score.0))
+    }
+}
+
+/// Id of a stream entry, milliseconds and sequence number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StreamId(u64, u64);
+
+impl StreamId {
+    /// Parse an id, a missing sequence number is filled in with the given default
+    fn parse(data: &[u8], default_sequence: u64) -> RedisResult<Self> {
+        let invalid = || error("Invalid stream ID specified as stream command argument");
+        let text = std::str::from_utf8(data).map_err(|_| invalid())?;
+        match text.split_once('-') {
+            Some((millis, sequence)) => Ok(Self(millis.parse().map_err(|_| invalid())?, sequence.parse().map_err(|_| invalid())?)),
+            None => Ok(Self(text.parse().map_err(|_| invalid())?, default_sequence)),
+        }
+    }
+
+    /// Parse the start or end of a range, where `-` and `+` are the lowest and highest possible ids
+    fn parse_bound(data: &[u8], start: bool) -> RedisResult<Self> {
+        match data {
+            b"-" => Ok(Self(0, 0)),
+            b"+" => Ok(Self(u64::MAX, u64::MAX)),
+            _ => Self::parse(data, if start { 0 } else { u64::MAX }),
+        }
+    }
+
+    fn to_value(self) -> Value {
+        bulk(self.to_string())
+    }
+}
+
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+
+/// An entry delivered to a consumer that hasn't been acknowledged yet
+struct PendingEntry {
+    consumer: Vec<u8>,
+    delivered: Instant,
+    deliveries: u64,
+}
+
+#[derive(Default)]
+struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+}
+
+#[derive(Default)]
+struct Stream {
+    entries: BTreeMap<StreamId, Vec<Vec<u8>>>,
+    last_id: StreamId,
+    groups: HashMap<Vec<u8>, ConsumerGroup>,
+}
+
+impl Stream {
+    fn entry_value(id: StreamId, fields: &[Vec<u8>]) -> Value {
+        Value::Array(vec![id.to_value(), Value::Array(fields.iter().map(bulk).collect())])
+    }
+
+    fn group(&mut self, name: &[u8]) -> RedisResult<&mut ConsumerGroup> {
+        self.groups.get_mut(name).ok_or_else(|| redis::make_extension_error(
+            "NOGROUP".to_owned(), Some("No such key or consumer group".to_owned())
+        ))
+    }
+}
+
+/// Arguments of an XREADGROUP command
+struct ReadGroup {
+    group: Vec<u8>,
+    consumer: Vec<u8>,
+    count: Option<usize>,
+    block: Option<u64>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl ReadGroup {
+    fn parse(args: &[Vec<u8>]) -> RedisResult<Self> {
+        let [option, group, consumer, rest @ ..] = args else { return Err(wrong_arguments()) };
+        if !option.eq_ignore_ascii_case(b"GROUP") {
+            return Err(error("syntax error"))
+        }
+        let mut request = ReadGroup { group: group.clone(), consumer: consumer.clone(), count: None, block: None, keys: vec![] };
+        let mut rest = rest.iter();
+        while let Some(option) = rest.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"COUNT" => request.count = Some(integer(rest.next().ok_or_else(wrong_arguments)?)?.max(0) as usize),
+                b"BLOCK" => request.block = Some(integer(rest.next().ok_or_else(wrong_arguments)?)?.max(0) as u64),
+                b"NOACK" => return Err(error("NOACK is not supported by the memory backend")),
+                b"STREAMS" => {
+                    let streams: Vec<&Vec<u8>> = rest.by_ref().collect();
+                    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+                        return Err(wrong_arguments())
+                    }
+                    let (keys, ids) = streams.split_at(streams.len() / 2);
+                    if ids.iter().any(|id| id.as_slice() != b">") {
+                        return Err(error("only reading new entries with > is supported by the memory backend"))
+                    }
+                    request.keys = keys.iter().map(|key| key.to_vec()).collect();
+                },
+                _ => return Err(error("syntax error")),
+            }
+        }
+        if request.keys.is_empty() {
+            return Err(wrong_arguments())
+        }
+        Ok(request)
+    }
+}
+
+/// Value stored under a key
+enum Data {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+/// Access to the value of a key as one specific type
+trait Container: Default {
+    fn from_data(data: &mut Data) -> Option<&mut Self>;
+    fn into_data(self) -> Data;
+}
+
+macro_rules! container {
+    ($type:ty, $variant:ident) => {
+        impl Container for $type {
+            fn from_data(data: &mut Data) -> Option<&mut Self> {
+                match data {
+                    Data::$variant(value) => Some(value),
+                    _ => None,
+                }
+            }
+
+            fn into_data(self) -> Data { Data::$variant(self) }
+        }
+    };
+}
+
+container!(Vec<u8>, String);
+container!(VecDeque<Vec<u8>>, List);
+container!(HashMap<Vec<u8>, Vec<u8>>, Hash);
+container!(HashSet<Vec<u8>>, Set);
+container!(SortedSet, SortedSet);
+container!(Stream, Stream);
+
+impl Data {
+    fn is_empty(&self) -> bool {
+        match self {
+            // unlike collections an empty string is still a value
+            Data::String(_) => false,
+            Data::List(list) => list.is_empty(),
+            Data::Hash(hash) => hash.is_empty(),
+            Data::Set(set) => set.is_empty(),
+            Data::SortedSet(set) => set.scores.is_empty(),
+            // streams stay around when their last entry is removed
+            Data::Stream(_) => false,
+        }
+    }
+}
+
+struct Entry {
+    data: Data,
+    expires: Option<Instant>,
+}
+
+/// All the keys held by a memory server
+#[derive(Default)]
+pub(crate) struct Keyspace {
+    entries: HashMap<Vec<u8>, Entry>,
+}
+
+impl Keyspace {
+    /// Remove a key if its expiry has passed, returning whether it is still present
+    fn check_expiry(&mut self, key: &[u8]) -> bool {
+        match self.entries.get(key) {
+            Some(entry) => {
+                if entry.expires.is_some_and(|expires| expires <= Instant::now()) {
+                    self.entries.remove(key);
+                    false
+                } else {
+                    true
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// Get the value under a key if it exists
+    fn read<T: Container>(&mut self, key: &[u8]) -> RedisResult<Option<&mut T>> {
+        if !self.check_expiry(key) {
+            return Ok(None)
+        }
+        match self.entries.get_mut(key) {
+            Some(entry) => T::from_data(&mut entry.data).map(Some).ok_or_else(wrong_type),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value under a key, creating an empty one if it doesn't exist
+    fn write<T: Container>(&mut self, key: &[u8]) -> RedisResult<&mut T> {
+        self.check_expiry(key);
+        let entry = self.entries.entry(key.to_vec())
+            .or_insert_with(|| Entry { data: T::default().into_data(), expires: None });
+        T::from_data(&mut entry.data).ok_or_else(wrong_type)
+    }
+
+    /// Keys holding an empty collection don't exist in redis
+    fn clean(&mut self, key: &[u8]) {
+        if self.entries.get(key).is_some_and(|entry| entry.data.is_empty()) {
+            self.entries.remove(key);
+        }
+    }
+
+    fn lpop(&mut self, key: &[u8], count: usize) -> RedisResult<Vec<Vec<u8>>> {
+        let Some(list) = self.read::<VecDeque<Vec<u8>>>(key)? else { return Ok(vec![]) };
+        let popped = list.drain(..count.min(list.len())).collect();
+        self.clean(key);
+        Ok(popped)
+    }
+
+    /// Move an item from one end of a list to an end of another, given the arguments of LMOVE
+    fn lmove(&mut self, args: &[Vec<u8>]) -> RedisResult<Option<Vec<u8>>> {
+        let from_left = direction(&args[2])?;
+        let to_left = direction(&args[3])?;
+        // make sure the destination is a list before anything is taken out of the source
+        self.read::<VecDeque<Vec<u8>>>(&args[1])?;
+        let Some(source) = self.read::<VecDeque<Vec<u8>>>(&args[0])? else { return Ok(None) };
+        let value = if from_left { source.pop_front() } else { source.pop_back() };
+        let Some(value) = value else { return Ok(None) };
+        self.clean(&args[0]);
+        let destination = self.write::<VecDeque<Vec<u8>>>(&args[1])?;
+        if to_left { destination.push_front(value.clone()) } else { destination.push_back(value.clone()) }
+        Ok(Some(value))
+    }
+
+    fn zpop(&mut self, key: &[u8], count: usize, highest: bool) -> RedisResult<Vec<(Vec<u8>, f64)>> {
+        let Some(set) = self.read::<SortedSet>(key)? else { return Ok(vec![]) };
+        let popped = std::iter::from_fn(|| set.pop(highest)).take(count).collect();
+        self.clean(key);
+        Ok(popped)
+    }
+
+    fn zrangebyscore(&mut self, key: &[u8], min: ScoreBound, max: ScoreBound, offset: usize, limit: Option<usize>) -> RedisResult<Vec<Vec<u8>>> {
+        let Some(set) = self.read::<SortedSet>(key)? else { return Ok(vec![]) };
+        Ok(set.range(min, max).skip(offset).take(limit.unwrap_or(usize::MAX)).map(|(_, member)| member.clone()).collect())
+    }
+
+    fn zrem(&mut self, key: &[u8], members: &[Vec<u8>]) -> RedisResult<i64> {
+        let Some(set) = self.read::<SortedSet>(key)? else { return Ok(0) };
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        self.clean(key);
+        Ok(removed as i64)
+    }
+
+    fn hget(&mut self, key: &[u8], field: &[u8]) -> RedisResult<Option<Vec<u8>>> {
+        Ok(self.read::<HashMap<Vec<u8>, Vec<u8>>>(key)?.and_then(|hash| hash.get(field).cloned()))
+    }
+
+    fn hdel(&mut self, key: &[u8], fields: &[Vec<u8>]) -> RedisResult<i64> {
+        let Some(hash) = self.read::<HashMap<Vec<u8>, Vec<u8>>>(key)? else { return Ok(0) };
+        let removed = fields.iter().filter(|field| hash.remove(*field).is_some()).count();
+        self.clean(key);
+        Ok(removed as i64)
+    }
+
+    fn scard(&mut self, key: &[u8]) -> RedisResult<i64> {
+        Ok(self.read::<HashSet<Vec<u8>>>(key)?.map(|set| set.len() as i64).unwrap_or_default())
+    }
+
+    fn sadd(&mut self, key: &[u8], members: &[Vec<u8>]) -> RedisResult<i64> {
+        let set = self.write::<HashSet<Vec<u8>>>(key)?;
+        Ok(members.iter().filter(|member| set.insert(member.to_vec())).count() as i64)
+    }
+
+    fn srem(&mut self, key: &[u8], members: &[Vec<u8>]) -> RedisResult<i64> {
+        let Some(set) = self.read::<HashSet<Vec<u8>>>(key)? else { return Ok(0) };
+        let removed = members.iter().filter(|member| set.remove(*member)).count();
+        self.clean(key);
+        Ok(removed as i64)
+    }
+
+    fn xreadgroup(&mut self, request: &ReadGroup) -> RedisResult<Value> {
+        let mut results = vec![];
+        for key in &request.keys {
+            let Some(stream) = self.read::<Stream>(key)? else {
+                return Err(redis::make_extension_error("NOGROUP".to_owned(), Some("No such key or consumer group".to_owned())))
+            };
+            let entries: Vec<(StreamId, Vec<Vec<u8>>)> = {
+                let last_delivered = stream.group(&request.group)?.last_delivered;
+                stream.entries.range((std::ops::Bound::Excluded(last_delivered), std::ops::Bound::Unbounded))
+                    .take(request.count.unwrap_or(usize::MAX))
+                    .map(|(id, fields)| (*id, fields.clone()))
+                    .collect()
+            };
+            if entries.is_empty() {
+                continue
+            }
+
+            let group = stream.group(&request.group)?;
+            let now = Instant::now();
+            for (id, _) in &entries {
+                group.last_delivered = *id;
+                group.pending.insert(*id, PendingEntry { consumer: request.consumer.clone(), delivered: now, deliveries: 1 });
+            }
+            let entries = entries.iter().map(|(id, fields)| Stream::entry_value(*id, fields)).collect();
+            results.push(Value::Array(vec![bulk(key), Value::Array(entries)]));
+        }
+        Ok(if results.is_empty() { Value::Nil } else { Value::Array(results) })
+    }
+
+    /// Run a command that doesn't need any state outside of the keyspace
+    fn execute(&mut self, command: &str, args: &[Vec<u8>]) -> RedisResult<Value> {
+        match command {
+            "PING" => Ok(Value::SimpleString("PONG".to_owned())),
+            "TIME" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                Ok(Value::Array(vec![bulk(now.as_secs().to_string()), bulk(now.subsec_micros().to_string())]))
+            },
+            "FLUSHDB" | "FLUSHALL" => {
+                self.entries.clear();
+                Ok(Value::Okay)
+            },
+            "DEL" => {
+                arity(args, 1, usize::MAX)?;
+                let mut removed = 0;
+                for key in args {
+                    if self.check_expiry(key) {
+                        self.entries.remove(key);
+                        removed += 1;
+                    }
+                }
+                Ok(Value::Int(removed))
+            },
+            "EXISTS" => {
+                arity(args, 1, usize::MAX)?;
+                Ok(Value::Int(args.iter().filter(|key| self.check_expiry(key)).count() as i64))
+            },
+            "EXPIRE" | "PEXPIRE" => {
+                arity(args, 2, 2)?;
+                if !self.check_expiry(&args[0]) {
+                    return Ok(Value::Int(0))
+                }
+                let ttl = integer(&args[1])?;
+                if ttl <= 0 {
+                    self.entries.remove(&args[0]);
+                } else if let Some(entry) = self.entries.get_mut(&args[0]) {
+                    let ttl = if command == "EXPIRE" { Duration::from_secs(ttl as u64) } else { Duration::from_millis(ttl as u64) };
+                    entry.expires = Some(Instant::now() + ttl);
+                }
+                Ok(Value::Int(1))
+            },
+            "KEYS" => {
+                arity(args, 1, 1)?;
+                let now = Instant::now();
+                self.entries.retain(|_, entry| entry.expires.is_none_or(|expires| expires > now));
+                Ok(Value::Array(self.entries.keys().filter(|key| glob_match(&args[0], key)).map(bulk).collect()))
+            },
+
+            "PTTL" | "TTL" => {
+                arity(args, 1, 1)?;
+                if !self.check_expiry(&args[0]) {
+                    return Ok(Value::Int(-2))
+                }
+                let remaining = self.entries.get(&args[0])
+                    .and_then(|entry| entry.expires)
+                    .map(|expires| expires.saturating_duration_since(Instant::now()));
+                Ok(Value::Int(match remaining {
+                    Some(remaining) if command == "PTTL" => remaining.as_millis() as i64,
+                    Some(remaining) => remaining.as_secs_f64().round() as i64,
+                    None => -1,
+                }))
+            },
+
+            // strings
+            "GET" => {
+                arity(args, 1, 1)?;
+                Ok(self.read::<Vec<u8>>(&args[0])?.map(|value| bulk(value.as_slice())).unwrap_or(Value::Nil))
+            },
+            "SET" => {
+                arity(args, 2, 5)?;
+                let (mut only_new, mut only_existing, mut ttl) = (false, false, None);
+                let mut options = args[2..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_ascii_uppercase().as_slice() {
+                        b"NX" => only_new = true,
+                        b"XX" => only_existing = true,
+                        unit @ (b"EX" | b"PX") => {
+                            let value = integer(options.next().ok_or_else(wrong_arguments)?)?;
+                            if value <= 0 {
+                                return Err(error("invalid expire time in 'set' command"))
+                            }
+                            ttl = Some(if unit == b"EX" { Duration::from_secs(value as u64) } else { Duration::from_millis(value as u64) });
+                        },
+                        _ => return Err(error("only the NX, XX, EX, and PX options of SET are supported by the memory backend")),
+                    }
+                }
+                let exists = self.check_expiry(&args[0]);
+                if (only_new && exists) || (only_existing && !exists) {
+                    return Ok(Value::Nil)
+                }
+                self.entries.insert(args[0].clone(), Entry {
+                    data: Data::String(args[1].clone()),
+                    expires: ttl.map(|ttl| Instant::now() + ttl),
+                });
+                Ok(Value::Okay)
+            },
+            "INCR" | "INCRBY" | "DECR" | "DECRBY" => {
+                let increment = match command {
+                    "INCR" | "DECR" => { arity(args, 1, 1)?; 1 },
+                    _ => { arity(args, 2, 2)?; integer(&args[1])? },
+                };
+                let increment = if command.starts_with("DECR") { -increment } else { increment };
+                let value = self.write::<Vec<u8>>(&args[0])?;
+                let current = if value.is_empty() { 0 } else { integer(value).map_err(|_| error("value is not an integer or out of range"))? };
+                let updated = current.checked_add(increment).ok_or_else(|| error("increment or decrement would overflow"))?;
+                *value = updated.to_string().into_bytes();
+                Ok(Value::Int(updated))
+            },
+
+            // lists
+            "RPUSH" | "LPUSH" => {
+                arity(args, 2, usize::MAX)?;
+                let list = self.write::<VecDeque<Vec<u8>>>(&args[0])?;
+                for value in &args[1..] {
+                    if command == "RPUSH" { list.push_back(value.clone()) } else { list.push_front(value.clone()) }
+                }
+                Ok(Value::Int(list.len() as i64))
+            },
+            "LLEN" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Int(self.read::<VecDeque<Vec<u8>>>(&args[0])?.map(|list| list.len() as i64).unwrap_or_default()))
+            },
+            "LRANGE" => {
+                arity(args, 3, 3)?;
+                let Some(list) = self.read::<VecDeque<Vec<u8>>>(&args[0])? else { return Ok(Value::Array(vec![])) };
+                let (start, end) = index_range(list.len(), integer(&args[1])?, integer(&args[2])?);
+                Ok(Value::Array(list.range(start..end).map(bulk).collect()))
+            },
+            "LPOP" => {
+                arity(args, 1, 2)?;
+                match args.get(1) {
+                    Some(count) => {
+                        let popped = self.lpop(&args[0], integer(count)? as usize)?;
+                        Ok(if popped.is_empty() { Value::Nil } else { Value::Array(popped.into_iter().map(bulk).collect()) })
+                    },
+                    None => Ok(self.lpop(&args[0], 1)?.into_iter().next().map(bulk).unwrap_or(Value::Nil)),
+                }
+            },
+
+            "LMOVE" => {
+                arity(args, 4, 4)?;
+                Ok(self.lmove(args)?.map(bulk).unwrap_or(Value::Nil))
+            },
+
+            // hashes
+            "HSET" => {
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(wrong_arguments())
+                }
+                let hash = self.write::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])?;
+                let added = args[1..].chunks(2).filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none()).count();
+                Ok(Value::Int(added as i64))
+            },
+            "HSETNX" => {
+                arity(args, 3, 3)?;
+                let hash = self.write::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])?;
+                if hash.contains_key(&args[1]) {
+                    return Ok(Value::Int(0))
+                }
+                hash.insert(args[1].clone(), args[2].clone());
+                Ok(Value::Int(1))
+            },
+            "HGET" => {
+                arity(args, 2, 2)?;
+                Ok(self.hget(&args[0], &args[1])?.map(bulk).unwrap_or(Value::Nil))
+            },
+            "HDEL" => {
+                arity(args, 2, usize::MAX)?;
+                Ok(Value::Int(self.hdel(&args[0], &args[1..])?))
+            },
+            "HEXISTS" => {
+                arity(args, 2, 2)?;
+                Ok(Value::Int(i64::from(self.hget(&args[0], &args[1])?.is_some())))
+            },
+            "HINCRBY" => {
+                arity(args, 3, 3)?;
+                let increment = integer(&args[2])?;
+                let hash = self.write::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])?;
+                let current = match hash.get(&args[1]) {
+                    Some(value) => integer(value).map_err(|_| error("hash value is not an integer"))?,
+                    None => 0,
+                };
+                let value = current.checked_add(increment).ok_or_else(|| error("increment or decrement would overflow"))?;
+                hash.insert(args[1].clone(), value.to_string().into_bytes());
+                Ok(Value::Int(value))
+            },
+            "HINCRBYFLOAT" => {
+                arity(args, 3, 3)?;
+                let increment = float(&args[2])?;
+                let hash = self.write::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])?;
+                let current = match hash.get(&args[1]) {
+                    Some(value) => float(value).map_err(|_| error("hash value is not a float"))?,
+                    None => 0.0,
+                };
+                let value = format_score(current + increment);
+                hash.insert(args[1].clone(), value.clone());
+                Ok(bulk(value))
+            },
+            "HKEYS" | "HVALS" | "HGETALL" => {
+                arity(args, 1, 1)?;
+                let Some(hash) = self.read::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])? else { return Ok(Value::Array(vec![])) };
+                Ok(Value::Array(match command {
+                    "HKEYS" => hash.keys().map(bulk).collect(),
+                    "HVALS" => hash.values().map(bulk).collect(),
+                    _ => hash.iter().flat_map(|(key, value)| [bulk(key), bulk(value)]).collect(),
+                }))
+            },
+            "HLEN" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Int(self.read::<HashMap<Vec<u8>, Vec<u8>>>(&args[0])?.map(|hash| hash.len() as i64).unwrap_or_default()))
+            },
+
+            // sets
+            "SADD" => {
+                arity(args, 2, usize::MAX)?;
+                Ok(Value::Int(self.sadd(&args[0], &args[1..])?))
+            },
+            "SREM" => {
+                arity(args, 2, usize::MAX)?;
+                Ok(Value::Int(self.srem(&args[0], &args[1..])?))
+            },
+            "SCARD" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Int(self.scard(&args[0])?))
+            },
+            "SISMEMBER" => {
+                arity(args, 2, 2)?;
+                let found = self.read::<HashSet<Vec<u8>>>(&args[0])?.is_some_and(|set| set.contains(&args[1]));
+                Ok(Value::Int(i64::from(found)))
+            },
+            "SMEMBERS" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Array(self.read::<HashSet<Vec<u8>>>(&args[0])?.map(|set| set.iter().map(bulk).collect()).unwrap_or_default()))
+            },
+            "SRANDMEMBER" => {
+                arity(args, 1, 1)?;
+                let member = self.read::<HashSet<Vec<u8>>>(&args[0])?.and_then(|set| set.iter().choose(&mut rand::rng()).cloned());
+                Ok(member.map(bulk).unwrap_or(Value::Nil))
+            },
+            "SPOP" => {
+                arity(args, 1, 2)?;
+                let count = match args.get(1) {
+                    Some(count) => Some(integer(count)? as usize),
+                    None => None,
+                };
+                let Some(set) = self.read::<HashSet<Vec<u8>>>(&args[0])? else {
+                    return Ok(if count.is_some() { Value::Array(vec![]) } else { Value::Nil })
+                };
+                let popped: Vec<Vec<u8>> = set.iter().cloned().choose_multiple(&mut rand::rng(), count.unwrap_or(1));
+                for member in &popped {
+                    set.remove(member);
+                }
+                self.clean(&args[0]);
+                Ok(match count {
+                    Some(_) => Value::Array(popped.into_iter().map(bulk).collect()),
+                    None => popped.into_iter().next().map(bulk).unwrap_or(Value::Nil),
+                })
+            },
+
+            // sorted sets
+            "ZADD" => {
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(wrong_arguments())
+                }
+                let mut pairs = vec![];
+                for pair in args[1..].chunks(2) {
+                    pairs.push((float(&pair[0])?, pair[1].clone()));
+                }
+                let set = self.write::<SortedSet>(&args[0])?;
+                let added = pairs.into_iter().filter(|(score, member)| set.insert(member.clone(), *score)).count();
+                Ok(Value::Int(added as i64))
+            },
+            "ZCARD" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Int(self.read::<SortedSet>(&args[0])?.map(|set| set.scores.len() as i64).unwrap_or_default()))
+            },
+            "ZCOUNT" => {
+                arity(args, 3, 3)?;
+                let (min, max) = (ScoreBound::parse(&args[1])?, ScoreBound::parse(&args[2])?);
+                Ok(Value::Int(self.read::<SortedSet>(&args[0])?.map(|set| set.range(min, max).count() as i64).unwrap_or_default()))
+            },
+            "ZSCORE" => {
+                arity(args, 2, 2)?;
+                let score = self.read::<SortedSet>(&args[0])?.and_then(|set| set.scores.get(&args[1]).copied());
+                Ok(score.map(|score| bulk(format_score(score))).unwrap_or(Value::Nil))
+            },
+            "ZRANK" => {
+                arity(args, 2, 2)?;
+                let rank = self.read::<SortedSet>(&args[0])?.and_then(|set| set.rank(&args[1]));
+                Ok(rank.map(|rank| Value::Int(rank as i64)).unwrap_or(Value::Nil))
+            },
+            "ZREM" => {
+                arity(args, 2, usize::MAX)?;
+                Ok(Value::Int(self.zrem(&args[0], &args[1..])?))
+            },
+            "ZPOPMIN" | "ZPOPMAX" => {
+                arity(args, 1, 2)?;
+                let count = match args.get(1) {
+                    Some(count) => integer(count)?.max(0) as usize,
+                    None => 1,
+                };
+                let popped = self.zpop(&args[0], count, command == "ZPOPMAX")?;
+                Ok(Value::Array(popped.into_iter().flat_map(|(member, score)| [bulk(member), bulk(format_score(score))]).collect()))
+            },
+            "ZRANGEBYSCORE" => {
+                arity(args, 3, 6)?;
+                let (min, max) = (ScoreBound::parse(&args[1])?, ScoreBound::parse(&args[2])?);
+                let (offset, limit) = match &args[3..] {
+                    [] => (0, None),
+                    [limit, offset, count] if limit.eq_ignore_ascii_case(b"LIMIT") => {
+                        let count = integer(count)?;
+                        (integer(offset)?.max(0) as usize, if count < 0 { None } else { Some(count as usize) })
+                    },
+                    _ => return Err(error("only the LIMIT option of ZRANGEBYSCORE is supported by the memory backend")),
+                };
+                Ok(Value::Array(self.zrangebyscore(&args[0], min, max, offset, limit)?.into_iter().map(bulk).collect()))
+            },
+            "ZREMRANGEBYSCORE" => {
+                arity(args, 3, 3)?;
+                let (min, max) = (ScoreBound::parse(&args[1])?, ScoreBound::parse(&args[2])?);
+                let members = self.zrangebyscore(&args[0], min, max, 0, None)?;
+                Ok(Value::Int(self.zrem(&args[0], &members)?))
+            },
+
+            // streams
+            "XADD" => {
+                if args.len() < 4 || !args.len().is_multiple_of(2) {
+                    return Err(wrong_arguments())
+                }
+                if args[1] != b"*" {
+                    return Err(error("only generated ids are supported by XADD on the memory backend"))
+                }
+                let stream = self.write::<Stream>(&args[0])?;
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+                let id = if millis > stream.last_id.0 { StreamId(millis, 0) } else { StreamId(stream.last_id.0, stream.last_id.1 + 1) };
+                stream.last_id = id;
+                stream.entries.insert(id, args[2..].to_vec());
+                Ok(id.to_value())
+            },
+            "XLEN" => {
+                arity(args, 1, 1)?;
+                Ok(Value::Int(self.read::<Stream>(&args[0])?.map(|stream| stream.entries.len() as i64).unwrap_or_default()))
+            },
+            "XDEL" => {
+                arity(args, 2, usize::MAX)?;
+                let ids = args[1..].iter().map(|id| StreamId::parse(id, 0)).collect::<RedisResult<Vec<_>>>()?;
+                let Some(stream) = self.read::<Stream>(&args[0])? else { return Ok(Value::Int(0)) };
+                Ok(Value::Int(ids.iter().filter(|id| stream.entries.remove(id).is_some()).count() as i64))
+            },
+            "XRANGE" => {
+                if args.len() != 3 && args.len() != 5 {
+                    return Err(wrong_arguments())
+                }
+                let (start, end) = (StreamId::parse_bound(&args[1], true)?, StreamId::parse_bound(&args[2], false)?);
+                let count = match args.get(3..5) {
+                    Some([option, count]) if option.eq_ignore_ascii_case(b"COUNT") => integer(count)?.max(0) as usize,
+                    Some(_) => return Err(error("syntax error")),
+                    None => usize::MAX,
+                };
+                let Some(stream) = self.read::<Stream>(&args[0])? else { return Ok(Value::Array(vec![])) };
+                if start > end {
+                    return Ok(Value::Array(vec![]))
+                }
+                Ok(Value::Array(stream.entries.range(start..=end).take(count).map(|(id, fields)| Stream::entry_value(*id, fields)).collect()))
+            },
+            "XGROUP" => {
+                arity(args, 3, 5)?;
+                if !args[0].eq_ignore_ascii_case(b"CREATE") {
+                    return Err(error("only XGROUP CREATE is supported by the memory backend"))
+                }
+                let [_, key, group, start, options @ ..] = args else { return Err(wrong_arguments()) };
+                let make_stream = match options {
+                    [] => false,
+                    [option] if option.eq_ignore_ascii_case(b"MKSTREAM") => true,
+                    _ => return Err(error("syntax error")),
+                };
+                if !make_stream && !self.check_expiry(key) {
+                    return Err(error("The XGROUP subcommand requires the key to exist"))
+                }
+                let stream = self.write::<Stream>(key)?;
+                if stream.groups.contains_key(group) {
+                    return Err(redis::make_extension_error("BUSYGROUP".to_owned(), Some("Consumer Group name already exists".to_owned())))
+                }
+                let last_delivered = if start == b"$" { stream.last_id } else { StreamId::parse(start, 0)? };
+                stream.groups.insert(group.clone(), ConsumerGroup { last_delivered, pending: Default::default() });
+                Ok(Value::Okay)
+            },
+            "XACK" => {
+                arity(args, 3, usize::MAX)?;
+                let ids = args[2..].iter().map(|id| StreamId::parse(id, 0)).collect::<RedisResult<Vec<_>>>()?;
+                let Some(stream) = self.read::<Stream>(&args[0])? else { return Ok(Value::Int(0)) };
+                let Some(group) = stream.groups.get_mut(&args[1]) else { return Ok(Value::Int(0)) };
+                Ok(Value::Int(ids.iter().filter(|id| group.pending.remove(id).is_some()).count() as i64))
+            },
+            "XPENDING" => {
+                // only the extended form listing individual entries is supported
+                let (min_idle, rest) = match args.get(2) {
+                    Some(option) if option.eq_ignore_ascii_case(b"IDLE") => {
+                        let idle = integer(args.get(3).ok_or_else(wrong_arguments)?)?;
+                        (Duration::from_millis(idle.max(0) as u64), &args[4..])
+                    },
+                    _ => (Duration::ZERO, args.get(2..).unwrap_or_default()),
+                };
+                let (start, end, count, consumer) = match rest {
+                    [start, end, count] => (start, end, count, None),
+                    [start, end, count, consumer] => (start, end, count, Some(consumer)),
+                    _ => return Err(error("only the extended form of XPENDING is supported by the memory backend")),
+                };
+                let (start, end) = (StreamId::parse_bound(start, true)?, StreamId::parse_bound(end, false)?);
+                let count = integer(count)?.max(0) as usize;
+                let Some(stream) = self.read::<Stream>(&args[0])? else {
+                    return Err(redis::make_extension_error("NOGROUP".to_owned(), Some("No such key or consumer group".to_owned())))
+                };
+                let group = stream.group(&args[1])?;
+                if start > end {
+                    return Ok(Value::Array(vec![]))
+                }
+                let now = Instant::now();
+                Ok(Value::Array(group.pending.range(start..=end)
+                    .filter(|(_, entry)| consumer.is_none_or(|consumer| *consumer == entry.consumer))
+                    .filter(|(_, entry)| now.duration_since(entry.delivered) >= min_idle)
+                    .take(count)
+                    .map(|(id, entry)| Value::Array(vec![
+                        id.to_value(),
+                        bulk(&entry.consumer),
+                        Value::Int(now.duration_since(entry.delivered).as_millis() as i64),
+                        Value::Int(entry.deliveries as i64),
+                    ]))
+                    .collect()))
+            },
+            "XAUTOCLAIM" => {
+                let [key, group, consumer, min_idle, start, options @ ..] = args else { return Err(wrong_arguments()) };
+                let count = match options {
+                    [] => 100,
+                    [option, count] if option.eq_ignore_ascii_case(b"COUNT") => integer(count)?.max(1) as usize,
+                    _ => return Err(error("only the COUNT option of XAUTOCLAIM is supported by the memory backend")),
+                };
+                let min_idle = Duration::from_millis(integer(min_idle)?.max(0) as u64);
+                let start = StreamId::parse_bound(start, true)?;
+                let Some(stream) = self.read::<Stream>(key)? else {
+                    return Err(redis::make_extension_error("NOGROUP".to_owned(), Some("No such key or consumer group".to_owned())))
+                };
+                let Stream { entries, groups, .. } = stream;
+                let group = groups.get_mut(group).ok_or_else(|| redis::make_extension_error(
+                    "NOGROUP".to_owned(), Some("No such key or consumer group".to_owned())
+                ))?;
+
+                let now = Instant::now();
+                let mut next = StreamId(0, 0);
+                let (mut claimed, mut deleted) = (vec![], vec![]);
+                for (scanned, (id, entry)) in group.pending.range_mut(start..).enumerate() {
+                    if scanned == count {
+                        next = *id;
+                        break
+                    }
+                    if now.duration_since(entry.delivered) < min_idle {
+                        continue
+                    }
+                    match entries.get(id) {
+                        Some(fields) => {
+                            entry.consumer = consumer.clone();
+                            entry.delivered = now;
+                            entry.deliveries += 1;
+                            claimed.push(Stream::entry_value(*id, fields));
+                        },
+                        None => deleted.push(*id),
+                    }
+                }
+                for id in &deleted {
+                    group.pending.remove(id);
+                }
+                Ok(Value::Array(vec![
+                    next.to_value(),
+                    Value::Array(claimed),
+                    Value::Array(deleted.into_iter().map(StreamId::to_value).collect()),
+                ]))
+            },
+
+            _ => Err(RedisError::from((ErrorKind::ResponseError, "unknown command", command.to_owned()))),
+        }
+    }
+}
+
+// Native versions of the lua scripts, see the modules they are defined in for the originals
+
+/// queue::PQ_DEQUEUE_RANGE_SCRIPT
+fn dequeue_range_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(key), [min_score, max_score, offset, limit]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    let min_score = float(min_score).unwrap_or(f64::NEG_INFINITY);
+    let max_score = float(max_score).unwrap_or(f64::INFINITY);
+    let limit = integer(limit)?;
+
+    let entries = keyspace.zrangebyscore(key,
+        ScoreBound { value: min_score, exclusive: false },
+        ScoreBound { value: max_score, exclusive: false },
+        integer(offset)?.max(0) as usize,
+        if limit < 0 { None } else { Some(limit as usize) },
+    )?;
+    keyspace.zrem(key, &entries)?;
+    Ok(Value::Array(entries.into_iter().map(bulk).collect()))
+}
+
+/// hashmap::POP_SCRIPT
+fn hash_pop_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [key]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    let result = keyspace.hget(name, key)?;
+    if result.is_some() {
+        keyspace.hdel(name, std::slice::from_ref(key))?;
+    }
+    Ok(result.map(bulk).unwrap_or(Value::Nil))
+}
+
+/// hashmap::CONDITIONAL_REMOVE_SCRIPT
+fn conditional_remove_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [key, expected]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    if keyspace.hget(name, key)?.as_ref() == Some(expected) {
+        keyspace.hdel(name, std::slice::from_ref(key))?;
+        return Ok(Value::Int(1))
+    }
+    Ok(Value::Int(0))
+}
+
+/// Remove the keys of an expiring hash whose time has passed
+fn expiring_hash_purge(keyspace: &mut Keyspace, hash: &[u8], expiry: &[u8], now: f64) -> RedisResult<()> {
+    let expired = keyspace.zrangebyscore(expiry,
+        ScoreBound { value: f64::NEG_INFINITY, exclusive: false },
+        ScoreBound { value: now, exclusive: false },
+        0, None
+    )?;
+    if !expired.is_empty() {
+        keyspace.hdel(hash, &expired)?;
+        keyspace.zrem(expiry, &expired)?;
+    }
+    Ok(())
+}
+
+/// hashmap::EXPIRING_SET_SCRIPT
+fn expiring_set_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([hash, expiry], [key, value, ttl, only_new]) = (keys, args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64;
+    expiring_hash_purge(keyspace, hash, expiry, now)?;
+
+    if only_new == b"1" && keyspace.hget(hash, key)?.is_some() {
+        return Ok(Value::Int(0))
+    }
+
+    let added = keyspace.write::<HashMap<Vec<u8>, Vec<u8>>>(hash)?.insert(key.clone(), value.clone()).is_none();
+    let set = keyspace.write::<SortedSet>(expiry)?;
+    set.insert(key.clone(), now + integer(ttl)? as f64);
+    let last = set.order.last().map(|(score, _)| score.0).unwrap_or(now);
+    let remaining = ((last - now) as i64).to_string().into_bytes();
+    keyspace.execute("PEXPIRE", &[hash.clone(), remaining.clone()])?;
+    keyspace.execute("PEXPIRE", &[expiry.clone(), remaining])?;
+    Ok(Value::Int(added as i64))
+}
+
+/// hashmap::EXPIRING_GET_SCRIPT
+fn expiring_get_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([hash, expiry], [key, remove]) = (keys, args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64;
+
+    let Some(score) = keyspace.read::<SortedSet>(expiry)?.and_then(|set| set.scores.get(key).copied()) else {
+        return Ok(Value::Nil)
+    };
+    if score <= now {
+        keyspace.hdel(hash, std::slice::from_ref(key))?;
+        keyspace.zrem(expiry, std::slice::from_ref(key))?;
+        return Ok(Value::Nil)
+    }
+
+    let result = keyspace.hget(hash, key)?;
+    if remove == b"1" {
+        keyspace.hdel(hash, std::slice::from_ref(key))?;
+        keyspace.zrem(expiry, std::slice::from_ref(key))?;
+    }
+    Ok(result.map(bulk).unwrap_or(Value::Nil))
+}
+
+/// hashmap::EXPIRING_READ_SCRIPT
+fn expiring_read_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([hash, expiry], [command]) = (keys, args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64;
+    expiring_hash_purge(keyspace, hash, expiry, now)?;
+    keyspace.execute(&String::from_utf8_lossy(command).to_uppercase(), std::slice::from_ref(hash))
+}
+
+/// set::DROP_CARD_SCRIPT
+fn drop_card_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [key]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    keyspace.srem(name, std::slice::from_ref(key))?;
+    Ok(Value::Int(keyspace.scard(name)?))
+}
+
+/// set::LIMITED_ADD
+fn limited_add_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [key, limit]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    if keyspace.scard(name)? < integer(limit)? {
+        keyspace.sadd(name, std::slice::from_ref(key))?;
+        return Ok(lua_bool(true))
+    }
+    Ok(lua_bool(false))
+}
+
+/// quota::BEGIN_SCRIPT
+fn quota_begin_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [max, timeout]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as f64;
+    let timeout = integer(timeout)? as f64 * 1_000_000.0;
+
+    let expired = keyspace.zrangebyscore(name,
+        ScoreBound { value: 0.0, exclusive: false },
+        ScoreBound { value: now - timeout, exclusive: false },
+        0, None
+    )?;
+    keyspace.zrem(name, &expired)?;
+
+    let count = keyspace.read::<SortedSet>(name)?.map(|set| set.scores.len() as i64).unwrap_or_default();
+    if count < integer(max)? {
+        keyspace.write::<SortedSet>(name)?.insert(format_score(now), now);
+        return Ok(lua_bool(true))
+    }
+    Ok(lua_bool(false))
+}
+
+/// lock::ACQUIRE_SCRIPT
+fn lock_acquire_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([name, fence], [token, ttl]) = (keys, args) else { return Err(wrong_arguments()) };
+    let set = keyspace.execute("SET", &[name.clone(), token.clone(), b"NX".to_vec(), b"PX".to_vec(), ttl.clone()])?;
+    if set == Value::Nil {
+        return Ok(lua_bool(false))
+    }
+    keyspace.execute("INCR", std::slice::from_ref(fence))
+}
+
+/// lock::RENEW_SCRIPT
+fn lock_renew_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [token, ttl]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    if keyspace.read::<Vec<u8>>(name)?.is_some_and(|owner| owner == token) {
+        return keyspace.execute("PEXPIRE", &[name.clone(), ttl.clone()])
+    }
+    Ok(Value::Int(0))
+}
+
+/// lock::RELEASE_SCRIPT
+fn lock_release_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [token]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    if keyspace.read::<Vec<u8>>(name)?.is_some_and(|owner| owner == token) {
+        return keyspace.execute("DEL", std::slice::from_ref(name))
+    }
+    Ok(Value::Int(0))
+}
+
+/// rate_limit::SLIDING_WINDOW_SCRIPT
+fn sliding_window_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [limit, window, cost, token]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64;
+    let (limit, window, cost) = (integer(limit)?, integer(window)?, integer(cost)?);
+
+    let expired = keyspace.zrangebyscore(name,
+        ScoreBound { value: f64::NEG_INFINITY, exclusive: false },
+        ScoreBound { value: now - window as f64, exclusive: false },
+        0, None
+    )?;
+    keyspace.zrem(name, &expired)?;
+
+    let count = keyspace.read::<SortedSet>(name)?.map(|set| set.scores.len() as i64).unwrap_or_default();
+    if count + cost <= limit {
+        let set = keyspace.write::<SortedSet>(name)?;
+        for index in 1..=cost {
+            let mut member = token.clone();
+            member.extend(format!("-{index}").bytes());
+            set.insert(member, now);
+        }
+        keyspace.execute("PEXPIRE", &[name.clone(), window.to_string().into_bytes()])?;
+        return Ok(Value::Array(vec![Value::Int(1), Value::Int(limit - count - cost), Value::Int(0)]))
+    }
+
+    let mut retry = window;
+    if cost <= limit {
+        let oldest = keyspace.read::<SortedSet>(name)?.and_then(|set| set.order.first().map(|(score, _)| score.0));
+        if let Some(oldest) = oldest {
+            retry = (oldest + window as f64 - now) as i64;
+        }
+    }
+    Ok(Value::Array(vec![Value::Int(0), Value::Int((limit - count).max(0)), Value::Int(retry)]))
+}
+
+/// rate_limit::TOKEN_BUCKET_SCRIPT
+fn token_bucket_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let (Some(name), [capacity, rate, cost]) = (keys.first(), args) else { return Err(wrong_arguments()) };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let (capacity, rate, cost) = (float(capacity)?, float(rate)?, float(cost)?);
+
+    let tokens = keyspace.hget(name, b"tokens")?.and_then(|value| float(&value).ok());
+    let updated = keyspace.hget(name, b"updated")?.and_then(|value| float(&value).ok());
+    let (tokens, updated) = match (tokens, updated) {
+        (Some(tokens), Some(updated)) => (tokens, updated),
+        _ => (capacity, now),
+    };
+    let mut tokens = capacity.min(tokens + (now - updated).max(0.0) * rate);
+
+    let (allowed, retry) = if tokens >= cost {
+        tokens -= cost;
+        (1, 0)
+    } else {
+        (0, ((cost - tokens) / rate * 1000.0).ceil() as i64)
+    };
+
+    keyspace.execute("HSET", &[name.clone(), b"tokens".to_vec(), tokens.to_string().into_bytes(), b"updated".to_vec(), now.to_string().into_bytes()])?;
+    let ttl = (capacity / rate * 1000.0).ceil() as i64 + 1000;
+    keyspace.execute("PEXPIRE", &[name.clone(), ttl.to_string().into_bytes()])?;
+    Ok(Value::Array(vec![Value::Int(allowed), Value::Int(tokens.floor() as i64), Value::Int(retry)]))
+}
+
+/// fair_queue::FAIR_PUSH_SCRIPT
+fn fair_push_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([active, queue, signal], [tenant, score, item]) = (keys, args) else { return Err(wrong_arguments()) };
+    if keyspace.read::<SortedSet>(queue)?.is_none() {
+        let tenants = keyspace.write::<VecDeque<Vec<u8>>>(active)?;
+        tenants.retain(|name| name != tenant);
+        tenants.push_back(tenant.clone());
+    }
+    keyspace.write::<SortedSet>(queue)?.insert(item.clone(), float(score)?);
+    fair_signal(keyspace, signal)?;
+    Ok(Value::Int(1))
+}
+
+/// stream::TRANSFER_SCRIPT
+fn stream_transfer_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([list, stream], [limit, field]) = (keys, args) else { return Err(wrong_arguments()) };
+    let items = keyspace.lpop(list, integer(limit)?.max(0) as usize)?;
+    for item in &items {
+        keyspace.execute("XADD", &[stream.clone(), b"*".to_vec(), field.clone(), item.clone()])?;
+    }
+    Ok(Value::Int(items.len() as i64))
+}
+
+/// fair_queue::FAIR_POP_SCRIPT
+fn fair_pop_script(keyspace: &mut Keyspace, keys: &[Vec<u8>], args: &[Vec<u8>]) -> RedisResult<Value> {
+    let ([active, credits, weights, signal], [prefix, low_priority]) = (keys, args) else { return Err(wrong_arguments()) };
+    let low_priority = low_priority == b"1";
+
+    let tenant_count = keyspace.read::<VecDeque<Vec<u8>>>(active)?.map(|list| list.len()).unwrap_or_default();
+    for _ in 0..tenant_count * 100 + 1 {
+        let Some(tenant) = keyspace.read::<VecDeque<Vec<u8>>>(active)?.and_then(|list| list.front().cloned()) else {
+            return Ok(Value::Nil)
+        };
+        let mut queue = prefix.clone();
+        queue.extend_from_slice(&tenant);
+
+        if keyspace.read::<SortedSet>(&queue)?.is_none() {
+            keyspace.lpop(active, 1)?;
+            keyspace.hdel(credits, std::slice::from_ref(&tenant))?;
+            continue
+        }
+
+        let mut credit = keyspace.hget(credits, &tenant)?.and_then(|value| float(&value).ok()).unwrap_or(0.0);
+        if credit < 1.0 {
+            credit += keyspace.hget(weights, &tenant)?.and_then(|value| float(&value).ok()).unwrap_or(1.0);
+        }
+
+        if credit < 1.0 {
+            keyspace.write::<HashMap<Vec<u8>, Vec<u8>>>(credits)?.insert(tenant, format_score(credit));
+            keyspace.write::<VecDeque<Vec<u8>>>(active)?.rotate_left(1);
+            continue
+        }
+
+        let popped = keyspace.zpop(&queue, 1, low_priority)?;
+        credit -= 1.0;
+
+        if keyspace.read::<SortedSet>(&queue)?.is_none() {
+            keyspace.lpop(active, 1)?;
+            keyspace.hdel(credits, std::slice::from_ref(&tenant))?;
+        } else {
+            keyspace.write::<HashMap<Vec<u8>, Vec<u8>>>(credits)?.insert(tenant, format_score(credit));
+            if credit < 1.0 {
+                keyspace.write::<VecDeque<Vec<u8>>>(active)?.rotate_left(1);
+            }
+        }
+
+        if keyspace.read::<VecDeque<Vec<u8>>>(active)?.is_some() {
+            fair_signal(keyspace, signal)?;
+        }
+        return Ok(popped.into_iter().next().map(|(member, _)| bulk(member)).unwrap_or(Value::Nil))
+    }
+    Ok(Value::Nil)
+}
+
+/// Leave a single wakeup in the signal list of a fair queue
+fn fair_signal(keyspace: &mut Keyspace, signal: &[u8]) -> RedisResult<()> {
+    let list = keyspace.write::<VecDeque<Vec<u8>>>(signal)?;
+    list.clear();
+    list.push_back(b"1".to_vec());
+    Ok(())
+}
+
+/// Lua booleans are converted to 1 or nil in replies
+fn lua_bool(value: bool) -> Value {
+    if value { Value::Int(1) } else { Value::Nil }
+}
+
+fn send_message(sender: &mpsc::UnboundedSender<Msg>, message: Vec<Value>) -> bool {
+    match Msg::from_owned_value(Value::Array(message)) {
+        Some(message) => sender.send(message).is_ok(),
+        None => false,
+    }
+}
+
+/// Match a key or channel against a redis glob style pattern
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some(end) = rest.iter().skip(1).position(|c| *c == b']').map(|end| end + 1) else {
+                return text.first() == Some(&b'[') && glob_match(rest, &text[1..])
+            };
+            let Some(first) = text.first() else { return false };
+            let (negate, class) = match rest[..end].strip_prefix(b"^") {
+                Some(class) => (true, class),
+                None => (false, &rest[..end]),
+            };
+            let mut matched = false;
+            let mut index = 0;
+            while index < class.len() {
+                if index + 2 < class.len() && class[index + 1] == b'-' {
+                    matched |= (class[index]..=class[index + 2]).contains(first);
+                    index += 3;
+                } else {
+                    matched |= class[index] == *first;
+                    index += 1;
+                }
+            }
+            matched != negate && glob_match(&rest[end + 1..], &text[1..])
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Convert redis style inclusive indices, which may count from the end, into a range
+fn index_range(len: usize, start: i64, stop: i64) -> (usize, usize) {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop {
+        return (0, 0)
+    }
+    (start as usize, (stop + 1) as usize)
+}
+
+/// Format scores the way redis does, without a fraction for integral values
+fn format_score(score: f64) -> Vec<u8> {
+    if score.is_infinite() {
+        return if score > 0.0 { b"inf".to_vec() } else { b"-inf".to_vec() }
+    }
+    if score.fract() == 0.0 && score.abs() < 1e17 {
+        return (score as i64).to_string().into_bytes()
+    }
+    score.to_string().into_bytes()
+}
+
+fn bulk(data: impl AsRef<[u8]>) -> Value {
+    Value::BulkString(data.as_ref().to_vec())
+}
+
+fn integer(data: &[u8]) -> RedisResult<i64> {
+    std::str::from_utf8(data).ok().and_then(|value| value.parse().ok())
+        .ok_or_else(|| error("value is not an integer or out of range"))
+}
+
+fn float(data: &[u8]) -> RedisResult<f64> {
+    std::str::from_utf8(data).ok().and_then(|value| value.parse().ok())
+        .ok_or_else(|| error("value is not a valid float"))
+}
+
+/// Parse a list end, true for the left
+fn direction(data: &[u8]) -> RedisResult<bool> {
+    match data.to_ascii_uppercase().as_slice() {
+        b"LEFT" => Ok(true),
+        b"RIGHT" => Ok(false),
+        _ => Err(error("syntax error")),
+    }
+}
+
+fn arity(args: &[Vec<u8>], min: usize, max: usize) -> RedisResult<()> {
+    if args.len() < min || args.len() > max {
+        return Err(wrong_arguments())
+    }
+    Ok(())
+}
+
+fn error(message: &'static str) -> RedisError {
+    RedisError::from((ErrorKind::ResponseError, message))
+}
+
+fn wrong_arguments() -> RedisError {
+    error("wrong number of arguments")
+}
+
+fn wrong_type() -> RedisError {
+    RedisError::from((ErrorKind::TypeError, "WRONGTYPE Operation against a key holding the wrong kind of value"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::RedisObjects;
+
+    use super::glob_match;
+
+    #[test]
+    fn patterns() {
+        assert!(glob_match(b"changes.*", b"changes.services.extract"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(!glob_match(b"changes.*", b"other"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+    }
+
+    #[tokio::test]
+    async fn shared_by_name() {
+        let first = RedisObjects::open_memory("shared_by_name");
+        let second = RedisObjects::open_memory("shared_by_name");
+        let other = RedisObjects::open_memory("shared_by_name_other");
+
+        first.queue::<u32>("queue".to_owned(), None).push(&1).await.unwrap();
+        assert_eq!(second.queue::<u32>("queue".to_owned(), None).pop().await.unwrap(), Some(1));
+        first.queue::<u32>("queue".to_owned(), None).push(&1).await.unwrap();
+        assert_eq!(other.queue::<u32>("queue".to_owned(), None).length().await.unwrap(), 0);
+        assert_eq!(first.keys("qu*").await.unwrap(), ["queue"]);
+        first.wipe().await.unwrap();
+        assert!(second.keys("*").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocking_pop() {
+        let redis = RedisObjects::open_memory("blocking_pop");
+        let queue = redis.queue::<u32>("queue".to_owned(), None);
+        let priority = redis.priority_queue::<u32>("priority".to_owned());
+
+        // nothing arrives before the timeout
+        assert_eq!(queue.pop_timeout(Duration::from_millis(50)).await.unwrap(), None);
+        assert_eq!(priority.blocking_pop(Duration::from_millis(50), false).await.unwrap(), None);
+
+        // waiting pops are woken by writes
+        let writer = redis.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.queue::<u32>("queue".to_owned(), None).push(&10).await.unwrap();
+            writer.priority_queue::<u32>("priority".to_owned()).push(1.0, &20).await.unwrap();
+        });
+        assert_eq!(queue.pop_timeout(Duration::from_secs(5)).await.unwrap(), Some(10));
+        assert_eq!(priority.blocking_pop(Duration::from_secs(5), false).await.unwrap(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn pubsub() {
+        let redis = RedisObjects::open_memory("pubsub");
+        let mut exact = redis.subscribe_json::<u32>("changes.test".to_owned()).await;
+        let mut pattern = redis.pubsub_json_listener::<u32>().psubscribe("changes.*".to_owned()).listen().await;
+
+        assert_eq!(redis.publish_json("changes.test", &100).await.unwrap(), 2);
+        assert_eq!(redis.publish_json("changes.other", &200).await.unwrap(), 1);
+        assert_eq!(redis.publish_json("unrelated", &300).await.unwrap(), 0);
+
+        assert_eq!(exact.recv().await.unwrap(), Some(100));
+        assert_eq!(pattern.recv().await.unwrap(), Some(100));
+        assert_eq!(pattern.recv().await.unwrap(), Some(200));
+    }
+
+    #[tokio::test]
+    async fn scripts() {
+        let redis = RedisObjects::open_memory("scripts");
+
+        let hash = redis.hashmap::<String>("hash".to_owned(), None);
+        hash.add("key", &"value".to_owned()).await.unwrap();
+        assert!(!hash.conditional_remove("key", &"value1".to_owned()).await.unwrap());
+        assert!(hash.conditional_remove("key", &"value".to_owned()).await.unwrap());
+        assert_eq!(hash.length().await.unwrap(), 0);
+
+        let set = redis.set::<String>("set".to_owned());
+        set.add_batch(&["a".to_owned(), "b".to_owned()]).await.unwrap();
+        assert_eq!(set.drop(&"a".to_owned()).await.unwrap(), 1);
+
+        let quota = redis.user_quota_tracker("quota".to_owned()).set_timeout(Duration::from_secs(1));
+        for _ in 0..3 {
+            assert!(quota.begin("user", 3).await.unwrap());
+        }
+        assert!(!quota.begin("user", 3).await.unwrap());
+        quota.end("user").await.unwrap();
+        assert!(quota.begin("user", 3).await.unwrap());
+        assert!(!quota.begin("user", 3).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert!(quota.begin("user", 3).await.unwrap());
+    }
+}
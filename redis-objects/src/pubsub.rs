@@ -9,7 +9,7 @@ use tokio::sync::mpsc;
 use serde::de::DeserializeOwned;
 use tracing::instrument;
 
-use crate::{retry_call, ErrorTypes, RedisObjects};
+use crate::{retry_call, ErrorTypes, Pool, RedisObjects};
 
 /// Struct to setup a stream reading from a pubsub
 /// The content of the pubsub is not processed
@@ -39,6 +39,20 @@ impl ListenerBuilder {
     pub async fn listen(self) -> mpsc::Receiver<Option<Msg>> {
 
         let (message_sender, message_receiver) = mpsc::channel(64);
+
+        if let Pool::Memory(server) = &self.store.pool {
+            // subscriptions on the memory store are in place as soon as they are registered
+            let mut messages = server.subscribe(self.channels, self.patterns);
+            tokio::spawn(async move {
+                while let Some(message) = messages.recv().await {
+                    if message_sender.send(Some(message)).await.is_err() {
+                        break
+                    }
+                }
+            });
+            return message_receiver
+        }
+
         let started = Arc::new(tokio::sync::Notify::new());
         let notify_started = started.clone();
 
@@ -54,7 +68,8 @@ impl ListenerBuilder {
                 }
                 exponent = (exponent + 1.0).min(maximum);
 
-                let mut pubsub = match self.store.client.get_async_pubsub().await {
+                // resolved on every attempt so that sentinel failovers are followed
+                let mut pubsub = match self.store.pool.pubsub().await {
                     Ok(connection) => connection,
                     Err(connection_error) => {
                         error!("Error connecting to pubsub: {connection_error}");
@@ -5,7 +5,7 @@ use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
-use redis::AsyncCommands;
+use redis::{AsyncCommands, Direction};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tracing::instrument;
@@ -46,6 +46,11 @@ impl<T: Serialize + DeserializeOwned> Queue<T> {
         self.raw.store.clone()
     }
 
+    /// get key name used for this queue
+    pub fn name(&self) -> &str {
+        self.raw.name()
+    }
+
     /// enqueue a single item
     pub async fn push(&self, data: &T) -> Result<(), ErrorTypes> {
         self.raw.push(&serde_json::to_vec(data)?).await
@@ -108,6 +113,22 @@ impl<T: Serialize + DeserializeOwned> Queue<T> {
         })
     }
 
+    /// Move the item at the front of this queue to the back of another in a single step, returning immediately if empty
+    pub async fn pop_into(&self, other: &Queue<T>) -> Result<Option<T>, ErrorTypes> {
+        Ok(match self.raw.pop_into(&other.raw).await? { 
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None
+        })
+    }
+
+    /// Move the item at the front of this queue to the back of another in a single step, waiting up to the timeout for one
+    pub async fn pop_into_timeout(&self, other: &Queue<T>, timeout: Duration) -> Result<Option<T>, ErrorTypes> {
+        Ok(match self.raw.pop_into_timeout(&other.raw, timeout).await? { 
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None
+        })
+    }
+
     /// Pop as many items as possible up to a certain limit
     pub async fn pop_batch(&self, limit: usize) -> Result<Vec<T>, ErrorTypes> {
         let response: Vec<Vec<u8>> = self.raw.pop_batch(limit).await?;
@@ -160,6 +181,11 @@ impl RawQueue {
         }
     }
 
+    /// get key name used for this queue
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
     /// set the expiry on the queue if it has not been recently set
     async fn conditional_expire(&self) -> Result<(), ErrorTypes> {
         // load the ttl of this object has one set
@@ -248,6 +274,28 @@ impl RawQueue {
         Ok(response.map(|(_, data)| data))
     }
 
+    /// Move the item at the front of this queue to the back of another in a single step, returning immediately if empty.
+    /// On a redis cluster both queues must be stored in the same slot.
+    #[instrument]
+    pub async fn pop_into(&self, other: &RawQueue) -> Result<Option<Vec<u8>>, ErrorTypes> {
+        let response: Option<Vec<u8>> = retry_call!(self.store.pool, lmove, &self.name, &other.name, Direction::Left, Direction::Right)?;
+        if response.is_some() {
+            other.conditional_expire().await?;
+        }
+        Ok(response)
+    }
+
+    /// Move the item at the front of this queue to the back of another in a single step, waiting up to the timeout for one.
+    /// On a redis cluster both queues must be stored in the same slot.
+    #[instrument]
+    pub async fn pop_into_timeout(&self, other: &RawQueue, timeout: Duration) -> Result<Option<Vec<u8>>, ErrorTypes> {
+        let response: Option<Vec<u8>> = retry_call!(self.store.pool, blmove, &self.name, &other.name, Direction::Left, Direction::Right, timeout.as_secs_f64())?;
+        if response.is_some() {
+            other.conditional_expire().await?;
+        }
+        Ok(response)
+    }
+
     /// Pop as many items as possible up to a certain limit
     #[instrument]
     pub async fn pop_batch(&self, limit: usize) -> Result<Vec<Vec<u8>>, ErrorTypes> {
@@ -283,7 +331,7 @@ impl RawQueue {
 ///   maximum score to pop
 ///   number of elements to skip before popping any
 ///   max element count to pop
-const PQ_DEQUEUE_RANGE_SCRIPT: &str = r#"
+pub (crate) const PQ_DEQUEUE_RANGE_SCRIPT: &str = r#"
 local unpack = table.unpack or unpack
 local min_score = tonumber(ARGV[1]);
 if min_score == nil then min_score = -math.huge end
@@ -298,7 +346,7 @@ return entries
 "#;
 
 /// The length of prefixes added to the entries in the priority queue
-const SORTING_KEY_LEN: usize = 21;
+pub (crate) const SORTING_KEY_LEN: usize = 21;
 
 /// A priority queue implemented on a redis sorted set
 pub struct PriorityQueue<T> {
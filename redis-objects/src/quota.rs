@@ -27,7 +27,7 @@ use crate::{retry_call, ErrorTypes, RedisObjects};
         //     self.quota_tracker.end(submission.params.submitter)
 
        
-const BEGIN_SCRIPT: &str = r#"
+pub (crate) const BEGIN_SCRIPT: &str = r#"
 local t = redis.call('time')
 local key = tonumber(t[1] .. string.format("%06d", t[2]))
 
@@ -0,0 +1,306 @@
+//! Rate limiters shared between every process using the same redis server.
+//!
+//! A sliding window counts the events in the trailing period and refuses any that would take the count
+//! above the limit, this gives exact limits like "100 per day" at the cost of keeping every event in the
+//! window. A token bucket only keeps a token count and the time it was last updated, it allows bursts up
+//! to its capacity and then limits to the refill rate.
+//!
+//! In both cases the limit is given with each call, as with `UserQuotaTracker`, so different keys under
+//! the same limiter can be held to different limits.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use tracing::instrument;
+
+use crate::{retry_call, ErrorTypes, RedisObjects};
+
+pub (crate) const SLIDING_WINDOW_SCRIPT: &str = r#"
+local t = redis.call('time')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+
+local name = KEYS[1]
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local token = ARGV[4]
+
+redis.call('zremrangebyscore', name, '-inf', now - window)
+local count = redis.call('zcard', name)
+if count + cost <= limit then
+    for index = 1, cost do
+        redis.call('zadd', name, now, token .. '-' .. index)
+    end
+    redis.call('pexpire', name, window)
+    return {1, limit - count - cost, 0}
+end
+
+local retry = window
+local oldest = redis.call('zrangebyscore', name, '-inf', '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+if cost <= limit and oldest[2] then
+    retry = tonumber(oldest[2]) + window - now
+end
+return {0, math.max(limit - count, 0), retry}
+"#;
+
+pub (crate) const TOKEN_BUCKET_SCRIPT: &str = r#"
+local t = redis.call('time')
+local now = tonumber(t[1]) + tonumber(t[2]) / 1000000
+
+local name = KEYS[1]
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local tokens = tonumber(redis.call('hget', name, 'tokens'))
+local updated = tonumber(redis.call('hget', name, 'updated'))
+if tokens == nil or updated == nil then
+    tokens = capacity
+    updated = now
+end
+tokens = math.min(capacity, tokens + math.max(now - updated, 0) * rate)
+
+local allowed = 0
+local retry = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+else
+    retry = math.ceil((cost - tokens) / rate * 1000)
+end
+
+redis.call('hset', name, 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('pexpire', name, math.ceil(capacity / rate * 1000) + 1000)
+return {allowed, math.floor(tokens), retry}
+"#;
+
+/// Outcome of asking a rate limiter for permission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateDecision {
+    /// Was the request within the limit
+    pub allowed: bool,
+    /// How many more requests would currently be allowed
+    pub remaining: u64,
+    /// How long until the refused request could be allowed, zero when it was allowed
+    pub retry_after: Duration,
+}
+
+impl RateDecision {
+    fn from_reply((allowed, remaining, retry_after): (i64, i64, i64)) -> Self {
+        Self {
+            allowed: allowed == 1,
+            remaining: remaining.max(0) as u64,
+            retry_after: Duration::from_millis(retry_after.max(0) as u64),
+        }
+    }
+}
+
+/// Limit events to a number per trailing window of time
+#[derive(Clone)]
+pub struct SlidingWindow {
+    store: Arc<RedisObjects>,
+    prefix: String,
+    window: Duration,
+    script: redis::Script,
+}
+
+impl std::fmt::Debug for SlidingWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlidingWindow").field("store", &self.store).field("prefix", &self.prefix).field("window", &self.window).finish()
+    }
+}
+
+impl SlidingWindow {
+    pub (crate) fn new(store: Arc<RedisObjects>, prefix: String, window: Duration) -> Self {
+        Self {
+            store,
+            prefix,
+            window: window.max(Duration::from_millis(1)),
+            script: redis::Script::new(SLIDING_WINDOW_SCRIPT),
+        }
+    }
+
+    fn key_name(&self, key: &str) -> String {
+        format!("{}-{key}", self.prefix)
+    }
+
+    /// Record a single event for the key if that keeps it within the limit
+    pub async fn acquire(&self, key: &str, limit: u64) -> Result<RateDecision, ErrorTypes> {
+        self.acquire_many(key, limit, 1).await
+    }
+
+    /// Record a number of events for the key if all of them fit within the limit, none are recorded otherwise
+    pub async fn acquire_many(&self, key: &str, limit: u64, count: u64) -> Result<RateDecision, ErrorTypes> {
+        self.acquire_as(key, limit, count, &format!("{:016x}", rand::random::<u64>())).await
+    }
+
+    /// Record events as `acquire_many` does, under a token chosen by the caller so they can be taken back with `release`.
+    /// The token should be unique to this call.
+    #[instrument]
+    pub async fn acquire_as(&self, key: &str, limit: u64, count: u64, token: &str) -> Result<RateDecision, ErrorTypes> {
+        let mut call = self.script.key(self.key_name(key));
+        let call = call.arg(limit).arg(self.window.as_millis() as u64).arg(count).arg(token);
+        let reply: (i64, i64, i64) = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(RateDecision::from_reply(reply))
+    }
+
+    /// Take back the events recorded by an allowed call to `acquire_as` with the same token and count
+    #[instrument]
+    pub async fn release(&self, key: &str, count: u64, token: &str) -> Result<(), ErrorTypes> {
+        let members: Vec<String> = (1..=count).map(|index| format!("{token}-{index}")).collect();
+        if members.is_empty() {
+            return Ok(())
+        }
+        retry_call!(self.store.pool, zrem, self.key_name(key), &members)
+    }
+
+    /// Forget all the events recorded for the key
+    #[instrument]
+    pub async fn reset(&self, key: &str) -> Result<(), ErrorTypes> {
+        retry_call!(self.store.pool, del, self.key_name(key))
+    }
+}
+
+/// Limit events to a steady rate while allowing short bursts
+#[derive(Clone)]
+pub struct TokenBucket {
+    store: Arc<RedisObjects>,
+    prefix: String,
+    script: redis::Script,
+}
+
+impl std::fmt::Debug for TokenBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenBucket").field("store", &self.store).field("prefix", &self.prefix).finish()
+    }
+}
+
+impl TokenBucket {
+    pub (crate) fn new(store: Arc<RedisObjects>, prefix: String) -> Self {
+        Self {
+            store,
+            prefix,
+            script: redis::Script::new(TOKEN_BUCKET_SCRIPT),
+        }
+    }
+
+    fn key_name(&self, key: &str) -> String {
+        format!("{}-{key}", self.prefix)
+    }
+
+    /// Take a token from the bucket for the key, which holds up to `capacity` tokens and
+    /// regains `refill_per_second` of them every second. A bucket not seen before starts full.
+    pub async fn acquire(&self, key: &str, capacity: u64, refill_per_second: f64) -> Result<RateDecision, ErrorTypes> {
+        self.acquire_many(key, capacity, refill_per_second, 1).await
+    }
+
+    /// Take a number of tokens at once, none are taken if there aren't enough
+    #[instrument]
+    pub async fn acquire_many(&self, key: &str, capacity: u64, refill_per_second: f64, count: u64) -> Result<RateDecision, ErrorTypes> {
+        // a bucket that never refills could never be retried
+        let refill_per_second = refill_per_second.max(f64::MIN_POSITIVE);
+        let mut call = self.script.key(self.key_name(key));
+        let call = call.arg(capacity).arg(refill_per_second).arg(count);
+        let reply: (i64, i64, i64) = retry_call!(method, self.store.pool, call, invoke_async)?;
+        Ok(RateDecision::from_reply(reply))
+    }
+
+    /// Refill the bucket for the key
+    #[instrument]
+    pub async fn reset(&self, key: &str) -> Result<(), ErrorTypes> {
+        retry_call!(self.store.pool, del, self.key_name(key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::test::{memory_connection, redis_connection};
+    use crate::{ErrorTypes, RedisObjects};
+
+    #[tokio::test]
+    async fn sliding_window() -> Result<(), ErrorTypes> {
+        check_sliding_window(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn sliding_window_memory() -> Result<(), ErrorTypes> {
+        check_sliding_window(memory_connection("sliding_window")).await
+    }
+
+    async fn check_sliding_window(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        let limiter = redis.sliding_window("test-window".to_owned(), Duration::from_millis(500));
+        limiter.reset("user").await?;
+        limiter.reset("other").await?;
+
+        // events are allowed up to the limit
+        for remaining in (0..3).rev() {
+            let decision = limiter.acquire("user", 3).await?;
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let refused = limiter.acquire("user", 3).await?;
+        assert!(!refused.allowed);
+        assert!(refused.retry_after > Duration::ZERO && refused.retry_after <= Duration::from_millis(500));
+
+        // keys are counted separately and can have their own limits
+        assert!(limiter.acquire("other", 5).await?.allowed);
+        assert!(limiter.acquire("user", 4).await?.allowed);
+        assert!(!limiter.acquire_many("other", 5, 5).await?.allowed);
+        assert!(limiter.acquire_many("other", 5, 4).await?.allowed);
+
+        // events age out of the window
+        tokio::time::sleep(refused.retry_after + Duration::from_millis(50)).await;
+        assert!(limiter.acquire("user", 3).await?.allowed);
+
+        limiter.reset("user").await?;
+        assert_eq!(limiter.acquire("user", 3).await?.remaining, 2);
+
+        // events recorded under a token can be taken back
+        assert!(limiter.acquire_as("user", 3, 2, "undo").await?.allowed);
+        assert!(!limiter.acquire("user", 3).await?.allowed);
+        limiter.release("user", 2, "undo").await?;
+        assert_eq!(limiter.acquire("user", 3).await?.remaining, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_bucket() -> Result<(), ErrorTypes> {
+        check_token_bucket(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn token_bucket_memory() -> Result<(), ErrorTypes> {
+        check_token_bucket(memory_connection("token_bucket")).await
+    }
+
+    async fn check_token_bucket(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        let bucket = redis.token_bucket("test-bucket".to_owned());
+        bucket.reset("source").await?;
+
+        // a new bucket allows a burst up to its capacity
+        for _ in 0..4 {
+            assert!(bucket.acquire("source", 4, 10.0).await?.allowed);
+        }
+        let refused = bucket.acquire("source", 4, 10.0).await?;
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert!(refused.retry_after > Duration::ZERO && refused.retry_after <= Duration::from_millis(100));
+
+        // tokens come back at the refill rate
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let decision = bucket.acquire_many("source", 4, 10.0, 2).await?;
+        assert!(decision.allowed);
+        assert!(!bucket.acquire_many("source", 4, 10.0, 4).await?.allowed);
+
+        // but never past the capacity
+        bucket.reset("source").await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!bucket.acquire_many("source", 4, 10.0, 5).await?.allowed);
+        assert!(bucket.acquire_many("source", 4, 10.0, 4).await?.allowed);
+        Ok(())
+    }
+}
@@ -12,7 +12,7 @@ use tracing::instrument;
 
 use crate::{RedisObjects, ErrorTypes, retry_call};
 
-const DROP_CARD_SCRIPT: &str = r#"
+pub (crate) const DROP_CARD_SCRIPT: &str = r#"
 local set_name = KEYS[1]
 local key = ARGV[1]
 
@@ -20,7 +20,7 @@ redis.call('srem', set_name, key)
 return redis.call('scard', set_name)
 "#;
 
-const LIMITED_ADD: &str = r#"
+pub (crate) const LIMITED_ADD: &str = r#"
 local set_name = KEYS[1]
 local key = ARGV[1]
 local limit = tonumber(ARGV[2])
@@ -0,0 +1,515 @@
+//! A durable queue built on a redis stream read through a consumer group.
+//!
+//! Unlike the list based queues, reading a message doesn't remove it. Each message stays pending
+//! against the consumer that read it until it is acknowledged, so a consumer that crashes part way
+//! through handling a message doesn't lose it. Once a message has been pending for longer than
+//! the claim timeout any other consumer of the group can take it over. A message that keeps getting
+//! delivered without being acknowledged is moved to a dead letter stream instead of being retried forever.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use parking_lot::Mutex;
+use redis::streams::{StreamAutoClaimOptions, StreamAutoClaimReply, StreamPendingCountReply, StreamRangeReply, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::{retry_call, ErrorTypes, RedisObjects};
+
+/// Field of each stream entry holding the serialized message
+const DATA_FIELD: &str = "data";
+
+/// Move items from the front of a list onto a stream without looking at their content
+pub (crate) const TRANSFER_SCRIPT: &str = r#"
+local items = redis.call('lpop', KEYS[1], ARGV[1])
+if not items then
+    return 0
+end
+for _, item in ipairs(items) do
+    redis.call('xadd', KEYS[2], '*', ARGV[2], item)
+end
+return #items
+"#;
+
+/// A message read from a stream queue, it must be acknowledged once it has been handled
+#[derive(Debug)]
+pub struct Delivery<T> {
+    /// Id of the stream entry, used to acknowledge it
+    pub id: String,
+    /// Message content
+    pub message: T,
+    /// Number of times this message has been handed to a consumer, including this one
+    pub deliveries: u64,
+}
+
+/// A message that has been read by a consumer but not acknowledged yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEntry {
+    /// Id of the stream entry
+    pub id: String,
+    /// Consumer currently responsible for the message
+    pub consumer: String,
+    /// Time since the message was last delivered
+    pub idle: Duration,
+    /// Number of times this message has been handed to a consumer
+    pub deliveries: u64,
+}
+
+/// A message that was given up on, either because it was never acknowledged or couldn't be parsed
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    /// Id of the entry in the dead letter stream
+    pub id: String,
+    /// Id the message had in the original stream
+    pub source_id: String,
+    /// Number of times the message was delivered before it was given up on
+    pub deliveries: u64,
+    /// The serialized message
+    pub data: Vec<u8>,
+}
+
+/// A queue where messages are kept until a consumer acknowledges them
+pub struct StreamQueue<T: Serialize + DeserializeOwned> {
+    name: String,
+    group: String,
+    consumer: String,
+    store: Arc<RedisObjects>,
+    max_deliveries: u64,
+    claim_idle: Duration,
+    state: Arc<StreamState>,
+    transfer_script: redis::Script,
+    _data: PhantomData<T>,
+}
+
+/// State shared between the clones of a stream queue
+struct StreamState {
+    /// Set once the consumer group is known to exist
+    group_ready: AtomicBool,
+    /// Earliest time to look for abandoned messages again after finding none
+    next_claim: Mutex<Instant>,
+}
+
+impl<T: Serialize + DeserializeOwned> std::fmt::Debug for StreamQueue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamQueue")
+            .field("name", &self.name)
+            .field("group", &self.group)
+            .field("consumer", &self.consumer)
+            .finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Clone for StreamQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            group: self.group.clone(),
+            consumer: self.consumer.clone(),
+            store: self.store.clone(),
+            max_deliveries: self.max_deliveries,
+            claim_idle: self.claim_idle,
+            state: self.state.clone(),
+            transfer_script: self.transfer_script.clone(),
+            _data: PhantomData,
+        }
+    }
+}
+
+/// Check if an error was raised because the stream or its consumer group doesn't exist
+fn is_missing_group(err: &ErrorTypes) -> bool {
+    matches!(err, ErrorTypes::Redis(err) if err.code() == Some("NOGROUP"))
+}
+
+impl<T: Serialize + DeserializeOwned> StreamQueue<T> {
+    pub (crate) fn new(name: String, group: String, store: Arc<RedisObjects>) -> Self {
+        Self {
+            name,
+            group,
+            consumer: format!("{:016x}", rand::random::<u64>()),
+            store,
+            max_deliveries: 5,
+            claim_idle: Duration::from_secs(60),
+            state: Arc::new(StreamState {
+                group_ready: AtomicBool::new(false),
+                next_claim: Mutex::new(Instant::now()),
+            }),
+            transfer_script: redis::Script::new(TRANSFER_SCRIPT),
+            _data: PhantomData,
+        }
+    }
+
+    /// Set the name this process reads messages under, a random name is used by default.
+    /// Reusing the name after a restart lets the messages pending from before the restart be recognized.
+    pub fn set_consumer(mut self, consumer: String) -> Self {
+        self.consumer = consumer;
+        self
+    }
+
+    /// Set how many times a message is delivered before it is moved to the dead letter stream
+    pub fn set_max_deliveries(mut self, max_deliveries: u64) -> Self {
+        self.max_deliveries = max_deliveries.max(1);
+        self
+    }
+
+    /// Set how long a message can go unacknowledged before another consumer may take it over
+    pub fn set_claim_idle(mut self, claim_idle: Duration) -> Self {
+        self.claim_idle = claim_idle;
+        self
+    }
+
+    /// Get a reference to the server/object collection holding this queue
+    pub fn host(&self) -> Arc<RedisObjects> {
+        self.store.clone()
+    }
+
+    /// Name of the stream holding the queue
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the stream messages are moved to when they are given up on
+    pub fn dead_letter_name(&self) -> String {
+        format!("{}-dead-letter", self.name)
+    }
+
+    /// Create the consumer group (and the stream along with it) if it hasn't been done yet
+    async fn ensure_group(&self) -> Result<(), ErrorTypes> {
+        if self.state.group_ready.load(Ordering::Acquire) {
+            return Ok(())
+        }
+        let result: Result<(), ErrorTypes> = retry_call!(self.store.pool, xgroup_create_mkstream, &self.name, &self.group, "0");
+        match result {
+            Ok(()) => {},
+            Err(ErrorTypes::Redis(err)) if err.code() == Some("BUSYGROUP") => {},
+            Err(err) => return Err(err),
+        }
+        self.state.group_ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// enqueue a single item
+    #[instrument(skip(data))]
+    pub async fn push(&self, data: &T) -> Result<String, ErrorTypes> {
+        let data = serde_json::to_vec(data)?;
+        let id: Option<String> = retry_call!(self.store.pool, xadd, &self.name, "*", &[(DATA_FIELD, &data)])?;
+        id.ok_or(ErrorTypes::UnknownRedisError)
+    }
+
+    /// enqueue a sequence of items
+    #[instrument(skip(data))]
+    pub async fn push_batch(&self, data: &[T]) -> Result<Vec<String>, ErrorTypes> {
+        if data.is_empty() {
+            return Ok(vec![])
+        }
+        let mut pipe = redis::pipe();
+        for item in data {
+            pipe.xadd(&self.name, "*", &[(DATA_FIELD, serde_json::to_vec(item)?)]);
+        }
+        Ok(retry_call!(method, self.store.pool, pipe, query_async)?)
+    }
+
+    /// Move up to `limit` items from the front of a list onto the stream in a single step.
+    ///
+    /// Items are moved as they are, any that can't be parsed are dead lettered when they are read.
+    /// On a redis cluster the list must be stored in the same slot as the stream.
+    #[instrument]
+    pub async fn transfer_from_list(&self, list: &str, limit: usize) -> Result<usize, ErrorTypes> {
+        let mut call = self.transfer_script.key(list);
+        call.key(&self.name).arg(limit).arg(DATA_FIELD);
+        retry_call!(method, self.store.pool, call, invoke_async)
+    }
+
+    /// Take the next message, waiting up to the timeout for one to arrive. A zero timeout doesn't wait.
+    ///
+    /// Messages abandoned by other consumers are taken over before new ones are read. The message
+    /// returned stays in the queue until it is acknowledged with `ack`. Messages that can't be parsed
+    /// are moved to the dead letter stream and reported as an error.
+    #[instrument]
+    pub async fn pop(&self, timeout: Duration) -> Result<Option<Delivery<T>>, ErrorTypes> {
+        match self._pop(timeout).await {
+            // the stream has been removed since the group was created
+            Err(err) if is_missing_group(&err) => {
+                self.state.group_ready.store(false, Ordering::Release);
+                self._pop(timeout).await
+            },
+            result => result,
+        }
+    }
+
+    async fn _pop(&self, timeout: Duration) -> Result<Option<Delivery<T>>, ErrorTypes> {
+        self.ensure_group().await?;
+
+        if let Some(delivery) = self.claim().await? {
+            return Ok(Some(delivery))
+        }
+
+        let mut options = StreamReadOptions::default()
+            .group(&self.group, &self.consumer)
+            .count(1);
+        // a block of zero means waiting forever, leaving it out returns right away
+        if !timeout.is_zero() {
+            options = options.block((timeout.as_millis() as usize).max(1));
+        }
+        let reply: Option<StreamReadReply> = retry_call!(self.store.pool, xread_options, &[&self.name], &[">"], &options)?;
+        let entry = reply.into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .next();
+        match entry {
+            Some(entry) => {
+                let data = entry.get(DATA_FIELD);
+                self.decode(entry.id, data, 1).await.map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Take over the oldest message that has been left unacknowledged for too long
+    async fn claim(&self) -> Result<Option<Delivery<T>>, ErrorTypes> {
+        if Instant::now() < *self.state.next_claim.lock() {
+            return Ok(None)
+        }
+
+        let min_idle = self.claim_idle.as_millis() as usize;
+        let mut start = "0-0".to_owned();
+        loop {
+            let reply: StreamAutoClaimReply = retry_call!(self.store.pool, xautoclaim_options,
+                &self.name, &self.group, &self.consumer, min_idle, &start, StreamAutoClaimOptions::default().count(1))?;
+
+            if let Some(entry) = reply.claimed.into_iter().next() {
+                let deliveries = self.delivery_count(&entry.id).await?;
+                if deliveries > self.max_deliveries {
+                    warn!("Message {} on {} was not acknowledged after {} deliveries", entry.id, self.name, deliveries - 1);
+                    let data: Vec<u8> = entry.get(DATA_FIELD).unwrap_or_default();
+                    self.dead_letter(&entry.id, &data, deliveries - 1).await?;
+                } else {
+                    let data = entry.get(DATA_FIELD);
+                    return self.decode(entry.id, data, deliveries).await.map(Some)
+                }
+            }
+
+            // the whole pending list has been scanned
+            if reply.next_stream_id == "0-0" {
+                break
+            }
+            start = reply.next_stream_id;
+        }
+
+        *self.state.next_claim.lock() = Instant::now() + self.claim_idle / 2;
+        Ok(None)
+    }
+
+    /// Read how many times a pending message has been delivered
+    async fn delivery_count(&self, id: &str) -> Result<u64, ErrorTypes> {
+        let reply: StreamPendingCountReply = retry_call!(self.store.pool, xpending_count, &self.name, &self.group, id, id, 1)?;
+        Ok(reply.ids.first().map(|entry| entry.times_delivered as u64).unwrap_or(1))
+    }
+
+    /// Parse a message that has just been delivered, dead lettering it if that isn't possible
+    async fn decode(&self, id: String, data: Option<Vec<u8>>, deliveries: u64) -> Result<Delivery<T>, ErrorTypes> {
+        let data = data.unwrap_or_default();
+        match serde_json::from_slice(&data) {
+            Ok(message) => Ok(Delivery { id, message, deliveries }),
+            Err(err) => {
+                warn!("Message {id} on {} could not be parsed: {err}", self.name);
+                self.dead_letter(&id, &data, deliveries).await?;
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Copy a message to the dead letter stream and remove it from this one
+    async fn dead_letter(&self, id: &str, data: &[u8], deliveries: u64) -> Result<(), ErrorTypes> {
+        let deliveries = deliveries.to_string();
+        let fields: [(&str, &[u8]); 3] = [
+            (DATA_FIELD, data),
+            ("source_id", id.as_bytes()),
+            ("deliveries", deliveries.as_bytes()),
+        ];
+        let mut pipe = redis::pipe();
+        pipe.xadd(self.dead_letter_name(), "*", &fields).ignore()
+            .xack(&self.name, &self.group, &[id]).ignore()
+            .xdel(&self.name, &[id]).ignore();
+        retry_call!(method, self.store.pool, pipe, query_async)
+    }
+
+    /// Mark a message as handled, removing it from the queue. Returns false if it was not pending.
+    #[instrument]
+    pub async fn ack(&self, id: &str) -> Result<bool, ErrorTypes> {
+        let mut pipe = redis::pipe();
+        pipe.xack(&self.name, &self.group, &[id])
+            .xdel(&self.name, &[id]).ignore();
+        let (acknowledged,): (usize,) = retry_call!(method, self.store.pool, pipe, query_async)?;
+        Ok(acknowledged > 0)
+    }
+
+    /// List up to `count` of the messages delivered but not acknowledged yet, oldest first
+    #[instrument]
+    pub async fn pending(&self, count: usize) -> Result<Vec<PendingEntry>, ErrorTypes> {
+        self.ensure_group().await?;
+        let reply: Result<StreamPendingCountReply, ErrorTypes> = retry_call!(self.store.pool, xpending_count, &self.name, &self.group, "-", "+", count);
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) if is_missing_group(&err) => {
+                self.state.group_ready.store(false, Ordering::Release);
+                return Ok(vec![])
+            },
+            Err(err) => return Err(err),
+        };
+        Ok(reply.ids.into_iter().map(|entry| PendingEntry {
+            id: entry.id,
+            consumer: entry.consumer,
+            idle: Duration::from_millis(entry.last_delivered_ms as u64),
+            deliveries: entry.times_delivered as u64,
+        }).collect())
+    }
+
+    /// List up to `count` of the messages moved to the dead letter stream, oldest first
+    #[instrument]
+    pub async fn dead_letters(&self, count: usize) -> Result<Vec<DeadLetter>, ErrorTypes> {
+        let reply: StreamRangeReply = retry_call!(self.store.pool, xrange_count, self.dead_letter_name(), "-", "+", count)?;
+        Ok(reply.ids.into_iter().map(|entry| DeadLetter {
+            source_id: entry.get("source_id").unwrap_or_default(),
+            deliveries: entry.get("deliveries").unwrap_or_default(),
+            data: entry.get(DATA_FIELD).unwrap_or_default(),
+            id: entry.id,
+        }).collect())
+    }
+
+    /// Load every message in the queue into memory, including those pending acknowledgement
+    pub async fn content(&self) -> Result<Vec<T>, ErrorTypes> {
+        let reply: StreamRangeReply = retry_call!(self.store.pool, xrange_all, &self.name)?;
+        let mut out = vec![];
+        for entry in reply.ids {
+            let data: Vec<u8> = entry.get(DATA_FIELD).unwrap_or_default();
+            out.push(serde_json::from_slice(&data)?);
+        }
+        Ok(out)
+    }
+
+    /// Read the number of messages in the queue, including those pending acknowledgement
+    pub async fn length(&self) -> Result<usize, ErrorTypes> {
+        retry_call!(self.store.pool, xlen, &self.name)
+    }
+
+    /// Clear all data for this object, including the dead letter stream
+    pub async fn delete(&self) -> Result<(), ErrorTypes> {
+        let _: () = retry_call!(self.store.pool, del, &self.name)?;
+        let _: () = retry_call!(self.store.pool, del, self.dead_letter_name())?;
+        self.state.group_ready.store(false, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::test::{memory_connection, redis_connection};
+    use crate::{ErrorTypes, RedisObjects};
+
+    #[tokio::test]
+    async fn stream_queue() -> Result<(), ErrorTypes> {
+        check_stream_queue(redis_connection().await).await
+    }
+
+    #[tokio::test]
+    async fn stream_queue_memory() -> Result<(), ErrorTypes> {
+        check_stream_queue(memory_connection("stream_queue")).await
+    }
+
+    async fn check_stream_queue(redis: Arc<RedisObjects>) -> Result<(), ErrorTypes> {
+        let queue = redis.stream_queue::<u32>("test-stream".to_owned(), "workers".to_owned())
+            .set_consumer("first".to_owned())
+            .set_claim_idle(Duration::from_millis(200))
+            .set_max_deliveries(2);
+        let other = redis.stream_queue::<u32>("test-stream".to_owned(), "workers".to_owned())
+            .set_consumer("second".to_owned())
+            .set_claim_idle(Duration::from_millis(200))
+            .set_max_deliveries(2);
+        queue.delete().await?;
+
+        // messages come out in order and stay in the queue until acknowledged
+        queue.push(&1).await?;
+        queue.push_batch(&[2, 3]).await?;
+        assert_eq!(queue.length().await?, 3);
+        let first = queue.pop(Duration::from_millis(100)).await?.unwrap();
+        assert_eq!((first.message, first.deliveries), (1, 1));
+        assert_eq!(queue.length().await?, 3);
+        assert!(queue.ack(&first.id).await?);
+        assert!(!queue.ack(&first.id).await?);
+        assert_eq!(queue.length().await?, 2);
+
+        // a message left unacknowledged is listed as pending and can't be read again right away
+        let second = queue.pop(Duration::from_millis(100)).await?.unwrap();
+        assert_eq!(second.message, 2);
+        let pending = queue.pending(10).await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, second.id);
+        assert_eq!(pending[0].consumer, "first");
+        let third = other.pop(Duration::from_millis(100)).await?.unwrap();
+        assert_eq!(third.message, 3);
+        assert!(other.ack(&third.id).await?);
+        assert!(other.pop(Duration::ZERO).await?.is_none());
+        assert_eq!(queue.content().await?, vec![2]);
+
+        // once the claim timeout passes another consumer takes it over
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let retry = other.pop(Duration::from_millis(100)).await?.unwrap();
+        assert_eq!((retry.id.as_str(), retry.message, retry.deliveries), (second.id.as_str(), 2, 2));
+        assert_eq!(queue.pending(10).await?[0].consumer, "second");
+
+        // after too many deliveries it is moved to the dead letters
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(queue.pop(Duration::from_millis(50)).await?.is_none());
+        assert_eq!(queue.length().await?, 0);
+        assert!(queue.pending(10).await?.is_empty());
+        let dead = queue.dead_letters(10).await?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].source_id, second.id);
+        assert_eq!(dead[0].deliveries, 2);
+        assert_eq!(dead[0].data, b"2");
+
+        // messages that can't be parsed are dead lettered right away
+        redis.stream_queue::<String>("test-stream".to_owned(), "workers".to_owned()).push(&"abc".to_owned()).await?;
+        assert!(queue.pop(Duration::from_millis(100)).await.unwrap_err().is_serialize_error());
+        assert_eq!(queue.length().await?, 0);
+        assert_eq!(queue.dead_letters(10).await?.len(), 2);
+
+        // items left on a list are moved over as they are
+        let list = redis.queue::<String>("test-stream-list".to_owned(), None);
+        list.raw().push_batch([&b"6"[..], b"not json", b"7"].into_iter()).await?;
+        assert_eq!(queue.transfer_from_list(list.name(), 2).await?, 2);
+        assert_eq!(queue.transfer_from_list(list.name(), 2).await?, 1);
+        assert_eq!(queue.transfer_from_list(list.name(), 2).await?, 0);
+        let item = queue.pop(Duration::ZERO).await?.unwrap();
+        assert_eq!(item.message, 6);
+        queue.ack(&item.id).await?;
+        assert!(queue.pop(Duration::ZERO).await.unwrap_err().is_serialize_error());
+        assert_eq!(queue.dead_letters(10).await?[2].data, b"not json");
+        let item = queue.pop(Duration::ZERO).await?.unwrap();
+        assert_eq!(item.message, 7);
+        queue.ack(&item.id).await?;
+
+        // waiting pops are woken by new messages
+        let writer = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.push(&4).await.unwrap();
+        });
+        assert_eq!(queue.pop(Duration::from_secs(2)).await?.unwrap().message, 4);
+
+        // the group is recreated if the stream is removed
+        redis.wipe().await?;
+        queue.push(&5).await?;
+        assert_eq!(queue.pop(Duration::from_millis(100)).await?.unwrap().message, 5);
+        queue.delete().await?;
+        Ok(())
+    }
+}